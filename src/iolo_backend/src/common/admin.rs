@@ -0,0 +1,105 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use candid::{candid_method, Principal};
+
+use crate::common::error::SmartVaultErr;
+use crate::utils::caller::get_caller;
+
+thread_local! {
+    // Principals allowed to call the admin endpoints, part of the persisted heap state.
+    // Only the controller installing or upgrading the canister gets registered, not every
+    // controller: the others become admins with their next upgrade or by add_admin.
+    pub static ADMINS: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::new());
+}
+
+pub fn is_admin(principal: &Principal) -> bool {
+    ADMINS.with(|a| a.borrow().contains(principal))
+}
+
+pub fn verify_caller_is_admin() -> Result<(), SmartVaultErr> {
+    let caller = get_caller();
+    if is_admin(&caller) {
+        Ok(())
+    } else {
+        Err(SmartVaultErr::NotAuthorized(caller.to_string()))
+    }
+}
+
+// This function is called by the init and post_upgrade hooks with the installing controller.
+// The controllers of a canister cannot be listed from within, so only this one is known.
+pub fn register_controller(controller: Principal) {
+    ADMINS.with(|a| a.borrow_mut().insert(controller));
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn add_admin(principal: Principal) -> Result<(), SmartVaultErr> {
+    verify_caller_is_admin()?;
+    ADMINS.with(|a| a.borrow_mut().insert(principal));
+    Ok(())
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn remove_admin(principal: Principal) -> Result<(), SmartVaultErr> {
+    verify_caller_is_admin()?;
+    ADMINS.with(|a| {
+        let mut admins = a.borrow_mut();
+        if !admins.contains(&principal) {
+            return Err(SmartVaultErr::AdminDoesNotExist(principal.to_string()));
+        }
+        // Without admins, the admin endpoints would be locked until the next upgrade
+        if admins.len() == 1 {
+            return Err(SmartVaultErr::CannotRemoveLastAdmin(principal.to_string()));
+        }
+        admins.remove(&principal);
+        Ok(())
+    })
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_admin_list() -> Result<Vec<Principal>, SmartVaultErr> {
+    verify_caller_is_admin()?;
+    Ok(ADMINS.with(|a| a.borrow().iter().cloned().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utest_admin_endpoints() {
+        let other: Principal = Principal::from_slice(&[
+            1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+
+        // nobody is admin yet
+        assert_eq!(
+            add_admin(other),
+            Err(SmartVaultErr::NotAuthorized(get_caller().to_string()))
+        );
+        assert!(get_admin_list().is_err());
+
+        register_controller(get_caller());
+        assert_eq!(add_admin(other), Ok(()));
+        assert_eq!(get_admin_list().unwrap(), vec![get_caller(), other]);
+
+        assert_eq!(remove_admin(other), Ok(()));
+        assert_eq!(
+            remove_admin(other),
+            Err(SmartVaultErr::AdminDoesNotExist(other.to_string()))
+        );
+        assert!(!is_admin(&other));
+
+        // the last admin stays
+        assert_eq!(
+            remove_admin(get_caller()),
+            Err(SmartVaultErr::CannotRemoveLastAdmin(
+                get_caller().to_string()
+            ))
+        );
+        assert!(is_admin(&get_caller()));
+    }
+}
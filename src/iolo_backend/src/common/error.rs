@@ -2,6 +2,75 @@ use std::fmt::Display;
 
 use candid::{CandidType, Deserialize};
 
+/// A reason why a testament cannot be stored, see SmartVaultErr::InvalidTestament.
+#[derive(Debug, CandidType, PartialEq, Eq, Deserialize, Clone)]
+pub enum TestamentProblem {
+    NoHeirs,
+    /// The testator or the anonymous principal
+    InvalidHeir(String),
+    HeirNotInAddressBook(String),
+    SecretDoesNotExist(String),
+    KeyBoxEntryMissing(String),
+    /// The allocation is for a principal who is not an heir of the testament
+    AllocationForNonHeir(String),
+    AllocatedSecretNotInTestament { heir: String, secret_id: String },
+    AllocationKeyBoxEntryMissing { heir: String, secret_id: String },
+    StagedSecretNotInTestament(String),
+    SecretInSeveralStages(String),
+    InvalidCondition(String),
+    InvalidReminderMilestone(u8),
+    /// The principal is a validator and an heir of the testament
+    ValidatorIsHeir(String),
+}
+
+impl Display for TestamentProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TestamentProblem::NoHeirs => write!(f, "the testament has no heirs"),
+            TestamentProblem::InvalidHeir(heir) => {
+                write!(f, "the following principal cannot be an heir: {}", heir)
+            }
+            TestamentProblem::HeirNotInAddressBook(heir) => {
+                write!(f, "the following heir needs to be added to the heirs first: {}", heir)
+            }
+            TestamentProblem::SecretDoesNotExist(id) => {
+                write!(f, "the following secret does not exist: {}", id)
+            }
+            TestamentProblem::KeyBoxEntryMissing(id) => {
+                write!(f, "the key box has no entry for the following secret: {}", id)
+            }
+            TestamentProblem::AllocationForNonHeir(principal) => {
+                write!(f, "the following principal gets an allocation, but is no heir: {}", principal)
+            }
+            TestamentProblem::AllocatedSecretNotInTestament { heir, secret_id } => write!(
+                f,
+                "the secret {} is allocated to {}, but is not part of the testament",
+                secret_id, heir
+            ),
+            TestamentProblem::AllocationKeyBoxEntryMissing { heir, secret_id } => write!(
+                f,
+                "the allocation of {} has no key box entry for the following secret: {}",
+                heir, secret_id
+            ),
+            TestamentProblem::StagedSecretNotInTestament(id) => {
+                write!(f, "the following secret is part of a release stage, but not of the testament: {}", id)
+            }
+            TestamentProblem::SecretInSeveralStages(id) => {
+                write!(f, "the following secret is part of several release stages: {}", id)
+            }
+            TestamentProblem::InvalidCondition(reason) => {
+                write!(f, "the condition is invalid: {}", reason)
+            }
+            TestamentProblem::InvalidReminderMilestone(milestone) => {
+                write!(f, "reminder milestones need to be between 1 and 99 percent: {}", milestone)
+            }
+            TestamentProblem::ValidatorIsHeir(principal) => {
+                write!(f, "the following validator is an heir as well: {}", principal)
+            }
+        }
+    }
+}
+
 #[derive(Debug, CandidType, PartialEq, Eq, Deserialize)]
 pub enum SmartVaultErr {
     UserAlreadyExists(String),
@@ -17,6 +86,45 @@ pub enum SmartVaultErr {
     InvalidTestamentCondition,
     NoTestamentsForHeir(String),
     KeyGenerationNotAllowed,
+    NotAuthorized(String),
+    ConditionTimerDoesNotExist(String),
+    InvalidConditionTimerInterval(u64),
+    SecretNotInTestament(String),
+    KeyBoxEntryDoesNotExist(String),
+    InvalidCondition(String),
+    ReleaseNotPending(String),
+    TestamentNotReleased(String),
+    InvalidNotificationEndpoint(String),
+    DeliveryDoesNotExist(String),
+    InvitationAlreadyExists(String),
+    InvitationDoesNotExist(String),
+    InvitationExpired(String),
+    InvitationNotPending(String),
+    InvalidInvitee(String),
+    HeirIsPartOfTestaments(Vec<String>),
+    SecretVersionDoesNotExist(u64),
+    TrashItemDoesNotExist(String),
+    InvalidTestament(Vec<TestamentProblem>),
+    SecretLocked(String),
+    InvalidCheckInSchedule(String),
+    CheckInNotConfigured(String),
+    InvalidLinkedPrincipal(String),
+    PrincipalLinkDoesNotExist(String),
+    PrincipalLinkExpired(String),
+    TooManyLinkedPrincipals(u64),
+    InvalidRecoveryGuardians(String),
+    RecoveryNotConfigured(String),
+    RecoveryAlreadyRequested(String),
+    RecoveryRequestDoesNotExist(String),
+    NotARecoveryGuardian(String),
+    InvalidRecoveryPrincipal(String),
+    RecoveryWindowClosed(String),
+    RecoveryWindowOpen(u64),
+    RecoveryNotApproved(String),
+    AdminDoesNotExist(String),
+    CannotRemoveLastAdmin(String),
+    ValueTooLarge(String),
+    TooManyInvitations(u64),
 }
 
 impl Display for SmartVaultErr {
@@ -71,6 +179,124 @@ impl Display for SmartVaultErr {
             SmartVaultErr::KeyGenerationNotAllowed => {
                 write!(f, "Key cannot be generated because some conditions are not met")
             }
+            SmartVaultErr::NotAuthorized(principal) => {
+                write!(f, "The following principal is not authorized: {}", principal)
+            }
+            SmartVaultErr::ConditionTimerDoesNotExist(id) => {
+                write!(f, "Failed to read condition timer with the following id: {}", id)
+            }
+            SmartVaultErr::InvalidConditionTimerInterval(secs) => {
+                write!(f, "Condition timer interval of {} seconds is not allowed", secs)
+            }
+            SmartVaultErr::SecretNotInTestament(id) => {
+                write!(f, "The following secret is not part of the testament: {}", id)
+            }
+            SmartVaultErr::KeyBoxEntryDoesNotExist(id) => {
+                write!(f, "Failed to read key box entry for the following secret: {}", id)
+            }
+            SmartVaultErr::InvalidCondition(reason) => {
+                write!(f, "Invalid testament condition: {}", reason)
+            }
+            SmartVaultErr::ReleaseNotPending(id) => {
+                write!(f, "There is no pending release for the testament with the following id: {}", id)
+            }
+            SmartVaultErr::TestamentNotReleased(id) => {
+                write!(f, "The testament with the following id is not released: {}", id)
+            }
+            SmartVaultErr::InvalidNotificationEndpoint(url) => {
+                write!(f, "The notification endpoint needs to be an http(s) url: {}", url)
+            }
+            SmartVaultErr::DeliveryDoesNotExist(idempotency_key) => {
+                write!(f, "There is no delivery with the following idempotency key: {}", idempotency_key)
+            }
+            SmartVaultErr::InvitationAlreadyExists(claim_code) => {
+                write!(f, "The following invitation already exists: {}", claim_code)
+            }
+            SmartVaultErr::InvitationDoesNotExist(claim_code) => {
+                write!(f, "The following invitation does not exist: {}", claim_code)
+            }
+            SmartVaultErr::InvitationExpired(claim_code) => {
+                write!(f, "The following invitation has expired: {}", claim_code)
+            }
+            SmartVaultErr::InvitationNotPending(claim_code) => {
+                write!(f, "The following invitation was already accepted: {}", claim_code)
+            }
+            SmartVaultErr::InvalidInvitee(principal) => {
+                write!(f, "The following principal cannot accept the invitation: {}", principal)
+            }
+            SmartVaultErr::HeirIsPartOfTestaments(testaments) => {
+                write!(f, "The heir is still part of the following testaments: {}", testaments.join(", "))
+            }
+            SmartVaultErr::SecretVersionDoesNotExist(version) => {
+                write!(f, "The following version of the secret does not exist: {}", version)
+            }
+            SmartVaultErr::TrashItemDoesNotExist(id) => {
+                write!(f, "The following item is not in the trash: {}", id)
+            }
+            SmartVaultErr::InvalidTestament(problems) => {
+                let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+                write!(f, "The testament is invalid: {}", problems.join(", "))
+            }
+            SmartVaultErr::SecretLocked(id) => {
+                write!(f, "The following secret is not unlocked for the heirs yet: {}", id)
+            }
+            SmartVaultErr::InvalidCheckInSchedule(reason) => {
+                write!(f, "Invalid check-in schedule: {}", reason)
+            }
+            SmartVaultErr::CheckInNotConfigured(user) => {
+                write!(f, "The following user has no check-in schedule: {}", user)
+            }
+            SmartVaultErr::InvalidLinkedPrincipal(principal) => {
+                write!(f, "The following principal cannot be linked to the account: {}", principal)
+            }
+            SmartVaultErr::PrincipalLinkDoesNotExist(principal) => {
+                write!(f, "There is no link for the following principal: {}", principal)
+            }
+            SmartVaultErr::PrincipalLinkExpired(principal) => {
+                write!(f, "The link request for the following principal has expired: {}", principal)
+            }
+            SmartVaultErr::TooManyLinkedPrincipals(max) => {
+                write!(f, "An account can have at most {} linked principals", max)
+            }
+            SmartVaultErr::InvalidRecoveryGuardians(reason) => {
+                write!(f, "Invalid recovery guardians: {}", reason)
+            }
+            SmartVaultErr::RecoveryNotConfigured(account) => {
+                write!(f, "The following account has no recovery guardians: {}", account)
+            }
+            SmartVaultErr::RecoveryAlreadyRequested(account) => {
+                write!(f, "The recovery of the following account was already requested: {}", account)
+            }
+            SmartVaultErr::RecoveryRequestDoesNotExist(account) => {
+                write!(f, "There is no recovery request for the following account: {}", account)
+            }
+            SmartVaultErr::NotARecoveryGuardian(principal) => {
+                write!(f, "The following principal is not a recovery guardian of the account: {}", principal)
+            }
+            SmartVaultErr::InvalidRecoveryPrincipal(principal) => {
+                write!(f, "The account cannot be recovered to the following principal: {}", principal)
+            }
+            SmartVaultErr::RecoveryWindowClosed(account) => {
+                write!(f, "The recovery window of the following account is closed: {}", account)
+            }
+            SmartVaultErr::RecoveryWindowOpen(closes_at) => {
+                write!(f, "The recovery can be completed once the window closes at: {}", closes_at)
+            }
+            SmartVaultErr::RecoveryNotApproved(account) => {
+                write!(f, "Not enough guardians approved the recovery of the following account: {}", account)
+            }
+            SmartVaultErr::AdminDoesNotExist(principal) => {
+                write!(f, "The following principal is not an admin: {}", principal)
+            }
+            SmartVaultErr::CannotRemoveLastAdmin(principal) => {
+                write!(f, "The following principal is the last admin and cannot be removed: {}", principal)
+            }
+            SmartVaultErr::ValueTooLarge(value) => {
+                write!(f, "The following value exceeds its maximum size: {}", value)
+            }
+            SmartVaultErr::TooManyInvitations(max) => {
+                write!(f, "A testator can have at most {} open invitations", max)
+            }
         }
     }
 }
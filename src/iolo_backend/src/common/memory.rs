@@ -0,0 +1,236 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::Principal;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, Memory as StableMemory, Storable};
+
+use crate::common::error::SmartVaultErr;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Every stable structure gets its own virtual memory.
+// Never reuse or reorder these ids, otherwise existing data gets lost on upgrade.
+const UPGRADES_MEMORY_ID: u8 = 0;
+const USER_VAULTS_MEMORY_ID: u8 = 1;
+const USERS_MEMORY_ID: u8 = 2;
+const HEIR_TO_TESTAMENTS_MEMORY_ID: u8 = 3;
+const TESTAMENT_TO_TESTATOR_MEMORY_ID: u8 = 4;
+const VALIDATOR_TO_TESTAMENTS_MEMORY_ID: u8 = 5;
+const DEADLINES_MEMORY_ID: u8 = 6;
+const TESTAMENT_TO_DEADLINE_MEMORY_ID: u8 = 7;
+const OUTBOXES_MEMORY_ID: u8 = 8;
+const DELIVERIES_MEMORY_ID: u8 = 9;
+const DEAD_LETTERS_MEMORY_ID: u8 = 10;
+const INVITATIONS_MEMORY_ID: u8 = 11;
+const LINKED_PRINCIPALS_MEMORY_ID: u8 = 12;
+const PENDING_LINKS_MEMORY_ID: u8 = 13;
+const ACCOUNT_RECOVERIES_MEMORY_ID: u8 = 14;
+const DELIVERY_DUE_DATES_MEMORY_ID: u8 = 15;
+const TESTATOR_TO_INVITATIONS_MEMORY_ID: u8 = 16;
+const PURGE_DEADLINES_MEMORY_ID: u8 = 17;
+const VAULT_TO_PURGE_DEADLINE_MEMORY_ID: u8 = 18;
+const OWNER_TO_LINKS_MEMORY_ID: u8 = 19;
+
+// Upper bounds for the stable btree map entries (in bytes).
+// Every entry reserves its upper bound in stable memory, however small its value is.
+// Values chosen by the clients are checked with verify_size before they are inserted.
+pub const MAX_UUID_SIZE: u32 = 16;
+pub const MAX_PRINCIPAL_SIZE: u32 = 29;
+pub const MAX_TESTAMENT_ID_SIZE: u32 = 128;
+pub const MAX_USER_SIZE: u32 = 2_048;
+// A vault holds the encrypted secrets of a user together with their history and the trash.
+// Passwords and notes take a few KB at most, so 1 MB leaves room for hundreds of them,
+// while 64 GB of stable memory still hold the vaults of more than 50,000 users.
+pub const MAX_USER_VAULT_SIZE: u32 = 1_000_000;
+pub const MAX_TESTAMENT_ID_SET_SIZE: u32 = 100_000;
+pub const MAX_OUTBOX_SIZE: u32 = 100_000;
+pub const MAX_IDEMPOTENCY_KEY_SIZE: u32 = 128;
+pub const MAX_DELIVERY_SIZE: u32 = 10_000;
+pub const MAX_CLAIM_CODE_SIZE: u32 = 64;
+pub const MAX_INVITATION_SIZE: u32 = 4_096;
+pub const MAX_CLAIM_CODE_SET_SIZE: u32 = 8_192;
+pub const MAX_PENDING_LINK_SIZE: u32 = 256;
+pub const MAX_OWNER_LINKS_SIZE: u32 = 1_024;
+pub const MAX_ACCOUNT_RECOVERY_SIZE: u32 = 2_048;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+// The baseline wrote its state with ic_cdk::storage::stable_save, which starts with the candid magic.
+// The memory manager starts the stable memory with its own magic instead.
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+
+thread_local! {
+    // The raw stable memory, only read directly to detect the baseline layout.
+    static STABLE_MEMORY: DefaultMemoryImpl = DefaultMemoryImpl::default();
+
+    // The memory manager is used for simulating multiple memories.
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(STABLE_MEMORY.with(|m| m.clone())));
+}
+
+fn get_memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
+
+pub fn get_stable_btree_memory_for_user_vaults() -> Memory {
+    get_memory(USER_VAULTS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_users() -> Memory {
+    get_memory(USERS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_heir_to_testaments() -> Memory {
+    get_memory(HEIR_TO_TESTAMENTS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_testament_to_testator() -> Memory {
+    get_memory(TESTAMENT_TO_TESTATOR_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_validator_to_testaments() -> Memory {
+    get_memory(VALIDATOR_TO_TESTAMENTS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_deadlines() -> Memory {
+    get_memory(DEADLINES_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_testament_to_deadline() -> Memory {
+    get_memory(TESTAMENT_TO_DEADLINE_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_purge_deadlines() -> Memory {
+    get_memory(PURGE_DEADLINES_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_vault_to_purge_deadline() -> Memory {
+    get_memory(VAULT_TO_PURGE_DEADLINE_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_outboxes() -> Memory {
+    get_memory(OUTBOXES_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_deliveries() -> Memory {
+    get_memory(DELIVERIES_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_dead_letters() -> Memory {
+    get_memory(DEAD_LETTERS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_delivery_due_dates() -> Memory {
+    get_memory(DELIVERY_DUE_DATES_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_invitations() -> Memory {
+    get_memory(INVITATIONS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_testator_to_invitations() -> Memory {
+    get_memory(TESTATOR_TO_INVITATIONS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_linked_principals() -> Memory {
+    get_memory(LINKED_PRINCIPALS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_pending_links() -> Memory {
+    get_memory(PENDING_LINKS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_owner_to_links() -> Memory {
+    get_memory(OWNER_TO_LINKS_MEMORY_ID)
+}
+
+pub fn get_stable_btree_memory_for_account_recoveries() -> Memory {
+    get_memory(ACCOUNT_RECOVERIES_MEMORY_ID)
+}
+
+/// Writes the (small) heap state which is not living in a stable structure
+/// into the upgrades memory. The layout is: [length: u64 LE][bytes].
+pub fn save_upgrade_state(bytes: &[u8]) {
+    let memory = get_memory(UPGRADES_MEMORY_ID);
+    let len = bytes.len() as u64;
+    let required_pages = (len + 8 + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+    if memory.size() < required_pages && memory.grow(required_pages - memory.size()) < 0 {
+        panic!("Failed to grow the upgrades memory");
+    }
+    memory.write(0, &len.to_le_bytes());
+    memory.write(8, bytes);
+}
+
+/// Reads the heap state written by `save_upgrade_state`.
+/// Returns None on a fresh canister.
+pub fn load_upgrade_state() -> Option<Vec<u8>> {
+    let memory = get_memory(UPGRADES_MEMORY_ID);
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut len_bytes = [0u8; 8];
+    memory.read(0, &mut len_bytes);
+    let mut bytes = vec![0u8; u64::from_le_bytes(len_bytes) as usize];
+    memory.read(8, &mut bytes);
+    Some(bytes)
+}
+
+/// Returns the whole stable memory if it still holds the baseline layout, i.e. the candid
+/// encoded state written by `ic_cdk::storage::stable_save`, None otherwise.
+/// This needs to run before any stable structure is accessed: the memory manager does not
+/// recognize the baseline layout and claims the stable memory as a fresh one.
+pub fn read_baseline_stable_memory() -> Option<Vec<u8>> {
+    STABLE_MEMORY.with(|memory| {
+        if memory.size() == 0 {
+            return None;
+        }
+        let mut magic = [0u8; 4];
+        memory.read(0, &mut magic);
+        if &magic != CANDID_MAGIC {
+            return None;
+        }
+        let mut bytes = vec![0u8; (memory.size() * WASM_PAGE_SIZE) as usize];
+        memory.read(0, &mut bytes);
+        Some(bytes)
+    })
+}
+
+/// Writes bytes to the beginning of the raw stable memory, like `stable_save` did in the baseline.
+#[cfg(test)]
+pub fn write_raw_stable_memory(bytes: &[u8]) {
+    STABLE_MEMORY.with(|memory| {
+        let required_pages = (bytes.len() as u64 + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
+        memory.grow(required_pages.saturating_sub(memory.size()));
+        memory.write(0, bytes);
+    })
+}
+
+/// Verifies that the value fits into a stable btree map entry of at most max_size bytes.
+/// Inserting a larger value traps, so client input needs to be checked beforehand.
+pub fn verify_size<T: Storable>(value: &T, max_size: u32, name: &str) -> Result<(), SmartVaultErr> {
+    if value.to_bytes().len() > max_size as usize {
+        return Err(SmartVaultErr::ValueTooLarge(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Principal cannot implement Storable directly (orphan rule), hence the wrapper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StorablePrincipal(pub Principal);
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.as_slice().to_vec())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        StorablePrincipal(Principal::from_slice(&bytes))
+    }
+}
+
+impl From<Principal> for StorablePrincipal {
+    fn from(p: Principal) -> Self {
+        StorablePrincipal(p)
+    }
+}
@@ -1,4 +1,7 @@
+pub mod admin;
 pub mod error;
+pub mod memory;
 pub mod messages;
+pub mod state;
 pub mod user;
 pub mod uuid;
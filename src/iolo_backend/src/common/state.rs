@@ -0,0 +1,564 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use candid::de::IDLDeserialize;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+
+use crate::common::admin::ADMINS;
+use crate::common::user::User;
+use crate::common::uuid::UUID;
+use crate::notifications::http_adapter::NOTIFICATION_ENDPOINT;
+use crate::smart_vaults::master_vault::MasterVault;
+use crate::smart_vaults::smart_vault::{
+    MASTERVAULT, TESTAMENT_REGISTRY, USER_REGISTRY, UUID_COUNTER,
+};
+use crate::smart_vaults::user_vault::{UserVault, UserVaultCompat};
+use crate::utils::time;
+use crate::utils::login_date_condition::{
+    ConditionTimer, CONDITION_TIMERS, DEFAULT_INTERVAL_SECS,
+};
+
+/// Version of the persisted state layout.
+/// Whenever the layout of the heap state or of a value stored in a stable structure
+/// (e.g. Secret, Testament, User) changes in a released version, bump this version
+/// and register a migration.
+pub const STATE_VERSION: u32 = 2;
+
+/// A migration takes the heap state bytes of version n and returns the bytes of version n+1.
+type Migration = fn(Vec<u8>) -> Vec<u8>;
+
+/// MIGRATIONS[i] migrates version i+1 to version i+2.
+const MIGRATIONS: &[Migration] = &[migrate_v1_to_v2];
+
+// Upper bound of the vaults completed by a single message of the backfill
+const MAX_BACKFILLS_PER_BATCH: usize = 50;
+
+/// The state living on the heap, which needs to be saved in pre_upgrade.
+/// Everything else lives in stable structures.
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq)]
+pub struct HeapState {
+    pub uuid_counter: u128,
+    pub condition_timers: Vec<ConditionTimer>,
+    pub admins: Vec<Principal>,
+    pub notification_endpoint: Option<String>,
+    pub vault_backfill: Option<VaultBackfill>,
+}
+
+/// What actually gets written into the upgrades memory.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct StateEnvelope {
+    pub version: u32,
+    pub heap_state: Vec<u8>,
+}
+
+/// The progress of completing the vaults restored from the baseline, see `backfill_user_vaults`.
+#[derive(Debug, CandidType, Deserialize, Clone, Default, PartialEq)]
+pub struct VaultBackfill {
+    /// The last completed vault, the backfill continues after it
+    pub after: Option<UUID>,
+}
+
+thread_local! {
+    // The pending backfill, part of the persisted heap state. None once it is complete.
+    pub static VAULT_BACKFILL: RefCell<Option<VaultBackfill>> = RefCell::new(None);
+}
+
+impl HeapState {
+    pub fn collect() -> Self {
+        Self {
+            uuid_counter: UUID_COUNTER.with(|c| *c.borrow()),
+            condition_timers: CONDITION_TIMERS.with(|ct| ct.borrow().values().cloned().collect()),
+            admins: ADMINS.with(|a| a.borrow().iter().cloned().collect()),
+            notification_endpoint: NOTIFICATION_ENDPOINT.with(|ne| ne.borrow().clone()),
+            vault_backfill: VAULT_BACKFILL.with(|vb| vb.borrow().clone()),
+        }
+    }
+
+    pub fn apply(self) {
+        UUID_COUNTER.with(|c| *c.borrow_mut() = self.uuid_counter);
+        CONDITION_TIMERS.with(|ct| {
+            *ct.borrow_mut() = self
+                .condition_timers
+                .into_iter()
+                .map(|t| (t.id, t))
+                .collect()
+        });
+        ADMINS.with(|a| *a.borrow_mut() = self.admins.into_iter().collect());
+        NOTIFICATION_ENDPOINT.with(|ne| *ne.borrow_mut() = self.notification_endpoint);
+        VAULT_BACKFILL.with(|vb| *vb.borrow_mut() = self.vault_backfill);
+    }
+}
+
+/// Wraps the current heap state into a versioned envelope.
+pub fn save(heap_state: &HeapState) -> Vec<u8> {
+    let envelope = StateEnvelope {
+        version: STATE_VERSION,
+        heap_state: Encode!(heap_state).unwrap(),
+    };
+    Encode!(&envelope).unwrap()
+}
+
+/// Reads a versioned envelope and runs all migrations required to get to the current version.
+pub fn restore(bytes: Vec<u8>) -> HeapState {
+    // Version 1 was written without an envelope and only contains the UUID counter
+    let envelope = Decode!(&bytes, StateEnvelope).unwrap_or(StateEnvelope {
+        version: 1,
+        heap_state: bytes,
+    });
+
+    if envelope.version > STATE_VERSION {
+        panic!(
+            "Cannot restore state version {} with code for version {}",
+            envelope.version, STATE_VERSION
+        );
+    }
+
+    let mut heap_state = envelope.heap_state;
+    for migration in &MIGRATIONS[(envelope.version - 1) as usize..] {
+        heap_state = migration(heap_state);
+    }
+    Decode!(&heap_state, HeapState).unwrap()
+}
+
+/// The master vault as the baseline saved it with stable_save, before the stable structures.
+#[derive(CandidType, Deserialize)]
+struct BaselineMasterVault {
+    user_vaults: BTreeMap<UUID, UserVaultCompat>,
+}
+
+/// The user registry as the baseline saved it with stable_save.
+#[derive(CandidType, Deserialize)]
+struct BaselineUserRegistry {
+    users: BTreeMap<Principal, User>,
+}
+
+/// The testament registry as the baseline saved it with stable_save.
+/// Its fields are skipped, as the registry is rebuilt from the vaults.
+#[derive(CandidType, Deserialize)]
+struct BaselineTestamentRegistry {}
+
+/// Moves the state of the baseline layout (see `memory::read_baseline_stable_memory`)
+/// into the stable structures and returns the heap state migrated to the current version.
+/// The vaults are completed afterwards by the backfill, in batches of their own.
+///
+/// The baseline state is the tuple (MasterVault, UserRegistry, TestamentRegistry, u128)
+/// at the beginning of the stable memory. The released baseline cannot leave its state
+/// behind like this: its pre_upgrade saves the four values with separate stable_save calls,
+/// each overwriting the previous one, and it runs before any newer code gets installed.
+/// Its images only hold the counter, and its vaults only survive on the heap of the
+/// running canister. Such an image cannot be restored: this traps, which rolls back
+/// the upgrade and keeps the baseline running with its heap, so no vault is lost.
+pub fn restore_baseline(bytes: Vec<u8>) -> HeapState {
+    let (baseline_vaults, baseline_users, _baseline_testaments, uuid_counter) =
+        decode_baseline(&bytes).unwrap_or_else(|e| {
+            panic!(
+                "The stable memory does not hold a complete baseline state, \
+                 the released baseline only saves its counter on upgrade: {}",
+                e
+            )
+        });
+
+    USER_REGISTRY.with(|ur| {
+        let mut user_registry = ur.borrow_mut();
+        for (_, user) in baseline_users.users {
+            user_registry
+                .add_user(user)
+                .expect("Failed to restore a baseline user");
+        }
+    });
+
+    // The registry only holds references into the vaults, so it is rebuilt from them
+    for (_, user_vault) in baseline_vaults.user_vaults {
+        let user_vault = UserVault::from(user_vault);
+        TESTAMENT_REGISTRY.with(|tr| {
+            let mut testament_registry = tr.borrow_mut();
+            for testament in user_vault.testaments().values() {
+                testament_registry.add_testament_to_registry(testament);
+            }
+        });
+        MASTERVAULT.with(|mv| mv.borrow_mut().save_user_vault(user_vault));
+    }
+
+    // The baseline heap state is the state version 1
+    restore(candid::encode_one(uuid_counter).unwrap())
+}
+
+fn decode_baseline(
+    bytes: &[u8],
+) -> Result<
+    (
+        BaselineMasterVault,
+        BaselineUserRegistry,
+        BaselineTestamentRegistry,
+        u128,
+    ),
+    candid::Error,
+> {
+    // Like stable_restore, this ignores the trailing bytes of the stable memory
+    let mut de = IDLDeserialize::new(bytes)?;
+    Ok((
+        de.get_value()?,
+        de.get_value()?,
+        de.get_value()?,
+        de.get_value()?,
+    ))
+}
+
+// v2 moves everything but the heap state into stable structures. The condition timers
+// did not survive upgrades before, so the default timer is armed again. The vaults of
+// the baseline are completed by the backfill.
+fn migrate_v1_to_v2(heap_state: Vec<u8>) -> Vec<u8> {
+    let uuid_counter: u128 = candid::decode_one(&heap_state).unwrap();
+    Encode!(&HeapState {
+        uuid_counter,
+        condition_timers: vec![ConditionTimer::new(0, DEFAULT_INTERVAL_SECS)],
+        admins: Vec::new(),
+        notification_endpoint: None,
+        vault_backfill: Some(VaultBackfill::default()),
+    })
+    .unwrap()
+}
+
+// This function is called by the post_upgrade hook, after the heap state has been restored
+pub fn resume_vault_backfill() {
+    if VAULT_BACKFILL.with(|vb| vb.borrow().is_some()) {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_vault_backfill);
+    }
+}
+
+/// Completes the next batch of vaults, the rest is continued in a message of its own.
+fn run_vault_backfill() {
+    if !backfill_user_vaults(MAX_BACKFILLS_PER_BATCH) {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_vault_backfill);
+    }
+}
+
+/// Completes at most limit vaults restored from the baseline, in the order of their ids:
+/// adds the heirs named in their testaments, removes the references to removed secrets,
+/// and schedules the evaluation of their testaments and the purge of their trash.
+/// Returns whether the backfill is complete.
+fn backfill_user_vaults(limit: usize) -> bool {
+    let Some(backfill) = VAULT_BACKFILL.with(|vb| vb.borrow().clone()) else {
+        return true;
+    };
+    let now = time::get_current_time();
+    let ids = MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
+        let mut master_vault = mv.borrow_mut();
+        let ids = master_vault.get_user_vault_ids_page(backfill.after.as_ref(), limit);
+        for id in &ids {
+            master_vault
+                .backfill_user_vault(id, now)
+                .expect("Failed to backfill a listed user vault");
+        }
+        ids
+    });
+
+    let complete = ids.len() < limit;
+    VAULT_BACKFILL.with(|vb| {
+        *vb.borrow_mut() = if complete {
+            None
+        } else {
+            Some(VaultBackfill {
+                after: ids.last().copied(),
+            })
+        }
+    });
+    complete
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ic_stable_structures::StableBTreeMap;
+
+    use super::*;
+    use crate::common::memory::{self, Memory, MAX_USER_VAULT_SIZE, MAX_UUID_SIZE};
+    use crate::common::user::UserType;
+    use crate::smart_vaults::condition::Condition;
+    use crate::smart_vaults::secret::{SecretCategory, SecretID};
+    use crate::smart_vaults::smart_vault::CONDITION_SCHEDULE;
+    use crate::smart_vaults::testament::TestamentID;
+    use crate::smart_vaults::user_vault::KeyBox;
+    use crate::utils::login_date_condition::ConditionTimerOutcome;
+
+    #[test]
+    fn utest_migrations_cover_all_versions() {
+        assert_eq!(MIGRATIONS.len() as u32, STATE_VERSION - 1);
+    }
+
+    fn migrated_heap_state() -> HeapState {
+        HeapState {
+            uuid_counter: 42,
+            condition_timers: vec![ConditionTimer::new(0, DEFAULT_INTERVAL_SECS)],
+            admins: Vec::new(),
+            notification_endpoint: None,
+            vault_backfill: Some(VaultBackfill::default()),
+        }
+    }
+
+    #[test]
+    fn utest_restore_current_state() {
+        let mut condition_timer = ConditionTimer::new(3, 120);
+        condition_timer.last_run = Some(1);
+        condition_timer.last_outcome = Some(ConditionTimerOutcome::Failure("boom".into()));
+        let heap_state = HeapState {
+            uuid_counter: 42,
+            condition_timers: vec![condition_timer],
+            admins: vec![Principal::anonymous()],
+            notification_endpoint: Some("https://example.com/notify".into()),
+            vault_backfill: Some(VaultBackfill {
+                after: Some(UUID(3)),
+            }),
+        };
+        assert_eq!(restore(save(&heap_state)), heap_state);
+    }
+
+    #[test]
+    fn utest_restore_v1_state() {
+        // v1: the bare uuid counter without envelope
+        let fixture = candid::encode_one(42u128).unwrap();
+        assert_eq!(restore(fixture), migrated_heap_state());
+    }
+
+    /// Secret layout of the baseline, still current
+    #[derive(CandidType)]
+    struct BaselineSecret {
+        id: SecretID,
+        date_created: u64,
+        date_modified: u64,
+        category: Option<SecretCategory>,
+        name: Option<String>,
+        username: Option<Vec<u8>>,
+        password: Option<Vec<u8>>,
+        url: Option<String>,
+        notes: Option<Vec<u8>>,
+    }
+
+    /// Testament layout of the baseline
+    #[derive(CandidType)]
+    struct BaselineTestament {
+        id: TestamentID,
+        name: Option<String>,
+        date_created: u64,
+        date_modified: u64,
+        testator: Principal,
+        heirs: HashSet<Principal>,
+        secrets: HashSet<SecretID>,
+        key_box: KeyBox,
+        condition_status: bool,
+        condition_arg: u64,
+    }
+
+    /// User layout of the baseline
+    #[derive(CandidType)]
+    struct BaselineUser {
+        id: Principal,
+        name: Option<String>,
+        email: Option<String>,
+        user_type: Option<UserType>,
+        date_created: u64,
+        date_modified: u64,
+        date_last_login: Option<u64>,
+        user_vault_id: Option<UUID>,
+    }
+
+    /// User vault layout of the baseline
+    #[derive(CandidType)]
+    struct BaselineUserVault {
+        id: UUID,
+        date_created: u64,
+        date_modified: u64,
+        secrets: BTreeMap<SecretID, BaselineSecret>,
+        key_box: KeyBox,
+        testaments: BTreeMap<TestamentID, BaselineTestament>,
+        heirs: BTreeMap<Principal, BaselineUser>,
+    }
+
+    /// Registry layouts of the baseline
+    #[derive(CandidType)]
+    struct BaselineRegistries {
+        heir_to_testaments: BTreeMap<Principal, HashSet<TestamentID>>,
+        testament_to_testator: BTreeMap<TestamentID, Principal>,
+    }
+
+    #[derive(CandidType)]
+    struct BaselineVaults {
+        user_vaults: BTreeMap<UUID, BaselineUserVault>,
+    }
+
+    #[derive(CandidType)]
+    struct BaselineUsers {
+        users: BTreeMap<Principal, BaselineUser>,
+    }
+
+    const TESTATOR: [u8; 29] = [1; 29];
+    const HEIR: [u8; 29] = [2; 29];
+    const VAULT_ID: UUID = UUID(7);
+
+    fn baseline_user() -> BaselineUser {
+        BaselineUser {
+            id: Principal::from_slice(&TESTATOR),
+            name: Some("testator".to_string()),
+            email: None,
+            user_type: Some(UserType::Person),
+            date_created: 1,
+            date_modified: 1,
+            date_last_login: Some(1),
+            user_vault_id: Some(VAULT_ID),
+        }
+    }
+
+    fn baseline_user_vault() -> BaselineUserVault {
+        let secret = BaselineSecret {
+            id: "s1".to_string(),
+            date_created: 1,
+            date_modified: 1,
+            category: Some(SecretCategory::Password),
+            name: Some("secret".to_string()),
+            username: None,
+            password: Some(vec![1, 2, 3]),
+            url: None,
+            notes: None,
+        };
+        let testament = BaselineTestament {
+            id: "t1".to_string(),
+            name: None,
+            date_created: 1,
+            date_modified: 1,
+            testator: Principal::from_slice(&TESTATOR),
+            heirs: HashSet::from([Principal::from_slice(&HEIR)]),
+            secrets: HashSet::from(["s1".to_string()]),
+            key_box: BTreeMap::new(),
+            condition_status: false,
+            condition_arg: 100,
+        };
+        BaselineUserVault {
+            id: VAULT_ID,
+            date_created: 1,
+            date_modified: 1,
+            secrets: BTreeMap::from([("s1".to_string(), secret)]),
+            key_box: BTreeMap::new(),
+            testaments: BTreeMap::from([("t1".to_string(), testament)]),
+            heirs: BTreeMap::new(),
+        }
+    }
+
+    /// Checks that the fixtures above arrived in the current layout.
+    fn assert_fixtures_restored() {
+        let testator = Principal::from_slice(&TESTATOR);
+        let heir = Principal::from_slice(&HEIR);
+
+        let user = USER_REGISTRY.with(|ur| ur.borrow().get_user(&testator).unwrap());
+        assert_eq!(user.name, Some("testator".to_string()));
+        assert_eq!(user.user_vault_id, Some(VAULT_ID));
+        assert!(user.check_in.is_none());
+        assert!(user.original_principal.is_none());
+
+        let user_vault = MASTERVAULT.with(|mv| mv.borrow().get_user_vault(&VAULT_ID).unwrap());
+        let secret = user_vault.secrets().get("s1").unwrap();
+        assert_eq!(secret.name(), Some("secret".to_string()));
+        assert_eq!(secret.category(), Some(SecretCategory::Password));
+        let testament = user_vault.testaments().get("t1").unwrap();
+        assert_eq!(
+            testament.condition(),
+            &Condition::LastLogin {
+                max_inactivity_secs: 100
+            }
+        );
+        assert!(testament.secrets().contains("s1"));
+        // the backfill adds the heirs of the testaments to the vault
+        assert!(user_vault.heirs().contains_key(&heir));
+        assert!(CONDITION_SCHEDULE.with(|cs| cs.borrow().get_deadline(&"t1".to_string()).is_some()));
+
+        // the vaults are stored in the current layout
+        let raw_user_vaults: StableBTreeMap<Memory, UUID, Vec<u8>> = StableBTreeMap::init(
+            memory::get_stable_btree_memory_for_user_vaults(),
+            MAX_UUID_SIZE,
+            MAX_USER_VAULT_SIZE,
+        );
+        let bytes = raw_user_vaults.get(&VAULT_ID).unwrap();
+        assert!(Decode!(&bytes, UserVault).is_ok());
+    }
+
+    #[test]
+    fn utest_restore_baseline_stable_memory() {
+        let testator = Principal::from_slice(&TESTATOR);
+        let heir = Principal::from_slice(&HEIR);
+        let image = Encode!(
+            &BaselineVaults {
+                user_vaults: BTreeMap::from([(VAULT_ID, baseline_user_vault())]),
+            },
+            &BaselineUsers {
+                users: BTreeMap::from([(testator, baseline_user())]),
+            },
+            &BaselineRegistries {
+                heir_to_testaments: BTreeMap::from([(heir, HashSet::from(["t1".to_string()]))]),
+                testament_to_testator: BTreeMap::from([("t1".to_string(), testator)]),
+            },
+            &42u128
+        )
+        .unwrap();
+        memory::write_raw_stable_memory(&image);
+
+        // the same steps as post_upgrade
+        let bytes = memory::read_baseline_stable_memory().expect("baseline layout not detected");
+        assert_eq!(restore_baseline(bytes), migrated_heap_state());
+
+        // the memory manager owns the stable memory now
+        assert!(memory::read_baseline_stable_memory().is_none());
+
+        // the vaults are completed by the backfill, which the restored heap state starts
+        migrated_heap_state().apply();
+        assert!(backfill_user_vaults(MAX_BACKFILLS_PER_BATCH));
+        assert_eq!(VAULT_BACKFILL.with(|vb| vb.borrow().clone()), None);
+
+        assert_fixtures_restored();
+        assert_eq!(
+            TESTAMENT_REGISTRY.with(|tr| tr.borrow().get_testament_ids_as_heir(heir)),
+            vec![("t1".to_string(), testator)]
+        );
+    }
+
+    #[test]
+    fn utest_backfill_user_vaults_in_batches() {
+        let ids: Vec<UUID> = (0..3)
+            .map(|_| MASTERVAULT.with(|mv| mv.borrow_mut().create_user_vault()))
+            .collect();
+        VAULT_BACKFILL.with(|vb| *vb.borrow_mut() = Some(VaultBackfill::default()));
+
+        // a full batch continues after its last vault
+        assert!(!backfill_user_vaults(2));
+        assert_eq!(
+            VAULT_BACKFILL.with(|vb| vb.borrow().clone()),
+            Some(VaultBackfill {
+                after: Some(ids[1]),
+            })
+        );
+        assert!(backfill_user_vaults(2));
+        assert_eq!(VAULT_BACKFILL.with(|vb| vb.borrow().clone()), None);
+
+        // nothing is left to backfill
+        assert!(backfill_user_vaults(2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn utest_restore_baseline_counter_only() {
+        // the released baseline overwrote everything but the counter with its last stable_save
+        memory::write_raw_stable_memory(&candid::encode_one(42u128).unwrap());
+        let bytes = memory::read_baseline_stable_memory().unwrap();
+        restore_baseline(bytes);
+    }
+
+    #[test]
+    #[should_panic]
+    fn utest_restore_newer_state() {
+        let envelope = StateEnvelope {
+            version: STATE_VERSION + 1,
+            heap_state: Vec::new(),
+        };
+        restore(Encode!(&envelope).unwrap());
+    }
+}
@@ -1,6 +1,10 @@
-use candid::{CandidType, Deserialize, Principal};
+use std::borrow::Cow;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::Storable;
 use serde::Serialize;
 
+use crate::smart_vaults::check_in::CheckIn;
 use crate::{smart_vaults::user_vault::UserVaultID, utils::time};
 
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
@@ -12,7 +16,20 @@ pub struct User {
     pub date_created: u64,
     pub date_modified: u64,
     pub date_last_login: Option<u64>,
-    pub user_vault_id: Option<UserVaultID>
+    pub user_vault_id: Option<UserVaultID>,
+    pub check_in: Option<CheckIn>,
+    /// The principal the account was created with, if it was recovered to another one since
+    pub original_principal: Option<Principal>,
+}
+
+impl Storable for User {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
 }
 
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
@@ -34,12 +51,14 @@ impl From<AddUserArgs> for User {
             date_created: now,
             date_modified: now,
             date_last_login: None,
-            user_vault_id: None
+            user_vault_id: None,
+            check_in: None,
+            original_principal: None,
         }
     }
 }
 
-#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
 pub enum UserType {
     Person,
     Company,
@@ -57,7 +76,9 @@ impl User {
             date_created: now,
             date_modified: now,
             date_last_login: Some(now),
-            user_vault_id: None
+            user_vault_id: None,
+            check_in: None,
+            original_principal: None,
         }
     }
 
@@ -74,6 +95,19 @@ impl User {
         self.date_modified = time::get_current_time();
     }
 
+    /// Moves the account to another principal, see account_recovery.
+    pub fn rebind(&mut self, id: Principal) {
+        self.original_principal.get_or_insert(self.id);
+        self.id = id;
+        self.date_modified = time::get_current_time();
+    }
+
+    /// The vetkd keys of the account stay derived from the principal it was created with,
+    /// otherwise the secrets could not be decrypted anymore after a recovery.
+    pub fn key_principal(&self) -> &Principal {
+        self.original_principal.as_ref().unwrap_or(&self.id)
+    }
+
     pub fn update_login_date(&mut self) {
         let now = time::get_current_time();
         self.date_last_login = Some(now);
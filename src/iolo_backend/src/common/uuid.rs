@@ -1,6 +1,7 @@
-use std::{cell::RefCell, fmt};
+use std::{borrow::Cow, cell::RefCell, fmt};
 
 use candid::{CandidType, Deserialize};
+use ic_stable_structures::Storable;
 use serde::Serialize;
 
 use crate::smart_vaults::smart_vault::UUID_COUNTER;
@@ -41,6 +42,19 @@ impl fmt::Display for UUID {
     }
 }
 
+impl Storable for UUID {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        // big endian, so the byte order matches the numeric order in the stable btree map
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        UUID(u128::from_be_bytes(
+            bytes.try_into().expect("UUID must consist of 16 bytes"),
+        ))
+    }
+}
+
 impl Default for UUID {
     fn default() -> Self {
         Self::new()
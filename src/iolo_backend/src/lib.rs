@@ -1,32 +1,66 @@
 pub mod common;
+pub mod notifications;
 pub mod smart_vaults;
 pub mod smart_wallets;
 pub mod utils;
 
 // for the candid file creation
+use crate::common::admin;
 use crate::common::error::SmartVaultErr;
 use crate::common::user::User;
+use crate::smart_vaults::account_recovery::AccountRecovery;
+use crate::smart_vaults::account_recovery::RecoveryRequest;
+use crate::smart_vaults::account_recovery::SetRecoveryGuardiansArgs;
+use crate::smart_vaults::check_in::CheckInSchedule;
+use crate::smart_vaults::check_in::CheckInStatus;
 use crate::smart_vaults::key_manager::TestamentKeyDerviationArgs;
 use crate::smart_vaults::secret::SecretID;
 use crate::smart_vaults::secret::SecretListEntry;
+use crate::smart_vaults::secret::SecretRemovalReport;
 use crate::smart_vaults::secret::SecretSymmetricCryptoMaterial;
+use crate::smart_vaults::secret::SecretVersion;
 use crate::smart_vaults::testament::AddTestamentArgs;
+use crate::smart_vaults::testament::RearmTestamentArgs;
 use crate::smart_vaults::testament::Testament;
 use crate::smart_vaults::testament::TestamentResponse;
 use crate::smart_vaults::testament::TestamentID;
 use crate::smart_vaults::testament::TestamentListEntry;
 use crate::common::user::AddUserArgs;
+use crate::smart_vaults::consistency::ConsistencyCheckArgs;
+use crate::smart_vaults::consistency::ConsistencyReport;
+use crate::smart_vaults::invitation_registry::ClaimCode;
+use crate::smart_vaults::invitation_registry::CreateHeirInvitationArgs;
+use crate::smart_vaults::invitation_registry::HeirInvitation;
+use crate::smart_vaults::linked_principals::LinkedPrincipals;
+use crate::smart_vaults::linked_principals::PendingPrincipalLink;
+use crate::smart_vaults::trash::TrashItemID;
+use crate::smart_vaults::trash::TrashListEntry;
+use crate::notifications::delivery::Delivery;
+use crate::notifications::outbox::Notification;
+use crate::notifications::outbox::NotificationID;
+use ic_cdk::api::management_canister::http_request::HttpResponse;
+use ic_cdk::api::management_canister::http_request::TransformArgs;
 use candid::candid_method;
 use candid::Principal;
 use crate::utils::login_date_condition;
+use crate::utils::login_date_condition::ConditionTimer;
+use crate::utils::login_date_condition::ConditionTimerID;
 
 use crate::smart_vaults::secret::{AddSecretArgs, Secret};
 
 #[ic_cdk_macros::init]
 fn init() {
+    // the controller installing the canister is an admin
+    admin::register_controller(utils::caller::get_caller());
 
     // initialize the timers for triggering the login date condition
     login_date_condition::init_condition();
+
+    // start delivering notifications
+    notifications::delivery::init_delivery_timer();
+
+    // start purging the trash
+    smart_vaults::trash::init_trash_timer();
 }
 
 #[ic_cdk_macros::query]
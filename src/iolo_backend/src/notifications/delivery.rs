@@ -0,0 +1,439 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use candid::{candid_method, CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Serialize;
+
+use crate::common::admin::verify_caller_is_admin;
+use crate::common::error::SmartVaultErr;
+use crate::common::memory::{
+    get_stable_btree_memory_for_dead_letters, get_stable_btree_memory_for_deliveries,
+    get_stable_btree_memory_for_delivery_due_dates, Memory, StorablePrincipal, MAX_DELIVERY_SIZE,
+    MAX_IDEMPOTENCY_KEY_SIZE, MAX_PRINCIPAL_SIZE,
+};
+use crate::common::uuid::UUID;
+use crate::notifications::http_adapter::{self, NOTIFICATION_ENDPOINT};
+use crate::notifications::outbox::Notification;
+use crate::smart_vaults::smart_vault::USER_REGISTRY;
+use crate::utils::time;
+
+// How often due deliveries are sent
+const DELIVERY_INTERVAL_SECS: u64 = 30;
+// Upper bound of the outcalls per tick, the rest is sent by the next ticks
+const MAX_DELIVERIES_PER_TICK: usize = 10;
+// A delivery is moved to the dead letters after this many failed attempts
+const MAX_ATTEMPTS: u32 = 5;
+// The delay after the first failed attempt, doubled with every further one
+const RETRY_BASE_DELAY_SECS: u64 = 60;
+// A delivery whose outcall did not come back within this time is sent again
+const IN_FLIGHT_TIMEOUT_SECS: u64 = 600;
+// Upper bound of the dead letters kept, the oldest ones are dropped beyond it
+const MAX_DEAD_LETTERS: u64 = 1000;
+const DUE_DATE_SIZE: u32 = 8;
+
+/// A notification on its way to the notification endpoint.
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq)]
+pub struct Delivery {
+    /// Identifies the notification, so the endpoint can drop deliveries it already received
+    pub idempotency_key: String,
+    pub principal: Principal,
+    /// The json body
+    pub payload: String,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+impl Storable for Delivery {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+/// Key of the due date index. The due date is encoded big endian and comes first,
+/// so the byte order of the keys is the order of the due dates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DueKey {
+    next_attempt: u64,
+    idempotency_key: String,
+}
+
+impl Storable for DueKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.next_attempt.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.idempotency_key.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        let (next_attempt, idempotency_key) = bytes.split_at(DUE_DATE_SIZE as usize);
+        DueKey {
+            next_attempt: u64::from_be_bytes(next_attempt.try_into().unwrap()),
+            idempotency_key: String::from_utf8(idempotency_key.to_vec()).unwrap(),
+        }
+    }
+}
+
+/// The json body posted to the notification endpoint.
+#[derive(Debug, Serialize)]
+struct NotificationPayload<'a> {
+    idempotency_key: &'a str,
+    principal: String,
+    email: Option<String>,
+    notification: &'a Notification,
+}
+
+/// Deliveries which are pending, and the ones which failed too often (dead letters).
+/// The idempotency keys are handed out in ascending order, so the maps are ordered by age.
+pub struct DeliveryQueue {
+    deliveries: StableBTreeMap<Memory, String, Delivery>,
+    dead_letters: StableBTreeMap<Memory, String, Delivery>,
+    // (next attempt, idempotency key) -> principal, so the due deliveries are found
+    // without reading the whole queue
+    due_dates: StableBTreeMap<Memory, DueKey, StorablePrincipal>,
+}
+
+impl Default for DeliveryQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeliveryQueue {
+    pub fn new() -> Self {
+        Self {
+            deliveries: StableBTreeMap::init(
+                get_stable_btree_memory_for_deliveries(),
+                MAX_IDEMPOTENCY_KEY_SIZE,
+                MAX_DELIVERY_SIZE,
+            ),
+            dead_letters: StableBTreeMap::init(
+                get_stable_btree_memory_for_dead_letters(),
+                MAX_IDEMPOTENCY_KEY_SIZE,
+                MAX_DELIVERY_SIZE,
+            ),
+            due_dates: StableBTreeMap::init(
+                get_stable_btree_memory_for_delivery_due_dates(),
+                DUE_DATE_SIZE + MAX_IDEMPOTENCY_KEY_SIZE,
+                MAX_PRINCIPAL_SIZE,
+            ),
+        }
+    }
+
+    /// Queues a delivery. A delivery with the same idempotency key is not queued twice.
+    pub fn enqueue(&mut self, delivery: Delivery) {
+        if self.deliveries.contains_key(&delivery.idempotency_key) {
+            return;
+        }
+        self.insert_delivery(delivery);
+    }
+
+    /// Returns at most limit deliveries which are due and marks them as in flight.
+    pub fn take_due(&mut self, now: u64, limit: usize) -> Vec<Delivery> {
+        let due_keys: Vec<String> = self
+            .due_dates
+            .iter()
+            .take_while(|(key, _)| key.next_attempt <= now)
+            .take(limit)
+            .map(|(key, _)| key.idempotency_key)
+            .collect();
+        let mut due = Vec::new();
+        for key in due_keys {
+            let Some(delivery) = self.remove_delivery(&key) else {
+                continue;
+            };
+            let mut in_flight = delivery.clone();
+            in_flight.next_attempt = now + IN_FLIGHT_TIMEOUT_SECS * 1000000000;
+            self.insert_delivery(in_flight);
+            due.push(delivery);
+        }
+        due
+    }
+
+    pub fn record_success(&mut self, idempotency_key: &String) {
+        self.remove_delivery(idempotency_key);
+    }
+
+    /// Schedules the next attempt with exponential backoff,
+    /// or moves the delivery to the dead letters once it failed too often.
+    pub fn record_failure(&mut self, idempotency_key: &String, error: String, now: u64) {
+        let Some(mut delivery) = self.remove_delivery(idempotency_key) else {
+            return;
+        };
+        delivery.attempts += 1;
+        delivery.last_error = Some(error);
+        if delivery.attempts >= MAX_ATTEMPTS {
+            self.add_dead_letter(delivery);
+        } else {
+            let delay_secs = RETRY_BASE_DELAY_SECS << (delivery.attempts - 1);
+            delivery.next_attempt = now + delay_secs * 1000000000;
+            self.insert_delivery(delivery);
+        }
+    }
+
+    /// Keeps at most MAX_DEAD_LETTERS dead letters by dropping the oldest ones.
+    fn add_dead_letter(&mut self, delivery: Delivery) {
+        self.dead_letters
+            .insert(delivery.idempotency_key.clone(), delivery)
+            .expect("Failed to insert into dead letters");
+        while self.dead_letters.len() > MAX_DEAD_LETTERS {
+            let Some((oldest, _)) = self.dead_letters.iter().next() else {
+                break;
+            };
+            self.dead_letters.remove(&oldest);
+        }
+    }
+
+    fn insert_delivery(&mut self, delivery: Delivery) {
+        self.due_dates
+            .insert(
+                DueKey {
+                    next_attempt: delivery.next_attempt,
+                    idempotency_key: delivery.idempotency_key.clone(),
+                },
+                StorablePrincipal(delivery.principal),
+            )
+            .expect("Failed to insert into delivery due dates");
+        self.deliveries
+            .insert(delivery.idempotency_key.clone(), delivery)
+            .expect("Failed to insert into deliveries");
+    }
+
+    fn remove_delivery(&mut self, idempotency_key: &String) -> Option<Delivery> {
+        let delivery = self.deliveries.remove(idempotency_key)?;
+        self.due_dates.remove(&DueKey {
+            next_attempt: delivery.next_attempt,
+            idempotency_key: idempotency_key.clone(),
+        });
+        Some(delivery)
+    }
+
+    pub fn get_deliveries(&self) -> Vec<Delivery> {
+        self.deliveries.iter().map(|(_, delivery)| delivery).collect()
+    }
+
+    pub fn get_dead_letters(&self) -> Vec<Delivery> {
+        self.dead_letters.iter().map(|(_, delivery)| delivery).collect()
+    }
+
+    /// Queues a dead letter again, with a fresh number of attempts.
+    pub fn retry_dead_letter(&mut self, idempotency_key: &String, now: u64) -> Result<(), SmartVaultErr> {
+        let mut delivery = self
+            .dead_letters
+            .remove(idempotency_key)
+            .ok_or_else(|| SmartVaultErr::DeliveryDoesNotExist(idempotency_key.clone()))?;
+        delivery.attempts = 0;
+        delivery.next_attempt = now;
+        self.insert_delivery(delivery);
+        Ok(())
+    }
+}
+
+thread_local! {
+    pub static DELIVERY_QUEUE: RefCell<DeliveryQueue> = RefCell::new(DeliveryQueue::new());
+}
+
+/// Queues the delivery of a notification, if a notification endpoint is configured.
+/// The email of the user is part of the payload, so the endpoint can forward the notification.
+pub fn enqueue(principal: Principal, notification: &Notification) {
+    if NOTIFICATION_ENDPOINT.with(|ne| ne.borrow().is_none()) {
+        return;
+    }
+    // Notification ids are only unique per outbox, and an outbox starts over when its user
+    // is deleted and created again. Zero padded, the keys sort in the order they are handed out.
+    let idempotency_key = format!("{:032x}", UUID::new().0);
+    let email = USER_REGISTRY
+        .with(|ur| ur.borrow().get_user(&principal).ok())
+        .and_then(|user| user.email);
+    let payload = serde_json::to_string(&NotificationPayload {
+        idempotency_key: &idempotency_key,
+        principal: principal.to_text(),
+        email,
+        notification,
+    })
+    .expect("Failed to serialize notification");
+    DELIVERY_QUEUE.with(|dq| {
+        dq.borrow_mut().enqueue(Delivery {
+            idempotency_key,
+            principal,
+            payload,
+            attempts: 0,
+            next_attempt: time::get_current_time(),
+            last_error: None,
+        })
+    });
+}
+
+// This function is called by the init and post_upgrade hooks
+pub fn init_delivery_timer() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(DELIVERY_INTERVAL_SECS), send_due_deliveries);
+}
+
+fn send_due_deliveries() {
+    let Some(url) = NOTIFICATION_ENDPOINT.with(|ne| ne.borrow().clone()) else {
+        return;
+    };
+    let now = time::get_current_time();
+    let due = DELIVERY_QUEUE.with(|dq| dq.borrow_mut().take_due(now, MAX_DELIVERIES_PER_TICK));
+    for delivery in due {
+        let url = url.clone();
+        ic_cdk::spawn(async move {
+            let key = delivery.idempotency_key;
+            let result = http_adapter::post(url, delivery.payload.into_bytes(), key.clone()).await;
+            DELIVERY_QUEUE.with(|dq| {
+                let mut delivery_queue = dq.borrow_mut();
+                match result {
+                    Ok(()) => delivery_queue.record_success(&key),
+                    Err(e) => {
+                        ic_cdk::println!("ERROR: delivering notification {} failed: {}", key, e);
+                        delivery_queue.record_failure(&key, e, time::get_current_time())
+                    }
+                }
+            });
+        });
+    }
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_pending_deliveries() -> Result<Vec<Delivery>, SmartVaultErr> {
+    verify_caller_is_admin()?;
+    Ok(DELIVERY_QUEUE.with(|dq| dq.borrow().get_deliveries()))
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_dead_letters() -> Result<Vec<Delivery>, SmartVaultErr> {
+    verify_caller_is_admin()?;
+    Ok(DELIVERY_QUEUE.with(|dq| dq.borrow().get_dead_letters()))
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn retry_dead_letter(idempotency_key: String) -> Result<(), SmartVaultErr> {
+    verify_caller_is_admin()?;
+    DELIVERY_QUEUE.with(|dq| {
+        dq.borrow_mut()
+            .retry_dead_letter(&idempotency_key, time::get_current_time())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1000000000;
+
+    fn delivery(key: &str) -> Delivery {
+        Delivery {
+            idempotency_key: key.to_string(),
+            principal: Principal::anonymous(),
+            payload: "{}".to_string(),
+            attempts: 0,
+            next_attempt: 0,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn utest_idempotent_enqueue() {
+        let mut queue = DeliveryQueue::new();
+        queue.enqueue(delivery("a"));
+        let mut again = delivery("a");
+        again.payload = "changed".to_string();
+        queue.enqueue(again);
+        assert_eq!(queue.get_deliveries(), vec![delivery("a")]);
+    }
+
+    #[test]
+    fn utest_in_flight() {
+        let mut queue = DeliveryQueue::new();
+        queue.enqueue(delivery("a"));
+        queue.enqueue(delivery("b"));
+        assert_eq!(queue.take_due(0, 1).len(), 1);
+        assert_eq!(queue.take_due(0, 10).len(), 1);
+
+        // in flight deliveries are sent again if the outcall never came back
+        assert!(queue.take_due(SECOND, 10).is_empty());
+        assert_eq!(queue.take_due(IN_FLIGHT_TIMEOUT_SECS * SECOND, 10).len(), 2);
+
+        queue.record_success(&"a".to_string());
+        assert_eq!(queue.get_deliveries().len(), 1);
+    }
+
+    #[test]
+    fn utest_retries_and_dead_letters() {
+        let key = "a".to_string();
+        let mut queue = DeliveryQueue::new();
+        queue.enqueue(delivery("a"));
+
+        // exponential backoff
+        queue.record_failure(&key, "500".to_string(), 0);
+        assert_eq!(queue.get_deliveries()[0].next_attempt, 60 * SECOND);
+        queue.record_failure(&key, "500".to_string(), 0);
+        assert_eq!(queue.get_deliveries()[0].next_attempt, 120 * SECOND);
+
+        for _ in 2..MAX_ATTEMPTS {
+            queue.record_failure(&key, "500".to_string(), 0);
+        }
+        assert!(queue.get_deliveries().is_empty());
+        let dead_letters = queue.get_dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].attempts, MAX_ATTEMPTS);
+        assert_eq!(dead_letters[0].last_error, Some("500".to_string()));
+
+        // dead letters do not keep other notifications from being queued
+        queue.enqueue(delivery("b"));
+        assert_eq!(queue.get_deliveries(), vec![delivery("b")]);
+        queue.record_success(&"b".to_string());
+
+        // and can be retried by an admin
+        queue.retry_dead_letter(&key, 5).unwrap();
+        assert_eq!(queue.get_deliveries()[0].attempts, 0);
+        assert!(queue.get_dead_letters().is_empty());
+        assert_eq!(
+            queue.retry_dead_letter(&key, 5),
+            Err(SmartVaultErr::DeliveryDoesNotExist(key))
+        );
+    }
+
+    #[test]
+    fn utest_due_dates() {
+        let mut queue = DeliveryQueue::new();
+        let mut late = delivery("a");
+        late.next_attempt = 20 * SECOND;
+        queue.enqueue(late);
+        let mut early = delivery("b");
+        early.next_attempt = 10 * SECOND;
+        queue.enqueue(early.clone());
+
+        // due deliveries come ordered by their next attempt
+        assert!(queue.take_due(5 * SECOND, 10).is_empty());
+        assert_eq!(queue.take_due(20 * SECOND, 1), vec![early]);
+        assert_eq!(queue.take_due(20 * SECOND, 10).len(), 1);
+        assert!(queue.take_due(20 * SECOND, 10).is_empty());
+
+        // a failure moves the due date of the delivery
+        queue.record_failure(&"a".to_string(), "500".to_string(), 20 * SECOND);
+        assert!(queue.take_due(79 * SECOND, 10).is_empty());
+        assert_eq!(queue.take_due(80 * SECOND, 10).len(), 1);
+    }
+
+    #[test]
+    fn utest_dead_letter_cap() {
+        let mut queue = DeliveryQueue::new();
+        for i in 0..=MAX_DEAD_LETTERS {
+            queue.add_dead_letter(delivery(&format!("{:032x}", i)));
+        }
+        let dead_letters = queue.get_dead_letters();
+        assert_eq!(dead_letters.len() as u64, MAX_DEAD_LETTERS);
+        // the oldest one got dropped
+        assert_eq!(dead_letters[0].idempotency_key, format!("{:032x}", 1));
+    }
+}
@@ -0,0 +1,110 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, Nat};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+    TransformArgs, TransformContext, TransformFunc,
+};
+
+use crate::common::admin::verify_caller_is_admin;
+use crate::common::error::SmartVaultErr;
+
+// The endpoint only has to acknowledge the delivery, so the response is not of interest
+const MAX_RESPONSE_BYTES: u64 = 2048;
+
+thread_local! {
+    // Where notifications are delivered to via http outcall, part of the persisted heap state.
+    // Without an endpoint, notifications are only available in the outbox.
+    pub static NOTIFICATION_ENDPOINT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn set_notification_endpoint(url: Option<String>) -> Result<(), SmartVaultErr> {
+    verify_caller_is_admin()?;
+    if let Some(url) = &url {
+        verify_url(url)?;
+    }
+    NOTIFICATION_ENDPOINT.with(|ne| *ne.borrow_mut() = url);
+    Ok(())
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_notification_endpoint() -> Result<Option<String>, SmartVaultErr> {
+    verify_caller_is_admin()?;
+    Ok(NOTIFICATION_ENDPOINT.with(|ne| ne.borrow().clone()))
+}
+
+/// Strips everything the replicas might disagree on (e.g. date headers),
+/// so they can reach consensus on the response.
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn transform_notification_response(raw: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: raw.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+/// Posts a json body to the given url. The idempotency key allows the endpoint to
+/// recognize retries of deliveries it already received.
+pub async fn post(url: String, body: Vec<u8>, idempotency_key: String) -> Result<(), String> {
+    let request = CanisterHttpRequestArgument {
+        url,
+        method: HttpMethod::POST,
+        body: Some(body),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::id(),
+                method: "transform_notification_response".to_string(),
+            }),
+            context: Vec::new(),
+        }),
+        headers: vec![
+            HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: idempotency_key,
+            },
+        ],
+    };
+    let (response,) = http_request(request)
+        .await
+        .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+    if response.status >= Nat::from(200u64) && response.status < Nat::from(300u64) {
+        Ok(())
+    } else {
+        Err(format!("endpoint responded with status {}", response.status))
+    }
+}
+
+fn verify_url(url: &str) -> Result<(), SmartVaultErr> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        Ok(())
+    } else {
+        Err(SmartVaultErr::InvalidNotificationEndpoint(url.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utest_verify_url() {
+        assert!(verify_url("https://example.com/notify").is_ok());
+        assert!(verify_url("http://localhost:8080").is_ok());
+        assert_eq!(
+            verify_url("ftp://example.com"),
+            Err(SmartVaultErr::InvalidNotificationEndpoint(
+                "ftp://example.com".to_string()
+            ))
+        );
+    }
+}
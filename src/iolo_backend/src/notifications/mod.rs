@@ -0,0 +1,3 @@
+pub mod delivery;
+pub mod http_adapter;
+pub mod outbox;
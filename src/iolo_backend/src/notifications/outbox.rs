@@ -0,0 +1,280 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Decode, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::common::error::SmartVaultErr;
+use crate::common::memory::{
+    get_stable_btree_memory_for_outboxes, Memory, StorablePrincipal, MAX_OUTBOX_SIZE,
+    MAX_PRINCIPAL_SIZE,
+};
+use crate::notifications::delivery;
+use crate::smart_vaults::smart_vault::{resolve_caller, USER_REGISTRY};
+use crate::smart_vaults::testament::{Testament, TestamentID};
+use crate::smart_vaults::user_registry::UserRegistry;
+use crate::utils::time;
+
+// Older notifications are dropped once a user has more unacknowledged ones than this
+const MAX_NOTIFICATIONS_PER_USER: usize = 100;
+
+pub type NotificationID = u64;
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum NotificationKind {
+    /// The testator has to log in before release_date (in nanoseconds),
+    /// otherwise the inactivity condition of the testament is met
+    InactivityReminder {
+        testament_id: TestamentID,
+        milestone: u8,
+        release_date: u64,
+    },
+    /// The testament of the testator got released to the heirs
+    TestamentReleased {
+        testament_id: TestamentID,
+        testator: Principal,
+    },
+    /// The testator made the user an heir of the testament
+    HeirAdded {
+        testament_id: TestamentID,
+        testator: Principal,
+    },
+    /// The testament is gone, because the testator deleted the account
+    TestamentRemoved {
+        testament_id: TestamentID,
+        testator: Principal,
+    },
+    /// The invited heir accepted the invitation of the testator
+    HeirInvitationAccepted {
+        claim_code: String,
+        heir: Principal,
+    },
+    /// The principal got linked to the account of the user
+    PrincipalLinked { principal: Principal },
+    /// The new principal asked to take over the account, which the owner can veto until closes_at
+    RecoveryRequested {
+        account: Principal,
+        new_principal: Principal,
+        closes_at: u64,
+    },
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Notification {
+    pub id: NotificationID,
+    pub date_created: u64,
+    pub kind: NotificationKind,
+}
+
+#[derive(Debug, Default, CandidType, Deserialize)]
+struct UserOutbox {
+    next_id: NotificationID,
+    notifications: Vec<Notification>,
+}
+
+impl Storable for UserOutbox {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+/// The notifications of every user which were not acknowledged yet.
+pub struct Outbox {
+    outboxes: StableBTreeMap<Memory, StorablePrincipal, UserOutbox>,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self {
+            outboxes: StableBTreeMap::init(
+                get_stable_btree_memory_for_outboxes(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_OUTBOX_SIZE,
+            ),
+        }
+    }
+
+    pub fn push(&mut self, principal: Principal, kind: NotificationKind, now: u64) -> Notification {
+        let key = StorablePrincipal(principal);
+        let mut user_outbox = self.outboxes.get(&key).unwrap_or_default();
+        let notification = Notification {
+            id: user_outbox.next_id,
+            date_created: now,
+            kind,
+        };
+        user_outbox.next_id += 1;
+        user_outbox.notifications.push(notification.clone());
+        if user_outbox.notifications.len() > MAX_NOTIFICATIONS_PER_USER {
+            user_outbox.notifications.remove(0);
+        }
+        self.outboxes
+            .insert(key, user_outbox)
+            .expect("Failed to insert into outboxes");
+        notification
+    }
+
+    pub fn get_notifications(&self, principal: Principal) -> Vec<Notification> {
+        self.outboxes
+            .get(&StorablePrincipal(principal))
+            .map(|user_outbox| user_outbox.notifications)
+            .unwrap_or_default()
+    }
+
+    pub fn remove_outbox(&mut self, principal: Principal) {
+        self.outboxes.remove(&StorablePrincipal(principal));
+    }
+
+    /// Hands the notifications over to another principal, whose own ones are kept as well.
+    pub fn move_outbox(&mut self, from: Principal, to: Principal) {
+        if let Some(user_outbox) = self.outboxes.remove(&StorablePrincipal(from)) {
+            for notification in user_outbox.notifications {
+                self.push(to, notification.kind, notification.date_created);
+            }
+        }
+    }
+
+    /// Removes the given notifications. The id counter is kept, so ids are never reused.
+    pub fn acknowledge(&mut self, principal: Principal, ids: &[NotificationID]) {
+        let key = StorablePrincipal(principal);
+        if let Some(mut user_outbox) = self.outboxes.get(&key) {
+            user_outbox
+                .notifications
+                .retain(|notification| !ids.contains(&notification.id));
+            self.outboxes
+                .insert(key, user_outbox)
+                .expect("Failed to insert into outboxes");
+        }
+    }
+}
+
+thread_local! {
+    pub static OUTBOX: RefCell<Outbox> = RefCell::new(Outbox::new());
+}
+
+/// Puts a notification into the outbox of a user and queues its delivery,
+/// if a notification endpoint is configured.
+pub fn notify(principal: Principal, kind: NotificationKind) -> Notification {
+    let notification = OUTBOX.with(|o| {
+        o.borrow_mut()
+            .push(principal, kind, time::get_current_time())
+    });
+    delivery::enqueue(principal, &notification);
+    notification
+}
+
+/// Tells the heirs that they have access to the testament now.
+pub fn notify_release(testament: &Testament) {
+    for heir in testament.heirs() {
+        notify(
+            *heir,
+            NotificationKind::TestamentReleased {
+                testament_id: testament.id().clone(),
+                testator: *testament.testator(),
+            },
+        );
+    }
+}
+
+/// Tells the heirs that the testament does not exist anymore.
+pub fn notify_removal(testament: &Testament) {
+    for heir in testament.heirs() {
+        notify(
+            *heir,
+            NotificationKind::TestamentRemoved {
+                testament_id: testament.id().clone(),
+                testator: *testament.testator(),
+            },
+        );
+    }
+}
+
+/// Tells the heirs of the testament who are not heirs of the old version of it yet.
+pub fn notify_added_heirs(testament: &Testament, old: Option<&Testament>) {
+    for heir in testament.heirs() {
+        if old.map_or(false, |old| old.heirs().contains(heir)) {
+            continue;
+        }
+        notify(
+            *heir,
+            NotificationKind::HeirAdded {
+                testament_id: testament.id().clone(),
+                testator: *testament.testator(),
+            },
+        );
+    }
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_notifications() -> Result<Vec<Notification>, SmartVaultErr> {
+    let principal = resolve_caller();
+    verify_user_exists(&principal)?;
+    Ok(OUTBOX.with(|o| o.borrow().get_notifications(principal)))
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn acknowledge_notifications(ids: Vec<NotificationID>) -> Result<(), SmartVaultErr> {
+    let principal = resolve_caller();
+    verify_user_exists(&principal)?;
+    OUTBOX.with(|o| o.borrow_mut().acknowledge(principal, &ids));
+    Ok(())
+}
+
+fn verify_user_exists(principal: &Principal) -> Result<(), SmartVaultErr> {
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| ur.borrow().get_user(principal))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reminder(milestone: u8) -> NotificationKind {
+        NotificationKind::InactivityReminder {
+            testament_id: "t".to_string(),
+            milestone,
+            release_date: 0,
+        }
+    }
+
+    #[test]
+    fn utest_outbox() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let mut outbox = Outbox::new();
+
+        assert_eq!(outbox.push(alice, reminder(50), 1).id, 0);
+        assert_eq!(outbox.push(alice, reminder(80), 2).id, 1);
+        assert_eq!(outbox.get_notifications(alice).len(), 2);
+        assert!(outbox.get_notifications(bob).is_empty());
+
+        // acknowledged notifications are gone, but their ids are not reused
+        outbox.acknowledge(alice, &[0, 1]);
+        assert!(outbox.get_notifications(alice).is_empty());
+        assert_eq!(outbox.push(alice, reminder(95), 3).id, 2);
+    }
+
+    #[test]
+    fn utest_outbox_limit() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let mut outbox = Outbox::new();
+        for i in 0..=MAX_NOTIFICATIONS_PER_USER as u64 {
+            outbox.push(alice, reminder(50), i);
+        }
+        let notifications = outbox.get_notifications(alice);
+        assert_eq!(notifications.len(), MAX_NOTIFICATIONS_PER_USER);
+        assert_eq!(notifications[0].id, 1);
+    }
+}
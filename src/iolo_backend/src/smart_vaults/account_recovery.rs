@@ -0,0 +1,601 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Serialize;
+
+use crate::common::error::SmartVaultErr;
+use crate::common::memory::{
+    get_stable_btree_memory_for_account_recoveries, Memory, StorablePrincipal,
+    MAX_ACCOUNT_RECOVERY_SIZE, MAX_PRINCIPAL_SIZE,
+};
+use crate::common::user::User;
+use crate::notifications::outbox::{self, NotificationKind, OUTBOX};
+use crate::utils::caller::get_caller;
+use crate::utils::time;
+
+use super::invitation_registry::InvitationRegistry;
+use super::master_vault::MasterVault;
+use super::smart_vault::{
+    get_owner_caller, resolve_caller, INVITATION_REGISTRY, MASTERVAULT, RECOVERY_REGISTRY,
+    USER_REGISTRY,
+};
+use super::user_registry::UserRegistry;
+
+// How long the guardians can approve and the owner can veto, if the owner does not say otherwise
+pub const DEFAULT_RECOVERY_WINDOW_SECS: u64 = 3 * 24 * 60 * 60;
+// Accounts are stored with a fixed maximum size, so the number of guardians is limited
+pub const MAX_RECOVERY_GUARDIANS: usize = 10;
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct SetRecoveryGuardiansArgs {
+    pub guardians: Vec<Principal>,
+    /// How many of the guardians have to approve a recovery
+    pub threshold: u64,
+    pub window_secs: Option<u64>,
+}
+
+/// A new principal asking to take over the account. Dates are in nanoseconds.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RecoveryRequest {
+    pub new_principal: Principal,
+    pub date_created: u64,
+    /// Until then the guardians can approve, afterwards the recovery can be completed
+    pub closes_at: u64,
+    pub approvals: Vec<Principal>,
+}
+
+/// The guardians of an account, who can hand it over to a new principal
+/// if the owner lost access to the old one.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct AccountRecovery {
+    pub account: Principal,
+    pub guardians: Vec<Principal>,
+    pub threshold: u64,
+    pub window_secs: u64,
+    pub request: Option<RecoveryRequest>,
+}
+
+impl Storable for AccountRecovery {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl AccountRecovery {
+    pub fn new(account: Principal, args: SetRecoveryGuardiansArgs) -> Result<Self, SmartVaultErr> {
+        let invalid =
+            |reason: &str| Err(SmartVaultErr::InvalidRecoveryGuardians(reason.to_string()));
+        let mut guardians = args.guardians.clone();
+        guardians.sort();
+        guardians.dedup();
+        if guardians.len() != args.guardians.len() {
+            return invalid("The guardians need to be distinct");
+        }
+        if guardians.len() > MAX_RECOVERY_GUARDIANS {
+            return Err(SmartVaultErr::InvalidRecoveryGuardians(format!(
+                "At most {} guardians are allowed",
+                MAX_RECOVERY_GUARDIANS
+            )));
+        }
+        if guardians
+            .iter()
+            .any(|guardian| *guardian == account || *guardian == Principal::anonymous())
+        {
+            return invalid("The owner and the anonymous principal cannot be guardians");
+        }
+        if args.threshold == 0 || args.threshold > guardians.len() as u64 {
+            return invalid("The threshold needs to be between 1 and the number of guardians");
+        }
+        let window_secs = args.window_secs.unwrap_or(DEFAULT_RECOVERY_WINDOW_SECS);
+        if window_secs == 0 {
+            return invalid("The window needs to be at least 1 second");
+        }
+        Ok(Self {
+            account,
+            guardians: args.guardians,
+            threshold: args.threshold,
+            window_secs,
+            request: None,
+        })
+    }
+
+    pub fn is_approved(&self) -> bool {
+        self.request.as_ref().map_or(false, |request| {
+            request.approvals.len() as u64 >= self.threshold
+        })
+    }
+
+    /// Opens the window for the other guardians to approve. Only a guardian can open it,
+    /// so nobody else can block the account with requests, and its approval counts right away.
+    /// A request which is still open or approved blocks new ones, until the owner vetoes it.
+    pub fn request(
+        &mut self,
+        guardian: Principal,
+        new_principal: Principal,
+        now: u64,
+    ) -> Result<RecoveryRequest, SmartVaultErr> {
+        if !self.guardians.contains(&guardian) {
+            return Err(SmartVaultErr::NotARecoveryGuardian(guardian.to_string()));
+        }
+        if let Some(request) = &self.request {
+            if now < request.closes_at || self.is_approved() {
+                return Err(SmartVaultErr::RecoveryAlreadyRequested(
+                    self.account.to_string(),
+                ));
+            }
+        }
+        let request = RecoveryRequest {
+            new_principal,
+            date_created: now,
+            closes_at: now.saturating_add(self.window_secs.saturating_mul(1000000000)),
+            approvals: vec![guardian],
+        };
+        self.request = Some(request.clone());
+        Ok(request)
+    }
+
+    /// The guardian names the new principal, so a request replaced in the meantime is not approved.
+    pub fn approve(
+        &mut self,
+        guardian: Principal,
+        new_principal: &Principal,
+        now: u64,
+    ) -> Result<RecoveryRequest, SmartVaultErr> {
+        if !self.guardians.contains(&guardian) {
+            return Err(SmartVaultErr::NotARecoveryGuardian(guardian.to_string()));
+        }
+        let account = self.account.to_string();
+        let request = self
+            .request
+            .as_mut()
+            .filter(|request| request.new_principal == *new_principal)
+            .ok_or_else(|| SmartVaultErr::RecoveryRequestDoesNotExist(account.clone()))?;
+        if now >= request.closes_at {
+            return Err(SmartVaultErr::RecoveryWindowClosed(account));
+        }
+        if !request.approvals.contains(&guardian) {
+            request.approvals.push(guardian);
+        }
+        Ok(request.clone())
+    }
+
+    /// The owner can veto until the recovery is completed.
+    pub fn veto(&mut self) -> Result<RecoveryRequest, SmartVaultErr> {
+        self.request
+            .take()
+            .ok_or_else(|| SmartVaultErr::RecoveryRequestDoesNotExist(self.account.to_string()))
+    }
+
+    /// The recovery can be completed once the window closed with enough approvals.
+    pub fn verify_completable(
+        &self,
+        new_principal: &Principal,
+        now: u64,
+    ) -> Result<(), SmartVaultErr> {
+        let request = self
+            .request
+            .as_ref()
+            .filter(|request| request.new_principal == *new_principal)
+            .ok_or_else(|| SmartVaultErr::RecoveryRequestDoesNotExist(self.account.to_string()))?;
+        if now < request.closes_at {
+            return Err(SmartVaultErr::RecoveryWindowOpen(request.closes_at));
+        }
+        if !self.is_approved() {
+            return Err(SmartVaultErr::RecoveryNotApproved(self.account.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// The recovery configurations of all accounts, by their owner.
+pub struct RecoveryRegistry {
+    recoveries: StableBTreeMap<Memory, StorablePrincipal, AccountRecovery>,
+}
+
+impl Default for RecoveryRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecoveryRegistry {
+    pub fn new() -> Self {
+        Self {
+            recoveries: StableBTreeMap::init(
+                get_stable_btree_memory_for_account_recoveries(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_ACCOUNT_RECOVERY_SIZE,
+            ),
+        }
+    }
+
+    pub fn get_recovery(&self, account: &Principal) -> Result<AccountRecovery, SmartVaultErr> {
+        self.recoveries
+            .get(&StorablePrincipal(*account))
+            .ok_or_else(|| SmartVaultErr::RecoveryNotConfigured(account.to_string()))
+    }
+
+    pub fn set_recovery(&mut self, recovery: AccountRecovery) {
+        self.recoveries
+            .insert(StorablePrincipal(recovery.account), recovery)
+            .expect("Failed to insert account recovery into stable memory");
+    }
+
+    pub fn remove_recovery(&mut self, account: &Principal) {
+        self.recoveries.remove(&StorablePrincipal(*account));
+    }
+
+    /// Loads the configuration of the account, applies the update and stores it again.
+    pub fn update_recovery<T>(
+        &mut self,
+        account: &Principal,
+        update: impl FnOnce(&mut AccountRecovery) -> Result<T, SmartVaultErr>,
+    ) -> Result<T, SmartVaultErr> {
+        let mut recovery = self.get_recovery(account)?;
+        let result = update(&mut recovery)?;
+        self.set_recovery(recovery);
+        Ok(result)
+    }
+
+    /// Moves the configuration to the new principal of the account, without the completed request.
+    /// Where the account is a guardian of other accounts, the new principal takes its place.
+    pub fn rebind_account(&mut self, account: &Principal, new_account: Principal) {
+        if let Some(mut recovery) = self.recoveries.remove(&StorablePrincipal(*account)) {
+            recovery.account = new_account;
+            recovery.request = None;
+            self.set_recovery(recovery);
+        }
+        let guarded: Vec<AccountRecovery> = self
+            .recoveries
+            .iter()
+            .map(|(_, recovery)| recovery)
+            .filter(|recovery| recovery.guardians.contains(account))
+            .collect();
+        for mut recovery in guarded {
+            for guardian in recovery.guardians.iter_mut() {
+                if guardian == account {
+                    *guardian = new_account;
+                }
+            }
+            if let Some(request) = recovery.request.as_mut() {
+                for approval in request.approvals.iter_mut() {
+                    if approval == account {
+                        *approval = new_account;
+                    }
+                }
+            }
+            self.set_recovery(recovery);
+        }
+    }
+}
+
+/// Nominates the guardians of the caller's account, or removes them.
+/// A pending recovery request is dropped either way. Only the owner can do so,
+/// a linked principal could otherwise take over the account with guardians of its own.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn set_recovery_guardians(args: Option<SetRecoveryGuardiansArgs>) -> Result<(), SmartVaultErr> {
+    let account = get_owner_caller()?;
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| ur.borrow().get_user(&account))?;
+    let recovery = args
+        .map(|args| AccountRecovery::new(account, args))
+        .transpose()?;
+    RECOVERY_REGISTRY.with(|rr: &RefCell<RecoveryRegistry>| {
+        let mut recovery_registry = rr.borrow_mut();
+        match recovery {
+            Some(recovery) => recovery_registry.set_recovery(recovery),
+            None => recovery_registry.remove_recovery(&account),
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_account_recovery() -> Result<AccountRecovery, SmartVaultErr> {
+    let account = resolve_caller();
+    RECOVERY_REGISTRY.with(|rr: &RefCell<RecoveryRegistry>| rr.borrow().get_recovery(&account))
+}
+
+/// Asks the other guardians of the account to hand it over to the new principal,
+/// as one of its guardians. The owner and the guardians are notified,
+/// so the owner can veto in time.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn request_account_recovery(
+    account: Principal,
+    new_principal: Principal,
+) -> Result<RecoveryRequest, SmartVaultErr> {
+    let guardian = resolve_caller();
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+        let user_registry = ur.borrow();
+        user_registry.get_user(&account)?;
+        user_registry
+            .verify_linkable(&account, &new_principal)
+            .map_err(|_| SmartVaultErr::InvalidRecoveryPrincipal(new_principal.to_string()))
+    })?;
+
+    let now = time::get_current_time();
+    let (request, guardians) = RECOVERY_REGISTRY.with(|rr: &RefCell<RecoveryRegistry>| {
+        rr.borrow_mut().update_recovery(&account, |recovery| {
+            Ok((
+                recovery.request(guardian, new_principal, now)?,
+                recovery.guardians.clone(),
+            ))
+        })
+    })?;
+
+    let kind = NotificationKind::RecoveryRequested {
+        account,
+        new_principal,
+        closes_at: request.closes_at,
+    };
+    outbox::notify(account, kind.clone());
+    for guardian in guardians {
+        outbox::notify(guardian, kind.clone());
+    }
+    Ok(request)
+}
+
+/// Approves the recovery of the account to the new principal, as one of its guardians.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn approve_account_recovery(
+    account: Principal,
+    new_principal: Principal,
+) -> Result<RecoveryRequest, SmartVaultErr> {
+    let guardian = resolve_caller();
+    let now = time::get_current_time();
+    RECOVERY_REGISTRY.with(|rr: &RefCell<RecoveryRegistry>| {
+        rr.borrow_mut().update_recovery(&account, |recovery| {
+            recovery.approve(guardian, &new_principal, now)
+        })
+    })
+}
+
+/// Cancels the recovery request of the caller's account, who needs to be the owner.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn veto_account_recovery() -> Result<(), SmartVaultErr> {
+    let account = get_owner_caller()?;
+    RECOVERY_REGISTRY.with(|rr: &RefCell<RecoveryRegistry>| {
+        rr.borrow_mut()
+            .update_recovery(&account, |recovery| recovery.veto())
+    })?;
+    Ok(())
+}
+
+/// The request as the owner, the guardians and the new principal see it.
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_account_recovery_request(account: Principal) -> Result<RecoveryRequest, SmartVaultErr> {
+    let caller = resolve_caller();
+    let recovery = RECOVERY_REGISTRY
+        .with(|rr: &RefCell<RecoveryRegistry>| rr.borrow().get_recovery(&account))?;
+    // Everybody else cannot even tell whether there is a request
+    recovery
+        .request
+        .filter(|request| {
+            caller == account
+                || caller == request.new_principal
+                || recovery.guardians.contains(&caller)
+        })
+        .ok_or_else(|| SmartVaultErr::RecoveryRequestDoesNotExist(account.to_string()))
+}
+
+/// Takes over the approved account with the caller, once the window closed.
+/// The user, the vault and the testaments are bound to the caller from then on,
+/// the old principal and the principals linked to it lose access.
+/// The vetkd keys of the account stay the same.
+/// Where the old principal is an heir or validator of other testators, it stays one.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn complete_account_recovery(account: Principal) -> Result<User, SmartVaultErr> {
+    let new_principal = get_caller();
+    RECOVERY_REGISTRY.with(|rr: &RefCell<RecoveryRegistry>| {
+        rr.borrow()
+            .get_recovery(&account)?
+            .verify_completable(&new_principal, time::get_current_time())
+    })?;
+
+    // the new principal might have created an account in the meantime
+    let user = USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+        let mut user_registry = ur.borrow_mut();
+        user_registry
+            .verify_linkable(&account, &new_principal)
+            .map_err(|_| SmartVaultErr::InvalidRecoveryPrincipal(new_principal.to_string()))?;
+        user_registry.rebind_user(&account, new_principal)
+    })?;
+
+    // The user is rebound already, an error would leave the vault with the old principal.
+    // Trapping rolls back the whole recovery instead.
+    if let Some(user_vault_id) = user.user_vault_id {
+        MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
+            mv.borrow_mut()
+                .rebind_user_vault(&user_vault_id, new_principal)
+        })
+        .expect("Failed to rebind the vault of the recovered account");
+    }
+    INVITATION_REGISTRY.with(|ir: &RefCell<InvitationRegistry>| {
+        ir.borrow_mut().rebind_testator(&account, new_principal)
+    });
+    RECOVERY_REGISTRY.with(|rr: &RefCell<RecoveryRegistry>| {
+        rr.borrow_mut().rebind_account(&account, new_principal)
+    });
+    OUTBOX.with(|o| o.borrow_mut().move_outbox(account, new_principal));
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1000000000;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    fn recovery(threshold: u64) -> AccountRecovery {
+        let args = SetRecoveryGuardiansArgs {
+            guardians: vec![principal(2), principal(3), principal(4)],
+            threshold,
+            window_secs: Some(60),
+        };
+        AccountRecovery::new(principal(1), args).unwrap()
+    }
+
+    #[test]
+    fn utest_validate_recovery_guardians() {
+        let args = |guardians: Vec<Principal>, threshold| SetRecoveryGuardiansArgs {
+            guardians,
+            threshold,
+            window_secs: None,
+        };
+        let owner = principal(1);
+        let recovery = AccountRecovery::new(owner, args(vec![principal(2)], 1)).unwrap();
+        assert_eq!(recovery.window_secs, DEFAULT_RECOVERY_WINDOW_SECS);
+        assert!(AccountRecovery::new(owner, args(vec![principal(2)], 0)).is_err());
+        assert!(AccountRecovery::new(owner, args(vec![principal(2)], 2)).is_err());
+        assert!(AccountRecovery::new(owner, args(vec![principal(2), principal(2)], 1)).is_err());
+        assert!(AccountRecovery::new(owner, args(vec![owner], 1)).is_err());
+        assert!(AccountRecovery::new(owner, args(vec![Principal::anonymous()], 1)).is_err());
+        let guardians = (2..=MAX_RECOVERY_GUARDIANS as u8 + 2)
+            .map(principal)
+            .collect();
+        assert!(AccountRecovery::new(owner, args(guardians, 1)).is_err());
+    }
+
+    #[test]
+    fn utest_account_recovery() {
+        let new_principal = principal(9);
+        let mut recovery = recovery(2);
+
+        // only guardians can ask for a recovery, their approval counts right away
+        assert_eq!(
+            recovery.request(new_principal, new_principal, 0),
+            Err(SmartVaultErr::NotARecoveryGuardian(
+                new_principal.to_string()
+            ))
+        );
+        let request = recovery.request(principal(2), new_principal, 0).unwrap();
+        assert_eq!(request.closes_at, 60 * SECOND);
+        assert_eq!(request.approvals, vec![principal(2)]);
+        assert_eq!(
+            recovery.request(principal(3), principal(8), SECOND),
+            Err(SmartVaultErr::RecoveryAlreadyRequested(
+                principal(1).to_string()
+            ))
+        );
+
+        // only guardians approve, and only the request they know of
+        assert_eq!(
+            recovery.approve(principal(8), &new_principal, SECOND),
+            Err(SmartVaultErr::NotARecoveryGuardian(
+                principal(8).to_string()
+            ))
+        );
+        assert_eq!(
+            recovery.approve(principal(2), &principal(8), SECOND),
+            Err(SmartVaultErr::RecoveryRequestDoesNotExist(
+                principal(1).to_string()
+            ))
+        );
+        recovery
+            .approve(principal(2), &new_principal, SECOND)
+            .unwrap();
+        recovery
+            .approve(principal(2), &new_principal, SECOND)
+            .unwrap();
+        assert!(!recovery.is_approved());
+        recovery
+            .approve(principal(3), &new_principal, SECOND)
+            .unwrap();
+        assert!(recovery.is_approved());
+
+        // the owner has the whole window to veto
+        assert_eq!(
+            recovery.verify_completable(&new_principal, 59 * SECOND),
+            Err(SmartVaultErr::RecoveryWindowOpen(60 * SECOND))
+        );
+        assert!(recovery
+            .verify_completable(&new_principal, 60 * SECOND)
+            .is_ok());
+        assert_eq!(
+            recovery.approve(principal(4), &new_principal, 60 * SECOND),
+            Err(SmartVaultErr::RecoveryWindowClosed(
+                principal(1).to_string()
+            ))
+        );
+
+        // an approved request blocks new ones until the veto
+        assert!(recovery
+            .request(principal(3), principal(8), 61 * SECOND)
+            .is_err());
+        recovery.veto().unwrap();
+        assert_eq!(
+            recovery.verify_completable(&new_principal, 61 * SECOND),
+            Err(SmartVaultErr::RecoveryRequestDoesNotExist(
+                principal(1).to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn utest_account_recovery_not_approved() {
+        let new_principal = principal(9);
+        let mut recovery = recovery(3);
+        recovery.request(principal(2), new_principal, 0).unwrap();
+        recovery
+            .approve(principal(3), &new_principal, SECOND)
+            .unwrap();
+        assert_eq!(
+            recovery.verify_completable(&new_principal, 60 * SECOND),
+            Err(SmartVaultErr::RecoveryNotApproved(principal(1).to_string()))
+        );
+
+        // a failed request can be replaced
+        let request = recovery
+            .request(principal(4), principal(8), 60 * SECOND)
+            .unwrap();
+        assert_eq!(request.approvals, vec![principal(4)]);
+    }
+
+    #[test]
+    fn utest_rebind_account_recovery() {
+        let mut recovery_registry = RecoveryRegistry::new();
+        let mut alice = recovery(1);
+        alice.request(principal(2), principal(9), 0).unwrap();
+        recovery_registry.set_recovery(alice);
+        let args = SetRecoveryGuardiansArgs {
+            guardians: vec![principal(1)],
+            threshold: 1,
+            window_secs: None,
+        };
+        recovery_registry.set_recovery(AccountRecovery::new(principal(5), args).unwrap());
+
+        recovery_registry.rebind_account(&principal(1), principal(9));
+        assert_eq!(
+            recovery_registry.get_recovery(&principal(1)),
+            Err(SmartVaultErr::RecoveryNotConfigured(
+                principal(1).to_string()
+            ))
+        );
+        let recovered = recovery_registry.get_recovery(&principal(9)).unwrap();
+        assert_eq!(recovered.account, principal(9));
+        assert_eq!(recovered.request, None);
+        assert_eq!(
+            recovery_registry
+                .get_recovery(&principal(5))
+                .unwrap()
+                .guardians,
+            vec![principal(9)]
+        );
+    }
+}
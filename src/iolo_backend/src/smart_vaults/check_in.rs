@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::common::error::SmartVaultErr;
+use crate::common::user::User;
+use crate::utils::time;
+
+use super::master_vault::MasterVault;
+use super::smart_vault::{resolve_caller, MASTERVAULT, USER_REGISTRY};
+use super::user_registry::UserRegistry;
+
+// Users are stored with a fixed maximum size, so the number of dates is limited
+pub const MAX_CHECK_IN_DATES: usize = 100;
+
+/// When the testator has to check in to prove being alive. Dates are in nanoseconds.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum CheckInSchedule {
+    /// A check-in is due interval_secs after the previous one
+    Recurring { interval_secs: u64 },
+    /// A check-in is due at each of the dates. A check-in meets the next date after it.
+    FixedDates { dates: Vec<u64> },
+}
+
+impl CheckInSchedule {
+    pub fn validate(&self) -> Result<(), SmartVaultErr> {
+        match self {
+            CheckInSchedule::Recurring { interval_secs } if *interval_secs == 0 => {
+                Err(SmartVaultErr::InvalidCheckInSchedule(
+                    "The interval needs to be at least 1 second".to_string(),
+                ))
+            }
+            CheckInSchedule::FixedDates { dates } if dates.is_empty() => Err(
+                SmartVaultErr::InvalidCheckInSchedule("At least one date is needed".to_string()),
+            ),
+            CheckInSchedule::FixedDates { dates } if dates.len() > MAX_CHECK_IN_DATES => {
+                Err(SmartVaultErr::InvalidCheckInSchedule(format!(
+                    "At most {} dates are allowed",
+                    MAX_CHECK_IN_DATES
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The check-in schedule of a user and when the user checked in last.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CheckIn {
+    pub schedule: CheckInSchedule,
+    /// Until the first check-in, the deadlines count from the configuration
+    pub configured_at: u64,
+    pub last_check_in: Option<u64>,
+}
+
+/// What the user sees of the check-in, see get_check_in_status.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CheckInStatus {
+    pub schedule: CheckInSchedule,
+    pub last_check_in: Option<u64>,
+    /// The deadlines missed in a row since the last check-in
+    pub missed_check_ins: u64,
+    /// The deadline the next check-in meets, if there is one left
+    pub next_deadline: Option<u64>,
+}
+
+impl CheckIn {
+    pub fn new(schedule: CheckInSchedule, now: u64) -> Self {
+        Self {
+            schedule,
+            configured_at: now,
+            last_check_in: None,
+        }
+    }
+
+    pub fn check_in(&mut self, now: u64) {
+        self.last_check_in = Some(now);
+    }
+
+    fn anchor(&self) -> u64 {
+        self.last_check_in.unwrap_or(self.configured_at)
+    }
+
+    /// Returns the nth (starting at 0) deadline the user has to meet after the anchor.
+    /// A deadline is missed once it has passed without a check-in.
+    pub fn deadline(&self, n: u64) -> Option<u64> {
+        match &self.schedule {
+            CheckInSchedule::Recurring { interval_secs } => Some(
+                self.anchor().saturating_add(
+                    n.saturating_add(1)
+                        .saturating_mul(interval_secs.saturating_mul(1000000000)),
+                ),
+            ),
+            CheckInSchedule::FixedDates { .. } => self.open_dates().nth(usize::try_from(n).ok()?),
+        }
+    }
+
+    pub fn missed_check_ins(&self, now: u64) -> u64 {
+        match &self.schedule {
+            CheckInSchedule::Recurring { interval_secs } => {
+                let interval = interval_secs.saturating_mul(1000000000).max(1);
+                now.saturating_sub(self.anchor()).saturating_sub(1) / interval
+            }
+            CheckInSchedule::FixedDates { .. } => {
+                self.open_dates().filter(|date| *date < now).count() as u64
+            }
+        }
+    }
+
+    pub fn status(&self, now: u64) -> CheckInStatus {
+        let missed_check_ins = self.missed_check_ins(now);
+        CheckInStatus {
+            schedule: self.schedule.clone(),
+            last_check_in: self.last_check_in,
+            missed_check_ins,
+            next_deadline: self.deadline(missed_check_ins),
+        }
+    }
+
+    // The dates after the anchor, without the one met by the last check-in
+    fn open_dates(&self) -> impl Iterator<Item = u64> {
+        let mut dates = match &self.schedule {
+            CheckInSchedule::FixedDates { dates } => dates.clone(),
+            CheckInSchedule::Recurring { .. } => Vec::new(),
+        };
+        dates.sort_unstable();
+        dates.dedup();
+        let anchor = self.anchor();
+        let met = usize::from(self.last_check_in.is_some());
+        dates
+            .into_iter()
+            .filter(move |date| *date >= anchor)
+            .skip(met)
+    }
+}
+
+/// Configures the check-in schedule of the caller, or removes it.
+/// Configuring counts as a check-in, so pending releases are cancelled.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn set_check_in_schedule(schedule: Option<CheckInSchedule>) -> Result<(), SmartVaultErr> {
+    if let Some(schedule) = &schedule {
+        schedule.validate()?;
+    }
+    let now = time::get_current_time();
+    let user = update_caller(|user| {
+        user.check_in = schedule.map(|schedule| CheckIn::new(schedule, now));
+        Ok(())
+    })?;
+    cancel_pending_releases(&user)
+}
+
+/// Proves that the caller is alive, which cancels pending releases as well.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn check_in() -> Result<CheckInStatus, SmartVaultErr> {
+    let now = time::get_current_time();
+    let user = update_caller(|user| {
+        user.check_in
+            .as_mut()
+            .ok_or_else(|| SmartVaultErr::CheckInNotConfigured(user.id.to_string()))?
+            .check_in(now);
+        Ok(())
+    })?;
+    cancel_pending_releases(&user)?;
+    Ok(user.check_in.unwrap().status(now))
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_check_in_status() -> Result<CheckInStatus, SmartVaultErr> {
+    let principal = resolve_caller();
+    let user = USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| ur.borrow().get_user(&principal))?;
+    user.check_in
+        .map(|check_in| check_in.status(time::get_current_time()))
+        .ok_or_else(|| SmartVaultErr::CheckInNotConfigured(principal.to_string()))
+}
+
+fn update_caller(
+    update: impl FnOnce(&mut User) -> Result<(), SmartVaultErr>,
+) -> Result<User, SmartVaultErr> {
+    // The caller is resolved through the registry, so before borrowing it mutably
+    let caller = resolve_caller();
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+        let mut user_registry = ur.borrow_mut();
+        let mut user = user_registry.get_user(&caller)?;
+        update(&mut user)?;
+        user_registry.update_user(user)
+    })
+}
+
+// The deadlines of the testaments changed, which is why they get evaluated again as well
+fn cancel_pending_releases(user: &User) -> Result<(), SmartVaultErr> {
+    match user.user_vault_id {
+        Some(user_vault_id) => MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
+            mv.borrow_mut().cancel_pending_releases(&user_vault_id)
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1000000000;
+
+    #[test]
+    fn utest_recurring_check_in() {
+        let mut check_in = CheckIn::new(CheckInSchedule::Recurring { interval_secs: 10 }, 0);
+        assert_eq!(check_in.missed_check_ins(10 * SECOND), 0);
+        assert_eq!(check_in.missed_check_ins(10 * SECOND + 1), 1);
+        assert_eq!(check_in.missed_check_ins(35 * SECOND), 3);
+        assert_eq!(
+            check_in.status(35 * SECOND).next_deadline,
+            Some(40 * SECOND)
+        );
+
+        // a check-in starts the count anew
+        check_in.check_in(35 * SECOND);
+        assert_eq!(check_in.missed_check_ins(45 * SECOND), 0);
+        assert_eq!(check_in.deadline(1), Some(55 * SECOND));
+    }
+
+    #[test]
+    fn utest_fixed_dates_check_in() {
+        let dates = vec![30 * SECOND, 10 * SECOND, 20 * SECOND];
+        let mut check_in = CheckIn::new(CheckInSchedule::FixedDates { dates }, 0);
+        assert_eq!(check_in.missed_check_ins(10 * SECOND), 0);
+        assert_eq!(check_in.missed_check_ins(25 * SECOND), 2);
+
+        // the check-in meets the next date, the one after it is still open
+        check_in.check_in(25 * SECOND);
+        assert_eq!(check_in.deadline(0), None);
+        assert_eq!(check_in.missed_check_ins(40 * SECOND), 0);
+        check_in.check_in(15 * SECOND);
+        assert_eq!(check_in.deadline(0), Some(30 * SECOND));
+        assert_eq!(check_in.missed_check_ins(40 * SECOND), 1);
+        assert_eq!(check_in.status(40 * SECOND).next_deadline, None);
+    }
+
+    #[test]
+    fn utest_validate_check_in_schedule() {
+        assert!(CheckInSchedule::Recurring { interval_secs: 1 }
+            .validate()
+            .is_ok());
+        assert!(CheckInSchedule::Recurring { interval_secs: 0 }
+            .validate()
+            .is_err());
+        assert!(CheckInSchedule::FixedDates { dates: Vec::new() }
+            .validate()
+            .is_err());
+        let dates = vec![0; MAX_CHECK_IN_DATES + 1];
+        assert!(CheckInSchedule::FixedDates { dates }.validate().is_err());
+    }
+}
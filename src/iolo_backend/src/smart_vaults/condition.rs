@@ -0,0 +1,266 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+use crate::common::error::SmartVaultErr;
+use crate::smart_vaults::check_in::CheckIn;
+
+/// A condition which has to be met before the heirs get access to a testament.
+/// Conditions can be composed using And and Or.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum Condition {
+    /// Met when the testator has not logged in for the given number of seconds
+    LastLogin { max_inactivity_secs: u64 },
+    /// Met from the given point in time on (in nanoseconds)
+    FixedDate { release_date: u64 },
+    /// Met when at least quorum heirs confirmed that the testator passed away
+    HeirQuorum { quorum: u64 },
+    /// Met when at least quorum validators confirmed that the testator passed away
+    ValidatorQuorum { quorum: u64 },
+    /// Met when the testator missed at least count check-ins in a row
+    MissedCheckIns { count: u64 },
+    /// Met when all of the conditions are met
+    And(Vec<Condition>),
+    /// Met when at least one of the conditions is met
+    Or(Vec<Condition>),
+}
+
+/// Everything the engine needs to know about a testament and its testator
+/// to evaluate a condition.
+#[derive(Debug, Clone)]
+pub struct ConditionContext {
+    pub now: u64,
+    pub last_login: Option<u64>,
+    pub heir_confirmations: u64,
+    pub validator_confirmations: u64,
+    pub check_in: Option<CheckIn>,
+}
+
+impl Condition {
+    pub fn evaluate(&self, ctx: &ConditionContext) -> bool {
+        match self {
+            Condition::LastLogin { max_inactivity_secs } => {
+                let max_inactivity: u64 = max_inactivity_secs.saturating_mul(1000000000); // in nanoseconds
+                match ctx.last_login {
+                    Some(last_login) => last_login < ctx.now.saturating_sub(max_inactivity),
+                    None => false,
+                }
+            }
+            Condition::FixedDate { release_date } => ctx.now >= *release_date,
+            Condition::HeirQuorum { quorum } => ctx.heir_confirmations >= *quorum,
+            Condition::ValidatorQuorum { quorum } => ctx.validator_confirmations >= *quorum,
+            Condition::MissedCheckIns { count } => {
+                ctx.check_in.as_ref().map_or(false, |check_in| {
+                    check_in.missed_check_ins(ctx.now) >= *count
+                })
+            }
+            Condition::And(conditions) => conditions.iter().all(|c| c.evaluate(ctx)),
+            Condition::Or(conditions) => conditions.iter().any(|c| c.evaluate(ctx)),
+        }
+    }
+
+    /// Returns the earliest point in time after now at which the passing of time alone
+    /// can change the outcome of the evaluation. Quorums only change with confirmations,
+    /// so they have no deadline.
+    pub fn next_deadline(&self, ctx: &ConditionContext) -> Option<u64> {
+        let deadline = match self {
+            Condition::LastLogin { max_inactivity_secs } => ctx.last_login.map(|last_login| {
+                last_login
+                    .saturating_add(max_inactivity_secs.saturating_mul(1000000000))
+                    .saturating_add(1)
+            }),
+            Condition::FixedDate { release_date } => Some(*release_date),
+            Condition::HeirQuorum { .. } | Condition::ValidatorQuorum { .. } => None,
+            // the count is reached once the deadline of the last check-in in question has passed
+            Condition::MissedCheckIns { count } => ctx
+                .check_in
+                .as_ref()
+                .and_then(|check_in| check_in.deadline(count.saturating_sub(1)))
+                .map(|deadline| deadline.saturating_add(1)),
+            Condition::And(conditions) | Condition::Or(conditions) => conditions
+                .iter()
+                .filter_map(|c| c.next_deadline(ctx))
+                .min(),
+        };
+        deadline.filter(|deadline| *deadline > ctx.now)
+    }
+
+    /// Returns the shortest inactivity period of all LastLogin conditions, in seconds.
+    pub fn min_inactivity_secs(&self) -> Option<u64> {
+        match self {
+            Condition::LastLogin { max_inactivity_secs } => Some(*max_inactivity_secs),
+            Condition::And(conditions) | Condition::Or(conditions) => conditions
+                .iter()
+                .filter_map(Condition::min_inactivity_secs)
+                .min(),
+            _ => None,
+        }
+    }
+
+    /// Checks that the condition can be met at all.
+    pub fn validate(&self) -> Result<(), SmartVaultErr> {
+        match self {
+            Condition::And(conditions) | Condition::Or(conditions) => {
+                if conditions.is_empty() {
+                    return Err(SmartVaultErr::InvalidCondition(
+                        "And/Or needs at least one condition".to_string(),
+                    ));
+                }
+                conditions.iter().try_for_each(Condition::validate)
+            }
+            Condition::HeirQuorum { quorum } | Condition::ValidatorQuorum { quorum } => {
+                if *quorum == 0 {
+                    return Err(SmartVaultErr::InvalidCondition(
+                        "Quorum needs to be at least 1".to_string(),
+                    ));
+                }
+                Ok(())
+            }
+            Condition::MissedCheckIns { count } => {
+                if *count == 0 {
+                    return Err(SmartVaultErr::InvalidCondition(
+                        "At least 1 missed check-in is needed".to_string(),
+                    ));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smart_vaults::check_in::CheckInSchedule;
+
+    const SECOND: u64 = 1000000000;
+
+    fn ctx(now: u64, last_login: u64) -> ConditionContext {
+        ConditionContext {
+            now,
+            last_login: Some(last_login),
+            heir_confirmations: 0,
+            validator_confirmations: 0,
+            check_in: None,
+        }
+    }
+
+    #[test]
+    fn utest_last_login() {
+        let condition = Condition::LastLogin {
+            max_inactivity_secs: 10,
+        };
+        assert!(!condition.evaluate(&ctx(100 * SECOND, 95 * SECOND)));
+        assert!(condition.evaluate(&ctx(100 * SECOND, 80 * SECOND)));
+        assert!(!condition.evaluate(&ConditionContext {
+            last_login: None,
+            ..ctx(100 * SECOND, 0)
+        }));
+    }
+
+    #[test]
+    fn utest_fixed_date() {
+        let condition = Condition::FixedDate {
+            release_date: 50 * SECOND,
+        };
+        assert!(!condition.evaluate(&ctx(49 * SECOND, 0)));
+        assert!(condition.evaluate(&ctx(50 * SECOND, 0)));
+    }
+
+    #[test]
+    fn utest_quorum() {
+        let condition = Condition::HeirQuorum { quorum: 2 };
+        let mut context = ctx(0, 0);
+        context.heir_confirmations = 1;
+        assert!(!condition.evaluate(&context));
+        context.heir_confirmations = 2;
+        assert!(condition.evaluate(&context));
+
+        // validator confirmations do not count for the heir quorum
+        let condition = Condition::ValidatorQuorum { quorum: 1 };
+        assert!(!condition.evaluate(&context));
+        context.validator_confirmations = 1;
+        assert!(condition.evaluate(&context));
+    }
+
+    #[test]
+    fn utest_missed_check_ins() {
+        let condition = Condition::MissedCheckIns { count: 2 };
+        let check_in = CheckIn::new(CheckInSchedule::Recurring { interval_secs: 10 }, 0);
+        let context = |now| ConditionContext {
+            check_in: Some(check_in.clone()),
+            ..ctx(now, 0)
+        };
+        assert!(!condition.evaluate(&context(20 * SECOND)));
+        assert!(condition.evaluate(&context(20 * SECOND + 1)));
+        assert_eq!(condition.next_deadline(&context(0)), Some(20 * SECOND + 1));
+
+        // without a check-in schedule the condition cannot be met
+        assert!(!condition.evaluate(&ctx(100 * SECOND, 0)));
+        assert_eq!(condition.next_deadline(&ctx(0, 0)), None);
+    }
+
+    #[test]
+    fn utest_composition() {
+        let inactive = Condition::LastLogin {
+            max_inactivity_secs: 10,
+        };
+        let released = Condition::FixedDate {
+            release_date: 50 * SECOND,
+        };
+        let and = Condition::And(vec![inactive.clone(), released.clone()]);
+        let or = Condition::Or(vec![inactive, released]);
+
+        // inactive, but release date not reached yet
+        assert!(!and.evaluate(&ctx(40 * SECOND, 0)));
+        assert!(or.evaluate(&ctx(40 * SECOND, 0)));
+
+        // both met
+        assert!(and.evaluate(&ctx(60 * SECOND, 0)));
+    }
+
+    #[test]
+    fn utest_next_deadline() {
+        let inactive = Condition::LastLogin {
+            max_inactivity_secs: 10,
+        };
+        let released = Condition::FixedDate {
+            release_date: 50 * SECOND,
+        };
+        assert_eq!(inactive.next_deadline(&ctx(0, 0)), Some(10 * SECOND + 1));
+        assert!(!inactive.evaluate(&ctx(10 * SECOND, 0)));
+        assert!(inactive.evaluate(&ctx(10 * SECOND + 1, 0)));
+
+        // passed deadlines do not count anymore
+        let or = Condition::Or(vec![inactive, released, Condition::HeirQuorum { quorum: 1 }]);
+        assert_eq!(or.next_deadline(&ctx(20 * SECOND, 0)), Some(50 * SECOND));
+        assert_eq!(or.next_deadline(&ctx(50 * SECOND, 0)), None);
+        assert_eq!(Condition::HeirQuorum { quorum: 1 }.next_deadline(&ctx(0, 0)), None);
+    }
+
+    #[test]
+    fn utest_min_inactivity_secs() {
+        let condition = Condition::Or(vec![
+            Condition::LastLogin {
+                max_inactivity_secs: 30,
+            },
+            Condition::And(vec![
+                Condition::LastLogin {
+                    max_inactivity_secs: 20,
+                },
+                Condition::HeirQuorum { quorum: 1 },
+            ]),
+        ]);
+        assert_eq!(condition.min_inactivity_secs(), Some(20));
+        assert_eq!(Condition::FixedDate { release_date: 0 }.min_inactivity_secs(), None);
+    }
+
+    #[test]
+    fn utest_validate() {
+        assert!(Condition::FixedDate { release_date: 0 }.validate().is_ok());
+        assert!(Condition::And(vec![]).validate().is_err());
+        assert!(Condition::HeirQuorum { quorum: 0 }.validate().is_err());
+        assert!(Condition::MissedCheckIns { count: 0 }.validate().is_err());
+        assert!(Condition::Or(vec![Condition::And(vec![])]).validate().is_err());
+    }
+}
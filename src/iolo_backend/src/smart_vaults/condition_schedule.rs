@@ -0,0 +1,197 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use candid::Principal;
+use ic_stable_structures::{StableBTreeMap, Storable};
+
+use crate::common::memory::{
+    get_stable_btree_memory_for_deadlines, get_stable_btree_memory_for_testament_to_deadline,
+    Memory, StorablePrincipal, MAX_PRINCIPAL_SIZE, MAX_TESTAMENT_ID_SIZE,
+};
+use crate::smart_vaults::smart_vault::CONDITION_SCHEDULE;
+use crate::smart_vaults::testament::{Testament, TestamentID};
+
+const DEADLINE_SIZE: u32 = 8;
+
+/// Key of the deadline index. The deadline is encoded big endian and comes first,
+/// so the byte order of the keys is the order of the deadlines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DeadlineKey {
+    deadline: u64,
+    testament_id: TestamentID,
+}
+
+impl Storable for DeadlineKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.deadline.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.testament_id.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        let (deadline, testament_id) = bytes.split_at(DEADLINE_SIZE as usize);
+        DeadlineKey {
+            deadline: u64::from_be_bytes(deadline.try_into().unwrap()),
+            testament_id: String::from_utf8(testament_id.to_vec()).unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Deadline(u64);
+
+impl Storable for Deadline {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Deadline(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Keeps track of when the condition of every testament needs to be evaluated next,
+/// so the periodic task only touches the testaments whose deadline has passed.
+/// A testament without an entry is not evaluated by the periodic task at all,
+/// e.g. because it is released or only waits for confirmations.
+pub struct ConditionSchedule {
+    // (deadline, testament id) -> testator
+    deadlines: StableBTreeMap<Memory, DeadlineKey, StorablePrincipal>,
+    // testament id -> deadline, needed to find the entry of a testament in the deadline index
+    testament_to_deadline: StableBTreeMap<Memory, TestamentID, Deadline>,
+}
+
+impl Default for ConditionSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConditionSchedule {
+    pub fn new() -> Self {
+        Self {
+            deadlines: StableBTreeMap::init(
+                get_stable_btree_memory_for_deadlines(),
+                DEADLINE_SIZE + MAX_TESTAMENT_ID_SIZE,
+                MAX_PRINCIPAL_SIZE,
+            ),
+            testament_to_deadline: StableBTreeMap::init(
+                get_stable_btree_memory_for_testament_to_deadline(),
+                MAX_TESTAMENT_ID_SIZE,
+                DEADLINE_SIZE,
+            ),
+        }
+    }
+
+    /// Sets the next evaluation of a testament, replacing the existing one.
+    pub fn schedule(&mut self, testament_id: &TestamentID, testator: Principal, deadline: u64) {
+        self.unschedule(testament_id);
+        self.deadlines
+            .insert(
+                DeadlineKey {
+                    deadline,
+                    testament_id: testament_id.clone(),
+                },
+                StorablePrincipal(testator),
+            )
+            .expect("Failed to insert into deadlines");
+        self.testament_to_deadline
+            .insert(testament_id.clone(), Deadline(deadline))
+            .expect("Failed to insert into testament_to_deadline");
+    }
+
+    pub fn unschedule(&mut self, testament_id: &TestamentID) {
+        if let Some(Deadline(deadline)) = self.testament_to_deadline.remove(testament_id) {
+            self.deadlines.remove(&DeadlineKey {
+                deadline,
+                testament_id: testament_id.clone(),
+            });
+        }
+    }
+
+    pub fn get_deadline(&self, testament_id: &TestamentID) -> Option<u64> {
+        self.testament_to_deadline.get(testament_id).map(|d| d.0)
+    }
+
+    /// Returns at most limit testaments (with their testator) whose deadline is not after now,
+    /// the most overdue first.
+    pub fn get_due(&self, now: u64, limit: usize) -> Vec<(TestamentID, Principal)> {
+        self.deadlines
+            .iter()
+            .take_while(|(key, _)| key.deadline <= now)
+            .take(limit)
+            .map(|(key, testator)| (key.testament_id, testator.0))
+            .collect()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.testament_to_deadline.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.testament_to_deadline.is_empty()
+    }
+}
+
+/// Makes the periodic task evaluate the condition of the testament at the given time.
+pub fn schedule_evaluation(testament: &Testament, deadline: u64) {
+    CONDITION_SCHEDULE.with(|cs: &RefCell<ConditionSchedule>| {
+        cs.borrow_mut()
+            .schedule(testament.id(), *testament.testator(), deadline)
+    });
+}
+
+pub fn unschedule_evaluation(testament_id: &TestamentID) {
+    CONDITION_SCHEDULE.with(|cs: &RefCell<ConditionSchedule>| {
+        cs.borrow_mut().unschedule(testament_id)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utest_deadline_key_order() {
+        let early = DeadlineKey {
+            deadline: 255,
+            testament_id: "z".to_string(),
+        };
+        let late = DeadlineKey {
+            deadline: 256,
+            testament_id: "a".to_string(),
+        };
+        assert!(early.to_bytes() < late.to_bytes());
+        assert_eq!(DeadlineKey::from_bytes(late.to_bytes().into_owned()), late);
+    }
+
+    #[test]
+    fn utest_schedule() {
+        let testator = Principal::from_slice(&[1; 29]);
+        let mut schedule = ConditionSchedule::new();
+        schedule.schedule(&"t1".to_string(), testator, 30);
+        schedule.schedule(&"t2".to_string(), testator, 10);
+        schedule.schedule(&"t3".to_string(), testator, 20);
+
+        // rescheduling replaces the existing entry
+        schedule.schedule(&"t1".to_string(), testator, 15);
+        assert_eq!(schedule.len(), 3);
+        assert_eq!(schedule.get_deadline(&"t1".to_string()), Some(15));
+
+        // due entries come ordered by deadline and bounded by the limit
+        assert_eq!(
+            schedule.get_due(20, 10),
+            vec![
+                ("t2".to_string(), testator),
+                ("t1".to_string(), testator),
+                ("t3".to_string(), testator)
+            ]
+        );
+        assert_eq!(schedule.get_due(20, 1), vec![("t2".to_string(), testator)]);
+        assert!(schedule.get_due(9, 10).is_empty());
+
+        schedule.unschedule(&"t2".to_string());
+        assert_eq!(schedule.get_due(10, 10), vec![]);
+        assert_eq!(schedule.get_deadline(&"t2".to_string()), None);
+    }
+}
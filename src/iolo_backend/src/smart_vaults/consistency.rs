@@ -0,0 +1,425 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+
+use crate::common::admin::verify_caller_is_admin;
+use crate::common::error::SmartVaultErr;
+
+use super::master_vault::MasterVault;
+use super::smart_vault::{MASTERVAULT, TESTAMENT_REGISTRY, USER_REGISTRY};
+use super::testament::{Testament, TestamentID};
+use super::testament_registry::TestamentRegistry;
+use super::user_registry::UserRegistry;
+
+// Upper bound of the entries checked by one call, if the caller does not say otherwise
+const DEFAULT_PAGE_SIZE: u64 = 100;
+
+/// Where a check continues. The vaults are checked against the registry first,
+/// then every index of the registry against the vaults.
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq)]
+pub enum ConsistencyCursor {
+    Vaults {
+        after: Option<Principal>,
+    },
+    Testators {
+        after: Option<TestamentID>,
+    },
+    Heirs {
+        after: Option<(Principal, TestamentID)>,
+    },
+    Validators {
+        after: Option<(Principal, TestamentID)>,
+    },
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone)]
+pub struct ConsistencyCheckArgs {
+    pub repair: bool,
+    /// Where to continue, None starts a new check
+    pub cursor: Option<ConsistencyCursor>,
+    pub page_size: Option<u64>,
+}
+
+/// Differences between the testament registry and the testaments in the vaults of the users,
+/// found in one page of a check. Testament ids are only unique per testator, so testaments
+/// are identified by their testator and id.
+#[derive(Debug, Default, CandidType, Deserialize, Clone, PartialEq)]
+pub struct ConsistencyReport {
+    /// Registered testaments whose testator or vault does not exist anymore
+    pub dangling_testaments: Vec<(Principal, TestamentID)>,
+    /// Heirs registered for testaments which do not name them
+    pub dangling_heir_entries: Vec<(Principal, TestamentID)>,
+    /// Validators registered for testaments which do not name them
+    pub dangling_validator_entries: Vec<(Principal, TestamentID)>,
+    /// Testaments in the vaults which are not (completely) registered
+    pub unregistered_testaments: Vec<(Principal, TestamentID)>,
+    /// Whether the differences were repaired
+    pub repaired: bool,
+    /// Where the next page starts, None once the check is complete
+    pub next_cursor: Option<ConsistencyCursor>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.dangling_testaments.is_empty()
+            && self.dangling_heir_entries.is_empty()
+            && self.dangling_validator_entries.is_empty()
+            && self.unregistered_testaments.is_empty()
+    }
+}
+
+/// The testaments of the vaults, read on demand and at most once per testator.
+pub struct VaultTestaments<F: FnMut(&Principal) -> BTreeMap<TestamentID, Testament>> {
+    load: F,
+    testaments: BTreeMap<Principal, BTreeMap<TestamentID, Testament>>,
+}
+
+impl<F: FnMut(&Principal) -> BTreeMap<TestamentID, Testament>> VaultTestaments<F> {
+    pub fn new(load: F) -> Self {
+        Self {
+            load,
+            testaments: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, testator: &Principal, testament_id: &TestamentID) -> Option<&Testament> {
+        let load = &mut self.load;
+        self.testaments
+            .entry(*testator)
+            .or_insert_with(|| load(testator))
+            .get(testament_id)
+            .filter(|testament| testament.testator() == testator)
+    }
+
+    fn get_all(&mut self, testator: &Principal) -> Vec<Testament> {
+        let load = &mut self.load;
+        self.testaments
+            .entry(*testator)
+            .or_insert_with(|| load(testator))
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Checks one page of at most page_size entries, starting at the cursor.
+/// A page does not span two steps of the check, so a step may end with a short page.
+pub fn check_registry_page<F: FnMut(&Principal) -> BTreeMap<TestamentID, Testament>>(
+    registry: &TestamentRegistry,
+    testators: &dyn Fn(Option<Principal>, usize) -> Vec<Principal>,
+    vaults: &mut VaultTestaments<F>,
+    cursor: ConsistencyCursor,
+    page_size: usize,
+) -> ConsistencyReport {
+    let mut report = ConsistencyReport::default();
+    let next_cursor = match cursor {
+        ConsistencyCursor::Vaults { after } => {
+            let page = testators(after, page_size);
+            for testator in &page {
+                for testament in vaults.get_all(testator) {
+                    if !registry.is_registered(&testament) {
+                        report
+                            .unregistered_testaments
+                            .push((*testator, testament.id().clone()));
+                    }
+                }
+            }
+            Some(match page.last() {
+                Some(last) if page.len() == page_size => {
+                    ConsistencyCursor::Vaults { after: Some(*last) }
+                }
+                _ => ConsistencyCursor::Testators { after: None },
+            })
+        }
+        ConsistencyCursor::Testators { after } => {
+            let page = registry.get_testament_ids_page(after.as_ref(), page_size);
+            for (testament_id, testator) in &page {
+                if vaults.get(testator, testament_id).is_none() {
+                    report
+                        .dangling_testaments
+                        .push((*testator, testament_id.clone()));
+                }
+            }
+            Some(match page.last() {
+                Some((last, _)) if page.len() == page_size => ConsistencyCursor::Testators {
+                    after: Some(last.clone()),
+                },
+                _ => ConsistencyCursor::Heirs { after: None },
+            })
+        }
+        ConsistencyCursor::Heirs { after } => {
+            let page = registry.get_heir_entries_page(after.as_ref(), page_size);
+            for (heir, testament_id) in &page {
+                let names_heir = registry
+                    .get_testator_of_testament(testament_id.clone())
+                    .and_then(|testator| vaults.get(&testator, testament_id))
+                    .map_or(false, |testament| testament.heirs().contains(heir));
+                if !names_heir {
+                    report
+                        .dangling_heir_entries
+                        .push((*heir, testament_id.clone()));
+                }
+            }
+            Some(match page.last() {
+                Some(last) if page.len() == page_size => ConsistencyCursor::Heirs {
+                    after: Some(last.clone()),
+                },
+                _ => ConsistencyCursor::Validators { after: None },
+            })
+        }
+        ConsistencyCursor::Validators { after } => {
+            let page = registry.get_validator_entries_page(after.as_ref(), page_size);
+            for (validator, testament_id) in &page {
+                let names_validator = registry
+                    .get_testator_of_testament(testament_id.clone())
+                    .and_then(|testator| vaults.get(&testator, testament_id))
+                    .map_or(false, |testament| {
+                        testament.validators().contains(validator)
+                    });
+                if !names_validator {
+                    report
+                        .dangling_validator_entries
+                        .push((*validator, testament_id.clone()));
+                }
+            }
+            match page.last() {
+                Some(last) if page.len() == page_size => Some(ConsistencyCursor::Validators {
+                    after: Some(last.clone()),
+                }),
+                _ => None,
+            }
+        }
+    };
+    ConsistencyReport {
+        next_cursor,
+        ..report
+    }
+}
+
+/// Removes the dangling entries and registers the missing testaments of the report.
+/// A testament whose id is registered to another testator is left unregistered,
+/// as registering it would take the id from the other testator.
+pub fn repair_registry<F: FnMut(&Principal) -> BTreeMap<TestamentID, Testament>>(
+    registry: &mut TestamentRegistry,
+    vaults: &mut VaultTestaments<F>,
+    report: &mut ConsistencyReport,
+) {
+    for (testator, testament_id) in &report.dangling_testaments {
+        if registry.get_testator_of_testament(testament_id.clone()) == Some(*testator) {
+            registry.remove_testator_entry(testament_id);
+        }
+    }
+    for (heir, testament_id) in &report.dangling_heir_entries {
+        registry.remove_heir_entry(heir, testament_id);
+    }
+    for (validator, testament_id) in &report.dangling_validator_entries {
+        registry.remove_validator_entry(validator, testament_id);
+    }
+    for (testator, testament_id) in &report.unregistered_testaments {
+        if let Some(testament) = vaults.get(testator, testament_id) {
+            if registry.verify_testament_id_available(testament).is_ok() {
+                registry.add_testament_to_registry(testament);
+            }
+        }
+    }
+    report.repaired = true;
+}
+
+/// Reports the differences between the testament registry and the vaults,
+/// and repairs them if asked to. A check runs in pages, each call checks
+/// the page at the cursor and returns the cursor of the next one.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn check_registry_consistency(
+    args: ConsistencyCheckArgs,
+) -> Result<ConsistencyReport, SmartVaultErr> {
+    verify_caller_is_admin()?;
+
+    let cursor = args
+        .cursor
+        .unwrap_or(ConsistencyCursor::Vaults { after: None });
+    let page_size = args.page_size.unwrap_or(DEFAULT_PAGE_SIZE).max(1) as usize;
+
+    // Only vaults which still belong to a user count
+    let testators = |after: Option<Principal>, limit: usize| {
+        USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+            ur.borrow()
+                .get_users_page(after.as_ref(), limit)
+                .into_iter()
+                .map(|user| *user.id())
+                .collect::<Vec<Principal>>()
+        })
+    };
+    let mut vaults = VaultTestaments::new(|testator: &Principal| {
+        let user_vault_id = USER_REGISTRY
+            .with(|ur: &RefCell<UserRegistry>| ur.borrow().get_user(testator).ok())
+            .and_then(|user| user.user_vault_id);
+        user_vault_id
+            .and_then(|user_vault_id| {
+                MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
+                    mv.borrow().get_user_vault(&user_vault_id).ok()
+                })
+            })
+            .map(|user_vault| user_vault.testaments().clone())
+            .unwrap_or_default()
+    });
+
+    TESTAMENT_REGISTRY.with(|tr: &RefCell<TestamentRegistry>| {
+        let mut testament_registry = tr.borrow_mut();
+        let mut report = check_registry_page(
+            &testament_registry,
+            &testators,
+            &mut vaults,
+            cursor,
+            page_size,
+        );
+        if args.repair && !report.is_consistent() {
+            repair_registry(&mut testament_registry, &mut vaults, &mut report);
+        }
+        Ok(report)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Vaults = BTreeMap<Principal, BTreeMap<TestamentID, Testament>>;
+
+    /// Runs a whole check page by page and merges the pages into one report.
+    fn check_all(
+        registry: &mut TestamentRegistry,
+        vaults: &Vaults,
+        page_size: usize,
+        repair: bool,
+    ) -> ConsistencyReport {
+        let testators = |after: Option<Principal>, limit: usize| -> Vec<Principal> {
+            vaults
+                .keys()
+                .filter(|testator| {
+                    after.map_or(true, |after| testator.as_slice() > after.as_slice())
+                })
+                .take(limit)
+                .cloned()
+                .collect()
+        };
+        let mut vault_testaments = VaultTestaments::new(|testator: &Principal| {
+            vaults.get(testator).cloned().unwrap_or_default()
+        });
+        let mut report = ConsistencyReport::default();
+        let mut cursor = Some(ConsistencyCursor::Vaults { after: None });
+        while let Some(page_cursor) = cursor {
+            let mut page = check_registry_page(
+                registry,
+                &testators,
+                &mut vault_testaments,
+                page_cursor,
+                page_size,
+            );
+            if repair {
+                repair_registry(registry, &mut vault_testaments, &mut page);
+                report.repaired = true;
+            }
+            report
+                .dangling_testaments
+                .append(&mut page.dangling_testaments);
+            report
+                .dangling_heir_entries
+                .append(&mut page.dangling_heir_entries);
+            report
+                .dangling_validator_entries
+                .append(&mut page.dangling_validator_entries);
+            report
+                .unregistered_testaments
+                .append(&mut page.unregistered_testaments);
+            cursor = page.next_cursor;
+        }
+        report
+    }
+
+    fn testament(id: &str, testator: Principal, heir: Principal) -> Testament {
+        let mut testament = Testament::new(id.to_string());
+        testament.set_testator(testator);
+        testament.add_heir(heir);
+        testament
+    }
+
+    #[test]
+    fn utest_check_and_repair_registry() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let carol = Principal::from_slice(&[3; 29]);
+        let mut registry = TestamentRegistry::new();
+
+        // a testament of a deleted vault, still registered
+        let deleted = testament("deleted", alice, bob);
+        registry.add_testament_to_registry(&deleted);
+
+        // a testament whose heir changed without the registry knowing
+        let mut changed = testament("changed", alice, bob);
+        registry.add_testament_to_registry(&changed);
+        changed.remove_heir(&bob);
+        changed.add_heir(carol);
+
+        let vaults: Vaults = BTreeMap::from([(
+            alice,
+            BTreeMap::from([(changed.id().clone(), changed.clone())]),
+        )]);
+        // pages of a single entry end up with the same report
+        for page_size in [1, 100] {
+            assert_eq!(
+                check_all(&mut registry, &vaults, page_size, false),
+                ConsistencyReport {
+                    dangling_testaments: vec![(alice, "deleted".to_string())],
+                    dangling_heir_entries: vec![
+                        (bob, "changed".to_string()),
+                        (bob, "deleted".to_string())
+                    ],
+                    dangling_validator_entries: Vec::new(),
+                    unregistered_testaments: vec![(alice, "changed".to_string())],
+                    repaired: false,
+                    next_cursor: None,
+                }
+            );
+        }
+
+        assert!(check_all(&mut registry, &vaults, 1, true).repaired);
+        assert!(check_all(&mut registry, &vaults, 1, false).is_consistent());
+        assert!(registry.get_testament_ids_as_heir(bob).is_empty());
+        assert_eq!(
+            registry.get_testament_id_as_heir(carol, "changed".to_string()),
+            Ok(("changed".to_string(), alice))
+        );
+    }
+
+    #[test]
+    fn utest_testament_ids_of_two_testators() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let eve = Principal::from_slice(&[5; 29]);
+        let mut registry = TestamentRegistry::new();
+
+        // Eve's testament got the id of Alice's testament before ids were checked
+        let of_alice = testament("shared", alice, bob);
+        let of_eve = testament("shared", eve, bob);
+        registry.add_testament_to_registry(&of_alice);
+        let vaults: Vaults = BTreeMap::from([
+            (alice, BTreeMap::from([("shared".to_string(), of_alice)])),
+            (eve, BTreeMap::from([("shared".to_string(), of_eve)])),
+        ]);
+
+        // only Eve's testament is reported, and the repair leaves Alice's registration alone
+        let report = check_all(&mut registry, &vaults, 100, true);
+        assert_eq!(
+            report.unregistered_testaments,
+            vec![(eve, "shared".to_string())]
+        );
+        assert!(report.dangling_testaments.is_empty());
+        assert!(report.dangling_heir_entries.is_empty());
+        assert_eq!(
+            registry.get_testator_of_testament("shared".to_string()),
+            Some(alice)
+        );
+    }
+}
@@ -0,0 +1,460 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Serialize;
+
+use crate::common::error::SmartVaultErr;
+use crate::common::memory::{
+    get_stable_btree_memory_for_invitations, get_stable_btree_memory_for_testator_to_invitations,
+    verify_size, Memory, StorablePrincipal, MAX_CLAIM_CODE_SET_SIZE, MAX_CLAIM_CODE_SIZE,
+    MAX_INVITATION_SIZE, MAX_PRINCIPAL_SIZE,
+};
+use crate::common::user::{AddUserArgs, UserType};
+
+// How long an invitation can be accepted, if the testator does not say otherwise
+pub const DEFAULT_INVITATION_VALIDITY_SECS: u64 = 7 * 24 * 60 * 60;
+// Pending and expired invitations of a single testator together, so the index fits MAX_CLAIM_CODE_SET_SIZE
+pub const MAX_INVITATIONS_PER_TESTATOR: u64 = 100;
+
+/// The one-time code the testator hands over to the heir.
+pub type ClaimCode = String;
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum InvitationStatus {
+    Pending,
+    /// Pending, but the invitation can no longer be accepted
+    Expired,
+    Accepted {
+        heir: Principal,
+        date_accepted: u64,
+    },
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct HeirInvitation {
+    pub claim_code: ClaimCode,
+    pub testator: Principal,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub user_type: Option<UserType>,
+    pub date_created: u64,
+    pub expires_at: u64,
+    pub status: InvitationStatus,
+}
+
+impl Storable for HeirInvitation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct CreateHeirInvitationArgs {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub user_type: Option<UserType>,
+    pub valid_for_secs: Option<u64>,
+}
+
+impl HeirInvitation {
+    pub fn new(
+        claim_code: ClaimCode,
+        testator: Principal,
+        args: CreateHeirInvitationArgs,
+        now: u64,
+    ) -> Self {
+        let valid_for_secs = args
+            .valid_for_secs
+            .unwrap_or(DEFAULT_INVITATION_VALIDITY_SECS);
+        Self {
+            claim_code,
+            testator,
+            name: args.name,
+            email: args.email,
+            user_type: args.user_type,
+            date_created: now,
+            expires_at: now.saturating_add(valid_for_secs.saturating_mul(1000000000)),
+            status: InvitationStatus::Pending,
+        }
+    }
+
+    /// Pending invitations are stored as such, expiry is only applied when they are read.
+    pub fn refresh_status(&mut self, now: u64) {
+        if self.status == InvitationStatus::Pending && now >= self.expires_at {
+            self.status = InvitationStatus::Expired;
+        }
+    }
+
+    /// The address book entry the heir gets in the vault of the testator.
+    pub fn heir_args(&self, heir: Principal) -> AddUserArgs {
+        AddUserArgs {
+            id: heir,
+            name: self.name.clone(),
+            email: self.email.clone(),
+            user_type: self.user_type.clone(),
+        }
+    }
+
+    pub fn verify_acceptable(&self, heir: &Principal, now: u64) -> Result<(), SmartVaultErr> {
+        if *heir == self.testator || *heir == Principal::anonymous() {
+            return Err(SmartVaultErr::InvalidInvitee(heir.to_string()));
+        }
+        let mut invitation = self.clone();
+        invitation.refresh_status(now);
+        match invitation.status {
+            InvitationStatus::Pending => Ok(()),
+            InvitationStatus::Expired => {
+                Err(SmartVaultErr::InvitationExpired(self.claim_code.clone()))
+            }
+            InvitationStatus::Accepted { .. } => {
+                Err(SmartVaultErr::InvitationNotPending(self.claim_code.clone()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, CandidType, Deserialize)]
+pub struct ClaimCodes(HashSet<ClaimCode>);
+
+impl Storable for ClaimCodes {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+/// All invitations which were neither revoked nor accepted, by their claim code.
+pub struct InvitationRegistry {
+    invitations: StableBTreeMap<Memory, ClaimCode, HeirInvitation>,
+    // testator -> claim codes, so the invitations of a testator are found without a full scan
+    testator_to_invitations: StableBTreeMap<Memory, StorablePrincipal, ClaimCodes>,
+}
+
+impl Default for InvitationRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InvitationRegistry {
+    pub fn new() -> Self {
+        Self {
+            invitations: StableBTreeMap::init(
+                get_stable_btree_memory_for_invitations(),
+                MAX_CLAIM_CODE_SIZE,
+                MAX_INVITATION_SIZE,
+            ),
+            testator_to_invitations: StableBTreeMap::init(
+                get_stable_btree_memory_for_testator_to_invitations(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_CLAIM_CODE_SET_SIZE,
+            ),
+        }
+    }
+
+    /// The name and email of the invitation are chosen by the testator, so its size is checked.
+    pub fn add_invitation(
+        &mut self,
+        invitation: HeirInvitation,
+    ) -> Result<HeirInvitation, SmartVaultErr> {
+        if self.invitations.contains_key(&invitation.claim_code) {
+            return Err(SmartVaultErr::InvitationAlreadyExists(
+                invitation.claim_code,
+            ));
+        }
+        if self.claim_codes_of(&invitation.testator).len() as u64 >= MAX_INVITATIONS_PER_TESTATOR {
+            return Err(SmartVaultErr::TooManyInvitations(
+                MAX_INVITATIONS_PER_TESTATOR,
+            ));
+        }
+        verify_size(&invitation, MAX_INVITATION_SIZE, "invitation")?;
+        self.invitations
+            .insert(invitation.claim_code.clone(), invitation.clone())
+            .expect("Failed to insert invitation into stable memory");
+        self.add_claim_code(&invitation.testator, &invitation.claim_code);
+        Ok(invitation)
+    }
+
+    /// The claim code comes straight from the caller, so codes which cannot be stored are
+    /// rejected before they reach the map.
+    pub fn get_invitation(&self, claim_code: &ClaimCode) -> Result<HeirInvitation, SmartVaultErr> {
+        if claim_code.len() > MAX_CLAIM_CODE_SIZE as usize {
+            return Err(SmartVaultErr::InvitationDoesNotExist(claim_code.clone()));
+        }
+        self.invitations
+            .get(claim_code)
+            .ok_or_else(|| SmartVaultErr::InvitationDoesNotExist(claim_code.clone()))
+    }
+
+    pub fn get_invitations_of_testator(
+        &self,
+        testator: &Principal,
+        now: u64,
+    ) -> Vec<HeirInvitation> {
+        self.claim_codes_of(testator)
+            .iter()
+            .filter_map(|claim_code| self.invitations.get(claim_code))
+            .map(|mut invitation| {
+                invitation.refresh_status(now);
+                invitation
+            })
+            .collect()
+    }
+
+    /// Redeems the claim code. The invitation is used up and removed, so the code cannot
+    /// be used a second time and does not count towards the invitations of the testator.
+    pub fn accept_invitation(
+        &mut self,
+        claim_code: &ClaimCode,
+        heir: Principal,
+        now: u64,
+    ) -> Result<HeirInvitation, SmartVaultErr> {
+        let mut invitation = self.get_invitation(claim_code)?;
+        invitation.verify_acceptable(&heir, now)?;
+        invitation.status = InvitationStatus::Accepted {
+            heir,
+            date_accepted: now,
+        };
+        self.invitations.remove(claim_code);
+        self.remove_claim_code(&invitation.testator, claim_code);
+        Ok(invitation)
+    }
+
+    /// Removes all invitations of the testator, e.g. because the testator was deleted.
+    pub fn remove_invitations_of_testator(&mut self, testator: &Principal) {
+        for claim_code in self.claim_codes_of(testator) {
+            self.invitations.remove(&claim_code);
+        }
+        self.testator_to_invitations
+            .remove(&StorablePrincipal(*testator));
+    }
+
+    /// The invitations follow the account of the testator to its new principal.
+    pub fn rebind_testator(&mut self, testator: &Principal, new_testator: Principal) {
+        for claim_code in self.claim_codes_of(testator) {
+            if let Some(mut invitation) = self.invitations.get(&claim_code) {
+                invitation.testator = new_testator;
+                self.invitations
+                    .insert(claim_code.clone(), invitation)
+                    .expect("Failed to insert invitation into stable memory");
+                self.add_claim_code(&new_testator, &claim_code);
+            }
+        }
+        self.testator_to_invitations
+            .remove(&StorablePrincipal(*testator));
+    }
+
+    fn claim_codes_of(&self, testator: &Principal) -> HashSet<ClaimCode> {
+        self.testator_to_invitations
+            .get(&StorablePrincipal(*testator))
+            .unwrap_or_default()
+            .0
+    }
+
+    fn add_claim_code(&mut self, testator: &Principal, claim_code: &ClaimCode) {
+        let mut claim_codes = self.claim_codes_of(testator);
+        claim_codes.insert(claim_code.clone());
+        self.testator_to_invitations
+            .insert(StorablePrincipal(*testator), ClaimCodes(claim_codes))
+            .expect("Failed to insert into invitation index");
+    }
+
+    fn remove_claim_code(&mut self, testator: &Principal, claim_code: &ClaimCode) {
+        let mut claim_codes = self.claim_codes_of(testator);
+        claim_codes.remove(claim_code);
+        if claim_codes.is_empty() {
+            self.testator_to_invitations
+                .remove(&StorablePrincipal(*testator));
+        } else {
+            self.testator_to_invitations
+                .insert(StorablePrincipal(*testator), ClaimCodes(claim_codes))
+                .expect("Failed to insert into invitation index");
+        }
+    }
+
+    /// Removes a pending or expired invitation of the testator.
+    pub fn revoke_invitation(
+        &mut self,
+        claim_code: &ClaimCode,
+        testator: &Principal,
+    ) -> Result<HeirInvitation, SmartVaultErr> {
+        let invitation = self.get_invitation(claim_code)?;
+        // Other testators cannot even tell whether the code exists
+        if invitation.testator != *testator {
+            return Err(SmartVaultErr::InvitationDoesNotExist(claim_code.clone()));
+        }
+        self.invitations.remove(claim_code);
+        self.remove_claim_code(testator, claim_code);
+        Ok(invitation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1000000000;
+
+    fn invitation(claim_code: &str, testator: Principal) -> HeirInvitation {
+        let args = CreateHeirInvitationArgs {
+            name: Some("Bob".to_string()),
+            email: None,
+            user_type: None,
+            valid_for_secs: Some(60),
+        };
+        HeirInvitation::new(claim_code.to_string(), testator, args, 0)
+    }
+
+    #[test]
+    fn utest_invitation_lifecycle() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let mut registry = InvitationRegistry::new();
+        let code = "code".to_string();
+
+        registry.add_invitation(invitation("code", alice)).unwrap();
+        assert_eq!(
+            registry.add_invitation(invitation("code", alice)),
+            Err(SmartVaultErr::InvitationAlreadyExists(code.clone()))
+        );
+
+        // the testator cannot become her own heir
+        assert_eq!(
+            registry.accept_invitation(&code, alice, SECOND),
+            Err(SmartVaultErr::InvalidInvitee(alice.to_string()))
+        );
+
+        // the claim code works exactly once
+        let accepted = registry.accept_invitation(&code, bob, SECOND).unwrap();
+        assert_eq!(
+            accepted.status,
+            InvitationStatus::Accepted {
+                heir: bob,
+                date_accepted: SECOND
+            }
+        );
+        assert_eq!(
+            registry.accept_invitation(&code, bob, SECOND),
+            Err(SmartVaultErr::InvitationDoesNotExist(code.clone()))
+        );
+        assert_eq!(
+            registry.revoke_invitation(&code, &alice),
+            Err(SmartVaultErr::InvitationDoesNotExist(code.clone()))
+        );
+
+        // accepted invitations are no longer indexed for the testator
+        assert!(registry
+            .get_invitations_of_testator(&alice, SECOND)
+            .is_empty());
+        assert!(registry.testator_to_invitations.is_empty());
+    }
+
+    #[test]
+    fn utest_invitation_limits() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let mut registry = InvitationRegistry::new();
+
+        let mut large = invitation("large", alice);
+        large.name = Some("a".repeat(MAX_INVITATION_SIZE as usize));
+        assert_eq!(
+            registry.add_invitation(large),
+            Err(SmartVaultErr::ValueTooLarge("invitation".to_string()))
+        );
+        assert!(registry.get_invitations_of_testator(&alice, 0).is_empty());
+
+        let long_code = "c".repeat(MAX_CLAIM_CODE_SIZE as usize + 1);
+        assert_eq!(
+            registry.accept_invitation(&long_code, alice, 0),
+            Err(SmartVaultErr::InvitationDoesNotExist(long_code.clone()))
+        );
+
+        for i in 0..MAX_INVITATIONS_PER_TESTATOR {
+            registry
+                .add_invitation(invitation(&i.to_string(), alice))
+                .unwrap();
+        }
+        assert_eq!(
+            registry.add_invitation(invitation("one too many", alice)),
+            Err(SmartVaultErr::TooManyInvitations(
+                MAX_INVITATIONS_PER_TESTATOR
+            ))
+        );
+
+        // revoking an invitation makes room for another one
+        registry
+            .revoke_invitation(&"0".to_string(), &alice)
+            .unwrap();
+        assert!(registry
+            .add_invitation(invitation("one more", alice))
+            .is_ok());
+    }
+
+    #[test]
+    fn utest_invitation_expiry_and_revocation() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let mut registry = InvitationRegistry::new();
+        let code = "expiring".to_string();
+        registry
+            .add_invitation(invitation("expiring", alice))
+            .unwrap();
+
+        let listed = registry.get_invitations_of_testator(&alice, 60 * SECOND);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].status, InvitationStatus::Expired);
+        assert!(registry.get_invitations_of_testator(&bob, 0).is_empty());
+        assert_eq!(
+            registry.accept_invitation(&code, bob, 60 * SECOND),
+            Err(SmartVaultErr::InvitationExpired(code.clone()))
+        );
+
+        // only the testator can revoke
+        assert_eq!(
+            registry.revoke_invitation(&code, &bob),
+            Err(SmartVaultErr::InvitationDoesNotExist(code.clone()))
+        );
+        assert!(registry.revoke_invitation(&code, &alice).is_ok());
+        assert_eq!(
+            registry.get_invitation(&code),
+            Err(SmartVaultErr::InvitationDoesNotExist(code))
+        );
+        assert!(registry.get_invitations_of_testator(&alice, 0).is_empty());
+    }
+
+    #[test]
+    fn utest_testator_index() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let carol = Principal::from_slice(&[3; 29]);
+        let mut registry = InvitationRegistry::new();
+        registry.add_invitation(invitation("a", alice)).unwrap();
+        registry.add_invitation(invitation("b", alice)).unwrap();
+        registry.add_invitation(invitation("c", carol)).unwrap();
+        assert_eq!(registry.get_invitations_of_testator(&alice, 0).len(), 2);
+
+        // the invitations move along with a recovered account
+        let recovered = Principal::from_slice(&[4; 29]);
+        registry.rebind_testator(&alice, recovered);
+        assert!(registry.get_invitations_of_testator(&alice, 0).is_empty());
+        let moved = registry.get_invitations_of_testator(&recovered, 0);
+        assert_eq!(moved.len(), 2);
+        assert!(moved
+            .iter()
+            .all(|invitation| invitation.testator == recovered));
+
+        registry.remove_invitations_of_testator(&recovered);
+        assert!(registry
+            .get_invitations_of_testator(&recovered, 0)
+            .is_empty());
+        assert!(registry.get_invitation(&"a".to_string()).is_err());
+        assert_eq!(registry.get_invitations_of_testator(&carol, 0).len(), 1);
+    }
+}
@@ -6,7 +6,7 @@ use serde::{Deserialize, Serialize};
 use crate::common::error::SmartVaultErr;
 use crate::common::uuid::UUID;
 use crate::smart_vaults::master_vault::MasterVault;
-use crate::smart_vaults::smart_vault::{MASTERVAULT, TESTAMENT_REGISTRY, USER_REGISTRY};
+use crate::smart_vaults::smart_vault::{resolve_caller, MASTERVAULT, TESTAMENT_REGISTRY, USER_REGISTRY};
 use crate::smart_vaults::testament::{Testament, TestamentID};
 use crate::smart_vaults::testament_registry::TestamentRegistry;
 use crate::smart_vaults::user_registry::UserRegistry;
@@ -22,6 +22,9 @@ const VETKD_SYSTEM_API_CANISTER_ID: &str = "s55qq-oqaaa-aaaaa-aaakq-cai";
 pub struct TestamentKeyDerviationArgs {
     pub encryption_public_key: Vec<u8>,
     pub testament_id: String,
+    /// Only the testator can choose the key epoch, e.g. to re-encrypt the key box before rearming.
+    /// Defaults to the current key epoch of the testament.
+    pub key_epoch: Option<u64>,
 }
 
 /// Computes a fresh vetkd symmetric key to encrypt the secrets in a user vault.
@@ -35,7 +38,7 @@ async fn encrypted_symmetric_key_for_uservault(encryption_public_key: Vec<u8>) -
     // debug_println_caller("encrypted_symmetric_key_for_caller");
 
     let request = VetKDEncryptedKeyRequest {
-        derivation_id: ic_cdk::caller().as_slice().to_vec(),
+        derivation_id: key_derivation_principal().as_slice().to_vec(),
         public_key_derivation_path: vec![b"symmetric_key".to_vec()],
         key_id: bls12_381_test_key_1(),
         encryption_public_key,
@@ -58,10 +61,10 @@ async fn encrypted_symmetric_key_for_uservault(encryption_public_key: Vec<u8>) -
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 async fn encrypted_symmetric_key_for_testament(args: TestamentKeyDerviationArgs) -> Result<String, SmartVaultErr> {
-    let caller = ic_cdk::caller(); //.as_slice().to_vec();
+    let caller = resolve_caller(); //.as_slice().to_vec();
 
-    // check if caller has the right to derive this key
-    let mut key_can_be_generated = false;
+    // check if caller has the right to derive this key, and for which key epoch
+    let key_epoch: u64;
 
     // Let's see if the testament is existing
     let result_1 = TESTAMENT_REGISTRY.with(
@@ -71,64 +74,36 @@ async fn encrypted_symmetric_key_for_testament(args: TestamentKeyDerviationArgs)
         },
     );
 
-    if result_1.is_none() {
+    match result_1 {
         // No testament with this id is existing, we can easily create a vetkey
-        key_can_be_generated = true;
-    } else {
-        // Testament is existing, further checks are needed
-        if result_1.unwrap() == caller {
-            // Caller is testator, all good
-            key_can_be_generated = true;
-        } else {
-            // Let's see if caller is heir
-            let result_2 = TESTAMENT_REGISTRY.with(
-                |tr: &RefCell<TestamentRegistry>| -> Result<(TestamentID, Principal), SmartVaultErr> {
-                    let testament_registry = tr.borrow();
-                    testament_registry.get_testament_id_as_heir(caller, args.testament_id.clone())
-                },
-            )?;
-
-            // Caller is heir, let's see if the associated testament is in correct condition status.
-            let result_3 = USER_REGISTRY.with(
-                |ur: &RefCell<UserRegistry>| -> Result<UUID, SmartVaultErr> {
-                    let user_registry = ur.borrow();
-                    let user = user_registry.get_user(&result_2.1)?;
-                    user.user_vault_id.ok_or_else(|| SmartVaultErr::UserVaultDoesNotExist("".to_string()))
-                },
-            )?;
-            let result_4 = MASTERVAULT.with(
-                |mv: &RefCell<MasterVault>| -> Result<Testament, SmartVaultErr> {
-                    mv.borrow()
-                        .get_user_vault(&result_3)?
-                        .get_testament(&args.testament_id)
-                        .cloned()
-                },
-            )?;
-            if *result_4.condition_status() {
-                key_can_be_generated = true;
+        None => key_epoch = args.key_epoch.unwrap_or_default(),
+        Some(testator) => {
+            // Testament is existing, further checks are needed
+            let testament = get_testament_of(testator, &args.testament_id)?;
+            if testator == caller {
+                // Caller is testator, all good
+                key_epoch = args.key_epoch.unwrap_or(*testament.key_epoch());
+            } else {
+                // Let's see if caller is heir
+                TESTAMENT_REGISTRY.with(
+                    |tr: &RefCell<TestamentRegistry>| -> Result<(TestamentID, Principal), SmartVaultErr> {
+                        let testament_registry = tr.borrow();
+                        testament_registry.get_testament_id_as_heir(caller, args.testament_id.clone())
+                    },
+                )?;
+
+                // Caller is heir, let's see if the associated testament is in correct condition status.
+                if !*testament.condition_status() {
+                    return Err(SmartVaultErr::KeyGenerationNotAllowed);
+                }
+
+                // Heirs always get the key of the current epoch
+                key_epoch = *testament.key_epoch();
             }
         }
     }
 
-
-    if !(key_can_be_generated) {
-        return Err(SmartVaultErr::KeyGenerationNotAllowed);
-    }
-
-    /*let mut derivation_id: Vec<u8> = ic_cdk::id().as_slice().to_vec();
-    ic_cdk::println!(
-        "backend id: {:?}, {:?}",
-        ic_cdk::id().to_string(),
-        &derivation_id,
-    );
-
-    derivation_id.extend_from_slice(&args.testament_id.as_bytes());
-    ic_cdk::println!(
-        "testament id: {:?}",
-        &args.testament_id.as_bytes()
-    );*/
-
-    let derivation_id = args.testament_id.as_bytes().to_vec();
+    let derivation_id = key_derivation_id(&args.testament_id, key_epoch);
 
     let request = VetKDEncryptedKeyRequest {
         derivation_id,
@@ -149,6 +124,41 @@ async fn encrypted_symmetric_key_for_testament(args: TestamentKeyDerviationArgs)
     Ok(response)
 }
 
+fn get_testament_of(testator: Principal, testament_id: &TestamentID) -> Result<Testament, SmartVaultErr> {
+    let user_vault_id = USER_REGISTRY.with(
+        |ur: &RefCell<UserRegistry>| -> Result<UUID, SmartVaultErr> {
+            let user_registry = ur.borrow();
+            let user = user_registry.get_user(&testator)?;
+            user.user_vault_id.ok_or_else(|| SmartVaultErr::UserVaultDoesNotExist("".to_string()))
+        },
+    )?;
+    MASTERVAULT.with(
+        |mv: &RefCell<MasterVault>| -> Result<Testament, SmartVaultErr> {
+            mv.borrow()
+                .get_user_vault(&user_vault_id)?
+                .get_testament(testament_id)
+                .cloned()
+        },
+    )
+}
+
+// Never part of valid UTF-8, so no plain testament id starts with it
+const KEY_EPOCH_MARKER: u8 = 0xff;
+
+/// The first key epoch uses the plain testament id, so keys derived before epochs existed stay valid.
+/// Later epochs use the marker, the length prefixed id and the epoch, so that no two pairs
+/// of testament id and key epoch share a derivation id.
+fn key_derivation_id(testament_id: &TestamentID, key_epoch: u64) -> Vec<u8> {
+    if key_epoch == 0 {
+        return testament_id.as_bytes().to_vec();
+    }
+    let mut derivation_id = vec![KEY_EPOCH_MARKER];
+    derivation_id.extend_from_slice(&(testament_id.len() as u64).to_be_bytes());
+    derivation_id.extend_from_slice(testament_id.as_bytes());
+    derivation_id.extend_from_slice(&key_epoch.to_be_bytes());
+    derivation_id
+}
+
 /*
     The verification key is used for authenticating that the symmetric key or the data has not
     been tampered with and is indeed generated or approved by a the IC API.
@@ -183,7 +193,7 @@ async fn encrypted_symmetric_key_for_caller(encryption_public_key: Vec<u8>) -> S
     // debug_println_caller("encrypted_symmetric_key_for_caller");
 
     let request = VetKDEncryptedKeyRequest {
-        derivation_id: ic_cdk::caller().as_slice().to_vec(),
+        derivation_id: key_derivation_principal().as_slice().to_vec(),
         public_key_derivation_path: vec![b"symmetric_key".to_vec()],
         key_id: bls12_381_test_key_1(),
         encryption_public_key,
@@ -226,7 +236,7 @@ async fn encrypted_ibe_decryption_key_for_caller(encryption_public_key: Vec<u8>)
     // debug_println_caller("encrypted_ibe_decryption_key_for_caller");
 
     let request = VetKDEncryptedKeyRequest {
-        derivation_id: ic_cdk::caller().as_slice().to_vec(),
+        derivation_id: key_derivation_principal().as_slice().to_vec(),
         public_key_derivation_path: vec![b"ibe_encryption".to_vec()],
         key_id: bls12_381_test_key_1(),
         encryption_public_key,
@@ -243,6 +253,17 @@ async fn encrypted_ibe_decryption_key_for_caller(encryption_public_key: Vec<u8>)
     hex::encode(response.encrypted_key)
 }
 
+// The keys of an account stay derived from the principal it was created with,
+// also after the account was recovered to another principal
+fn key_derivation_principal() -> Principal {
+    let principal = resolve_caller();
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+        ur.borrow()
+            .get_user(&principal)
+            .map_or(principal, |user| *user.key_principal())
+    })
+}
+
 fn bls12_381_test_key_1() -> VetKDKeyId {
     VetKDKeyId {
         curve: VetKDCurve::Bls12_381,
@@ -253,3 +274,24 @@ fn bls12_381_test_key_1() -> VetKDKeyId {
 fn vetkd_system_api_canister_id() -> CanisterId {
     CanisterId::from_str(VETKD_SYSTEM_API_CANISTER_ID).expect("failed to create canister ID")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utest_key_derivation_id() {
+        // keys of the first epoch stay the same
+        assert_eq!(key_derivation_id(&"t".to_string(), 0), b"t".to_vec());
+
+        // ids which used to collide with the epoch suffix
+        assert_ne!(
+            key_derivation_id(&"t#1".to_string(), 0),
+            key_derivation_id(&"t".to_string(), 1)
+        );
+        assert_ne!(
+            key_derivation_id(&"t".to_string(), 1),
+            key_derivation_id(&"t".to_string(), 2)
+        );
+    }
+}
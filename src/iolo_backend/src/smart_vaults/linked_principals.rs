@@ -0,0 +1,111 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use candid::{candid_method, CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::Storable;
+use serde::Serialize;
+
+use crate::common::error::SmartVaultErr;
+use crate::notifications::outbox::{self, NotificationKind};
+use crate::utils::caller::get_caller;
+use crate::utils::time;
+
+use super::smart_vault::{get_owner_caller, resolve_caller, USER_REGISTRY};
+use super::user_registry::UserRegistry;
+
+// How long a link request can be confirmed
+pub const LINK_REQUEST_VALIDITY_SECS: u64 = 24 * 60 * 60;
+// Linked principals and pending requests of a single user together
+pub const MAX_LINKED_PRINCIPALS: u64 = 10;
+
+/// A principal the owner wants to link to the account. The link is only made
+/// once the principal confirms it, which proves that it is controlled by the owner.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PendingPrincipalLink {
+    pub owner: Principal,
+    pub principal: Principal,
+    pub expires_at: u64,
+}
+
+impl Storable for PendingPrincipalLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+/// The linked principals and pending link requests of an owner, at most MAX_LINKED_PRINCIPALS.
+#[derive(Debug, Default, CandidType, Deserialize)]
+pub struct OwnerLinks(pub BTreeSet<Principal>);
+
+impl Storable for OwnerLinks {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+/// The principals besides the owner which act on behalf of a user account.
+#[derive(Debug, Default, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LinkedPrincipals {
+    pub linked: Vec<Principal>,
+    pub pending: Vec<PendingPrincipalLink>,
+}
+
+/// Asks the principal to link itself to the account of the caller, see confirm_principal_link.
+/// Only the owner of the account can do so, not the principals linked to it.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn request_principal_link(principal: Principal) -> Result<PendingPrincipalLink, SmartVaultErr> {
+    let owner = get_owner_caller()?;
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+        ur.borrow_mut()
+            .request_principal_link(&owner, principal, time::get_current_time())
+    })
+}
+
+/// Links the caller to the account of the owner, who requested it before.
+/// From then on the caller has access to the vault of the owner.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn confirm_principal_link(owner: Principal) -> Result<(), SmartVaultErr> {
+    // The caller is not linked yet, so it does not need to be resolved
+    let principal = get_caller();
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+        ur.borrow_mut()
+            .confirm_principal_link(&owner, principal, time::get_current_time())
+    })?;
+
+    // the owner learns about every principal getting access to the account
+    outbox::notify(owner, NotificationKind::PrincipalLinked { principal });
+    Ok(())
+}
+
+/// Removes a linked principal or a pending link request from the account of the caller,
+/// who needs to be the owner.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn remove_linked_principal(principal: Principal) -> Result<(), SmartVaultErr> {
+    let owner = get_owner_caller()?;
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+        ur.borrow_mut().remove_principal_link(&owner, &principal)
+    })
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_linked_principals() -> Result<LinkedPrincipals, SmartVaultErr> {
+    let owner = resolve_caller();
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| {
+        let user_registry = ur.borrow();
+        user_registry.get_user(&owner)?;
+        Ok(user_registry.get_linked_principals(&owner))
+    })
+}
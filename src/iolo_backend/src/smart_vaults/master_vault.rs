@@ -1,22 +1,33 @@
-use std::{cell::RefCell, collections::BTreeMap};
+use std::cell::RefCell;
 
-use candid::{CandidType, Deserialize, Principal};
+use candid::Principal;
+use ic_stable_structures::{StableBTreeMap, Storable};
 
+use crate::common::memory::{
+    get_stable_btree_memory_for_user_vaults, verify_size, Memory, MAX_USER_VAULT_SIZE,
+    MAX_UUID_SIZE,
+};
 use crate::common::{error::SmartVaultErr,uuid::UUID};
 use crate::common::user::{AddUserArgs, User};
+use crate::notifications::outbox;
 use crate::smart_vaults::testament::TestamentID;
+use crate::utils::time;
 
 use super::{
-    secret::{AddSecretArgs, Secret},
+    condition_schedule::{schedule_evaluation, unschedule_evaluation},
+    purge_schedule::PurgeSchedule,
+    secret::{AddSecretArgs, Secret, SecretRemovalReport},
     smart_vault::TESTAMENT_REGISTRY,
-    testament::{AddTestamentArgs, Testament},
+    testament::{AddTestamentArgs, RearmTestamentArgs, Testament},
     testament_registry::TestamentRegistry,
+    trash::{TrashItemID, TrashedItem},
     user_vault::UserVault,
 };
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct MasterVault {
-    user_vaults: BTreeMap<UUID, UserVault>,
+    user_vaults: StableBTreeMap<Memory, UUID, UserVault>,
+    // When the trash of a vault needs to be purged next, kept up to date by save_user_vault
+    purge_schedule: PurgeSchedule,
 }
 
 impl Default for MasterVault {
@@ -28,11 +39,16 @@ impl Default for MasterVault {
 impl MasterVault {
     pub fn new() -> Self {
         Self {
-            user_vaults: BTreeMap::new(),
+            user_vaults: StableBTreeMap::init(
+                get_stable_btree_memory_for_user_vaults(),
+                MAX_UUID_SIZE,
+                MAX_USER_VAULT_SIZE,
+            ),
+            purge_schedule: PurgeSchedule::new(),
         }
     }
 
-    pub fn user_vaults(&self) -> &BTreeMap<UUID, UserVault> {
+    pub fn user_vaults(&self) -> &StableBTreeMap<Memory, UUID, UserVault> {
         &self.user_vaults
     }
 
@@ -43,33 +59,93 @@ impl MasterVault {
     pub fn create_user_vault(&mut self) -> UUID {
         let new_user_vault = UserVault::new();
         let new_user_vault_id = *new_user_vault.id();
-        self.user_vaults.insert(new_user_vault_id, new_user_vault);
+        self.save_user_vault(new_user_vault);
         new_user_vault_id
     }
 
-    pub fn get_user_vault(&self, vault_id: &UUID) -> Result<&UserVault, SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
+    pub fn get_user_vault(&self, vault_id: &UUID) -> Result<UserVault, SmartVaultErr> {
+        self.user_vaults
+            .get(vault_id)
+            .ok_or_else(|| SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()))
+    }
 
-        Ok(self.user_vaults.get(vault_id).unwrap())
+    /// Verifies that the vault still fits into stable memory, before anything else is changed.
+    /// Every mutation which grows the vault by client input has to check it.
+    fn verify_user_vault_size(user_vault: &UserVault) -> Result<(), SmartVaultErr> {
+        verify_size(
+            user_vault,
+            MAX_USER_VAULT_SIZE,
+            &format!("user vault {}", user_vault.id()),
+        )
+    }
+
+    /// Writes a (modified) user vault back into stable memory.
+    /// Vaults are read by value, so every mutation has to be stored explicitly.
+    pub fn save_user_vault(&mut self, user_vault: UserVault) {
+        self.purge_schedule
+            .schedule(user_vault.id(), user_vault.trash().next_purge());
+        self.user_vaults
+            .insert(*user_vault.id(), user_vault)
+            .expect("Failed to insert user vault into stable memory");
     }
 
     // Delete a user_vault from the master_vault
     pub fn remove_user_vault(&mut self, id: &UUID) {
+        self.purge_schedule.schedule(id, None);
         self.user_vaults.remove(id);
     }
 
+    /// Deletes a user_vault together with its testaments, which are removed
+    /// from the testament registry and the condition schedule as well.
+    /// Returns the removed testaments.
+    pub fn delete_user_vault(&mut self, vault_id: &UUID) -> Result<Vec<Testament>, SmartVaultErr> {
+        let user_vault = self.get_user_vault(vault_id)?;
+        let testaments: Vec<Testament> = user_vault.testaments().values().cloned().collect();
+        TESTAMENT_REGISTRY.with(|tr: &RefCell<TestamentRegistry>| {
+            let mut testament_registry = tr.borrow_mut();
+            for testament in &testaments {
+                testament_registry.remove_testament_from_registry(testament);
+            }
+        });
+        for testament in &testaments {
+            unschedule_evaluation(testament.id());
+        }
+        self.remove_user_vault(vault_id);
+        Ok(testaments)
+    }
+
+    /// Returns at most limit vault ids in their numeric order, starting after the given id.
+    pub fn get_user_vault_ids_page(&self, after: Option<&UUID>, limit: usize) -> Vec<UUID> {
+        // the range starts at the cursor itself, the entries before it are not read
+        let start = after.map(|after| after.to_bytes().to_vec());
+        self.user_vaults
+            .range(Vec::new(), start)
+            .map(|(id, _)| id)
+            .skip_while(|id| after == Some(id))
+            .take(limit)
+            .collect()
+    }
+
+    /// Completes a vault restored from the baseline: adds the heirs named in its testaments
+    /// to its address book, removes the references to removed secrets and schedules the
+    /// evaluation of its testaments. Saving the vault schedules the purge of its trash.
+    pub fn backfill_user_vault(&mut self, vault_id: &UUID, now: u64) -> Result<(), SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        user_vault.add_missing_heirs();
+        user_vault.remove_dangling_secret_references();
+        for testament in user_vault.testaments().values() {
+            schedule_evaluation(testament, now);
+        }
+        self.save_user_vault(user_vault);
+        Ok(())
+    }
+
     pub fn add_user_secret(
         &mut self,
         vault_id: &UUID,
         asa: AddSecretArgs,
     ) -> Result<Secret, SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
-
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
+        let mut user_vault = self.get_user_vault(vault_id)?;
         let secret: Secret = asa.clone().into();
         let added_secret = user_vault.add_secret(secret)?;
 
@@ -78,6 +154,8 @@ impl MasterVault {
             .key_box_mut()
             .insert(added_secret.id().clone(), decryption_material);
 
+        Self::verify_user_vault_size(&user_vault)?;
+        self.save_user_vault(user_vault);
         Ok(added_secret)
     }
 
@@ -87,13 +165,12 @@ impl MasterVault {
         vault_id: &UUID,
         ata: AddTestamentArgs, // might be required later
     ) -> Result<Testament, SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
-
+        let mut user_vault = self.get_user_vault(vault_id)?;
         let testament: Testament = Testament::from(ata);
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
+        TESTAMENT_REGISTRY
+            .with(|tr: &RefCell<TestamentRegistry>| tr.borrow().verify_registrable(&testament))?;
         user_vault.add_testament(testament.clone())?;
+        Self::verify_user_vault_size(&user_vault)?;
 
         // Add entry to testament registry (reverse index)
         TESTAMENT_REGISTRY.with(
@@ -104,7 +181,11 @@ impl MasterVault {
             },
         )?;
 
-        Ok(user_vault.get_testament(testament.id()).unwrap().clone())
+        let added_testament = user_vault.get_testament(testament.id())?.clone();
+        self.save_user_vault(user_vault);
+        schedule_evaluation(&added_testament, time::get_current_time());
+        outbox::notify_added_heirs(&added_testament, None);
+        Ok(added_testament)
     }
 
     pub fn update_user_testament(
@@ -112,75 +193,76 @@ impl MasterVault {
         vault_id: &UUID,
         t: Testament,
     ) -> Result<Testament, SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let t_old = user_vault.get_testament(t.id())?.clone();
+
+        // Update real testament, the registry is only touched once the update is valid
+        let updated_testament = user_vault.update_testament(t)?;
+        TESTAMENT_REGISTRY.with(|tr: &RefCell<TestamentRegistry>| {
+            tr.borrow().verify_registrable(&updated_testament)
+        })?;
+        Self::verify_user_vault_size(&user_vault)?;
 
         // Update testament registry
-        let t_old = user_vault.get_testament(&t.id())?;
         TESTAMENT_REGISTRY.with(
             |tr: &RefCell<TestamentRegistry>| -> Result<(), SmartVaultErr> {
                 let mut testament_registry = tr.borrow_mut();
-                testament_registry.update_testament_in_registry(&t, &t_old);
+                testament_registry.update_testament_in_registry(&updated_testament, &t_old);
                 Ok(())
             },
         )?;
+        self.save_user_vault(user_vault);
 
-        // Update real testament
-        user_vault.update_testament(t)
+        // The condition might have changed, the next evaluation computes the new deadline
+        schedule_evaluation(&updated_testament, time::get_current_time());
+        outbox::notify_added_heirs(&updated_testament, Some(&t_old));
+        Ok(updated_testament)
     }
 
-    pub fn get_user_testament_list_mut(
+    pub fn update_user_secret(
         &mut self,
         vault_id: &UUID,
-    ) -> Result<Vec<&mut Testament>, SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
-
-        Ok(user_vault.testaments_mut().values_mut().collect())
+        s: Secret,
+    ) -> Result<Secret, SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let updated_secret = user_vault.update_secret(s)?;
+        Self::verify_user_vault_size(&user_vault)?;
+        self.save_user_vault(user_vault);
+        Ok(updated_secret)
     }
 
-    pub fn update_user_secret(
+    pub fn restore_user_secret_version(
         &mut self,
         vault_id: &UUID,
-        s: Secret,
+        secret_id: &str,
+        version: u64,
     ) -> Result<Secret, SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
-
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
-        user_vault.update_secret(s)
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let restored_secret = user_vault.restore_secret_version(secret_id, version)?;
+        Self::verify_user_vault_size(&user_vault)?;
+        self.save_user_vault(user_vault);
+        Ok(restored_secret)
     }
 
-    // Remove a secret
+    // Move a secret to the trash
     pub fn remove_user_secret(
         &mut self,
         vault_id: &UUID,
         secret_id: &str,
-    ) -> Result<(), SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
-
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
-        user_vault.remove_secret(secret_id)
+    ) -> Result<SecretRemovalReport, SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let report = user_vault.remove_secret(secret_id)?;
+        self.save_user_vault(user_vault);
+        Ok(report)
     }
 
-    // Remove a testament
+    // Move a testament to the trash, the heirs lose access right away
     pub fn remove_user_testament(
         &mut self,
         vault_id: &UUID,
         testament_id: &TestamentID,
     ) -> Result<(), SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
-
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
+        let mut user_vault = self.get_user_vault(vault_id)?;
         let testament = user_vault.get_testament(testament_id)?;
         TESTAMENT_REGISTRY.with(
             |tr: &RefCell<TestamentRegistry>| -> Result<(), SmartVaultErr> {
@@ -189,24 +271,83 @@ impl MasterVault {
                 Ok(())
             },
         )?;
-        user_vault.remove_testament(testament_id)
+        user_vault.remove_testament(testament_id)?;
+        self.save_user_vault(user_vault);
+        unschedule_evaluation(testament_id);
+        Ok(())
     }
 
-    // Add a user to the address_book
-    pub fn add_heir(
+    /// Restored testaments are registered and scheduled again.
+    pub fn restore_user_trash_item(
         &mut self,
         vault_id: &UUID,
-        aua: AddUserArgs,
-    ) -> Result<User, SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
+        id: &TrashItemID,
+    ) -> Result<(), SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let item = user_vault.restore_from_trash(id)?;
+        if let TrashedItem::Testament(testament) = &item {
+            // another testator might have taken the id in the meantime
+            TESTAMENT_REGISTRY.with(|tr: &RefCell<TestamentRegistry>| {
+                tr.borrow().verify_registrable(testament)
+            })?;
         }
+        Self::verify_user_vault_size(&user_vault)?;
+        self.save_user_vault(user_vault);
+        if let TrashedItem::Testament(testament) = item {
+            TESTAMENT_REGISTRY.with(|tr: &RefCell<TestamentRegistry>| {
+                tr.borrow_mut().add_testament_to_registry(&testament)
+            });
+            schedule_evaluation(&testament, time::get_current_time());
+        }
+        Ok(())
+    }
 
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
-        let user: User = aua.clone().into();
-        let added_user = user_vault.add_heir(user)?;
+    pub fn empty_user_trash(&mut self, vault_id: &UUID) -> Result<(), SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        user_vault.trash_mut().empty();
+        self.save_user_vault(user_vault);
+        Ok(())
+    }
 
-        Ok(added_user.clone())
+    pub fn set_user_trash_retention(
+        &mut self,
+        vault_id: &UUID,
+        retention_secs: u64,
+    ) -> Result<(), SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        user_vault.trash_mut().set_retention_secs(retention_secs);
+        self.save_user_vault(user_vault);
+        Ok(())
+    }
+
+    /// Deletes the trashed items whose retention period is over, in at most limit vaults.
+    /// Only the vaults whose next purge is due are read. Returns how many vaults were due.
+    pub fn purge_trash(&mut self, now: u64, limit: usize) -> usize {
+        let due = self.purge_schedule.get_due(now, limit);
+        for id in &due {
+            match self.get_user_vault(id) {
+                Ok(mut user_vault) => {
+                    user_vault.trash_mut().purge(now);
+                    self.save_user_vault(user_vault);
+                }
+                Err(_) => self.purge_schedule.schedule(id, None),
+            }
+        }
+        due.len()
+    }
+
+    // Add a user to the address_book, only done for heirs who accepted an invitation
+    pub fn add_heir(
+        &mut self,
+        vault_id: &UUID,
+        aua: AddUserArgs,
+    ) -> Result<User, SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let user: User = aua.into();
+        let added_user = user_vault.add_heir(user)?.clone();
+        Self::verify_user_vault_size(&user_vault)?;
+        self.save_user_vault(user_vault);
+        Ok(added_user)
     }
 
     pub fn update_user_heir (
@@ -214,12 +355,11 @@ impl MasterVault {
         vault_id: &UUID,
         u: User,
     ) -> Result<User, SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
-        }
-
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
-        user_vault.update_heir(u)
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let updated_user = user_vault.update_heir(u)?;
+        Self::verify_user_vault_size(&user_vault)?;
+        self.save_user_vault(user_vault);
+        Ok(updated_user)
     }
 
     pub fn remove_user_heir(
@@ -227,12 +367,125 @@ impl MasterVault {
         vault_id: &UUID,
         user_id: &Principal
     ) -> Result<(), SmartVaultErr> {
-        if !self.user_vaults.contains_key(vault_id) {
-            return Err(SmartVaultErr::UserVaultDoesNotExist(vault_id.to_string()));
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        user_vault.remove_heir(user_id)?;
+        self.save_user_vault(user_vault);
+        Ok(())
+    }
+
+    // Record the confirmation of an heir or validator and release the testament if the condition is met by it
+    pub fn confirm_testator_passed(
+        &mut self,
+        vault_id: &UUID,
+        testament_id: &TestamentID,
+        principal: Principal,
+        testator: &User,
+    ) -> Result<(), SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let testament = user_vault.get_testament_mut(testament_id)?;
+        testament.confirm_testator_passed(principal)?;
+        let now = time::get_current_time();
+        let released = testament.evaluate_condition(
+            now,
+            testator.date_last_login,
+            testator.check_in.as_ref(),
+        );
+        schedule_evaluation(testament, now);
+        if released {
+            outbox::notify_release(testament);
+        }
+        self.save_user_vault(user_vault);
+        Ok(())
+    }
+
+    // Cancel the pending release of a single testament
+    pub fn veto_release(
+        &mut self,
+        vault_id: &UUID,
+        testament_id: &TestamentID,
+    ) -> Result<Testament, SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let testament = user_vault.get_testament_mut(testament_id)?;
+        testament.cancel_release(time::get_current_time())?;
+        let vetoed_testament = testament.clone();
+        self.save_user_vault(user_vault);
+        schedule_evaluation(&vetoed_testament, time::get_current_time());
+        Ok(vetoed_testament)
+    }
+
+    // Revert an erroneous release
+    pub fn rearm_testament(
+        &mut self,
+        vault_id: &UUID,
+        args: RearmTestamentArgs,
+    ) -> Result<Testament, SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let rearmed_testament = user_vault.rearm_testament(
+            &args.testament_id,
+            args.key_box,
+            args.allocation_key_boxes.unwrap_or_default(),
+            args.reason,
+            time::get_current_time(),
+        )?;
+        Self::verify_user_vault_size(&user_vault)?;
+        self.save_user_vault(user_vault);
+        schedule_evaluation(&rearmed_testament, time::get_current_time());
+        Ok(rearmed_testament)
+    }
+
+    // Cancel the pending releases of all testaments of a user, e.g. because the user logged in.
+    // The login moves the deadlines of the testaments as well, so all of them are evaluated again.
+    pub fn cancel_pending_releases(&mut self, vault_id: &UUID) -> Result<(), SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let now = time::get_current_time();
+        let mut cancelled = false;
+        for testament in user_vault.testaments_mut().values_mut() {
+            cancelled |= testament.cancel_release(now).is_ok();
+            schedule_evaluation(testament, now);
+        }
+        if cancelled {
+            self.save_user_vault(user_vault);
+        }
+        Ok(())
+    }
+
+    /// Hands the testaments of the vault over to the new principal of the testator,
+    /// after the account was recovered. They are registered and evaluated again.
+    pub fn rebind_user_vault(
+        &mut self,
+        vault_id: &UUID,
+        testator: Principal,
+    ) -> Result<(), SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let now = time::get_current_time();
+        for testament in user_vault.testaments_mut().values_mut() {
+            let old_testament = testament.clone();
+            testament.set_testator(testator);
+            TESTAMENT_REGISTRY.with(|tr: &RefCell<TestamentRegistry>| {
+                tr.borrow_mut()
+                    .update_testament_in_registry(testament, &old_testament)
+            });
+            schedule_evaluation(testament, now);
         }
+        user_vault.trash_mut().set_testator(testator);
+        self.save_user_vault(user_vault);
+        Ok(())
+    }
 
-        let user_vault = self.user_vaults.get_mut(vault_id).unwrap();
-        user_vault.remove_heir(user_id)
+    pub fn revoke_testator_passed(
+        &mut self,
+        vault_id: &UUID,
+        testament_id: &TestamentID,
+        principal: &Principal,
+    ) -> Result<(), SmartVaultErr> {
+        let mut user_vault = self.get_user_vault(vault_id)?;
+        let testament = user_vault.get_testament_mut(testament_id)?;
+        testament.revoke_testator_passed(principal)?;
+
+        // Without the confirmation the condition might not be met anymore
+        schedule_evaluation(testament, time::get_current_time());
+        self.save_user_vault(user_vault);
+        Ok(())
     }
 }
 
@@ -240,6 +493,7 @@ impl MasterVault {
 mod tests {
 
     use super::*;
+    use crate::smart_vaults::secret::SecretSymmetricCryptoMaterial;
 
     #[test]
     fn utest_new_master_vault() {
@@ -253,6 +507,66 @@ mod tests {
         );
     }
 
+    #[test]
+    fn utest_purge_trash() {
+        let second = 1000000000;
+        let mut master_vault = MasterVault::new();
+        let untouched_id = master_vault.create_user_vault();
+        let vault_id = master_vault.create_user_vault();
+        let mut user_vault = master_vault.get_user_vault(&vault_id).unwrap();
+        user_vault.trash_mut().set_retention_secs(60);
+        user_vault.trash_mut().put(
+            TrashItemID::Testament("t".to_string()),
+            TrashedItem::Testament(Testament::new("t".to_string())),
+            0,
+        );
+        master_vault.save_user_vault(user_vault);
+
+        // only vaults with a due purge are read
+        assert_eq!(master_vault.purge_trash(59 * second, 10), 0);
+        assert_eq!(
+            master_vault.purge_schedule.get_deadline(&untouched_id),
+            None
+        );
+        assert_eq!(master_vault.purge_trash(60 * second, 10), 1);
+        let user_vault = master_vault.get_user_vault(&vault_id).unwrap();
+        assert!(user_vault.trash().is_empty());
+
+        // an empty trash is not purged again
+        assert_eq!(master_vault.purge_schedule.get_deadline(&vault_id), None);
+        assert_eq!(master_vault.purge_trash(120 * second, 10), 0);
+    }
+
+    #[test]
+    fn utest_user_vault_size() {
+        let mut master_vault = MasterVault::new();
+        let vault_id = master_vault.create_user_vault();
+        let secret = |id: &str, notes_size: usize| AddSecretArgs {
+            id: id.to_string(),
+            category: None,
+            name: None,
+            username: None,
+            password: None,
+            url: None,
+            notes: Some(vec![0; notes_size]),
+            symmetric_crypto_material: SecretSymmetricCryptoMaterial::default(),
+        };
+
+        // a vault which would not fit into stable memory is rejected instead of trapping
+        assert_eq!(
+            master_vault
+                .add_user_secret(&vault_id, secret("large", MAX_USER_VAULT_SIZE as usize))
+                .unwrap_err(),
+            SmartVaultErr::ValueTooLarge(format!("user vault {}", vault_id))
+        );
+        let user_vault = master_vault.get_user_vault(&vault_id).unwrap();
+        assert!(user_vault.secrets().is_empty());
+
+        assert!(master_vault
+            .add_user_secret(&vault_id, secret("small", 1_000))
+            .is_ok());
+    }
+
     #[test]
     fn utest_create_user_vault() {
         let mut master_vault = MasterVault::new();
@@ -1,9 +1,18 @@
+pub mod account_recovery;
+pub mod check_in;
+pub mod condition;
+pub mod condition_schedule;
+pub mod consistency;
+pub mod invitation_registry;
 pub mod key_manager;
+pub mod linked_principals;
 pub mod master_vault;
+pub mod purge_schedule;
 pub mod secret;
 pub mod smart_vault;
 pub mod testament;
 pub mod testament_registry;
+pub mod trash;
 pub mod user_registry;
 pub mod user_vault;
 pub mod vetkd_types;
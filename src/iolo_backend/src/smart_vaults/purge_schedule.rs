@@ -0,0 +1,149 @@
+use std::borrow::Cow;
+
+use ic_stable_structures::{StableBTreeMap, Storable};
+
+use crate::common::memory::{
+    get_stable_btree_memory_for_purge_deadlines,
+    get_stable_btree_memory_for_vault_to_purge_deadline, Memory, MAX_UUID_SIZE,
+};
+use crate::common::uuid::UUID;
+
+const DEADLINE_SIZE: u32 = 8;
+
+/// Key of the deadline index. The deadline is encoded big endian and comes first,
+/// so the byte order of the keys is the order of the deadlines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PurgeKey {
+    deadline: u64,
+    vault_id: UUID,
+}
+
+impl Storable for PurgeKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.deadline.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.vault_id.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        let (deadline, vault_id) = bytes.split_at(DEADLINE_SIZE as usize);
+        PurgeKey {
+            deadline: u64::from_be_bytes(deadline.try_into().unwrap()),
+            vault_id: UUID::from_bytes(vault_id.to_vec()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Deadline(u64);
+
+impl Storable for Deadline {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Deadline(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Keeps track of when the trash of every vault holds an item whose retention period is over,
+/// so the purge only touches those vaults. A vault with an empty trash has no entry.
+pub struct PurgeSchedule {
+    // (deadline, vault id) -> deadline
+    deadlines: StableBTreeMap<Memory, PurgeKey, Deadline>,
+    // vault id -> deadline, needed to find the entry of a vault in the deadline index
+    vault_to_deadline: StableBTreeMap<Memory, UUID, Deadline>,
+}
+
+impl Default for PurgeSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PurgeSchedule {
+    pub fn new() -> Self {
+        Self {
+            deadlines: StableBTreeMap::init(
+                get_stable_btree_memory_for_purge_deadlines(),
+                DEADLINE_SIZE + MAX_UUID_SIZE,
+                DEADLINE_SIZE,
+            ),
+            vault_to_deadline: StableBTreeMap::init(
+                get_stable_btree_memory_for_vault_to_purge_deadline(),
+                MAX_UUID_SIZE,
+                DEADLINE_SIZE,
+            ),
+        }
+    }
+
+    /// Sets the next purge of a vault, replacing the existing one. None removes it.
+    pub fn schedule(&mut self, vault_id: &UUID, deadline: Option<u64>) {
+        let current = self.vault_to_deadline.get(vault_id).map(|d| d.0);
+        if current == deadline {
+            return;
+        }
+        if let Some(current) = current {
+            self.deadlines.remove(&PurgeKey {
+                deadline: current,
+                vault_id: *vault_id,
+            });
+            self.vault_to_deadline.remove(vault_id);
+        }
+        if let Some(deadline) = deadline {
+            self.deadlines
+                .insert(
+                    PurgeKey {
+                        deadline,
+                        vault_id: *vault_id,
+                    },
+                    Deadline(deadline),
+                )
+                .expect("Failed to insert into purge deadlines");
+            self.vault_to_deadline
+                .insert(*vault_id, Deadline(deadline))
+                .expect("Failed to insert into vault_to_purge_deadline");
+        }
+    }
+
+    pub fn get_deadline(&self, vault_id: &UUID) -> Option<u64> {
+        self.vault_to_deadline.get(vault_id).map(|d| d.0)
+    }
+
+    /// Returns at most limit vaults whose deadline is not after now, the most overdue first.
+    pub fn get_due(&self, now: u64, limit: usize) -> Vec<UUID> {
+        self.deadlines
+            .iter()
+            .take_while(|(key, _)| key.deadline <= now)
+            .take(limit)
+            .map(|(key, _)| key.vault_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utest_purge_schedule() {
+        let mut schedule = PurgeSchedule::new();
+        schedule.schedule(&UUID(1), Some(30));
+        schedule.schedule(&UUID(2), Some(10));
+        schedule.schedule(&UUID(3), Some(20));
+
+        // rescheduling replaces the existing entry
+        schedule.schedule(&UUID(1), Some(15));
+        assert_eq!(schedule.get_deadline(&UUID(1)), Some(15));
+
+        // due vaults come ordered by deadline and bounded by the limit
+        assert_eq!(schedule.get_due(20, 10), vec![UUID(2), UUID(1), UUID(3)]);
+        assert_eq!(schedule.get_due(20, 1), vec![UUID(2)]);
+        assert!(schedule.get_due(9, 10).is_empty());
+
+        schedule.schedule(&UUID(2), None);
+        assert_eq!(schedule.get_due(10, 10), vec![]);
+        assert_eq!(schedule.get_deadline(&UUID(2)), None);
+    }
+}
@@ -1,6 +1,7 @@
 use candid::{CandidType, Deserialize};
 use serde::Serialize;
 
+use crate::smart_vaults::testament::TestamentID;
 use crate::utils::time;
 
 pub type SecretID = String;
@@ -92,6 +93,25 @@ pub struct SecretSymmetricCryptoMaterial {
     pub notes_decryption_nonce: Option<Vec<u8>>,
 }
 
+/// What removing a secret changed besides the secret itself.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SecretRemovalReport {
+    pub secret_id: SecretID,
+    /// Whether the vault key box had an entry for the secret
+    pub removed_key_box_entry: bool,
+    /// The testaments the secret and its key box entry were removed from
+    pub testaments: Vec<TestamentID>,
+}
+
+/// A previous version of a secret, together with the material required to decrypt it.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct SecretVersion {
+    pub version: u64,
+    pub date_archived: u64,
+    pub secret: Secret,
+    pub symmetric_crypto_material: Option<SecretSymmetricCryptoMaterial>,
+}
+
 impl Secret {
     pub fn new_test_instance() -> Self {
         let now: u64 = time::get_current_time();
@@ -120,6 +140,10 @@ impl Secret {
         &self.date_modified
     }
 
+    pub fn set_date_modified(&mut self, date_modified: u64) {
+        self.date_modified = date_modified;
+    }
+
     pub fn category(&self) -> Option<SecretCategory> {
         self.category
     }
@@ -1,22 +1,39 @@
 use std::cell::RefCell;
 
 use candid::{candid_method, Principal};
-use ic_cdk::{post_upgrade, pre_upgrade, storage};
+use ic_cdk::{post_upgrade, pre_upgrade};
 
+use crate::common::admin;
 use crate::common::error::SmartVaultErr;
+use crate::common::memory;
+use crate::common::state::{self, HeapState};
 use crate::common::user::{AddUserArgs, User};
 use crate::common::uuid::UUID;
+use crate::notifications::delivery;
+use crate::notifications::outbox::{self, NotificationKind, OUTBOX};
 use crate::smart_vaults::testament::TestamentResponse;
 use crate::smart_vaults::user_registry::UserRegistry;
 use crate::smart_vaults::user_vault::UserVaultID;
 use crate::utils::caller::get_caller;
-
+use crate::utils::login_date_condition;
+use crate::utils::random::get_new_random;
+use crate::utils::time;
+
+use super::account_recovery::RecoveryRegistry;
+use super::condition_schedule::ConditionSchedule;
+use super::invitation_registry::{
+    ClaimCode, CreateHeirInvitationArgs, HeirInvitation, InvitationRegistry,
+};
 use super::master_vault::MasterVault;
 use super::secret::{
-    AddSecretArgs, Secret, SecretID, SecretListEntry, SecretSymmetricCryptoMaterial,
+    AddSecretArgs, Secret, SecretID, SecretListEntry, SecretRemovalReport,
+    SecretSymmetricCryptoMaterial, SecretVersion,
+};
+use super::testament::{
+    AddTestamentArgs, RearmTestamentArgs, Testament, TestamentID, TestamentListEntry,
 };
-use super::testament::{AddTestamentArgs, Testament, TestamentID, TestamentListEntry};
 use super::testament_registry::TestamentRegistry;
+use super::trash;
 
 thread_local! {
     // Master_vault holding all the user vaults
@@ -28,6 +45,15 @@ thread_local! {
     // Testament Registsry
     pub static TESTAMENT_REGISTRY: RefCell<TestamentRegistry> = RefCell::new(TestamentRegistry::new());
 
+    // Heir invitations, by their claim code
+    pub static INVITATION_REGISTRY: RefCell<InvitationRegistry> = RefCell::new(InvitationRegistry::new());
+
+    // Recovery guardians and requests, by account
+    pub static RECOVERY_REGISTRY: RefCell<RecoveryRegistry> = RefCell::new(RecoveryRegistry::new());
+
+    // Deadlines of the condition evaluations
+    pub static CONDITION_SCHEDULE: RefCell<ConditionSchedule> = RefCell::new(ConditionSchedule::new());
+
     // counter for the UUIDs
     pub static UUID_COUNTER: RefCell<u128>  = RefCell::new(1);
 }
@@ -35,7 +61,7 @@ thread_local! {
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn create_user(args: AddUserArgs) -> Result<User, SmartVaultErr> {
-    let mut new_user = User::new(&get_caller(), args);
+    let mut new_user = User::new(&resolve_caller(), args);
 
     // Let's create the user vault
     let new_user_vault_id: UUID =
@@ -51,10 +77,7 @@ pub fn create_user(args: AddUserArgs) -> Result<User, SmartVaultErr> {
     USER_REGISTRY.with(
         |ur: &RefCell<UserRegistry>| -> Result<User, SmartVaultErr> {
             let mut user_registry = ur.borrow_mut();
-            match user_registry.add_user(new_user) {
-                Ok(u) => Ok(u.clone()),
-                Err(e) => Err(e),
-            }
+            user_registry.add_user(new_user)
         },
     )
 }
@@ -66,12 +89,7 @@ pub fn get_current_user() -> Result<User, SmartVaultErr> {
     USER_REGISTRY.with(
         |ur: &RefCell<UserRegistry>| -> Result<User, SmartVaultErr> {
             let user_registry = ur.borrow();
-            match user_registry.get_user(&get_caller()) {
-                Ok(u) => {
-                    Ok(u.clone())
-                },
-                Err(e) => Err(e),
-            }
+            user_registry.get_user(&resolve_caller())
         },
     )
 }
@@ -79,19 +97,17 @@ pub fn get_current_user() -> Result<User, SmartVaultErr> {
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn update_user(user: User) -> Result<User, SmartVaultErr> {
+    // The caller is resolved through the registry, so before borrowing it mutably
+    let caller = resolve_caller();
 
     // Update the login date
     USER_REGISTRY.with(
         |ur: &RefCell<UserRegistry>| -> Result<User, SmartVaultErr> {
             let mut user_registry = ur.borrow_mut();
-            match user_registry.get_user_mut(&get_caller()) {
-                Ok(u) => {
-                    u.name = user.name;
-                    u.email = user.email;
-                    Ok(u.clone())
-                },
-                Err(e) => Err(e),
-            }
+            let mut u = user_registry.get_user(&caller)?;
+            u.name = user.name;
+            u.email = user.email;
+            user_registry.update_user(u)
         },
     )
 }
@@ -99,32 +115,51 @@ pub fn update_user(user: User) -> Result<User, SmartVaultErr> {
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn update_user_login_date() -> Result<User, SmartVaultErr> {
+    // The caller is resolved through the registry, so before borrowing it mutably
+    let caller = resolve_caller();
 
     // Update the login date
-    USER_REGISTRY.with(
+    let user = USER_REGISTRY.with(
         |ur: &RefCell<UserRegistry>| -> Result<User, SmartVaultErr> {
             let mut user_registry = ur.borrow_mut();
-            match user_registry.get_user_mut(&get_caller()) {
-                Ok(u) => {
-                    u.update_login_date();
-                    Ok(u.clone())
-                },
-                Err(e) => Err(e),
-            }
+            let mut u = user_registry.get_user(&caller)?;
+            u.update_login_date();
+            user_registry.update_user(u)
         },
-    )
+    )?;
+
+    // A login proves the user is alive, so pending releases are cancelled
+    if let Some(user_vault_id) = user.user_vault_id {
+        MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<(), SmartVaultErr> {
+            let mut master_vault = ms.borrow_mut();
+            master_vault.cancel_pending_releases(&user_vault_id)
+        })?;
+    }
+    Ok(user)
 }
 
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn delete_user() -> Result<(), SmartVaultErr> {
-    let principal = get_caller();
+    let principal = get_owner_caller()?;
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
-    MASTERVAULT.with(|ms: &RefCell<MasterVault>| {
-        let mut master_vault = ms.borrow_mut();
-        master_vault.remove_user_vault(&user_vault_id);
+    // delete the user vault, its testaments are removed from the registry and the schedule
+    let removed_testaments = MASTERVAULT.with(
+        |ms: &RefCell<MasterVault>| -> Result<Vec<Testament>, SmartVaultErr> {
+            let mut master_vault = ms.borrow_mut();
+            master_vault.delete_user_vault(&user_vault_id)
+        },
+    )?;
+
+    // delete everything else belonging to the user
+    INVITATION_REGISTRY.with(|ir: &RefCell<InvitationRegistry>| {
+        ir.borrow_mut().remove_invitations_of_testator(&principal)
+    });
+    RECOVERY_REGISTRY.with(|rr: &RefCell<RecoveryRegistry>| {
+        rr.borrow_mut().remove_recovery(&principal)
     });
+    OUTBOX.with(|o| o.borrow_mut().remove_outbox(principal));
 
     // delete the user
     USER_REGISTRY.with(
@@ -133,13 +168,18 @@ pub fn delete_user() -> Result<(), SmartVaultErr> {
             user_registry.delete_user(&principal)
         },
     )?;
+
+    // the heirs learn that they will not inherit anything
+    for testament in &removed_testaments {
+        outbox::notify_removal(testament);
+    }
     Ok(())
 }
 
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn add_secret(args: AddSecretArgs) -> Result<Secret, SmartVaultErr> {
-    let user_vault_id: UUID = get_vault_id_for(get_caller())?;
+    let user_vault_id: UUID = get_vault_id_for(resolve_caller())?;
 
     MASTERVAULT.with(
         |ms: &RefCell<MasterVault>| -> Result<Secret, SmartVaultErr> {
@@ -152,7 +192,7 @@ pub fn add_secret(args: AddSecretArgs) -> Result<Secret, SmartVaultErr> {
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn update_secret(s: Secret) -> Result<Secret, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(
@@ -163,10 +203,39 @@ pub fn update_secret(s: Secret) -> Result<Secret, SmartVaultErr> {
     )
 }
 
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_secret_history(sid: SecretID) -> Result<Vec<SecretVersion>, SmartVaultErr> {
+    let principal = resolve_caller();
+    let user_vault_id: UUID = get_vault_id_for(principal)?;
+
+    MASTERVAULT.with(
+        |mv: &RefCell<MasterVault>| -> Result<Vec<SecretVersion>, SmartVaultErr> {
+            mv.borrow()
+                .get_user_vault(&user_vault_id)?
+                .get_secret_history(&sid)
+        },
+    )
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn restore_secret_version(sid: SecretID, version: u64) -> Result<Secret, SmartVaultErr> {
+    let principal = resolve_caller();
+    let user_vault_id: UUID = get_vault_id_for(principal)?;
+
+    MASTERVAULT.with(
+        |mv: &RefCell<MasterVault>| -> Result<Secret, SmartVaultErr> {
+            mv.borrow_mut()
+                .restore_user_secret_version(&user_vault_id, &sid, version)
+        },
+    )
+}
+
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn get_secret(sid: SecretID) -> Result<Secret, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(
@@ -182,7 +251,7 @@ pub fn get_secret(sid: SecretID) -> Result<Secret, SmartVaultErr> {
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn get_secret_as_heir(sid: SecretID, testament_id: TestamentID) -> Result<Secret, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
 
     // Verify that heir belongs to testament
     let result_tr = TESTAMENT_REGISTRY.with(
@@ -200,13 +269,20 @@ pub fn get_secret_as_heir(sid: SecretID, testament_id: TestamentID) -> Result<Se
         |mv: &RefCell<MasterVault>| -> Result<Testament, SmartVaultErr> {
             mv.borrow()
                 .get_user_vault(&user_vault_id)?
-                .get_testament(&testament_id)
-                .cloned()
+                .get_testament_for_heir(&testament_id, &principal)
         },
     )?;
 
     // Check that heir is allowed to read testament
     if result_mv.condition_status().clone() {
+        // Heirs can only read the secrets of their share of the testament
+        if !result_mv.secrets().contains(&sid) {
+            return Err(SmartVaultErr::SecretNotInTestament(sid));
+        }
+        if !result_mv.is_unlocked(&sid, time::get_current_time()) {
+            return Err(SmartVaultErr::SecretLocked(sid));
+        }
+
         // Read secret in testator user vault
         MASTERVAULT.with(
             |mv: &RefCell<MasterVault>| -> Result<Secret, SmartVaultErr> {
@@ -223,11 +299,11 @@ pub fn get_secret_as_heir(sid: SecretID, testament_id: TestamentID) -> Result<Se
 
 #[ic_cdk_macros::update]
 #[candid_method(update)]
-pub fn remove_secret(secret_id: String) -> Result<(), SmartVaultErr> {
-    let principal = get_caller();
+pub fn remove_secret(secret_id: String) -> Result<SecretRemovalReport, SmartVaultErr> {
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
-    MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<(), SmartVaultErr> {
+    MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<SecretRemovalReport, SmartVaultErr> {
         let mut master_vault = ms.borrow_mut();
         master_vault.remove_user_secret(&user_vault_id, &secret_id)
     })
@@ -236,7 +312,7 @@ pub fn remove_secret(secret_id: String) -> Result<(), SmartVaultErr> {
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn get_secret_list() -> Result<Vec<SecretListEntry>, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
@@ -259,14 +335,16 @@ pub fn get_secret_list() -> Result<Vec<SecretListEntry>, SmartVaultErr> {
 pub fn get_secret_symmetric_crypto_material (
     sid: SecretID,
 ) -> Result<SecretSymmetricCryptoMaterial, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
         let mv: &MasterVault = &mv.borrow();
         let uv = mv.get_user_vault(&user_vault_id)?;
-        let sdm: &SecretSymmetricCryptoMaterial = uv.key_box().get(&sid).unwrap();
-        Ok(sdm.clone())
+        uv.key_box()
+            .get(&sid)
+            .cloned()
+            .ok_or(SmartVaultErr::KeyBoxEntryDoesNotExist(sid))
     })
 }
 
@@ -276,7 +354,7 @@ pub fn get_secret_symmetric_crypto_material_as_heir (
     secret_id: SecretID,
     testament_id: TestamentID
 ) -> Result<SecretSymmetricCryptoMaterial, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
 
     // Verify that heir belongs to testament
     let result_tr = TESTAMENT_REGISTRY.with(
@@ -294,16 +372,26 @@ pub fn get_secret_symmetric_crypto_material_as_heir (
         |mv: &RefCell<MasterVault>| -> Result<Testament, SmartVaultErr> {
             mv.borrow()
                 .get_user_vault(&user_vault_id)?
-                .get_testament(&testament_id)
-                .cloned()
+                .get_testament_for_heir(&testament_id, &principal)
         },
     )?;
 
     // Check that heir is allowed to read testament
     if result_mv.condition_status().clone() {
-        // Read secret crypto material from testament
+        // Heirs can only read the secrets of their share of the testament
+        if !result_mv.secrets().contains(&secret_id) {
+            return Err(SmartVaultErr::SecretNotInTestament(secret_id));
+        }
+        if !result_mv.is_unlocked(&secret_id, time::get_current_time()) {
+            return Err(SmartVaultErr::SecretLocked(secret_id));
+        }
 
-        Ok(result_mv.key_box().get(&secret_id).unwrap().clone())
+        // Read secret crypto material from testament
+        result_mv
+            .key_box()
+            .get(&secret_id)
+            .cloned()
+            .ok_or(SmartVaultErr::KeyBoxEntryDoesNotExist(secret_id))
     } else {
         Err(SmartVaultErr::InvalidTestamentCondition)
     }
@@ -312,7 +400,7 @@ pub fn get_secret_symmetric_crypto_material_as_heir (
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn add_testament(args: AddTestamentArgs) -> Result<Testament, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(
@@ -326,7 +414,7 @@ pub fn add_testament(args: AddTestamentArgs) -> Result<Testament, SmartVaultErr>
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn update_testament(t: Testament) -> Result<Testament, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(
@@ -337,10 +425,48 @@ pub fn update_testament(t: Testament) -> Result<Testament, SmartVaultErr> {
     )
 }
 
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn veto_release(testament_id: TestamentID) -> Result<Testament, SmartVaultErr> {
+    let principal = resolve_caller();
+    let user_vault_id: UUID = get_vault_id_for(principal)?;
+
+    let testament = MASTERVAULT.with(
+        |ms: &RefCell<MasterVault>| -> Result<Testament, SmartVaultErr> {
+            let mut master_vault = ms.borrow_mut();
+            master_vault.veto_release(&user_vault_id, &testament_id)
+        },
+    )?;
+
+    // A veto proves the testator is alive just like a login,
+    // so inactivity is counted from the veto on
+    update_user_login_date()?;
+    Ok(testament)
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn rearm_testament(args: RearmTestamentArgs) -> Result<Testament, SmartVaultErr> {
+    let principal = resolve_caller();
+    let user_vault_id: UUID = get_vault_id_for(principal)?;
+
+    let testament = MASTERVAULT.with(
+        |ms: &RefCell<MasterVault>| -> Result<Testament, SmartVaultErr> {
+            let mut master_vault = ms.borrow_mut();
+            master_vault.rearm_testament(&user_vault_id, args)
+        },
+    )?;
+
+    // Rearming proves the testator is alive like a veto, otherwise an inactivity
+    // condition would release the testament again right away
+    update_user_login_date()?;
+    Ok(testament)
+}
+
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn get_testament_as_testator(testament_id: TestamentID) -> Result<TestamentResponse, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     let result = MASTERVAULT.with(
@@ -374,11 +500,13 @@ pub fn get_testament_as_testator(testament_id: TestamentID) -> Result<TestamentR
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn get_testament_as_heir(testament_id: TestamentID) -> Result<TestamentResponse, SmartVaultErr> {
+    let principal = resolve_caller();
+
     // Verify that heir belongs to testament
     let result_tr = TESTAMENT_REGISTRY.with(
         |tr: &RefCell<TestamentRegistry>| -> Result<(TestamentID, Principal), SmartVaultErr> {
             let testament_registry = tr.borrow();
-            testament_registry.get_testament_id_as_heir(get_caller(), testament_id.clone())
+            testament_registry.get_testament_id_as_heir(principal, testament_id.clone())
         },
     )?;
 
@@ -390,8 +518,7 @@ pub fn get_testament_as_heir(testament_id: TestamentID) -> Result<TestamentRespo
         |mv: &RefCell<MasterVault>| -> Result<Testament, SmartVaultErr> {
             mv.borrow()
                 .get_user_vault(&user_vault_id)?
-                .get_testament(&testament_id)
-                .cloned()
+                .get_testament_for_heir(&testament_id, &principal)
         },
     )?;
 
@@ -400,6 +527,7 @@ pub fn get_testament_as_heir(testament_id: TestamentID) -> Result<TestamentRespo
         // Get more secret data for heirs...
         let testator_vault_id = get_vault_id_for(*result_mv.testator())?;
         let mut testament_for_heir = TestamentResponse::from(result_mv.clone());
+        testament_for_heir.lock_secrets(result_mv.locked_secrets(time::get_current_time()));
         for secret in result_mv.secrets() {
             let result_mv_2 = MASTERVAULT.with(
                 |mv: &RefCell<MasterVault>| -> Result<Secret, SmartVaultErr> {
@@ -422,13 +550,78 @@ pub fn get_testament_as_heir(testament_id: TestamentID) -> Result<TestamentRespo
     }
 }
 
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn confirm_testator_passed(testament_id: TestamentID) -> Result<(), SmartVaultErr> {
+    let principal = resolve_caller();
+
+    // Verify that the caller is heir or validator of the testament
+    let result_tr = get_testament_id_as_heir_or_validator(principal, testament_id.clone())?;
+
+    // The confirmation might already meet the condition, so the testator's last login and check-in are needed as well
+    let testator = USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| ur.borrow().get_user(&result_tr.1))?;
+    let user_vault_id: UUID = get_vault_id_for(result_tr.1)?;
+
+    MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<(), SmartVaultErr> {
+        let mut master_vault = ms.borrow_mut();
+        master_vault.confirm_testator_passed(
+            &user_vault_id,
+            &testament_id,
+            principal,
+            &testator,
+        )
+    })
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn revoke_testator_passed(testament_id: TestamentID) -> Result<(), SmartVaultErr> {
+    let principal = resolve_caller();
+
+    // Verify that the caller is heir or validator of the testament
+    let result_tr = get_testament_id_as_heir_or_validator(principal, testament_id.clone())?;
+
+    let user_vault_id: UUID = get_vault_id_for(result_tr.1)?;
+
+    MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<(), SmartVaultErr> {
+        let mut master_vault = ms.borrow_mut();
+        master_vault.revoke_testator_passed(&user_vault_id, &testament_id, &principal)
+    })
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_testament_list_as_validator() -> Result<Vec<TestamentListEntry>, SmartVaultErr> {
+    let result_tr = TESTAMENT_REGISTRY.with(
+        |tr: &RefCell<TestamentRegistry>| -> Vec<(TestamentID, Principal)> {
+            let testament_registry = tr.borrow();
+            testament_registry.get_testament_ids_as_validator(resolve_caller())
+        },
+    );
+
+    let mut response = Vec::new();
+    for item in result_tr {
+        let user_vault_id: UUID = get_vault_id_for(item.1)?;
+        let result_mv = MASTERVAULT.with(
+            |mv: &RefCell<MasterVault>| -> Result<Testament, SmartVaultErr> {
+                mv.borrow()
+                    .get_user_vault(&user_vault_id)?
+                    .get_testament(&item.0)
+                    .cloned()
+            },
+        )?;
+        response.push(TestamentListEntry::from(result_mv))
+    }
+    Ok(response)
+}
+
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn get_testament_list_as_heir() -> Result<Vec<TestamentListEntry>, SmartVaultErr> {
     let result_tr = TESTAMENT_REGISTRY.with(
         |tr: &RefCell<TestamentRegistry>| -> Vec<(TestamentID, Principal)> {
             let testament_registry = tr.borrow();
-            testament_registry.get_testament_ids_as_heir(get_caller())
+            testament_registry.get_testament_ids_as_heir(resolve_caller())
         },
     );
 
@@ -452,7 +645,7 @@ pub fn get_testament_list_as_heir() -> Result<Vec<TestamentListEntry>, SmartVaul
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn get_testament_list_as_testator() -> Result<Vec<TestamentListEntry>, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
@@ -473,7 +666,7 @@ pub fn get_testament_list_as_testator() -> Result<Vec<TestamentListEntry>, Smart
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn remove_testament(testament_id: String) -> Result<(), SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<(), SmartVaultErr> {
@@ -482,22 +675,10 @@ pub fn remove_testament(testament_id: String) -> Result<(), SmartVaultErr> {
     })
 }
 
-#[ic_cdk_macros::update]
-#[candid_method(update)]
-pub fn add_heir(args: AddUserArgs) -> Result<User, SmartVaultErr> {
-    let principal = get_caller();
-    let user_vault_id: UUID = get_vault_id_for(principal)?;
-
-    MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<User, SmartVaultErr> {
-        let mut master_vault = ms.borrow_mut();
-        master_vault.add_heir(&user_vault_id, args)
-    })
-}
-
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn get_heir_list() -> Result<Vec<User>, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
@@ -518,7 +699,7 @@ pub fn get_heir_list() -> Result<Vec<User>, SmartVaultErr> {
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn update_heir(u: User) -> Result<User, SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(
@@ -532,7 +713,7 @@ pub fn update_heir(u: User) -> Result<User, SmartVaultErr> {
 #[ic_cdk_macros::update]
 #[candid_method(update)]
 pub fn remove_heir(user_id: Principal) -> Result<(), SmartVaultErr> {
-    let principal = get_caller();
+    let principal = resolve_caller();
     let user_vault_id: UUID = get_vault_id_for(principal)?;
 
     MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<(), SmartVaultErr> {
@@ -541,17 +722,121 @@ pub fn remove_heir(user_id: Principal) -> Result<(), SmartVaultErr> {
     })
 }
 
+/// Creates an invitation with a one-time claim code, which the testator hands over to the heir.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub async fn create_heir_invitation(
+    args: CreateHeirInvitationArgs,
+) -> Result<HeirInvitation, SmartVaultErr> {
+    let principal = resolve_caller();
+    get_vault_id_for(principal)?;
+
+    let claim_code: ClaimCode = hex::encode(get_new_random().await);
+    let invitation = HeirInvitation::new(claim_code, principal, args, time::get_current_time());
+    INVITATION_REGISTRY.with(
+        |ir: &RefCell<InvitationRegistry>| -> Result<HeirInvitation, SmartVaultErr> {
+            let mut invitation_registry = ir.borrow_mut();
+            invitation_registry.add_invitation(invitation)
+        },
+    )
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_heir_invitations() -> Result<Vec<HeirInvitation>, SmartVaultErr> {
+    let principal = resolve_caller();
+    get_vault_id_for(principal)?;
+
+    Ok(INVITATION_REGISTRY.with(|ir: &RefCell<InvitationRegistry>| {
+        ir.borrow()
+            .get_invitations_of_testator(&principal, time::get_current_time())
+    }))
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn revoke_heir_invitation(claim_code: ClaimCode) -> Result<(), SmartVaultErr> {
+    let principal = resolve_caller();
+
+    INVITATION_REGISTRY.with(|ir: &RefCell<InvitationRegistry>| -> Result<(), SmartVaultErr> {
+        let mut invitation_registry = ir.borrow_mut();
+        invitation_registry.revoke_invitation(&claim_code, &principal)?;
+        Ok(())
+    })
+}
+
+/// Redeems the claim code of an invitation. The caller is added to the heirs
+/// of the testator and can be named in the testator's testaments from now on.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn accept_heir_invitation(claim_code: ClaimCode) -> Result<HeirInvitation, SmartVaultErr> {
+    let heir = resolve_caller();
+    let now = time::get_current_time();
+
+    let invitation = INVITATION_REGISTRY.with(|ir: &RefCell<InvitationRegistry>| {
+        ir.borrow().get_invitation(&claim_code)
+    })?;
+    invitation.verify_acceptable(&heir, now)?;
+
+    let user_vault_id: UUID = get_vault_id_for(invitation.testator)?;
+    MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> Result<User, SmartVaultErr> {
+        let mut master_vault = ms.borrow_mut();
+        master_vault.add_heir(&user_vault_id, invitation.heir_args(heir))
+    })?;
+
+    let accepted = INVITATION_REGISTRY.with(
+        |ir: &RefCell<InvitationRegistry>| -> Result<HeirInvitation, SmartVaultErr> {
+            let mut invitation_registry = ir.borrow_mut();
+            invitation_registry.accept_invitation(&claim_code, heir, now)
+        },
+    )?;
+    outbox::notify(
+        invitation.testator,
+        NotificationKind::HeirInvitationAccepted { claim_code, heir },
+    );
+    Ok(accepted)
+}
+
 #[ic_cdk_macros::query]
 #[candid_method(query)]
 pub fn is_user_vault_existing() -> bool {
-    let principal = get_caller();
+    let principal = resolve_caller();
     if get_vault_id_for(principal).is_ok() {
         return true;
     }
     false
 }
 
-fn get_vault_id_for(principal: Principal) -> Result<UserVaultID, SmartVaultErr> {
+fn get_testament_id_as_heir_or_validator(
+    principal: Principal,
+    testament_id: TestamentID,
+) -> Result<(TestamentID, Principal), SmartVaultErr> {
+    TESTAMENT_REGISTRY.with(|tr: &RefCell<TestamentRegistry>| {
+        let testament_registry = tr.borrow();
+        testament_registry
+            .get_testament_id_as_heir(principal, testament_id.clone())
+            .or_else(|_| testament_registry.get_testament_id_as_validator(principal, testament_id))
+    })
+}
+
+/// The caller, or the owner of the account the caller is linked to.
+/// All principals of an account act on behalf of its owner, see linked_principals.
+pub(crate) fn resolve_caller() -> Principal {
+    let caller = get_caller();
+    USER_REGISTRY.with(|ur: &RefCell<UserRegistry>| ur.borrow().resolve_principal(&caller))
+}
+
+/// The caller, if it is the owner of an account or has none.
+/// Managing the account is up to its owner, the principals linked to it cannot.
+pub(crate) fn get_owner_caller() -> Result<Principal, SmartVaultErr> {
+    let caller = get_caller();
+    if resolve_caller() != caller {
+        return Err(SmartVaultErr::NotAuthorized(caller.to_string()));
+    }
+    Ok(caller)
+}
+
+pub(crate) fn get_vault_id_for(principal: Principal) -> Result<UserVaultID, SmartVaultErr> {
     USER_REGISTRY.with(
         |ur: &RefCell<UserRegistry>| -> Result<UUID, SmartVaultErr> {
             let user_registry = ur.borrow();
@@ -565,23 +850,35 @@ fn get_vault_id_for(principal: Principal) -> Result<UserVaultID, SmartVaultErr>
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    MASTERVAULT.with(|ms| storage::stable_save((ms,)).unwrap());
-    USER_REGISTRY.with(|ur| storage::stable_save((ur,)).unwrap());
-    TESTAMENT_REGISTRY.with(|tr| storage::stable_save((tr,)).unwrap());
-    UUID_COUNTER.with(|c| storage::stable_save((c,)).unwrap());
+    // The vaults and registries live in stable structures and survive upgrades as they are.
+    // Only the heap state needs to be saved, wrapped into a versioned envelope.
+    memory::save_upgrade_state(&state::save(&HeapState::collect()));
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (old_ms,): (MasterVault,) = storage::stable_restore().unwrap();
-    MASTERVAULT.with(|ms| *ms.borrow_mut() = old_ms);
-
-    let (old_ur,): (UserRegistry,) = storage::stable_restore().unwrap();
-    USER_REGISTRY.with(|ur| *ur.borrow_mut() = old_ur);
+    // The baseline layout has to be read before the memory manager claims the stable memory
+    let heap_state = match memory::read_baseline_stable_memory() {
+        Some(bytes) => Some(state::restore_baseline(bytes)),
+        // Restoring runs all migrations from the persisted version to the current one
+        None => memory::load_upgrade_state().map(state::restore),
+    };
+    match heap_state {
+        Some(heap_state) => {
+            heap_state.apply();
+
+            // The ic timers do not survive upgrades, so the persisted schedules are armed again
+            login_date_condition::rearm_timers();
+            state::resume_vault_backfill();
+        }
+        // Nothing persisted yet, start the timers like init does
+        None => login_date_condition::init_condition(),
+    }
 
-    let (old_tr,): (TestamentRegistry,) = storage::stable_restore().unwrap();
-    TESTAMENT_REGISTRY.with(|tr| *tr.borrow_mut() = old_tr);
+    // The delivery and purge timers have no persisted schedule, they are simply started again
+    delivery::init_delivery_timer();
+    trash::init_trash_timer();
 
-    let (old_c,): (u128,) = storage::stable_restore().unwrap();
-    UUID_COUNTER.with(|c| *c.borrow_mut() = old_c);
+    // The controller performing the upgrade is an admin
+    admin::register_controller(get_caller());
 }
@@ -1,15 +1,97 @@
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
+use crate::common::error::{SmartVaultErr, TestamentProblem};
+use crate::smart_vaults::check_in::CheckIn;
+use crate::smart_vaults::condition::{Condition, ConditionContext};
 use crate::smart_vaults::secret::SecretListEntry;
 
-use crate::utils::{caller::get_caller, time};
+use crate::utils::time;
 
-use super::{secret::SecretID, user_vault::KeyBox};
+use super::{secret::SecretID, smart_vault::resolve_caller, user_vault::KeyBox};
 
 pub type TestamentID = String;
 
+/// Reminders are sent at these percentages of the inactivity period, unless configured otherwise
+pub const DEFAULT_REMINDER_MILESTONES: [u8; 3] = [50, 80, 95];
+
+/// The release of a testament goes through these states:
+/// Armed -> Triggered -> GracePeriod -> Released.
+/// During the grace period the testator can still cancel the release
+/// by logging in or vetoing it, which leads to Cancelled.
+/// A veto holds for one grace period, then the testament is armed again.
+/// Dates are in nanoseconds.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum ReleaseState {
+    /// The condition is not met
+    Armed,
+    /// The condition is met, the grace period starts
+    Triggered { triggered_at: u64 },
+    /// The testament gets released at release_at unless the testator objects
+    GracePeriod { triggered_at: u64, release_at: u64 },
+    /// The heirs have access to the testament
+    Released { released_at: u64 },
+    /// The testator objected. Armed again as soon as the condition is not met anymore,
+    /// at the latest one grace period after the veto.
+    Cancelled { cancelled_at: u64 },
+}
+
+/// A release which the testator reverted by rearming the testament.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ReleaseIncident {
+    pub released_at: u64,
+    pub rearmed_at: u64,
+    pub reason: Option<String>,
+}
+
+/// The last reminder sent to the testator. Reminders count from the last login on,
+/// so a mark of an earlier login does not count anymore.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ReminderMark {
+    pub last_login: u64,
+    pub milestone: u8,
+}
+
+/// The share of the testament a single heir gets: a subset of its secrets,
+/// with the key box entries for them.
+#[derive(Debug, Default, CandidType, Deserialize, Serialize, Clone)]
+pub struct HeirAllocation {
+    pub secrets: HashSet<SecretID>,
+    pub key_box: KeyBox,
+}
+
+/// When the secrets of a release stage are unlocked for the heirs.
+/// They are never unlocked before the testament is released.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum StageUnlock {
+    /// Some time after the release of the testament
+    AfterRelease { delay_secs: u64 },
+    /// At a fixed date in nanoseconds, e.g. the 18th birthday of an heir
+    AtDate { date: u64 },
+}
+
+impl StageUnlock {
+    /// The unlock date in nanoseconds for a testament released at the given date.
+    pub fn unlock_date(&self, released_at: u64) -> u64 {
+        match self {
+            StageUnlock::AfterRelease { delay_secs } => {
+                released_at.saturating_add(delay_secs.saturating_mul(1000000000))
+            }
+            StageUnlock::AtDate { date } => released_at.max(*date),
+        }
+    }
+}
+
+/// Secrets of the testament which the heirs only get some time after the release.
+/// Secrets which are not part of any stage are unlocked with the release.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct ReleaseStage {
+    pub name: Option<String>,
+    pub secrets: HashSet<SecretID>,
+    pub unlock: StageUnlock,
+}
+
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
 pub struct Testament {
     id: TestamentID,
@@ -26,8 +108,30 @@ pub struct Testament {
     /// This key is itself encrypted using the Testament decryption key,
     /// which itself is derived by vetkd.
     key_box: KeyBox,
+    /// Heirs who only get a part of the secrets, with their share.
+    /// Without allocations, every heir gets all secrets of the testament.
+    /// Once there are allocations, heirs without one get nothing.
+    allocations: BTreeMap<Principal, HeirAllocation>,
+    release_stages: Vec<ReleaseStage>,
     condition_status: bool,
-    condition_arg: u64 // currently only for max difference to last_login_date, in seconds
+    condition: Condition,
+    release_state: ReleaseState,
+    /// Time between meeting the condition and the release, in seconds
+    grace_period_secs: u64,
+    /// Released testaments which were rearmed by the testator
+    release_history: Vec<ReleaseIncident>,
+    /// Part of the key derivation for the key box. Increased on every rearm,
+    /// so keys derived by the heirs during an erroneous release become useless.
+    key_epoch: u64,
+    /// Percentages of the inactivity period at which the testator gets reminded to log in
+    reminder_milestones: Vec<u8>,
+    last_reminder: Option<ReminderMark>,
+    /// Heirs who confirmed that the testator passed away, with the time of confirmation
+    heir_confirmations: BTreeMap<Principal, u64>,
+    /// Trusted persons (e.g. a notary) who cannot read the secrets,
+    /// but whose confirmation can be required for the release
+    validators: HashSet<Principal>,
+    validator_confirmations: BTreeMap<Principal, u64>,
 }
 
 /// The struct provided by the backend when calling "create_secret". It contains:
@@ -38,7 +142,22 @@ pub struct AddTestamentArgs {
     heirs: HashSet<Principal>,
     secrets: HashSet<SecretID>,
     key_box: KeyBox,
-    condition_arg: u64
+    condition: Condition,
+    grace_period_secs: u64,
+    validators: HashSet<Principal>,
+    reminder_milestones: Option<Vec<u8>>,
+    allocations: Option<BTreeMap<Principal, HeirAllocation>>,
+    release_stages: Option<Vec<ReleaseStage>>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct RearmTestamentArgs {
+    pub testament_id: TestamentID,
+    /// The key box encrypted with the key of the next key epoch
+    pub key_box: KeyBox,
+    /// The key boxes of the allocations, encrypted with the key of the next key epoch as well
+    pub allocation_key_boxes: Option<BTreeMap<Principal, KeyBox>>,
+    pub reason: Option<String>,
 }
 
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
@@ -46,7 +165,8 @@ pub struct TestamentListEntry {
     pub id: TestamentID,
     pub name: Option<String>,
     pub testator: Principal,
-    pub condition_status: bool
+    pub condition_status: bool,
+    pub release_state: ReleaseState,
 }
 
 impl From<Testament> for TestamentListEntry {
@@ -56,6 +176,7 @@ impl From<Testament> for TestamentListEntry {
             name: t.name,
             testator: t.testator.clone(),
             condition_status: t.condition_status,
+            release_state: t.release_state,
         }
     }
 }
@@ -69,12 +190,25 @@ impl Testament {
             name: None,
             date_created: now,
             date_modified: now,
-            testator: get_caller(),
+            testator: resolve_caller(),
             heirs: HashSet::new(),
             secrets: HashSet::new(),
             key_box: BTreeMap::new(),
-            condition_arg: 0,
-            condition_status: false
+            allocations: BTreeMap::new(),
+            release_stages: Vec::new(),
+            condition: Condition::LastLogin {
+                max_inactivity_secs: 0,
+            },
+            condition_status: false,
+            release_state: ReleaseState::Armed,
+            grace_period_secs: 0,
+            release_history: Vec::new(),
+            key_epoch: 0,
+            reminder_milestones: DEFAULT_REMINDER_MILESTONES.to_vec(),
+            last_reminder: None,
+            heir_confirmations: BTreeMap::new(),
+            validators: HashSet::new(),
+            validator_confirmations: BTreeMap::new(),
         }
     }
 
@@ -94,6 +228,13 @@ impl Testament {
         &self.testator
     }
 
+    /// Binds the testament to the testator, e.g. after the account was recovered
+    /// to another principal.
+    pub fn set_testator(&mut self, testator: Principal) {
+        self.testator = testator;
+        self.date_modified = time::get_current_time();
+    }
+
     pub fn name(&self) -> &Option<String> {
         &self.name
     }
@@ -106,8 +247,332 @@ impl Testament {
         &self.secrets
     }
 
-    pub fn condition_arg(&self) -> &u64{
-        &self.condition_arg
+    pub fn condition(&self) -> &Condition {
+        &self.condition
+    }
+
+    /// Collects what the condition engine needs to know about this testament.
+    pub fn condition_context(
+        &self,
+        now: u64,
+        last_login: Option<u64>,
+        check_in: Option<&CheckIn>,
+    ) -> ConditionContext {
+        ConditionContext {
+            now,
+            last_login,
+            heir_confirmations: self.heir_confirmations.len() as u64,
+            validator_confirmations: self.validator_confirmations.len() as u64,
+            check_in: check_in.cloned(),
+        }
+    }
+
+    pub fn release_state(&self) -> &ReleaseState {
+        &self.release_state
+    }
+
+    pub fn grace_period_secs(&self) -> &u64 {
+        &self.grace_period_secs
+    }
+
+    /// Moves the release state forward as far as the condition and the grace period allow.
+    /// Returns whether the testament got released by this evaluation.
+    pub fn evaluate_condition(
+        &mut self,
+        now: u64,
+        last_login: Option<u64>,
+        check_in: Option<&CheckIn>,
+    ) -> bool {
+        let ctx = self.condition_context(now, last_login, check_in);
+        let condition_met = self.condition.evaluate(&ctx);
+        loop {
+            let next_state = match self.release_state {
+                ReleaseState::Armed if condition_met => {
+                    ReleaseState::Triggered { triggered_at: now }
+                }
+                ReleaseState::Triggered { triggered_at } => ReleaseState::GracePeriod {
+                    triggered_at,
+                    release_at: triggered_at
+                        .saturating_add(self.grace_period_secs.saturating_mul(1000000000)),
+                },
+                ReleaseState::GracePeriod { .. } if !condition_met => ReleaseState::Armed,
+                ReleaseState::GracePeriod { release_at, .. } if now >= release_at => {
+                    ReleaseState::Released { released_at: now }
+                }
+                ReleaseState::Cancelled { cancelled_at }
+                    if !condition_met || now >= self.veto_expiry(cancelled_at) =>
+                {
+                    ReleaseState::Armed
+                }
+                _ => break,
+            };
+            self.set_release_state(next_state);
+        }
+        matches!(self.release_state, ReleaseState::Released { released_at } if released_at == now)
+    }
+
+    /// Returns when the condition needs to be evaluated next, if the passing of time
+    /// can change the release state at all. Everything else (logins, confirmations,
+    /// updates) schedules an evaluation by itself.
+    pub fn next_evaluation(
+        &self,
+        now: u64,
+        last_login: Option<u64>,
+        check_in: Option<&CheckIn>,
+    ) -> Option<u64> {
+        match self.release_state {
+            ReleaseState::Released { .. } => None,
+            ReleaseState::GracePeriod { release_at, .. } => Some(release_at),
+            _ => {
+                let deadline = self
+                    .condition
+                    .next_deadline(&self.condition_context(now, last_login, check_in));
+                let reminder = self.next_reminder(now, last_login);
+                let veto_expiry = match self.release_state {
+                    ReleaseState::Cancelled { cancelled_at } => {
+                        Some(self.veto_expiry(cancelled_at))
+                    }
+                    _ => None,
+                };
+                deadline
+                    .into_iter()
+                    .chain(reminder)
+                    .chain(veto_expiry)
+                    .min()
+            }
+        }
+    }
+
+    pub fn reminder_milestones(&self) -> &Vec<u8> {
+        &self.reminder_milestones
+    }
+
+    pub fn last_reminder(&self) -> &Option<ReminderMark> {
+        &self.last_reminder
+    }
+
+    /// Takes over the last reminder of the old version of this testament.
+    pub fn set_last_reminder(&mut self, old: &Testament) {
+        self.last_reminder = old.last_reminder.clone();
+    }
+
+    /// Returns the reminder milestones with their due dates since the last login.
+    /// Only armed testaments with a LastLogin condition remind the testator.
+    fn reminder_dates(&self, last_login: Option<u64>) -> Vec<(u8, u64)> {
+        let (Some(last_login), Some(max_inactivity_secs)) =
+            (last_login, self.condition.min_inactivity_secs())
+        else {
+            return Vec::new();
+        };
+        if self.release_state != ReleaseState::Armed {
+            return Vec::new();
+        }
+        self.reminder_milestones
+            .iter()
+            .map(|milestone| {
+                // one percent of the inactivity period is max_inactivity_secs * 10^7 nanoseconds
+                let offset = max_inactivity_secs
+                    .saturating_mul(10000000)
+                    .saturating_mul(*milestone as u64);
+                (*milestone, last_login.saturating_add(offset))
+            })
+            .collect()
+    }
+
+    /// The point in time (in nanoseconds) at which the shortest inactivity period ends.
+    pub fn inactivity_release_date(&self, last_login: Option<u64>) -> Option<u64> {
+        let max_inactivity_secs = self.condition.min_inactivity_secs()?;
+        Some(last_login?.saturating_add(max_inactivity_secs.saturating_mul(1000000000)))
+    }
+
+    fn is_reminded_of(&self, last_login: Option<u64>, milestone: u8) -> bool {
+        matches!(&self.last_reminder, Some(mark)
+            if Some(mark.last_login) == last_login && mark.milestone >= milestone)
+    }
+
+    /// Returns the highest milestone reached since the last login, if the testator
+    /// was not reminded of it yet, and remembers it as reminded.
+    /// Milestones missed in between are not reminded of separately.
+    pub fn due_reminder(&mut self, now: u64, last_login: Option<u64>) -> Option<u8> {
+        let milestone = self
+            .reminder_dates(last_login)
+            .into_iter()
+            .filter(|(_, date)| *date <= now)
+            .map(|(milestone, _)| milestone)
+            .max()?;
+        if self.is_reminded_of(last_login, milestone) {
+            return None;
+        }
+        self.last_reminder = Some(ReminderMark {
+            last_login: last_login?,
+            milestone,
+        });
+        Some(milestone)
+    }
+
+    fn next_reminder(&self, now: u64, last_login: Option<u64>) -> Option<u64> {
+        self.reminder_dates(last_login)
+            .into_iter()
+            .filter(|(milestone, date)| *date > now && !self.is_reminded_of(last_login, *milestone))
+            .map(|(_, date)| date)
+            .min()
+    }
+
+    /// A condition which stays met, like a passed fixed date, is evaluated again from this
+    /// point in time on, so a veto does not leave the testament cancelled for good.
+    fn veto_expiry(&self, cancelled_at: u64) -> u64 {
+        cancelled_at.saturating_add(self.grace_period_secs.saturating_mul(1000000000))
+    }
+
+    /// Cancels a pending release. The confirmations are dropped as well,
+    /// since the testator proved them wrong.
+    pub fn cancel_release(&mut self, now: u64) -> Result<(), SmartVaultErr> {
+        match self.release_state {
+            ReleaseState::Triggered { .. } | ReleaseState::GracePeriod { .. } => {
+                self.heir_confirmations.clear();
+                self.validator_confirmations.clear();
+                self.set_release_state(ReleaseState::Cancelled { cancelled_at: now });
+                Ok(())
+            }
+            _ => Err(SmartVaultErr::ReleaseNotPending(self.id.clone())),
+        }
+    }
+
+    pub fn release_history(&self) -> &Vec<ReleaseIncident> {
+        &self.release_history
+    }
+
+    pub fn key_epoch(&self) -> &u64 {
+        &self.key_epoch
+    }
+
+    /// Takes over the release history and key epoch of the old version of this testament.
+    pub fn set_release_history(&mut self, old: &Testament) {
+        self.release_history = old.release_history.clone();
+        self.key_epoch = old.key_epoch;
+    }
+
+    /// Reverts an erroneous release. The key boxes need to be encrypted
+    /// with the key of the next key epoch, which the heirs have not seen yet.
+    /// Allocations without a new key box lose their key box entries,
+    /// which the validation of the vault rejects, see UserVault::rearm_testament.
+    pub fn rearm(
+        &mut self,
+        key_box: KeyBox,
+        mut allocation_key_boxes: BTreeMap<Principal, KeyBox>,
+        reason: Option<String>,
+        now: u64,
+    ) -> Result<(), SmartVaultErr> {
+        let released_at = match self.release_state {
+            ReleaseState::Released { released_at } => released_at,
+            _ => return Err(SmartVaultErr::TestamentNotReleased(self.id.clone())),
+        };
+        self.release_history.push(ReleaseIncident {
+            released_at,
+            rearmed_at: now,
+            reason,
+        });
+        self.key_epoch += 1;
+        self.key_box = key_box;
+        for (heir, allocation) in self.allocations.iter_mut() {
+            allocation.key_box = allocation_key_boxes.remove(heir).unwrap_or_default();
+        }
+        self.heir_confirmations.clear();
+        self.validator_confirmations.clear();
+        self.set_release_state(ReleaseState::Armed);
+        Ok(())
+    }
+
+    pub fn set_release_state(&mut self, release_state: ReleaseState) {
+        let released = matches!(release_state, ReleaseState::Released { .. });
+        self.release_state = release_state;
+        self.set_condition_status(released);
+    }
+
+    pub fn heir_confirmations(&self) -> &BTreeMap<Principal, u64> {
+        &self.heir_confirmations
+    }
+
+    pub fn validators(&self) -> &HashSet<Principal> {
+        &self.validators
+    }
+
+    pub fn validator_confirmations(&self) -> &BTreeMap<Principal, u64> {
+        &self.validator_confirmations
+    }
+
+    /// Checks the parts of the testament which do not depend on the vault.
+    pub fn problems(&self) -> Vec<TestamentProblem> {
+        let mut problems = Vec::new();
+        if let Err(err) = self.condition.validate() {
+            let reason = match err {
+                SmartVaultErr::InvalidCondition(reason) => reason,
+                err => err.to_string(),
+            };
+            problems.push(TestamentProblem::InvalidCondition(reason));
+        }
+        let milestones: BTreeSet<&u8> = self.reminder_milestones.iter().collect();
+        for milestone in milestones {
+            if !(1..100).contains(milestone) {
+                problems.push(TestamentProblem::InvalidReminderMilestone(*milestone));
+            }
+        }
+        let validators: BTreeSet<&Principal> = self.validators.intersection(&self.heirs).collect();
+        for validator in validators {
+            problems.push(TestamentProblem::ValidatorIsHeir(validator.to_string()));
+        }
+        problems
+    }
+
+    /// Takes over the confirmations of the old version of this testament,
+    /// as far as the confirming heirs and validators are still part of it.
+    pub fn set_confirmations(&mut self, old: &Testament) {
+        self.heir_confirmations = retain_confirmations(&old.heir_confirmations, &self.heirs);
+        self.validator_confirmations =
+            retain_confirmations(&old.validator_confirmations, &self.validators);
+    }
+
+    /// Records that an heir or validator confirmed that the testator passed away.
+    /// Confirming twice keeps the date of the first confirmation.
+    /// A confirmation after a cancelled release arms the testament again.
+    pub fn confirm_testator_passed(&mut self, principal: Principal) -> Result<(), SmartVaultErr> {
+        let now = time::get_current_time();
+        self.confirmations_mut(&principal)?
+            .entry(principal)
+            .or_insert(now);
+        if let ReleaseState::Cancelled { .. } = self.release_state {
+            self.set_release_state(ReleaseState::Armed);
+        }
+        self.date_modified = now;
+        Ok(())
+    }
+
+    /// Withdraws a confirmation. Not possible anymore once the testament is released.
+    pub fn revoke_testator_passed(&mut self, principal: &Principal) -> Result<(), SmartVaultErr> {
+        if self.condition_status {
+            return Err(SmartVaultErr::InvalidTestamentCondition);
+        }
+        if self
+            .confirmations_mut(principal)?
+            .remove(principal)
+            .is_some()
+        {
+            self.date_modified = time::get_current_time();
+        }
+        Ok(())
+    }
+
+    fn confirmations_mut(
+        &mut self,
+        principal: &Principal,
+    ) -> Result<&mut BTreeMap<Principal, u64>, SmartVaultErr> {
+        if self.heirs.contains(principal) {
+            Ok(&mut self.heir_confirmations)
+        } else if self.validators.contains(principal) {
+            Ok(&mut self.validator_confirmations)
+        } else {
+            Err(SmartVaultErr::TestamentDoesNotExist(self.id.clone()))
+        }
     }
 
     pub fn condition_status(&self) -> &bool{
@@ -127,6 +592,7 @@ impl Testament {
     }
 
     pub fn remove_heir(&mut self, heir: &Principal) -> bool {
+        self.allocations.remove(heir);
         self.heirs.remove(heir)
     }
 
@@ -134,10 +600,104 @@ impl Testament {
         self.secrets.insert(secret)
     }
 
+    /// The secret is removed from the allocations as well. Allocations which become empty
+    /// are kept, so that their heir does not get access to all secrets instead.
     pub fn remove_secret(&mut self, secret: &SecretID) -> bool {
+        for allocation in self.allocations.values_mut() {
+            allocation.secrets.remove(secret);
+            allocation.key_box.remove(secret);
+        }
+        for stage in self.release_stages.iter_mut() {
+            stage.secrets.remove(secret);
+        }
         self.secrets.remove(secret)
     }
 
+    pub fn release_stages(&self) -> &Vec<ReleaseStage> {
+        &self.release_stages
+    }
+
+    pub fn release_stages_mut(&mut self) -> &mut Vec<ReleaseStage> {
+        &mut self.release_stages
+    }
+
+    /// Returns when the secret gets unlocked for the heirs, in nanoseconds.
+    /// None as long as the testament is not released.
+    pub fn unlock_date(&self, secret: &SecretID) -> Option<u64> {
+        let ReleaseState::Released { released_at } = self.release_state else {
+            return None;
+        };
+        // a secret in several stages is unlocked by the latest of them
+        let unlock_date = self
+            .release_stages
+            .iter()
+            .filter(|stage| stage.secrets.contains(secret))
+            .map(|stage| stage.unlock.unlock_date(released_at))
+            .max()
+            .unwrap_or(released_at);
+        Some(unlock_date)
+    }
+
+    /// The secrets of the testament the heirs cannot read yet, with their unlock dates.
+    /// Empty as long as the testament is not released.
+    pub fn locked_secrets(&self, now: u64) -> BTreeMap<SecretID, u64> {
+        self.secrets
+            .iter()
+            .filter_map(|secret| Some((secret.clone(), self.unlock_date(secret)?)))
+            .filter(|(_, unlock_date)| *unlock_date > now)
+            .collect()
+    }
+
+    pub fn is_unlocked(&self, secret: &SecretID, now: u64) -> bool {
+        self.unlock_date(secret)
+            .map_or(false, |unlock_date| unlock_date <= now)
+    }
+
+    pub fn allocations(&self) -> &BTreeMap<Principal, HeirAllocation> {
+        &self.allocations
+    }
+
+    pub fn allocations_mut(&mut self) -> &mut BTreeMap<Principal, HeirAllocation> {
+        &mut self.allocations
+    }
+
+    /// The testament as the given heir sees it: only the secrets allocated to the heir,
+    /// with the key box entries of the allocation. The allocations of other heirs, and the
+    /// stages of their secrets, are left out. If there are allocations, but none for the
+    /// heir, the share is empty.
+    pub fn share_of(&self, heir: &Principal) -> Testament {
+        let mut share = self.clone();
+        share.allocations.clear();
+        if !self.allocations.is_empty() && !self.allocations.contains_key(heir) {
+            share.secrets.clear();
+            share.key_box.clear();
+        }
+        if let Some(allocation) = self.allocations.get(heir) {
+            share.secrets = allocation
+                .secrets
+                .intersection(&self.secrets)
+                .cloned()
+                .collect();
+            share.key_box = allocation
+                .key_box
+                .iter()
+                .filter(|(secret_id, _)| share.secrets.contains(*secret_id))
+                .map(|(secret_id, key)| (secret_id.clone(), key.clone()))
+                .collect();
+            let allocation = HeirAllocation {
+                secrets: share.secrets.clone(),
+                key_box: share.key_box.clone(),
+            };
+            share.allocations.insert(*heir, allocation);
+        }
+        for stage in share.release_stages.iter_mut() {
+            stage
+                .secrets
+                .retain(|secret_id| share.secrets.contains(secret_id));
+        }
+        share
+    }
+
     // TODO: make proper CRUD functions
     pub fn key_box_mut(&mut self) -> &mut KeyBox {
         &mut self.key_box
@@ -155,11 +715,29 @@ impl From<AddTestamentArgs> for Testament {
         new_testament.heirs = ata.heirs;
         new_testament.secrets = ata.secrets;
         new_testament.key_box = ata.key_box;
-        new_testament.condition_arg = ata.condition_arg;
+        new_testament.condition = ata.condition;
+        new_testament.grace_period_secs = ata.grace_period_secs;
+        new_testament.validators = ata.validators;
+        if let Some(reminder_milestones) = ata.reminder_milestones {
+            new_testament.reminder_milestones = reminder_milestones;
+        }
+        new_testament.allocations = ata.allocations.unwrap_or_default();
+        new_testament.release_stages = ata.release_stages.unwrap_or_default();
         new_testament
     }
 }
 
+fn retain_confirmations(
+    confirmations: &BTreeMap<Principal, u64>,
+    principals: &HashSet<Principal>,
+) -> BTreeMap<Principal, u64> {
+    confirmations
+        .iter()
+        .filter(|(principal, _)| principals.contains(principal))
+        .map(|(principal, date)| (*principal, *date))
+        .collect()
+}
+
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
 pub struct TestamentResponse {
     id: TestamentID,
@@ -170,8 +748,21 @@ pub struct TestamentResponse {
     heirs: HashSet<Principal>,
     secrets: HashSet<SecretListEntry>,
     key_box: KeyBox,
+    allocations: BTreeMap<Principal, HeirAllocation>,
+    release_stages: Vec<ReleaseStage>,
+    /// The secrets the heirs cannot read yet, with their unlock dates
+    locked_secrets: BTreeMap<SecretID, u64>,
     condition_status: bool,
-    condition_arg: u64 // currently only for max difference to last_login_date, in seconds
+    condition: Condition,
+    release_state: ReleaseState,
+    grace_period_secs: u64,
+    release_history: Vec<ReleaseIncident>,
+    key_epoch: u64,
+    reminder_milestones: Vec<u8>,
+    last_reminder: Option<ReminderMark>,
+    heir_confirmations: BTreeMap<Principal, u64>,
+    validators: HashSet<Principal>,
+    validator_confirmations: BTreeMap<Principal, u64>,
 }
 
 impl TestamentResponse {
@@ -182,18 +773,44 @@ impl TestamentResponse {
             name: None,
             date_created: now,
             date_modified: now,
-            testator: get_caller(),
+            testator: resolve_caller(),
             heirs: HashSet::new(),
             secrets: HashSet::new(),
             key_box: BTreeMap::new(),
-            condition_arg: 0,
-            condition_status: false
+            allocations: BTreeMap::new(),
+            release_stages: Vec::new(),
+            locked_secrets: BTreeMap::new(),
+            condition: Condition::LastLogin {
+                max_inactivity_secs: 0,
+            },
+            condition_status: false,
+            release_state: ReleaseState::Armed,
+            grace_period_secs: 0,
+            release_history: Vec::new(),
+            key_epoch: 0,
+            reminder_milestones: DEFAULT_REMINDER_MILESTONES.to_vec(),
+            last_reminder: None,
+            heir_confirmations: BTreeMap::new(),
+            validators: HashSet::new(),
+            validator_confirmations: BTreeMap::new(),
         }
     }
 
     pub fn secrets(&mut self) -> &mut HashSet<SecretListEntry> {
         &mut self.secrets
     }
+
+    /// Withholds the key box entries of the secrets which are not unlocked yet.
+    /// The secrets stay listed, so the heirs know what to expect and when.
+    pub fn lock_secrets(&mut self, locked_secrets: BTreeMap<SecretID, u64>) {
+        for secret_id in locked_secrets.keys() {
+            self.key_box.remove(secret_id);
+            for allocation in self.allocations.values_mut() {
+                allocation.key_box.remove(secret_id);
+            }
+        }
+        self.locked_secrets = locked_secrets;
+    }
 }
 
 impl From<Testament> for TestamentResponse {
@@ -203,8 +820,580 @@ impl From<Testament> for TestamentResponse {
         new_testament.testator = t.testator;
         new_testament.heirs = t.heirs;
         new_testament.key_box = t.key_box;
-        new_testament.condition_arg = t.condition_arg;
+        new_testament.allocations = t.allocations;
+        new_testament.release_stages = t.release_stages;
+        new_testament.condition = t.condition;
         new_testament.condition_status = t.condition_status;
+        new_testament.release_state = t.release_state;
+        new_testament.grace_period_secs = t.grace_period_secs;
+        new_testament.release_history = t.release_history;
+        new_testament.key_epoch = t.key_epoch;
+        new_testament.reminder_milestones = t.reminder_milestones;
+        new_testament.last_reminder = t.last_reminder;
+        new_testament.heir_confirmations = t.heir_confirmations;
+        new_testament.validators = t.validators;
+        new_testament.validator_confirmations = t.validator_confirmations;
         new_testament
     }
-}
\ No newline at end of file
+}
+
+/// Decodes testaments of the baseline layout as well as of the current one.
+/// Fields which were added or removed after the baseline are optional here,
+/// so that the baseline vaults can be moved into the current layout.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct TestamentCompat {
+    id: TestamentID,
+    name: Option<String>,
+    date_created: u64,
+    date_modified: u64,
+    testator: Principal,
+    heirs: HashSet<Principal>,
+    secrets: HashSet<SecretID>,
+    key_box: KeyBox,
+    condition_status: bool,
+    // replaced by condition
+    condition_arg: Option<u64>,
+    condition: Option<Condition>,
+    heir_confirmations: Option<BTreeMap<Principal, u64>>,
+    validators: Option<HashSet<Principal>>,
+    validator_confirmations: Option<BTreeMap<Principal, u64>>,
+    release_state: Option<ReleaseState>,
+    grace_period_secs: Option<u64>,
+    release_history: Option<Vec<ReleaseIncident>>,
+    key_epoch: Option<u64>,
+    reminder_milestones: Option<Vec<u8>>,
+    last_reminder: Option<ReminderMark>,
+    allocations: Option<BTreeMap<Principal, HeirAllocation>>,
+    release_stages: Option<Vec<ReleaseStage>>,
+}
+
+impl From<TestamentCompat> for Testament {
+    fn from(t: TestamentCompat) -> Self {
+        let condition = t.condition.unwrap_or(Condition::LastLogin {
+            max_inactivity_secs: t.condition_arg.unwrap_or_default(),
+        });
+        let release_state = t.release_state.unwrap_or(if t.condition_status {
+            ReleaseState::Released {
+                released_at: t.date_modified,
+            }
+        } else {
+            ReleaseState::Armed
+        });
+        Self {
+            id: t.id,
+            name: t.name,
+            date_created: t.date_created,
+            date_modified: t.date_modified,
+            testator: t.testator,
+            heirs: t.heirs,
+            secrets: t.secrets,
+            key_box: t.key_box,
+            allocations: t.allocations.unwrap_or_default(),
+            release_stages: t.release_stages.unwrap_or_default(),
+            condition_status: t.condition_status,
+            condition,
+            release_state,
+            // testaments without grace period are released right away, as before
+            grace_period_secs: t.grace_period_secs.unwrap_or_default(),
+            release_history: t.release_history.unwrap_or_default(),
+            key_epoch: t.key_epoch.unwrap_or_default(),
+            reminder_milestones: t
+                .reminder_milestones
+                .unwrap_or_else(|| DEFAULT_REMINDER_MILESTONES.to_vec()),
+            last_reminder: t.last_reminder,
+            heir_confirmations: t.heir_confirmations.unwrap_or_default(),
+            validators: t.validators.unwrap_or_default(),
+            validator_confirmations: t.validator_confirmations.unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::{Decode, Encode};
+
+    use super::*;
+    use crate::smart_vaults::check_in::CheckInSchedule;
+
+    /// Testament layout of the baseline
+    #[derive(CandidType)]
+    struct TestamentV4 {
+        id: TestamentID,
+        name: Option<String>,
+        date_created: u64,
+        date_modified: u64,
+        testator: Principal,
+        heirs: HashSet<Principal>,
+        secrets: HashSet<SecretID>,
+        key_box: KeyBox,
+        condition_status: bool,
+        condition_arg: u64,
+    }
+
+    #[test]
+    fn utest_testament_compat() {
+        let v4 = TestamentV4 {
+            id: "t1".to_string(),
+            name: Some("name".to_string()),
+            date_created: 1,
+            date_modified: 2,
+            testator: Principal::anonymous(),
+            heirs: HashSet::new(),
+            secrets: HashSet::new(),
+            key_box: BTreeMap::new(),
+            condition_status: true,
+            condition_arg: 100,
+        };
+        let bytes = Encode!(&v4).unwrap();
+        let testament = Testament::from(Decode!(&bytes, TestamentCompat).unwrap());
+        assert_eq!(testament.id(), "t1");
+        assert!(*testament.condition_status());
+        assert_eq!(
+            testament.release_state(),
+            &ReleaseState::Released { released_at: 2 }
+        );
+        assert_eq!(
+            testament.condition(),
+            &Condition::LastLogin {
+                max_inactivity_secs: 100
+            }
+        );
+
+        // the current layout decodes as well
+        let mut current = Testament::new("t2".to_string());
+        current.condition = Condition::FixedDate { release_date: 5 };
+        let bytes = Encode!(&current).unwrap();
+        let testament = Testament::from(Decode!(&bytes, TestamentCompat).unwrap());
+        assert_eq!(testament.condition(), current.condition());
+    }
+
+    #[test]
+    fn utest_heir_quorum() {
+        let heir_1 = Principal::from_slice(&[2; 29]);
+        let heir_2 = Principal::from_slice(&[3; 29]);
+        let mut testament = Testament::new("t".to_string());
+        testament.add_heir(heir_1);
+        testament.add_heir(heir_2);
+        testament.condition = Condition::HeirQuorum { quorum: 2 };
+
+        // only heirs can confirm
+        assert_eq!(
+            testament.confirm_testator_passed(Principal::anonymous()),
+            Err(SmartVaultErr::TestamentDoesNotExist("t".to_string()))
+        );
+
+        // confirming twice does not count twice
+        testament.confirm_testator_passed(heir_1).unwrap();
+        testament.confirm_testator_passed(heir_1).unwrap();
+        assert!(!testament.evaluate_condition(0, None, None));
+
+        // a revoked confirmation does not count
+        testament.confirm_testator_passed(heir_2).unwrap();
+        testament.revoke_testator_passed(&heir_1).unwrap();
+        assert!(!testament.evaluate_condition(0, None, None));
+
+        testament.confirm_testator_passed(heir_1).unwrap();
+        assert!(testament.evaluate_condition(0, None, None));
+        assert!(*testament.condition_status());
+
+        // once released, confirmations cannot be revoked anymore
+        assert_eq!(
+            testament.revoke_testator_passed(&heir_1),
+            Err(SmartVaultErr::InvalidTestamentCondition)
+        );
+
+        // confirmations of removed heirs are dropped
+        let old = testament.clone();
+        testament.remove_heir(&heir_2);
+        testament.set_confirmations(&old);
+        assert_eq!(testament.heir_confirmations().len(), 1);
+    }
+
+    #[test]
+    fn utest_validators() {
+        let heir = Principal::from_slice(&[2; 29]);
+        let validator = Principal::from_slice(&[3; 29]);
+        let mut testament = Testament::new("t".to_string());
+        testament.add_heir(heir);
+        testament.validators.insert(validator);
+        testament.condition = Condition::And(vec![
+            Condition::HeirQuorum { quorum: 1 },
+            Condition::ValidatorQuorum { quorum: 1 },
+        ]);
+        assert!(testament.problems().is_empty());
+
+        // the heir alone cannot release the testament
+        testament.confirm_testator_passed(heir).unwrap();
+        assert!(!testament.evaluate_condition(0, None, None));
+        assert!(testament.validator_confirmations().is_empty());
+
+        testament.confirm_testator_passed(validator).unwrap();
+        assert!(testament.evaluate_condition(0, None, None));
+
+        // validators need to be distinct from the heirs
+        testament.validators.insert(heir);
+        assert_eq!(
+            testament.problems(),
+            vec![TestamentProblem::ValidatorIsHeir(heir.to_string())]
+        );
+    }
+
+    #[test]
+    fn utest_grace_period() {
+        const SECOND: u64 = 1000000000;
+        let mut testament = Testament::new("t".to_string());
+        testament.condition = Condition::LastLogin {
+            max_inactivity_secs: 10,
+        };
+        testament.grace_period_secs = 5;
+
+        // condition not met
+        assert!(!testament.evaluate_condition(5 * SECOND, Some(0), None));
+        assert_eq!(testament.release_state(), &ReleaseState::Armed);
+        assert_eq!(
+            testament.cancel_release(5 * SECOND),
+            Err(SmartVaultErr::ReleaseNotPending("t".to_string()))
+        );
+
+        // condition met, the grace period starts
+        assert!(!testament.evaluate_condition(20 * SECOND, Some(0), None));
+        assert_eq!(
+            testament.release_state(),
+            &ReleaseState::GracePeriod {
+                triggered_at: 20 * SECOND,
+                release_at: 25 * SECOND
+            }
+        );
+        assert!(!*testament.condition_status());
+
+        // the testator objects
+        testament.cancel_release(22 * SECOND).unwrap();
+        assert!(!testament.evaluate_condition(26 * SECOND, Some(0), None));
+        assert_eq!(
+            testament.release_state(),
+            &ReleaseState::Cancelled {
+                cancelled_at: 22 * SECOND
+            }
+        );
+        assert_eq!(
+            testament.next_evaluation(26 * SECOND, Some(0), None),
+            Some(27 * SECOND)
+        );
+
+        // after logging in, the testament is armed again and released after the grace period
+        assert!(!testament.evaluate_condition(31 * SECOND, Some(30 * SECOND), None));
+        assert_eq!(testament.release_state(), &ReleaseState::Armed);
+        assert!(!testament.evaluate_condition(50 * SECOND, Some(30 * SECOND), None));
+        assert!(testament.evaluate_condition(55 * SECOND, Some(30 * SECOND), None));
+        assert!(*testament.condition_status());
+        assert_eq!(
+            testament.cancel_release(56 * SECOND),
+            Err(SmartVaultErr::ReleaseNotPending("t".to_string()))
+        );
+    }
+
+    #[test]
+    fn utest_veto_expiry() {
+        const SECOND: u64 = 1000000000;
+        let mut testament = Testament::new("t".to_string());
+        testament.condition = Condition::FixedDate {
+            release_date: 10 * SECOND,
+        };
+        testament.grace_period_secs = 5;
+
+        assert!(!testament.evaluate_condition(10 * SECOND, None, None));
+        testament.cancel_release(12 * SECOND).unwrap();
+
+        // the fixed date stays passed, so the veto holds for one grace period
+        assert!(!testament.evaluate_condition(13 * SECOND, None, None));
+        assert_eq!(
+            testament.release_state(),
+            &ReleaseState::Cancelled {
+                cancelled_at: 12 * SECOND
+            }
+        );
+        assert_eq!(
+            testament.next_evaluation(13 * SECOND, None, None),
+            Some(17 * SECOND)
+        );
+
+        // then it is armed and triggered again
+        assert!(!testament.evaluate_condition(17 * SECOND, None, None));
+        assert_eq!(
+            testament.release_state(),
+            &ReleaseState::GracePeriod {
+                triggered_at: 17 * SECOND,
+                release_at: 22 * SECOND
+            }
+        );
+
+        // a veto of a last login condition counts from the veto, as it updates the login date
+        testament.condition = Condition::LastLogin {
+            max_inactivity_secs: 10,
+        };
+        testament.cancel_release(18 * SECOND).unwrap();
+        assert!(!testament.evaluate_condition(18 * SECOND, Some(18 * SECOND), None));
+        assert_eq!(testament.release_state(), &ReleaseState::Armed);
+        assert_eq!(
+            testament.next_evaluation(18 * SECOND, Some(18 * SECOND), None),
+            Some(28 * SECOND + 1)
+        );
+    }
+
+    #[test]
+    fn utest_rearm() {
+        let heir = Principal::from_slice(&[2; 29]);
+        let mut testament = Testament::new("t".to_string());
+        testament.add_heir(heir);
+        testament.condition = Condition::HeirQuorum { quorum: 1 };
+
+        // only released testaments can be rearmed
+        assert_eq!(
+            testament.rearm(BTreeMap::new(), BTreeMap::new(), None, 1),
+            Err(SmartVaultErr::TestamentNotReleased("t".to_string()))
+        );
+
+        testament.confirm_testator_passed(heir).unwrap();
+        assert!(testament.evaluate_condition(1, None, None));
+
+        testament
+            .rearm(
+                BTreeMap::new(),
+                BTreeMap::new(),
+                Some("I am still alive".to_string()),
+                2,
+            )
+            .unwrap();
+        assert!(!*testament.condition_status());
+        assert_eq!(testament.release_state(), &ReleaseState::Armed);
+        assert_eq!(*testament.key_epoch(), 1);
+        assert!(testament.heir_confirmations().is_empty());
+        assert_eq!(
+            testament.release_history(),
+            &vec![ReleaseIncident {
+                released_at: 1,
+                rearmed_at: 2,
+                reason: Some("I am still alive".to_string())
+            }]
+        );
+    }
+
+    #[test]
+    fn utest_next_evaluation() {
+        let second: u64 = 1000000000;
+        let mut testament = Testament::new("t".to_string());
+        testament.condition = Condition::FixedDate {
+            release_date: 10 * second,
+        };
+        testament.grace_period_secs = 5;
+        assert_eq!(testament.next_evaluation(0, None, None), Some(10 * second));
+
+        testament.evaluate_condition(10 * second, None, None);
+        assert_eq!(
+            testament.next_evaluation(10 * second, None, None),
+            Some(15 * second)
+        );
+
+        testament.evaluate_condition(15 * second, None, None);
+        assert_eq!(testament.next_evaluation(15 * second, None, None), None);
+    }
+
+    #[test]
+    fn utest_missed_check_ins() {
+        let second: u64 = 1000000000;
+        let mut testament = Testament::new("t".to_string());
+        testament.condition = Condition::MissedCheckIns { count: 2 };
+        testament.grace_period_secs = 0;
+        let mut check_in = CheckIn::new(CheckInSchedule::Recurring { interval_secs: 10 }, 0);
+        assert_eq!(
+            testament.next_evaluation(0, None, Some(&check_in)),
+            Some(20 * second + 1)
+        );
+
+        // a check-in moves the deadline
+        check_in.check_in(15 * second);
+        assert!(!testament.evaluate_condition(21 * second, None, Some(&check_in)));
+        assert_eq!(
+            testament.next_evaluation(21 * second, None, Some(&check_in)),
+            Some(35 * second + 1)
+        );
+        assert!(testament.evaluate_condition(35 * second + 1, None, Some(&check_in)));
+    }
+
+    #[test]
+    fn utest_reminders() {
+        let second: u64 = 1000000000;
+        let mut testament = Testament::new("t".to_string());
+        testament.condition = Condition::LastLogin {
+            max_inactivity_secs: 100,
+        };
+        let last_login = Some(1000 * second);
+
+        assert_eq!(testament.due_reminder(1049 * second, last_login), None);
+        assert_eq!(
+            testament.next_evaluation(1049 * second, last_login, None),
+            Some(1050 * second)
+        );
+
+        // the 50% milestone is reminded of once
+        assert_eq!(testament.due_reminder(1050 * second, last_login), Some(50));
+        assert_eq!(testament.due_reminder(1051 * second, last_login), None);
+        assert_eq!(
+            testament.next_evaluation(1051 * second, last_login, None),
+            Some(1080 * second)
+        );
+
+        // a missed milestone is skipped in favour of the highest one reached
+        assert_eq!(testament.due_reminder(1096 * second, last_login), Some(95));
+        assert_eq!(
+            testament.next_evaluation(1096 * second, last_login, None),
+            Some(1100 * second + 1)
+        );
+
+        // a new login starts over
+        assert_eq!(
+            testament.due_reminder(1200 * second, Some(1150 * second)),
+            Some(50)
+        );
+
+        // no reminders without inactivity condition or milestones
+        testament.reminder_milestones = Vec::new();
+        assert_eq!(
+            testament.due_reminder(1300 * second, Some(1150 * second)),
+            None
+        );
+        testament.reminder_milestones = vec![100, 0, 50];
+        testament.condition = Condition::And(vec![]);
+        assert_eq!(
+            testament.problems(),
+            vec![
+                TestamentProblem::InvalidCondition(
+                    "And/Or needs at least one condition".to_string()
+                ),
+                TestamentProblem::InvalidReminderMilestone(0),
+                TestamentProblem::InvalidReminderMilestone(100),
+            ]
+        );
+    }
+
+    #[test]
+    fn utest_allocations() {
+        let bob = Principal::from_slice(&[2; 29]);
+        let carol = Principal::from_slice(&[3; 29]);
+        let dave = Principal::from_slice(&[4; 29]);
+        let mut testament = Testament::new("t".to_string());
+        for heir in [bob, carol, dave] {
+            testament.add_heir(heir);
+        }
+        for secret_id in ["house", "seed"] {
+            testament.add_secret(secret_id.to_string());
+            testament
+                .key_box_mut()
+                .insert(secret_id.to_string(), Default::default());
+        }
+        let allocation = |secret_id: &str| HeirAllocation {
+            secrets: HashSet::from([secret_id.to_string()]),
+            key_box: BTreeMap::from([(secret_id.to_string(), Default::default())]),
+        };
+        testament.allocations_mut().insert(bob, allocation("house"));
+        testament
+            .allocations_mut()
+            .insert(carol, allocation("seed"));
+
+        // heirs with an allocation only see their share
+        let share = testament.share_of(&bob);
+        assert_eq!(share.secrets(), &HashSet::from(["house".to_string()]));
+        assert_eq!(share.key_box().keys().collect::<Vec<_>>(), vec!["house"]);
+        assert_eq!(share.allocations().keys().collect::<Vec<_>>(), vec![&bob]);
+
+        // once there are allocations, heirs without one get nothing
+        let share = testament.share_of(&dave);
+        assert!(share.secrets().is_empty());
+        assert!(share.key_box().is_empty());
+        assert!(share.allocations().is_empty());
+
+        // an allocation emptied by the removal of its secret does not grant everything
+        testament.remove_secret(&"seed".to_string());
+        assert!(testament.share_of(&carol).secrets().is_empty());
+        assert!(testament.allocations()[&carol].key_box.is_empty());
+
+        // rearming replaces the key boxes of the allocations
+        testament.condition = Condition::HeirQuorum { quorum: 1 };
+        testament.confirm_testator_passed(bob).unwrap();
+        assert!(testament.evaluate_condition(1, None, None));
+        testament
+            .rearm(
+                BTreeMap::new(),
+                BTreeMap::from([(carol, BTreeMap::new())]),
+                None,
+                2,
+            )
+            .unwrap();
+        assert!(testament.allocations()[&bob].key_box.is_empty());
+        assert_eq!(testament.allocations()[&bob].secrets.len(), 1);
+    }
+
+    #[test]
+    fn utest_release_stages() {
+        const SECOND: u64 = 1000000000;
+        let bob = Principal::from_slice(&[2; 29]);
+        let carol = Principal::from_slice(&[3; 29]);
+        let mut testament = Testament::new("t".to_string());
+        testament.add_heir(bob);
+        testament.add_heir(carol);
+        for secret_id in ["letter", "logins", "seed"] {
+            testament.add_secret(secret_id.to_string());
+        }
+        testament.release_stages_mut().push(ReleaseStage {
+            name: Some("Financial logins".to_string()),
+            secrets: HashSet::from(["logins".to_string()]),
+            unlock: StageUnlock::AfterRelease { delay_secs: 30 },
+        });
+        testament.release_stages_mut().push(ReleaseStage {
+            name: Some("18th birthday".to_string()),
+            secrets: HashSet::from(["seed".to_string()]),
+            unlock: StageUnlock::AtDate { date: 100 * SECOND },
+        });
+
+        // nothing is unlocked before the release
+        assert_eq!(testament.unlock_date(&"letter".to_string()), None);
+        assert!(testament.locked_secrets(0).is_empty());
+        assert!(!testament.is_unlocked(&"letter".to_string(), 0));
+
+        testament.condition = Condition::HeirQuorum { quorum: 1 };
+        testament.confirm_testator_passed(bob).unwrap();
+        assert!(testament.evaluate_condition(10 * SECOND, None, None));
+
+        // unstaged secrets are unlocked with the release, the others on their schedule
+        assert!(testament.is_unlocked(&"letter".to_string(), 10 * SECOND));
+        assert_eq!(
+            testament.locked_secrets(10 * SECOND),
+            BTreeMap::from([
+                ("logins".to_string(), 40 * SECOND),
+                ("seed".to_string(), 100 * SECOND)
+            ])
+        );
+        assert_eq!(
+            testament.locked_secrets(40 * SECOND),
+            BTreeMap::from([("seed".to_string(), 100 * SECOND)])
+        );
+        assert!(testament.locked_secrets(100 * SECOND).is_empty());
+
+        // a date before the release unlocks with the release
+        assert_eq!(StageUnlock::AtDate { date: 0 }.unlock_date(10), 10);
+
+        // heirs only see the stages of their share
+        testament.allocations_mut().insert(
+            carol,
+            HeirAllocation {
+                secrets: HashSet::from(["letter".to_string()]),
+                key_box: BTreeMap::new(),
+            },
+        );
+        let share = testament.share_of(&carol);
+        assert!(share
+            .release_stages()
+            .iter()
+            .all(|stage| stage.secrets.is_empty()));
+    }
+}
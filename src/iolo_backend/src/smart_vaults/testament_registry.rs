@@ -1,58 +1,118 @@
-use std::collections::{BTreeMap, HashSet};
+use std::borrow::Cow;
+use std::collections::HashSet;
 
-use candid::{CandidType, Principal};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::{StableBTreeMap, Storable};
 use serde::Deserialize;
 use crate::common::error::SmartVaultErr;
+use crate::common::memory::{
+    get_stable_btree_memory_for_heir_to_testaments,
+    get_stable_btree_memory_for_testament_to_testator,
+    get_stable_btree_memory_for_validator_to_testaments, verify_size, Memory, StorablePrincipal,
+    MAX_PRINCIPAL_SIZE, MAX_TESTAMENT_ID_SET_SIZE, MAX_TESTAMENT_ID_SIZE,
+};
 use crate::smart_vaults::testament::{Testament, TestamentID};
 
-#[derive(Debug, CandidType, Deserialize)]
+#[derive(Debug, Default, CandidType, Deserialize)]
+pub struct TestamentIDs(HashSet<TestamentID>);
+
+impl Storable for TestamentIDs {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
 pub struct TestamentRegistry {
-    heir_to_testaments: BTreeMap<Principal, HashSet<TestamentID>>,
-    testament_to_testator: BTreeMap<TestamentID, Principal>,
+    heir_to_testaments: StableBTreeMap<Memory, StorablePrincipal, TestamentIDs>,
+    testament_to_testator: StableBTreeMap<Memory, TestamentID, StorablePrincipal>,
+    validator_to_testaments: StableBTreeMap<Memory, StorablePrincipal, TestamentIDs>,
+}
+
+impl Default for TestamentRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TestamentRegistry {
     pub fn new() -> Self {
         Self {
-            heir_to_testaments: BTreeMap::new(),
-            testament_to_testator: BTreeMap::new(),
+            heir_to_testaments: StableBTreeMap::init(
+                get_stable_btree_memory_for_heir_to_testaments(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_TESTAMENT_ID_SET_SIZE,
+            ),
+            testament_to_testator: StableBTreeMap::init(
+                get_stable_btree_memory_for_testament_to_testator(),
+                MAX_TESTAMENT_ID_SIZE,
+                MAX_PRINCIPAL_SIZE,
+            ),
+            validator_to_testaments: StableBTreeMap::init(
+                get_stable_btree_memory_for_validator_to_testaments(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_TESTAMENT_ID_SET_SIZE,
+            ),
         }
     }
 
     pub fn remove_testament_from_registry(&mut self, testament: &Testament) {
         for heir in testament.heirs() {
-            if let Some(testament_ids) = self.heir_to_testaments.get_mut(heir) {
-                testament_ids.remove(testament.id());
-                if testament_ids.is_empty() {
-                    self.heir_to_testaments.remove(heir);
-                }
-            }
+            remove_entry(&mut self.heir_to_testaments, heir, testament.id());
+        }
+        for validator in testament.validators() {
+            remove_entry(&mut self.validator_to_testaments, validator, testament.id());
         }
         self.testament_to_testator.remove(testament.id());
     }
 
     pub fn add_testament_to_registry(&mut self, testament: &Testament) {
         for heir in testament.heirs() {
-            self.heir_to_testaments
-                .entry(heir.clone())
-                .or_insert_with(HashSet::new)
-                .insert(testament.id().clone());
+            add_entry(&mut self.heir_to_testaments, heir, testament.id());
+        }
+        for validator in testament.validators() {
+            add_entry(&mut self.validator_to_testaments, validator, testament.id());
         }
         self.testament_to_testator
-            .insert(testament.id().clone(), testament.testator().clone());
+            .insert(testament.id().clone(), StorablePrincipal(*testament.testator()))
+            .expect("Failed to insert into testament_to_testator");
+    }
+
+    /// Testament ids are chosen by the testators, but the registry, the condition schedule and
+    /// the heir endpoints identify a testament by its id alone. So an id can only be used
+    /// by one testator at a time.
+    pub fn verify_testament_id_available(
+        &self,
+        testament: &Testament,
+    ) -> Result<(), SmartVaultErr> {
+        match self.testament_to_testator.get(testament.id()) {
+            Some(testator) if testator.0 != *testament.testator() => Err(
+                SmartVaultErr::TestamentAlreadyExists(testament.id().to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Verifies that the testament can be added to the registry: its id needs to be available
+    /// and to fit into the registry, as well as into the testaments of each of its heirs and validators.
+    pub fn verify_registrable(&self, testament: &Testament) -> Result<(), SmartVaultErr> {
+        verify_size(testament.id(), MAX_TESTAMENT_ID_SIZE, "testament id")?;
+        self.verify_testament_id_available(testament)?;
+        for heir in testament.heirs() {
+            verify_entry_size(&self.heir_to_testaments, heir, testament.id())?;
+        }
+        for validator in testament.validators() {
+            verify_entry_size(&self.validator_to_testaments, validator, testament.id())?;
+        }
+        Ok(())
     }
 
     pub fn update_testament_in_registry(&mut self, testament_new: &Testament, testament_old: &Testament) {
         // Delete all existing entries for old testament
-        for heir in testament_old.heirs() {
-            if let Some(testaments) = self.heir_to_testaments.get_mut(heir) {
-                testaments.remove(testament_old.id());
-                if testaments.is_empty() {
-                    self.heir_to_testaments.remove(heir);
-                }
-            }
-        }
-        self.testament_to_testator.remove(testament_old.id());
+        self.remove_testament_from_registry(testament_old);
 
         // Add new testament
         self.add_testament_to_registry(testament_new);
@@ -64,11 +124,11 @@ impl TestamentRegistry {
         testament_id: TestamentID
     ) -> Result<(TestamentID, Principal), SmartVaultErr> {
         // Check if the heir exists in the map and contains the testament_id
-        if let Some(testament_ids) = self.heir_to_testaments.get(&heir) {
-            return if testament_ids.contains(&testament_id) {
+        if let Some(testament_ids) = self.heir_to_testaments.get(&StorablePrincipal(heir)) {
+            return if testament_ids.0.contains(&testament_id) {
                 // If testament_id is found for the heir, retrieve the associated testator
                 if let Some(testator) = self.testament_to_testator.get(&testament_id) {
-                    Ok((testament_id, testator.clone()))
+                    Ok((testament_id, testator.0))
                 } else {
                     // Return an error if testament_id doesn't have a corresponding testator
                     Err(SmartVaultErr::TestamentDoesNotExist(testament_id)) // Replace with appropriate error variant
@@ -89,18 +149,237 @@ impl TestamentRegistry {
         let mut result = Vec::new();
 
         // Check if the heir exists
-        if let Some(testament_ids) = self.heir_to_testaments.get(&heir) {
+        if let Some(testament_ids) = self.heir_to_testaments.get(&StorablePrincipal(heir)) {
             // For each testament_id, get the corresponding testator
-            for testament_id in testament_ids {
-                if let Some(testator) = self.testament_to_testator.get(testament_id) {
-                    result.push((testament_id.clone(), testator.clone()));
+            for testament_id in testament_ids.0 {
+                if let Some(testator) = self.testament_to_testator.get(&testament_id) {
+                    result.push((testament_id, testator.0));
                 }
             }
         }
         result
     }
 
+    pub fn get_testament_id_as_validator(
+        &self,
+        validator: Principal,
+        testament_id: TestamentID,
+    ) -> Result<(TestamentID, Principal), SmartVaultErr> {
+        let is_validator = self
+            .validator_to_testaments
+            .get(&StorablePrincipal(validator))
+            .map_or(false, |testament_ids| testament_ids.0.contains(&testament_id));
+        match self.testament_to_testator.get(&testament_id) {
+            Some(testator) if is_validator => Ok((testament_id, testator.0)),
+            _ => Err(SmartVaultErr::TestamentDoesNotExist(testament_id)),
+        }
+    }
+
+    pub fn get_testament_ids_as_validator(&self, validator: Principal) -> Vec<(TestamentID, Principal)> {
+        self.validator_to_testaments
+            .get(&StorablePrincipal(validator))
+            .map(|testament_ids| {
+                testament_ids
+                    .0
+                    .into_iter()
+                    .filter_map(|testament_id| {
+                        let testator = self.testament_to_testator.get(&testament_id)?;
+                        Some((testament_id, testator.0))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
     pub fn get_testator_of_testament(&self, testament_id: TestamentID) -> Option<Principal> {
-        self.testament_to_testator.get(&testament_id).copied()
+        self.testament_to_testator.get(&testament_id).map(|p| p.0)
+    }
+
+    /// Returns at most limit registered testaments with their testator, in the order
+    /// of their ids, starting after the given id.
+    pub fn get_testament_ids_page(
+        &self,
+        after: Option<&TestamentID>,
+        limit: usize,
+    ) -> Vec<(TestamentID, Principal)> {
+        // the range starts at the cursor itself, the entries before it are not read
+        let start = after.map(|after| after.as_bytes().to_vec());
+        self.testament_to_testator
+            .range(Vec::new(), start)
+            .skip_while(|(testament_id, _)| after == Some(testament_id))
+            .take(limit)
+            .map(|(testament_id, testator)| (testament_id, testator.0))
+            .collect()
+    }
+
+    pub fn get_heir_entries_page(
+        &self,
+        after: Option<&(Principal, TestamentID)>,
+        limit: usize,
+    ) -> Vec<(Principal, TestamentID)> {
+        get_entries_page(&self.heir_to_testaments, after, limit)
+    }
+
+    pub fn get_validator_entries_page(
+        &self,
+        after: Option<&(Principal, TestamentID)>,
+        limit: usize,
+    ) -> Vec<(Principal, TestamentID)> {
+        get_entries_page(&self.validator_to_testaments, after, limit)
+    }
+
+    /// Whether the testator, all heirs and all validators of the testament are registered.
+    pub fn is_registered(&self, testament: &Testament) -> bool {
+        let contains = |index: &StableBTreeMap<Memory, StorablePrincipal, TestamentIDs>,
+                        principal: &Principal| {
+            index
+                .get(&StorablePrincipal(*principal))
+                .map_or(false, |testament_ids| testament_ids.0.contains(testament.id()))
+        };
+        self.get_testator_of_testament(testament.id().clone()) == Some(*testament.testator())
+            && testament
+                .heirs()
+                .iter()
+                .all(|heir| contains(&self.heir_to_testaments, heir))
+            && testament
+                .validators()
+                .iter()
+                .all(|validator| contains(&self.validator_to_testaments, validator))
+    }
+
+    pub fn remove_testator_entry(&mut self, testament_id: &TestamentID) {
+        self.testament_to_testator.remove(testament_id);
+    }
+
+    pub fn remove_heir_entry(&mut self, heir: &Principal, testament_id: &TestamentID) {
+        remove_entry(&mut self.heir_to_testaments, heir, testament_id);
+    }
+
+    pub fn remove_validator_entry(&mut self, validator: &Principal, testament_id: &TestamentID) {
+        remove_entry(&mut self.validator_to_testaments, validator, testament_id);
     }
  }
+
+/// Returns at most limit entries in the order of the index, starting after the given entry.
+/// The index is ordered by the bytes of the principals, not by the order of Principal.
+fn get_entries_page(
+    index: &StableBTreeMap<Memory, StorablePrincipal, TestamentIDs>,
+    after: Option<&(Principal, TestamentID)>,
+    limit: usize,
+) -> Vec<(Principal, TestamentID)> {
+    let is_after = |principal: &Principal, testament_id: &TestamentID| {
+        after.map_or(true, |(after_principal, after_testament_id)| {
+            (principal.as_slice(), testament_id) > (after_principal.as_slice(), after_testament_id)
+        })
+    };
+    // the range starts at the principal of the cursor, the principals before it are not read
+    let start = after.map(|(after_principal, _)| after_principal.as_slice().to_vec());
+    index
+        .range(Vec::new(), start)
+        .flat_map(|(principal, testament_ids)| {
+            let mut testament_ids: Vec<TestamentID> = testament_ids.0.into_iter().collect();
+            testament_ids.sort();
+            testament_ids
+                .into_iter()
+                .map(move |testament_id| (principal.0, testament_id))
+        })
+        .filter(|(principal, testament_id)| is_after(principal, testament_id))
+        .take(limit)
+        .collect()
+}
+
+fn add_entry(
+    index: &mut StableBTreeMap<Memory, StorablePrincipal, TestamentIDs>,
+    principal: &Principal,
+    testament_id: &TestamentID,
+) {
+    let key = StorablePrincipal(*principal);
+    let mut testament_ids = index.get(&key).unwrap_or_default();
+    testament_ids.0.insert(testament_id.clone());
+    index
+        .insert(key, testament_ids)
+        .expect("Failed to insert into testament index");
+}
+
+fn verify_entry_size(
+    index: &StableBTreeMap<Memory, StorablePrincipal, TestamentIDs>,
+    principal: &Principal,
+    testament_id: &TestamentID,
+) -> Result<(), SmartVaultErr> {
+    let mut testament_ids = index
+        .get(&StorablePrincipal(*principal))
+        .unwrap_or_default();
+    testament_ids.0.insert(testament_id.clone());
+    verify_size(
+        &testament_ids,
+        MAX_TESTAMENT_ID_SET_SIZE,
+        &format!("testaments of {}", principal),
+    )
+}
+
+fn remove_entry(
+    index: &mut StableBTreeMap<Memory, StorablePrincipal, TestamentIDs>,
+    principal: &Principal,
+    testament_id: &TestamentID,
+) {
+    let key = StorablePrincipal(*principal);
+    if let Some(mut testament_ids) = index.get(&key) {
+        testament_ids.0.remove(testament_id);
+        if testament_ids.0.is_empty() {
+            index.remove(&key);
+        } else {
+            index
+                .insert(key, testament_ids)
+                .expect("Failed to insert into testament index");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utest_testament_id_size() {
+        let testament_registry = TestamentRegistry::new();
+        let testament = Testament::new("t".repeat(MAX_TESTAMENT_ID_SIZE as usize + 1));
+        assert_eq!(
+            testament_registry.verify_registrable(&testament),
+            Err(SmartVaultErr::ValueTooLarge("testament id".to_string()))
+        );
+        let testament = Testament::new("t".repeat(MAX_TESTAMENT_ID_SIZE as usize));
+        assert!(testament_registry.verify_registrable(&testament).is_ok());
+    }
+
+    #[test]
+    fn utest_testament_id_taken_by_other_testator() {
+        let mut testament_registry = TestamentRegistry::new();
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+
+        let mut testament = Testament::new("t".to_string());
+        testament.set_testator(alice);
+        assert!(testament_registry
+            .verify_testament_id_available(&testament)
+            .is_ok());
+        testament_registry.add_testament_to_registry(&testament);
+
+        // the testator can keep using the id, e.g. for updates
+        assert!(testament_registry
+            .verify_testament_id_available(&testament)
+            .is_ok());
+
+        let mut other = Testament::new("t".to_string());
+        other.set_testator(bob);
+        assert_eq!(
+            testament_registry.verify_testament_id_available(&other),
+            Err(SmartVaultErr::TestamentAlreadyExists("t".to_string()))
+        );
+
+        // once removed, the id is free again
+        testament_registry.remove_testament_from_registry(&testament);
+        assert!(testament_registry
+            .verify_testament_id_available(&other)
+            .is_ok());
+    }
+}
@@ -0,0 +1,348 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+use crate::common::error::SmartVaultErr;
+use crate::utils::time;
+
+use super::master_vault::MasterVault;
+use super::secret::{Secret, SecretID, SecretSymmetricCryptoMaterial, SecretVersion};
+use super::smart_vault::{get_vault_id_for, resolve_caller, MASTERVAULT};
+use super::testament::{StageUnlock, Testament, TestamentCompat, TestamentID};
+
+// How long deleted items are kept, if the user does not say otherwise
+pub const DEFAULT_TRASH_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+// How often the items whose retention period is over are purged
+const PURGE_INTERVAL_SECS: u64 = 60 * 60;
+// Upper bound of the vaults purged per tick
+const MAX_PURGES_PER_TICK: usize = 50;
+
+/// Secrets and testaments have separate id spaces, so the kind is part of the id.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrashItemID {
+    Secret(SecretID),
+    Testament(TestamentID),
+}
+
+impl TrashItemID {
+    pub fn id(&self) -> &String {
+        match self {
+            TrashItemID::Secret(id) => id,
+            TrashItemID::Testament(id) => id,
+        }
+    }
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub enum TrashedItem {
+    /// The secret together with everything needed to restore it
+    Secret {
+        secret: Secret,
+        symmetric_crypto_material: Option<SecretSymmetricCryptoMaterial>,
+        history: Vec<SecretVersion>,
+        /// The testaments which contained the secret, with their key box entry for it
+        testaments: BTreeMap<TestamentID, Option<SecretSymmetricCryptoMaterial>>,
+        /// The heirs the secret was allocated to, with their key box entry for it, by testament
+        allocations:
+            BTreeMap<TestamentID, BTreeMap<Principal, Option<SecretSymmetricCryptoMaterial>>>,
+        /// The schedule of the release stage the secret was part of, by testament
+        stages: BTreeMap<TestamentID, StageUnlock>,
+    },
+    Testament(Testament),
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct TrashEntry {
+    pub date_trashed: u64,
+    pub item: TrashedItem,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TrashListEntry {
+    pub id: TrashItemID,
+    pub name: Option<String>,
+    pub date_trashed: u64,
+    /// The entry is deleted for good after this date
+    pub purge_at: u64,
+}
+
+/// The deleted items of a user vault, kept until their retention period is over.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct Trash {
+    retention_secs: u64,
+    entries: BTreeMap<TrashItemID, TrashEntry>,
+}
+
+impl Default for Trash {
+    fn default() -> Self {
+        Self {
+            retention_secs: DEFAULT_TRASH_RETENTION_SECS,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl Trash {
+    pub fn retention_secs(&self) -> u64 {
+        self.retention_secs
+    }
+
+    /// Applies to the items already in the trash as well.
+    pub fn set_retention_secs(&mut self, retention_secs: u64) {
+        self.retention_secs = retention_secs;
+    }
+
+    /// An older entry with the same id is replaced.
+    pub fn put(&mut self, id: TrashItemID, item: TrashedItem, now: u64) {
+        self.entries.insert(
+            id,
+            TrashEntry {
+                date_trashed: now,
+                item,
+            },
+        );
+    }
+
+    pub fn get(&self, id: &TrashItemID) -> Result<&TrashEntry, SmartVaultErr> {
+        self.entries
+            .get(id)
+            .ok_or_else(|| SmartVaultErr::TrashItemDoesNotExist(id.id().clone()))
+    }
+
+    pub fn take(&mut self, id: &TrashItemID) -> Result<TrashEntry, SmartVaultErr> {
+        self.entries
+            .remove(id)
+            .ok_or_else(|| SmartVaultErr::TrashItemDoesNotExist(id.id().clone()))
+    }
+
+    pub fn list(&self) -> Vec<TrashListEntry> {
+        self.entries
+            .iter()
+            .map(|(id, entry)| TrashListEntry {
+                id: id.clone(),
+                name: match &entry.item {
+                    TrashedItem::Secret { secret, .. } => secret.name(),
+                    TrashedItem::Testament(testament) => testament.name().clone(),
+                },
+                date_trashed: entry.date_trashed,
+                purge_at: self.purge_at(entry),
+            })
+            .collect()
+    }
+
+    /// The trashed testaments follow the account of the testator to its new principal.
+    pub fn set_testator(&mut self, testator: Principal) {
+        for entry in self.entries.values_mut() {
+            if let TrashedItem::Testament(testament) = &mut entry.item {
+                testament.set_testator(testator);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn empty(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Deletes the entries whose retention period is over and returns how many there were.
+    pub fn purge(&mut self, now: u64) -> usize {
+        let before = self.entries.len();
+        let retention_nanos = self.retention_secs.saturating_mul(1000000000);
+        self.entries
+            .retain(|_, entry| entry.date_trashed.saturating_add(retention_nanos) > now);
+        before - self.entries.len()
+    }
+
+    /// When the next entry's retention period is over, None for an empty trash.
+    pub fn next_purge(&self) -> Option<u64> {
+        self.entries
+            .values()
+            .map(|entry| self.purge_at(entry))
+            .min()
+    }
+
+    fn purge_at(&self, entry: &TrashEntry) -> u64 {
+        entry
+            .date_trashed
+            .saturating_add(self.retention_secs.saturating_mul(1000000000))
+    }
+}
+
+/// Decodes trashes of every layout persisted so far, see UserVaultCompat.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct TrashCompat {
+    retention_secs: u64,
+    entries: BTreeMap<TrashItemID, TrashEntryCompat>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+struct TrashEntryCompat {
+    date_trashed: u64,
+    item: TrashedItemCompat,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+enum TrashedItemCompat {
+    Secret {
+        secret: Secret,
+        symmetric_crypto_material: Option<SecretSymmetricCryptoMaterial>,
+        history: Vec<SecretVersion>,
+        testaments: Option<BTreeMap<TestamentID, Option<SecretSymmetricCryptoMaterial>>>,
+        allocations: Option<
+            BTreeMap<TestamentID, BTreeMap<Principal, Option<SecretSymmetricCryptoMaterial>>>,
+        >,
+        stages: Option<BTreeMap<TestamentID, StageUnlock>>,
+    },
+    Testament(TestamentCompat),
+}
+
+impl From<TrashCompat> for Trash {
+    fn from(trash: TrashCompat) -> Self {
+        let entries = trash
+            .entries
+            .into_iter()
+            .map(|(id, entry)| {
+                let item = match entry.item {
+                    TrashedItemCompat::Secret {
+                        secret,
+                        symmetric_crypto_material,
+                        history,
+                        testaments,
+                        allocations,
+                        stages,
+                    } => TrashedItem::Secret {
+                        secret,
+                        symmetric_crypto_material,
+                        history,
+                        testaments: testaments.unwrap_or_default(),
+                        allocations: allocations.unwrap_or_default(),
+                        stages: stages.unwrap_or_default(),
+                    },
+                    TrashedItemCompat::Testament(testament) => {
+                        TrashedItem::Testament(Testament::from(testament))
+                    }
+                };
+                let entry = TrashEntry {
+                    date_trashed: entry.date_trashed,
+                    item,
+                };
+                (id, entry)
+            })
+            .collect();
+        Self {
+            retention_secs: trash.retention_secs,
+            entries,
+        }
+    }
+}
+
+// This function is called by the init and post_upgrade hooks
+pub fn init_trash_timer() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(PURGE_INTERVAL_SECS), purge_due_trash);
+}
+
+/// Purges the trash of the vaults whose next purge is due. The vaults left over by a full
+/// batch are purged right after, in a message of their own.
+fn purge_due_trash() {
+    let purged = MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
+        mv.borrow_mut()
+            .purge_trash(time::get_current_time(), MAX_PURGES_PER_TICK)
+    });
+    if purged == MAX_PURGES_PER_TICK {
+        ic_cdk_timers::set_timer(Duration::ZERO, purge_due_trash);
+    }
+}
+
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn list_trash() -> Result<Vec<TrashListEntry>, SmartVaultErr> {
+    let user_vault_id = get_vault_id_for(resolve_caller())?;
+    MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
+        Ok(mv.borrow().get_user_vault(&user_vault_id)?.trash().list())
+    })
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn restore_from_trash(id: TrashItemID) -> Result<(), SmartVaultErr> {
+    let user_vault_id = get_vault_id_for(resolve_caller())?;
+    MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
+        mv.borrow_mut().restore_user_trash_item(&user_vault_id, &id)
+    })
+}
+
+/// Deletes everything in the trash for good.
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn empty_trash() -> Result<(), SmartVaultErr> {
+    let user_vault_id = get_vault_id_for(resolve_caller())?;
+    MASTERVAULT.with(|mv: &RefCell<MasterVault>| mv.borrow_mut().empty_user_trash(&user_vault_id))
+}
+
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn set_trash_retention(retention_secs: u64) -> Result<(), SmartVaultErr> {
+    let user_vault_id = get_vault_id_for(resolve_caller())?;
+    MASTERVAULT.with(|mv: &RefCell<MasterVault>| {
+        mv.borrow_mut()
+            .set_user_trash_retention(&user_vault_id, retention_secs)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1000000000;
+
+    fn trashed_secret(name: &str) -> TrashedItem {
+        let mut secret = Secret::new_test_instance();
+        secret.set_name(name.to_string());
+        TrashedItem::Secret {
+            secret,
+            symmetric_crypto_material: None,
+            history: Vec::new(),
+            testaments: BTreeMap::new(),
+            allocations: BTreeMap::new(),
+            stages: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn utest_trash_retention_and_purge() {
+        let mut trash = Trash::default();
+        trash.set_retention_secs(60);
+        let id = TrashItemID::Secret("s".to_string());
+        trash.put(id.clone(), trashed_secret("first"), 0);
+        trash.put(
+            TrashItemID::Testament("s".to_string()),
+            TrashedItem::Testament(Testament::new("s".to_string())),
+            30 * SECOND,
+        );
+
+        // secrets and testaments with the same id do not collide
+        let list = trash.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].name, Some("first".to_string()));
+        assert_eq!(list[0].purge_at, 60 * SECOND);
+
+        // a secret deleted again replaces the older entry
+        trash.put(id.clone(), trashed_secret("second"), 10 * SECOND);
+        assert_eq!(trash.list()[0].name, Some("second".to_string()));
+
+        assert_eq!(trash.purge(69 * SECOND), 0);
+        assert_eq!(trash.purge(70 * SECOND), 1);
+        assert_eq!(
+            trash.take(&id).unwrap_err(),
+            SmartVaultErr::TrashItemDoesNotExist("s".to_string())
+        );
+        trash.empty();
+        assert!(trash.is_empty());
+    }
+}
@@ -1,12 +1,29 @@
-use std::collections::BTreeMap;
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
 
-use candid::{CandidType, Deserialize, Principal};
+use std::collections::BTreeSet;
 
+use crate::common::memory::{
+    get_stable_btree_memory_for_linked_principals, get_stable_btree_memory_for_owner_to_links,
+    get_stable_btree_memory_for_pending_links, get_stable_btree_memory_for_users, verify_size,
+    Memory, StorablePrincipal, MAX_OWNER_LINKS_SIZE, MAX_PENDING_LINK_SIZE, MAX_PRINCIPAL_SIZE,
+    MAX_USER_SIZE,
+};
 use crate::common::{error::SmartVaultErr, user::User};
 
-#[derive(Debug, CandidType, Deserialize)]
+use super::linked_principals::{
+    LinkedPrincipals, OwnerLinks, PendingPrincipalLink, LINK_REQUEST_VALIDITY_SECS,
+    MAX_LINKED_PRINCIPALS,
+};
+
 pub struct UserRegistry {
-    users: BTreeMap<Principal, User>,
+    users: StableBTreeMap<Memory, StorablePrincipal, User>,
+    // linked principal -> owner of the account
+    linked_principals: StableBTreeMap<Memory, StorablePrincipal, StorablePrincipal>,
+    // principal to be linked -> link request
+    pending_links: StableBTreeMap<Memory, StorablePrincipal, PendingPrincipalLink>,
+    // owner -> linked and pending principals, so the links of an owner are found without a full scan
+    owner_to_links: StableBTreeMap<Memory, StorablePrincipal, OwnerLinks>,
 }
 
 impl Default for UserRegistry {
@@ -18,47 +35,309 @@ impl Default for UserRegistry {
 impl UserRegistry {
     pub fn new() -> Self {
         Self {
-            users: BTreeMap::new(),
+            users: StableBTreeMap::init(
+                get_stable_btree_memory_for_users(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_USER_SIZE,
+            ),
+            linked_principals: StableBTreeMap::init(
+                get_stable_btree_memory_for_linked_principals(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_PRINCIPAL_SIZE,
+            ),
+            pending_links: StableBTreeMap::init(
+                get_stable_btree_memory_for_pending_links(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_PENDING_LINK_SIZE,
+            ),
+            owner_to_links: StableBTreeMap::init(
+                get_stable_btree_memory_for_owner_to_links(),
+                MAX_PRINCIPAL_SIZE,
+                MAX_OWNER_LINKS_SIZE,
+            ),
         }
     }
 
-    pub fn add_user(&mut self, user: User) -> Result<&User, SmartVaultErr> {
-        if self.users.insert(*user.id(), user.clone()).is_some() {
-            Err(SmartVaultErr::UserAlreadyExists(user.id().to_string()))
-        } else {
-            self.get_user(user.id())
+    pub fn add_user(&mut self, user: User) -> Result<User, SmartVaultErr> {
+        let key = StorablePrincipal(*user.id());
+        if self.users.contains_key(&key) || self.linked_principals.contains_key(&key) {
+            return Err(SmartVaultErr::UserAlreadyExists(user.id().to_string()));
         }
+        verify_size(&user, MAX_USER_SIZE, &user.id().to_string())?;
+        self.users
+            .insert(key, user.clone())
+            .expect("Failed to insert user into stable memory");
+        Ok(user)
     }
 
-    pub fn get_user(&self, user_id: &Principal) -> Result<&User, SmartVaultErr> {
+    pub fn get_user(&self, user_id: &Principal) -> Result<User, SmartVaultErr> {
         self.users
-            .get(user_id)
+            .get(&StorablePrincipal(*user_id))
             .ok_or_else(|| SmartVaultErr::UserDoesNotExist(user_id.to_string()))
     }
 
+    pub fn update_user(&mut self, user: User) -> Result<User, SmartVaultErr> {
+        let key = StorablePrincipal(*user.id());
+        if !self.users.contains_key(&key) {
+            return Err(SmartVaultErr::UserDoesNotExist(user.id().to_string()));
+        }
+        verify_size(&user, MAX_USER_SIZE, &user.id().to_string())?;
+        self.users
+            .insert(key, user.clone())
+            .expect("Failed to insert user into stable memory");
+        Ok(user)
+    }
+
+    /// The linked principals and pending link requests of the user are removed as well.
     pub fn delete_user(&mut self, user_id: &Principal) -> Result<User, SmartVaultErr> {
+        let user = self
+            .users
+            .remove(&StorablePrincipal(*user_id))
+            .ok_or_else(|| SmartVaultErr::UserDeletionFailed(user_id.to_string()))?;
+        let links = self.get_linked_principals(user_id);
+        for principal in links.linked {
+            self.linked_principals.remove(&StorablePrincipal(principal));
+        }
+        for link in links.pending {
+            self.pending_links
+                .remove(&StorablePrincipal(link.principal));
+        }
+        self.owner_to_links.remove(&StorablePrincipal(*user_id));
+        Ok(user)
+    }
+
+    /// Moves the account of the user to the new principal, see account_recovery.
+    /// The linked principals and pending link requests are dropped, a device the owner
+    /// lost control of would keep access to the account otherwise.
+    pub fn rebind_user(
+        &mut self,
+        user_id: &Principal,
+        new_id: Principal,
+    ) -> Result<User, SmartVaultErr> {
+        let mut user = self.get_user(user_id)?;
+        let key = StorablePrincipal(new_id);
+        if self.users.contains_key(&key) || self.linked_principals.contains_key(&key) {
+            return Err(SmartVaultErr::UserAlreadyExists(new_id.to_string()));
+        }
+        // the recovered user keeps the original principal, which makes it larger
+        user.rebind(new_id);
+        verify_size(&user, MAX_USER_SIZE, &new_id.to_string())?;
+
+        let links = self.get_linked_principals(user_id);
+        for principal in links.linked {
+            self.linked_principals.remove(&StorablePrincipal(principal));
+        }
+        for link in links.pending {
+            self.pending_links
+                .remove(&StorablePrincipal(link.principal));
+        }
+        self.owner_to_links.remove(&StorablePrincipal(*user_id));
+
+        self.users.remove(&StorablePrincipal(*user_id));
         self.users
-            .remove(user_id)
-            .ok_or_else(|| SmartVaultErr::UserDeletionFailed(user_id.to_string()))
+            .insert(key, user.clone())
+            .expect("Failed to insert user into stable memory");
+        Ok(user)
+    }
+
+    pub fn get_all_users(&self) -> Vec<User> {
+        self.users.iter().map(|(_, user)| user).collect()
     }
 
-    pub fn get_user_mut(&mut self, user_id: &Principal) -> Result<&mut User, SmartVaultErr> {
+    /// Returns at most limit users in the order of the stable map, i.e. by the bytes
+    /// of their principal, starting after the given principal.
+    pub fn get_users_page(&self, after: Option<&Principal>, limit: usize) -> Vec<User> {
+        // the range starts at the cursor itself, the entries before it are not read
+        let start = after.map(|after| after.as_slice().to_vec());
         self.users
-            .get_mut(user_id)
-            .ok_or_else(|| SmartVaultErr::UserDoesNotExist(user_id.to_string()))
+            .range(Vec::new(), start)
+            .skip_while(|(principal, _)| after == Some(&principal.0))
+            .take(limit)
+            .map(|(_, user)| user)
+            .collect()
     }
 
     pub fn get_all_last_login_dates(&self) -> Vec<(Principal, u64)> {
-        self.users.iter()
+        self.users
+            .iter()
             .filter_map(|(principal, user)| {
-                if let Some(login_date) = user.date_last_login {
-                    Some((principal.clone(), login_date))
-                } else {
-                    None
-                }
+                user.date_last_login
+                    .map(|login_date| (principal.0, login_date))
             })
             .collect()
     }
+
+    /// Returns the owner of the account the principal is linked to,
+    /// or the principal itself if it is not linked.
+    pub fn resolve_principal(&self, principal: &Principal) -> Principal {
+        self.linked_principals
+            .get(&StorablePrincipal(*principal))
+            .map_or(*principal, |owner| owner.0)
+    }
+
+    /// A principal with an account of its own cannot be linked, its vault would become unreachable.
+    pub fn verify_linkable(
+        &self,
+        owner: &Principal,
+        principal: &Principal,
+    ) -> Result<(), SmartVaultErr> {
+        let key = StorablePrincipal(*principal);
+        if principal == owner
+            || *principal == Principal::anonymous()
+            || self.users.contains_key(&key)
+            || self.linked_principals.contains_key(&key)
+        {
+            return Err(SmartVaultErr::InvalidLinkedPrincipal(principal.to_string()));
+        }
+        Ok(())
+    }
+
+    /// A request for the same principal replaces the older one.
+    pub fn request_principal_link(
+        &mut self,
+        owner: &Principal,
+        principal: Principal,
+        now: u64,
+    ) -> Result<PendingPrincipalLink, SmartVaultErr> {
+        self.get_user(owner)?;
+        self.verify_linkable(owner, &principal)?;
+
+        // expired requests do not count
+        let links = self.get_linked_principals(owner);
+        for link in &links.pending {
+            if link.expires_at <= now {
+                self.pending_links
+                    .remove(&StorablePrincipal(link.principal));
+                self.remove_link(owner, &link.principal);
+            }
+        }
+        let pending = links
+            .pending
+            .iter()
+            .filter(|link| link.expires_at > now && link.principal != principal)
+            .count();
+        if (links.linked.len() + pending) as u64 >= MAX_LINKED_PRINCIPALS {
+            return Err(SmartVaultErr::TooManyLinkedPrincipals(
+                MAX_LINKED_PRINCIPALS,
+            ));
+        }
+
+        let link = PendingPrincipalLink {
+            owner: *owner,
+            principal,
+            expires_at: now.saturating_add(LINK_REQUEST_VALIDITY_SECS.saturating_mul(1000000000)),
+        };
+        // the principal might have been requested by another owner, whose request is replaced
+        let replaced = self
+            .pending_links
+            .insert(StorablePrincipal(principal), link.clone())
+            .expect("Failed to insert link request into stable memory");
+        if let Some(replaced) = replaced.filter(|replaced| replaced.owner != *owner) {
+            self.remove_link(&replaced.owner, &principal);
+        }
+        self.add_link(owner, principal);
+        Ok(link)
+    }
+
+    /// Links the principal to the account of the owner, if the owner requested it.
+    pub fn confirm_principal_link(
+        &mut self,
+        owner: &Principal,
+        principal: Principal,
+        now: u64,
+    ) -> Result<(), SmartVaultErr> {
+        let key = StorablePrincipal(principal);
+        let link = self
+            .pending_links
+            .get(&key)
+            .filter(|link| link.owner == *owner)
+            .ok_or_else(|| SmartVaultErr::PrincipalLinkDoesNotExist(principal.to_string()))?;
+        if link.expires_at <= now {
+            self.pending_links.remove(&key);
+            self.remove_link(owner, &principal);
+            return Err(SmartVaultErr::PrincipalLinkExpired(principal.to_string()));
+        }
+        // the principal might have created an account in the meantime
+        self.verify_linkable(owner, &principal)?;
+        self.get_user(owner)?;
+
+        self.pending_links.remove(&key);
+        self.linked_principals
+            .insert(key, StorablePrincipal(*owner))
+            .expect("Failed to insert linked principal into stable memory");
+        Ok(())
+    }
+
+    /// Removes a linked principal or a pending link request of the owner.
+    pub fn remove_principal_link(
+        &mut self,
+        owner: &Principal,
+        principal: &Principal,
+    ) -> Result<(), SmartVaultErr> {
+        let key = StorablePrincipal(*principal);
+        if self.linked_principals.get(&key) == Some(StorablePrincipal(*owner)) {
+            self.linked_principals.remove(&key);
+            self.remove_link(owner, principal);
+            return Ok(());
+        }
+        if self
+            .pending_links
+            .get(&key)
+            .map_or(false, |link| link.owner == *owner)
+        {
+            self.pending_links.remove(&key);
+            self.remove_link(owner, principal);
+            return Ok(());
+        }
+        Err(SmartVaultErr::PrincipalLinkDoesNotExist(
+            principal.to_string(),
+        ))
+    }
+
+    /// Only reads the links of the owner, which are looked up through the owner index.
+    pub fn get_linked_principals(&self, owner: &Principal) -> LinkedPrincipals {
+        let mut links = LinkedPrincipals::default();
+        for principal in self.links_of(owner) {
+            let key = StorablePrincipal(principal);
+            if self.linked_principals.get(&key) == Some(StorablePrincipal(*owner)) {
+                links.linked.push(principal);
+            } else if let Some(link) = self
+                .pending_links
+                .get(&key)
+                .filter(|link| link.owner == *owner)
+            {
+                links.pending.push(link);
+            }
+        }
+        links
+    }
+
+    fn links_of(&self, owner: &Principal) -> BTreeSet<Principal> {
+        self.owner_to_links
+            .get(&StorablePrincipal(*owner))
+            .unwrap_or_default()
+            .0
+    }
+
+    fn add_link(&mut self, owner: &Principal, principal: Principal) {
+        let mut links = self.links_of(owner);
+        links.insert(principal);
+        self.owner_to_links
+            .insert(StorablePrincipal(*owner), OwnerLinks(links))
+            .expect("Failed to insert into link index");
+    }
+
+    fn remove_link(&mut self, owner: &Principal, principal: &Principal) {
+        let mut links = self.links_of(owner);
+        links.remove(principal);
+        if links.is_empty() {
+            self.owner_to_links.remove(&StorablePrincipal(*owner));
+        } else {
+            self.owner_to_links
+                .insert(StorablePrincipal(*owner), OwnerLinks(links))
+                .expect("Failed to insert into link index");
+        }
+    }
 }
 
 #[cfg(test)]
@@ -66,7 +345,7 @@ mod tests {
     use candid::Principal;
 
     use crate::{
-        common::{error::SmartVaultErr, user::User},
+        common::{error::SmartVaultErr, memory::MAX_USER_SIZE, user::User},
         smart_vaults::user_registry::UserRegistry,
     };
     use crate::common::user::AddUserArgs;
@@ -104,5 +383,233 @@ mod tests {
             user_registry.get_user(&principal_2).unwrap_err(),
             SmartVaultErr::UserDoesNotExist(principal_2.to_string())
         );
+
+        // users which do not fit into stable memory are rejected instead of trapping
+        let mut large_user = new_user.clone();
+        large_user.name = Some("a".repeat(MAX_USER_SIZE as usize));
+        assert_eq!(
+            user_registry.update_user(large_user).unwrap_err(),
+            SmartVaultErr::ValueTooLarge(principal.to_string())
+        );
+        assert_eq!(user_registry.get_user(&principal).unwrap().name, None);
+    }
+
+    #[test]
+    fn utest_linked_principals() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let device = Principal::from_slice(&[2; 29]);
+        let bob = Principal::from_slice(&[3; 29]);
+        let args = |id| AddUserArgs {
+            id,
+            name: None,
+            email: None,
+            user_type: None,
+        };
+        let mut user_registry = UserRegistry::new();
+        user_registry
+            .add_user(User::new(&alice, args(alice)))
+            .unwrap();
+        user_registry.add_user(User::new(&bob, args(bob))).unwrap();
+
+        // principals with an account of their own cannot be linked
+        assert_eq!(
+            user_registry.request_principal_link(&alice, bob, 0),
+            Err(SmartVaultErr::InvalidLinkedPrincipal(bob.to_string()))
+        );
+
+        // only the owner who asked for it can be confirmed
+        user_registry
+            .request_principal_link(&alice, device, 0)
+            .unwrap();
+        assert_eq!(
+            user_registry.confirm_principal_link(&bob, device, 0),
+            Err(SmartVaultErr::PrincipalLinkDoesNotExist(device.to_string()))
+        );
+        assert_eq!(user_registry.resolve_principal(&device), device);
+        user_registry
+            .confirm_principal_link(&alice, device, 1)
+            .unwrap();
+        assert_eq!(user_registry.resolve_principal(&device), alice);
+        assert_eq!(
+            user_registry.get_linked_principals(&alice).linked,
+            vec![device]
+        );
+        assert!(user_registry
+            .get_linked_principals(&alice)
+            .pending
+            .is_empty());
+
+        // a linked principal cannot get an account of its own
+        assert_eq!(
+            user_registry
+                .add_user(User::new(&device, args(device)))
+                .unwrap_err(),
+            SmartVaultErr::UserAlreadyExists(device.to_string())
+        );
+
+        // deleting the owner removes the links as well
+        user_registry.delete_user(&alice).unwrap();
+        assert_eq!(user_registry.resolve_principal(&device), device);
+    }
+
+    #[test]
+    fn utest_rebind_user() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let device = Principal::from_slice(&[2; 29]);
+        let recovered = Principal::from_slice(&[3; 29]);
+        let mut user_registry = UserRegistry::new();
+        let args = AddUserArgs {
+            id: alice,
+            name: Some("Alice".to_string()),
+            email: None,
+            user_type: None,
+        };
+        user_registry.add_user(User::new(&alice, args)).unwrap();
+        user_registry
+            .request_principal_link(&alice, device, 0)
+            .unwrap();
+        user_registry
+            .confirm_principal_link(&alice, device, 0)
+            .unwrap();
+
+        // an account cannot be moved onto a principal which is taken
+        assert_eq!(
+            user_registry.rebind_user(&alice, device).unwrap_err(),
+            SmartVaultErr::UserAlreadyExists(device.to_string())
+        );
+
+        let user = user_registry.rebind_user(&alice, recovered).unwrap();
+        assert_eq!(user.id, recovered);
+        assert_eq!(user.name, Some("Alice".to_string()));
+        assert_eq!(*user.key_principal(), alice);
+        assert_eq!(
+            user_registry.get_user(&alice).unwrap_err(),
+            SmartVaultErr::UserDoesNotExist(alice.to_string())
+        );
+
+        // the linked device loses access
+        assert_eq!(user_registry.resolve_principal(&device), device);
+        assert!(user_registry
+            .get_linked_principals(&recovered)
+            .linked
+            .is_empty());
+
+        // the keys stay derived from the principal the account was created with
+        let user = user_registry.rebind_user(&recovered, alice).unwrap();
+        assert_eq!(*user.key_principal(), alice);
+    }
+
+    #[test]
+    fn utest_users_page() {
+        let mut user_registry = UserRegistry::new();
+        let principals: Vec<Principal> = (1..=5)
+            .map(|byte| Principal::from_slice(&[byte; 29]))
+            .collect();
+        for principal in &principals {
+            let args = AddUserArgs {
+                id: *principal,
+                name: None,
+                email: None,
+                user_type: None,
+            };
+            user_registry.add_user(User::new(principal, args)).unwrap();
+        }
+        let ids = |users: Vec<User>| users.into_iter().map(|user| user.id).collect::<Vec<_>>();
+
+        assert_eq!(ids(user_registry.get_users_page(None, 2)), principals[..2]);
+        assert_eq!(
+            ids(user_registry.get_users_page(Some(&principals[1]), 2)),
+            principals[2..4]
+        );
+        assert_eq!(
+            ids(user_registry.get_users_page(Some(&principals[3]), 2)),
+            principals[4..]
+        );
+        assert!(user_registry
+            .get_users_page(Some(&principals[4]), 2)
+            .is_empty());
+    }
+
+    #[test]
+    fn utest_principal_link_expiry() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let device = Principal::from_slice(&[2; 29]);
+        let mut user_registry = UserRegistry::new();
+        let args = AddUserArgs {
+            id: alice,
+            name: None,
+            email: None,
+            user_type: None,
+        };
+        user_registry.add_user(User::new(&alice, args)).unwrap();
+
+        let link = user_registry
+            .request_principal_link(&alice, device, 0)
+            .unwrap();
+        assert_eq!(
+            user_registry.confirm_principal_link(&alice, device, link.expires_at),
+            Err(SmartVaultErr::PrincipalLinkExpired(device.to_string()))
+        );
+
+        // removing a request which is not there fails
+        assert_eq!(
+            user_registry.remove_principal_link(&alice, &device),
+            Err(SmartVaultErr::PrincipalLinkDoesNotExist(device.to_string()))
+        );
+        user_registry
+            .request_principal_link(&alice, device, 0)
+            .unwrap();
+        assert!(user_registry.remove_principal_link(&alice, &device).is_ok());
+        assert!(user_registry
+            .get_linked_principals(&alice)
+            .pending
+            .is_empty());
+    }
+
+    #[test]
+    fn utest_link_index() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let device = Principal::from_slice(&[2; 29]);
+        let bob = Principal::from_slice(&[3; 29]);
+        let args = |id| AddUserArgs {
+            id,
+            name: None,
+            email: None,
+            user_type: None,
+        };
+        let mut user_registry = UserRegistry::new();
+        user_registry
+            .add_user(User::new(&alice, args(alice)))
+            .unwrap();
+        user_registry.add_user(User::new(&bob, args(bob))).unwrap();
+
+        // a request for the same principal by another owner replaces the older one
+        user_registry
+            .request_principal_link(&alice, device, 0)
+            .unwrap();
+        user_registry
+            .request_principal_link(&bob, device, 0)
+            .unwrap();
+        assert!(user_registry
+            .get_linked_principals(&alice)
+            .pending
+            .is_empty());
+        assert!(user_registry.links_of(&alice).is_empty());
+        user_registry
+            .confirm_principal_link(&bob, device, 0)
+            .unwrap();
+        assert_eq!(
+            user_registry.get_linked_principals(&bob).linked,
+            vec![device]
+        );
+
+        // the index is cleaned up with the links
+        user_registry.remove_principal_link(&bob, &device).unwrap();
+        assert!(user_registry.owner_to_links.is_empty());
+        user_registry
+            .request_principal_link(&bob, device, 0)
+            .unwrap();
+        user_registry.delete_user(&bob).unwrap();
+        assert!(user_registry.owner_to_links.is_empty());
     }
 }
@@ -1,18 +1,27 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::Storable;
 use serde::Serialize;
 
-use std::collections::BTreeMap;
-use crate::common::user::{User};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use crate::common::user::{AddUserArgs, User};
 
-use super::secret::{Secret, SecretID, SecretSymmetricCryptoMaterial};
-use super::testament::{Testament, TestamentID};
+use super::secret::{
+    Secret, SecretID, SecretRemovalReport, SecretSymmetricCryptoMaterial, SecretVersion,
+};
+use super::testament::{ReleaseStage, Testament, TestamentCompat, TestamentID};
+use super::trash::{Trash, TrashCompat, TrashItemID, TrashedItem};
 use crate::common::uuid::UUID;
 use crate::utils::time;
+use crate::common::error::TestamentProblem;
 use crate::SmartVaultErr;
 
 pub type UserVaultID = UUID;
 pub type KeyBox = BTreeMap<SecretID, SecretSymmetricCryptoMaterial>;
 
+// Older versions are dropped once a secret has more previous versions than this
+pub const MAX_SECRET_VERSIONS: usize = 10;
+
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
 pub struct UserVault {
     id: UserVaultID,
@@ -27,6 +36,21 @@ pub struct UserVault {
     key_box: KeyBox, // TODO: make getter and setter
     testaments: BTreeMap<TestamentID, Testament>,
     heirs: BTreeMap<Principal, User>,
+    /// The previous versions of the secrets, oldest first
+    secret_history: BTreeMap<SecretID, Vec<SecretVersion>>,
+    /// Removed secrets and testaments, until they are restored or purged
+    trash: Trash,
+}
+
+impl Storable for UserVault {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        // Vaults stored in an older layout are decoded as well
+        UserVault::from(Decode!(&bytes, UserVaultCompat).unwrap())
+    }
 }
 
 impl Default for UserVault {
@@ -47,6 +71,8 @@ impl UserVault {
             key_box: BTreeMap::new(),
             testaments: BTreeMap::new(),
             heirs: BTreeMap::new(),
+            secret_history: BTreeMap::new(),
+            trash: Trash::default(),
         }
     }
 
@@ -98,34 +124,142 @@ impl UserVault {
         Ok(self.secrets.get(&sid).unwrap().clone())
     }
 
-    pub fn remove_secret(&mut self, secret_id: &str) -> Result<(), SmartVaultErr> {
-        if !self.secrets.contains_key(secret_id) {
-            return Err(SmartVaultErr::SecretDoesNotExist(secret_id.to_string()));
+    /// Moves the secret, its key box entry and its history to the trash.
+    /// The secret is removed from the testaments as well, so that none of them refers to it anymore.
+    pub fn remove_secret(&mut self, secret_id: &str) -> Result<SecretRemovalReport, SmartVaultErr> {
+        let sid = secret_id.to_string();
+        let secret = self
+            .secrets
+            .remove(secret_id)
+            .ok_or_else(|| SmartVaultErr::SecretDoesNotExist(sid.clone()))?;
+        let symmetric_crypto_material = self.key_box.remove(secret_id);
+        let mut testaments = BTreeMap::new();
+        let mut allocations = BTreeMap::new();
+        let mut stages = BTreeMap::new();
+        for testament in self.testaments.values_mut() {
+            let allocated: BTreeMap<Principal, Option<SecretSymmetricCryptoMaterial>> = testament
+                .allocations()
+                .iter()
+                .filter(|(_, allocation)| allocation.secrets.contains(&sid))
+                .map(|(heir, allocation)| (*heir, allocation.key_box.get(&sid).cloned()))
+                .collect();
+            let unlock = testament
+                .release_stages()
+                .iter()
+                .find(|stage| stage.secrets.contains(&sid))
+                .map(|stage| stage.unlock.clone());
+            if testament.remove_secret(&sid) {
+                let testament_key = testament.key_box_mut().remove(&sid);
+                testaments.insert(testament.id().clone(), testament_key);
+                if !allocated.is_empty() {
+                    allocations.insert(testament.id().clone(), allocated);
+                }
+                if let Some(unlock) = unlock {
+                    stages.insert(testament.id().clone(), unlock);
+                }
+            }
         }
-        self.secrets.remove(secret_id);
-        self.date_modified = time::get_current_time();
-        Ok(())
+        let report = SecretRemovalReport {
+            secret_id: sid.clone(),
+            removed_key_box_entry: symmetric_crypto_material.is_some(),
+            testaments: testaments.keys().cloned().collect(),
+        };
+        let item = TrashedItem::Secret {
+            secret,
+            symmetric_crypto_material,
+            history: self.secret_history.remove(secret_id).unwrap_or_default(),
+            testaments,
+            allocations,
+            stages,
+        };
+        let now = time::get_current_time();
+        self.trash.put(TrashItemID::Secret(sid), item, now);
+        self.date_modified = now;
+        Ok(report)
     }
 
+    /// The previous version is kept in the history of the secret.
     pub fn update_secret(&mut self, secret: Secret) -> Result<Secret, SmartVaultErr> {
         let sid = secret.id().clone();
         if !self.secrets.contains_key(secret.id()) {
             return Err(SmartVaultErr::SecretDoesNotExist(secret.id().to_string()));
         }
 
+        self.archive_secret(&sid);
         self.secrets.insert(sid.clone(), secret);
         self.date_modified = time::get_current_time();
         Ok(self.secrets.get(&sid).unwrap().clone())
     }
 
-    pub fn update_testament(&mut self, mut t: Testament) -> Result<Testament, SmartVaultErr> {
-        if !self.testaments.contains_key(t.id()) {
-            return Err(SmartVaultErr::SecretDoesNotExist(t.id().to_string()));
+    pub fn get_secret_history(&self, secret_id: &str) -> Result<Vec<SecretVersion>, SmartVaultErr> {
+        self.get_secret(secret_id)?;
+        Ok(self
+            .secret_history
+            .get(secret_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Makes a previous version the current one again, including its key box entry.
+    /// The current version is kept in the history, so restoring can be undone.
+    pub fn restore_secret_version(
+        &mut self,
+        secret_id: &str,
+        version: u64,
+    ) -> Result<Secret, SmartVaultErr> {
+        self.get_secret(secret_id)?;
+        let restored = self
+            .secret_history
+            .get(secret_id)
+            .and_then(|versions| versions.iter().find(|v| v.version == version))
+            .cloned()
+            .ok_or(SmartVaultErr::SecretVersionDoesNotExist(version))?;
+
+        let sid = secret_id.to_string();
+        self.archive_secret(&sid);
+        let now = time::get_current_time();
+        let mut secret = restored.secret;
+        secret.set_date_modified(now);
+        self.secrets.insert(sid.clone(), secret.clone());
+        if let Some(symmetric_crypto_material) = restored.symmetric_crypto_material {
+            self.key_box.insert(sid, symmetric_crypto_material);
+        }
+        self.date_modified = now;
+        Ok(secret)
+    }
+
+    /// Appends the current version of the secret and its key box entry to its history.
+    fn archive_secret(&mut self, secret_id: &SecretID) {
+        let Some(secret) = self.secrets.get(secret_id) else {
+            return;
+        };
+        let versions = self.secret_history.entry(secret_id.clone()).or_default();
+        versions.push(SecretVersion {
+            version: versions.last().map_or(1, |v| v.version + 1),
+            date_archived: time::get_current_time(),
+            secret: secret.clone(),
+            symmetric_crypto_material: self.key_box.get(secret_id).cloned(),
+        });
+        if versions.len() > MAX_SECRET_VERSIONS {
+            versions.remove(0);
         }
+    }
+
+    pub fn update_testament(&mut self, mut t: Testament) -> Result<Testament, SmartVaultErr> {
+        let Some(t_old) = self.testaments.get(t.id()) else {
+            return Err(SmartVaultErr::TestamentDoesNotExist(t.id().to_string()));
+        };
+        // the testament stays with its testator, whatever the client sent
+        t.set_testator(*t_old.testator());
+        self.validate_testament(&t)?;
         let tid = t.id().clone();
 
-        // condition_status cannot be updated
-        t.set_condition_status(self.testaments.get(t.id()).unwrap().condition_status().clone());
+        // release state, condition_status, confirmations, the release history
+        // and the last reminder cannot be updated by the testator
+        t.set_release_state(t_old.release_state().clone());
+        t.set_confirmations(t_old);
+        t.set_release_history(t_old);
+        t.set_last_reminder(t_old);
 
         self.testaments.insert(t.id().clone(), t);
         self.date_modified = time::get_current_time();
@@ -142,31 +276,222 @@ impl UserVault {
 
     pub fn add_testament(&mut self, testament: Testament) -> Result<(), SmartVaultErr> {
         if self.testaments.contains_key(testament.id()) {
-            return Err(SmartVaultErr::SecretAlreadyExists(
+            return Err(SmartVaultErr::TestamentAlreadyExists(
                 testament.id().to_string(),
             ));
         }
+        self.validate_testament(&testament)?;
 
         self.testaments.insert(testament.id().clone(), testament);
         self.date_modified = time::get_current_time();
         Ok(())
     }
 
+    /// Reverts an erroneous release, see Testament::rearm.
+    /// The new key boxes are validated like those of an update, so they need an entry
+    /// for every secret of the testament and of the allocations.
+    pub fn rearm_testament(
+        &mut self,
+        testament_id: &TestamentID,
+        key_box: KeyBox,
+        allocation_key_boxes: BTreeMap<Principal, KeyBox>,
+        reason: Option<String>,
+        now: u64,
+    ) -> Result<Testament, SmartVaultErr> {
+        let mut testament = self.get_testament(testament_id)?.clone();
+        testament.rearm(key_box, allocation_key_boxes, reason, now)?;
+        self.validate_testament(&testament)?;
+
+        self.testaments
+            .insert(testament_id.clone(), testament.clone());
+        self.date_modified = now;
+        Ok(testament)
+    }
+
     pub fn get_testament(&self, testament_id: &TestamentID) -> Result<&Testament, SmartVaultErr> {
         self.testaments
             .get(testament_id)
             .ok_or_else(|| SmartVaultErr::TestamentDoesNotExist(testament_id.to_string()))
     }
 
+    pub fn get_testament_mut(
+        &mut self,
+        testament_id: &TestamentID,
+    ) -> Result<&mut Testament, SmartVaultErr> {
+        self.testaments
+            .get_mut(testament_id)
+            .ok_or_else(|| SmartVaultErr::TestamentDoesNotExist(testament_id.to_string()))
+    }
+
+    /// Moves the testament to the trash.
     pub fn remove_testament(&mut self, testament_id: &TestamentID) -> Result<(), SmartVaultErr> {
-        if !self.testaments.contains_key(testament_id) {
-            return Err(SmartVaultErr::TestamentDoesNotExist(
-                testament_id.to_string(),
-            ));
+        let testament = self.testaments.remove(testament_id).ok_or_else(|| {
+            SmartVaultErr::TestamentDoesNotExist(testament_id.to_string())
+        })?;
+        let now = time::get_current_time();
+        self.trash.put(
+            TrashItemID::Testament(testament_id.clone()),
+            TrashedItem::Testament(testament),
+            now,
+        );
+        self.date_modified = now;
+        Ok(())
+    }
+
+    /// The testament as the heir sees it: only the share of the heir,
+    /// without the secrets which were removed from the vault.
+    pub fn get_testament_for_heir(
+        &self,
+        testament_id: &TestamentID,
+        heir: &Principal,
+    ) -> Result<Testament, SmartVaultErr> {
+        let testament = self.get_testament(testament_id)?;
+        if !testament.heirs().contains(heir) {
+            return Err(SmartVaultErr::NoTestamentsForHeir(heir.to_string()));
+        }
+        let mut testament = testament.share_of(heir);
+        self.remove_missing_secrets(&mut testament);
+        Ok(testament)
+    }
+
+    /// Removes the secrets which are not in the vault, and their key box entries, from the testament.
+    /// Returns whether there were any.
+    fn remove_missing_secrets(&self, testament: &mut Testament) -> bool {
+        let missing: Vec<SecretID> = testament
+            .secrets()
+            .iter()
+            .filter(|secret_id| !self.secrets.contains_key(*secret_id))
+            .cloned()
+            .collect();
+        for secret_id in &missing {
+            testament.remove_secret(secret_id);
+            testament.key_box_mut().remove(secret_id);
+        }
+        !missing.is_empty()
+    }
+
+    /// Removes the references to secrets which do not exist anymore, left behind by
+    /// versions which did not cascade the removal of secrets.
+    /// Returns the ids of the testaments which were changed.
+    pub fn remove_dangling_secret_references(&mut self) -> Vec<TestamentID> {
+        let secrets = &self.secrets;
+        self.key_box
+            .retain(|secret_id, _| secrets.contains_key(secret_id));
+        let mut testaments = std::mem::take(&mut self.testaments);
+        let mut changed = Vec::new();
+        for testament in testaments.values_mut() {
+            if self.remove_missing_secrets(testament) {
+                changed.push(testament.id().clone());
+            }
+        }
+        self.testaments = testaments;
+        changed
+    }
+
+    pub fn trash(&self) -> &Trash {
+        &self.trash
+    }
+
+    pub fn trash_mut(&mut self) -> &mut Trash {
+        &mut self.trash
+    }
+
+    /// Puts the item back where it was removed from and returns it.
+    /// Restored testaments need to satisfy the same rules as newly added ones.
+    pub fn restore_from_trash(&mut self, id: &TrashItemID) -> Result<TrashedItem, SmartVaultErr> {
+        match &self.trash.get(id)?.item {
+            TrashedItem::Secret { secret, .. } => {
+                if self.secrets.contains_key(secret.id()) {
+                    return Err(SmartVaultErr::SecretAlreadyExists(secret.id().to_string()));
+                }
+            }
+            TrashedItem::Testament(testament) => {
+                if self.testaments.contains_key(testament.id()) {
+                    return Err(SmartVaultErr::TestamentAlreadyExists(
+                        testament.id().to_string(),
+                    ));
+                }
+                // the secrets removed in the meantime are not part of the restored testament
+                let mut testament = testament.clone();
+                self.remove_missing_secrets(&mut testament);
+                self.validate_testament(&testament)?;
+            }
+        }
+
+        let mut item = self.trash.take(id)?.item;
+        match &mut item {
+            TrashedItem::Secret {
+                secret,
+                symmetric_crypto_material,
+                history,
+                testaments,
+                allocations,
+                stages,
+            } => {
+                let sid = secret.id().clone();
+                if let Some(symmetric_crypto_material) = symmetric_crypto_material {
+                    self.key_box
+                        .insert(sid.clone(), symmetric_crypto_material.clone());
+                }
+                if !history.is_empty() {
+                    self.secret_history.insert(sid.clone(), history.clone());
+                }
+                // testaments which were removed in the meantime are not restored
+                for (testament_id, testament_key) in testaments.iter() {
+                    if let Some(testament) = self.testaments.get_mut(testament_id) {
+                        testament.add_secret(sid.clone());
+                        if let Some(testament_key) = testament_key {
+                            testament
+                                .key_box_mut()
+                                .insert(sid.clone(), testament_key.clone());
+                        }
+                    }
+                }
+                // as are the allocations of heirs who lost theirs in the meantime
+                for (testament_id, allocated) in allocations.iter() {
+                    let Some(testament) = self.testaments.get_mut(testament_id) else {
+                        continue;
+                    };
+                    for (heir, heir_key) in allocated {
+                        if let Some(allocation) = testament.allocations_mut().get_mut(heir) {
+                            allocation.secrets.insert(sid.clone());
+                            if let Some(heir_key) = heir_key {
+                                allocation.key_box.insert(sid.clone(), heir_key.clone());
+                            }
+                        }
+                    }
+                }
+                // the secret goes back into a stage with the same schedule, so it is not
+                // unlocked earlier than before
+                for (testament_id, unlock) in stages.iter() {
+                    let Some(testament) = self.testaments.get_mut(testament_id) else {
+                        continue;
+                    };
+                    let release_stages = testament.release_stages_mut();
+                    let same_schedule = release_stages
+                        .iter_mut()
+                        .find(|stage| stage.unlock == *unlock);
+                    match same_schedule {
+                        Some(stage) => {
+                            stage.secrets.insert(sid.clone());
+                        }
+                        None => release_stages.push(ReleaseStage {
+                            name: None,
+                            secrets: HashSet::from([sid.clone()]),
+                            unlock: unlock.clone(),
+                        }),
+                    }
+                }
+                self.secrets.insert(sid, secret.clone());
+            }
+            TrashedItem::Testament(testament) => {
+                self.remove_missing_secrets(testament);
+                self.testaments
+                    .insert(testament.id().clone(), testament.clone());
+            }
         }
-        self.testaments.remove(testament_id);
         self.date_modified = time::get_current_time();
-        Ok(())
+        Ok(item)
     }
 
     pub fn heirs(&self) -> &BTreeMap<Principal, User> {
@@ -199,23 +524,174 @@ impl UserVault {
         Ok(self.heirs.get(&uid).unwrap().clone())
     }
 
+    /// Heirs are only removed once no testament names them anymore.
     pub fn remove_heir(&mut self, user_id: &Principal) -> Result<(), SmartVaultErr> {
         if !self.heirs.contains_key(user_id) {
             return Err(SmartVaultErr::UserDoesNotExist(user_id.to_string()));
         }
+        let testaments = self.get_testaments_of_heir(user_id);
+        if !testaments.is_empty() {
+            return Err(SmartVaultErr::HeirIsPartOfTestaments(testaments));
+        }
         self.heirs.remove(user_id);
         self.date_modified = time::get_current_time();
         Ok(())
     }
 
+    pub fn get_testaments_of_heir(&self, user_id: &Principal) -> Vec<TestamentID> {
+        self.testaments
+            .values()
+            .filter(|testament| testament.heirs().contains(user_id))
+            .map(|testament| testament.id().clone())
+            .collect()
+    }
+
+    /// Testaments can only name heirs of the address book.
+    /// Checks the testament against the vault it goes into.
+    /// All problems are reported at once, so they can be fixed in one go.
+    fn validate_testament(&self, testament: &Testament) -> Result<(), SmartVaultErr> {
+        let mut problems = testament.problems();
+        if testament.heirs().is_empty() {
+            problems.push(TestamentProblem::NoHeirs);
+        }
+        let heirs: BTreeSet<&Principal> = testament.heirs().iter().collect();
+        for heir in heirs {
+            if heir == testament.testator() || *heir == Principal::anonymous() {
+                problems.push(TestamentProblem::InvalidHeir(heir.to_string()));
+            } else if !self.heirs.contains_key(heir) {
+                problems.push(TestamentProblem::HeirNotInAddressBook(heir.to_string()));
+            }
+        }
+        // The key box of the testament is only needed as long as there are no allocations
+        let shared_key_box_needed = testament.allocations().is_empty();
+        let secrets: BTreeSet<&SecretID> = testament.secrets().iter().collect();
+        for secret_id in secrets {
+            if !self.secrets.contains_key(secret_id) {
+                problems.push(TestamentProblem::SecretDoesNotExist(secret_id.clone()));
+            }
+            if shared_key_box_needed && !testament.key_box().contains_key(secret_id) {
+                problems.push(TestamentProblem::KeyBoxEntryMissing(secret_id.clone()));
+            }
+        }
+        let mut staged = BTreeSet::new();
+        for stage in testament.release_stages() {
+            let stage_secrets: BTreeSet<&SecretID> = stage.secrets.iter().collect();
+            for secret_id in stage_secrets {
+                if !testament.secrets().contains(secret_id) {
+                    problems.push(TestamentProblem::StagedSecretNotInTestament(
+                        secret_id.clone(),
+                    ));
+                } else if !staged.insert(secret_id) {
+                    problems.push(TestamentProblem::SecretInSeveralStages(secret_id.clone()));
+                }
+            }
+        }
+        for (heir, allocation) in testament.allocations() {
+            if !testament.heirs().contains(heir) {
+                problems.push(TestamentProblem::AllocationForNonHeir(heir.to_string()));
+            }
+            let allocated: BTreeSet<&SecretID> = allocation.secrets.iter().collect();
+            for secret_id in allocated {
+                if !testament.secrets().contains(secret_id) {
+                    problems.push(TestamentProblem::AllocatedSecretNotInTestament {
+                        heir: heir.to_string(),
+                        secret_id: secret_id.clone(),
+                    });
+                } else if !allocation.key_box.contains_key(secret_id) {
+                    problems.push(TestamentProblem::AllocationKeyBoxEntryMissing {
+                        heir: heir.to_string(),
+                        secret_id: secret_id.clone(),
+                    });
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(SmartVaultErr::InvalidTestament(problems))
+        }
+    }
+
+    /// Adds the heirs named in testaments, but missing in the address book.
+    /// Returns the added heirs.
+    pub fn add_missing_heirs(&mut self) -> Vec<Principal> {
+        let missing: Vec<Principal> = self
+            .testaments
+            .values()
+            .flat_map(|testament| testament.heirs().iter())
+            .filter(|heir| !self.heirs.contains_key(heir))
+            .cloned()
+            .collect::<BTreeSet<Principal>>()
+            .into_iter()
+            .collect();
+        for heir in &missing {
+            self.heirs.insert(
+                *heir,
+                User::from(AddUserArgs {
+                    id: *heir,
+                    name: None,
+                    email: None,
+                    user_type: None,
+                }),
+            );
+        }
+        missing
+    }
+
+}
+
+/// Decodes user vaults of the baseline layout as well as of the current one, see TestamentCompat.
+/// The fields added after the baseline are optional here.
+#[derive(Debug, CandidType, Deserialize)]
+pub struct UserVaultCompat {
+    id: UserVaultID,
+    date_created: u64,
+    date_modified: u64,
+    secrets: BTreeMap<SecretID, Secret>,
+    key_box: KeyBox,
+    testaments: BTreeMap<TestamentID, TestamentCompat>,
+    heirs: BTreeMap<Principal, User>,
+    secret_history: Option<BTreeMap<SecretID, Vec<SecretVersion>>>,
+    trash: Option<TrashCompat>,
+}
+
+impl From<UserVaultCompat> for UserVault {
+    fn from(uv: UserVaultCompat) -> Self {
+        Self {
+            id: uv.id,
+            date_created: uv.date_created,
+            date_modified: uv.date_modified,
+            secrets: uv.secrets,
+            key_box: uv.key_box,
+            testaments: uv
+                .testaments
+                .into_iter()
+                .map(|(id, t)| (id, Testament::from(t)))
+                .collect(),
+            heirs: uv.heirs,
+            secret_history: uv.secret_history.unwrap_or_default(),
+            trash: uv.trash.map(Trash::from).unwrap_or_default(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::smart_vaults::testament::{HeirAllocation, ReleaseState, StageUnlock};
     use std::thread;
 
+    fn heir(id: Principal) -> User {
+        User::from(AddUserArgs {
+            id,
+            name: None,
+            email: None,
+            user_type: None,
+        })
+    }
+
     #[test]
     fn utest_user_vault_create_uservault() {
         // Create empty user_vault
@@ -401,7 +877,14 @@ mod tests {
         // Remove secret
         modified_before_update = user_vault.date_modified;
         created_before_update = user_vault.date_created;
-        assert_eq!(user_vault.remove_secret(secret.id()), Ok(()));
+        assert_eq!(
+            user_vault.remove_secret(secret.id()),
+            Ok(SecretRemovalReport {
+                secret_id: secret.id().clone(),
+                removed_key_box_entry: false,
+                testaments: Vec::new(),
+            })
+        );
         assert_eq!(
             user_vault.secrets().len(),
             0,
@@ -430,4 +913,501 @@ mod tests {
             modified_before_update
         );
     }
+
+    #[test]
+    fn utest_user_vault_heir_integrity() {
+        let mut user_vault: UserVault = UserVault::new();
+        let bob = Principal::from_slice(&[2; 29]);
+        let carol = Principal::from_slice(&[3; 29]);
+        let mut testament = Testament::new("testament".to_string());
+        testament.add_heir(bob);
+        testament.add_heir(carol);
+
+        // testaments can only name heirs of the address book
+        assert_eq!(
+            user_vault.add_testament(testament.clone()),
+            Err(SmartVaultErr::InvalidTestament(vec![
+                TestamentProblem::HeirNotInAddressBook(bob.to_string()),
+                TestamentProblem::HeirNotInAddressBook(carol.to_string()),
+            ]))
+        );
+        user_vault.add_heir(heir(bob)).unwrap();
+        user_vault.add_heir(heir(carol)).unwrap();
+        assert!(user_vault.add_testament(testament.clone()).is_ok());
+
+        // heirs cannot be removed as long as a testament names them
+        assert_eq!(
+            user_vault.remove_heir(&bob),
+            Err(SmartVaultErr::HeirIsPartOfTestaments(vec![
+                "testament".to_string()
+            ]))
+        );
+        testament.remove_heir(&bob);
+        user_vault.update_testament(testament).unwrap();
+        assert!(user_vault.remove_heir(&bob).is_ok());
+    }
+
+    #[test]
+    fn utest_user_vault_testament_validation() {
+        let mut user_vault: UserVault = UserVault::new();
+        let bob = Principal::from_slice(&[2; 29]);
+        user_vault.add_heir(heir(bob)).unwrap();
+        let secret: Secret = Secret::new_test_instance();
+        let sid = secret.id().clone();
+        user_vault.add_secret(secret).unwrap();
+
+        // all problems are reported at once
+        let mut testament = Testament::new("testament".to_string());
+        testament.add_secret(sid.clone());
+        testament.add_secret("unknown".to_string());
+        assert_eq!(
+            user_vault.add_testament(testament.clone()),
+            Err(SmartVaultErr::InvalidTestament(vec![
+                TestamentProblem::NoHeirs,
+                TestamentProblem::KeyBoxEntryMissing(sid.clone()),
+                TestamentProblem::SecretDoesNotExist("unknown".to_string()),
+                TestamentProblem::KeyBoxEntryMissing("unknown".to_string()),
+            ]))
+        );
+
+        // the testator cannot be an heir
+        testament.add_heir(*testament.testator());
+        testament.remove_secret(&"unknown".to_string());
+        testament
+            .key_box_mut()
+            .insert(sid, SecretSymmetricCryptoMaterial::default());
+        assert_eq!(
+            user_vault.add_testament(testament.clone()),
+            Err(SmartVaultErr::InvalidTestament(vec![
+                TestamentProblem::InvalidHeir(testament.testator().to_string())
+            ]))
+        );
+        let testator = *testament.testator();
+        testament.remove_heir(&testator);
+        testament.add_heir(bob);
+        assert!(user_vault.add_testament(testament.clone()).is_ok());
+        assert_eq!(
+            user_vault.add_testament(testament.clone()),
+            Err(SmartVaultErr::TestamentAlreadyExists(
+                "testament".to_string()
+            ))
+        );
+
+        // updates are validated the same way
+        testament.remove_heir(&bob);
+        assert_eq!(
+            user_vault.update_testament(testament.clone()).unwrap_err(),
+            SmartVaultErr::InvalidTestament(vec![TestamentProblem::NoHeirs])
+        );
+        assert_eq!(
+            user_vault
+                .update_testament(Testament::new("unknown".to_string()))
+                .unwrap_err(),
+            SmartVaultErr::TestamentDoesNotExist("unknown".to_string())
+        );
+
+        // the testator cannot be changed by an update
+        let mut foreign = testament.clone();
+        foreign.add_heir(bob);
+        foreign.set_testator(Principal::from_slice(&[9; 29]));
+        let updated = user_vault.update_testament(foreign).unwrap();
+        assert_eq!(*updated.testator(), testator);
+        assert_eq!(
+            *user_vault.get_testament(testament.id()).unwrap().testator(),
+            testator
+        );
+    }
+
+    #[test]
+    fn utest_user_vault_rearm_validation() {
+        let mut user_vault: UserVault = UserVault::new();
+        let bob = Principal::from_slice(&[2; 29]);
+        user_vault.add_heir(heir(bob)).unwrap();
+        let secret: Secret = Secret::new_test_instance();
+        let sid = secret.id().clone();
+        user_vault.add_secret(secret).unwrap();
+
+        let mut testament = Testament::new("testament".to_string());
+        testament.add_heir(bob);
+        testament.add_secret(sid.clone());
+        testament
+            .key_box_mut()
+            .insert(sid.clone(), SecretSymmetricCryptoMaterial::default());
+        user_vault.add_testament(testament.clone()).unwrap();
+        user_vault
+            .get_testament_mut(testament.id())
+            .unwrap()
+            .set_release_state(ReleaseState::Released { released_at: 1 });
+
+        // the key box of the next epoch needs to be complete
+        assert_eq!(
+            user_vault
+                .rearm_testament(testament.id(), BTreeMap::new(), BTreeMap::new(), None, 2)
+                .unwrap_err(),
+            SmartVaultErr::InvalidTestament(vec![TestamentProblem::KeyBoxEntryMissing(
+                sid.clone()
+            )])
+        );
+        let unchanged = user_vault.get_testament(testament.id()).unwrap();
+        assert_eq!(unchanged.key_epoch(), &0);
+        assert!(*unchanged.condition_status());
+
+        let key_box = BTreeMap::from([(sid, SecretSymmetricCryptoMaterial::default())]);
+        let rearmed = user_vault
+            .rearm_testament(testament.id(), key_box, BTreeMap::new(), None, 2)
+            .unwrap();
+        assert_eq!(rearmed.key_epoch(), &1);
+        assert_eq!(rearmed.release_state(), &ReleaseState::Armed);
+    }
+
+    #[test]
+    fn utest_user_vault_add_missing_heirs() {
+        let mut user_vault: UserVault = UserVault::new();
+        let bob = Principal::from_slice(&[2; 29]);
+        let mut testament = Testament::new("testament".to_string());
+        testament.add_heir(bob);
+        // testaments persisted before the address book was enforced
+        user_vault
+            .testaments
+            .insert(testament.id().clone(), testament);
+
+        assert_eq!(user_vault.add_missing_heirs(), vec![bob]);
+        assert!(user_vault.get_heir(&bob).is_ok());
+        assert!(user_vault.add_missing_heirs().is_empty());
+    }
+
+    #[test]
+    fn utest_user_vault_secret_history() {
+        let mut user_vault: UserVault = UserVault::new();
+        let mut secret: Secret = Secret::new_test_instance();
+        let sid = secret.id().clone();
+        user_vault.add_secret(secret.clone()).unwrap();
+        let original_key = SecretSymmetricCryptoMaterial {
+            iv: vec![1],
+            ..Default::default()
+        };
+        user_vault.key_box_mut().insert(sid.clone(), original_key);
+        assert!(user_vault.get_secret_history(&sid).unwrap().is_empty());
+
+        // every update keeps the previous version with its key box entry
+        secret.set_name("renamed".to_string());
+        user_vault.update_secret(secret.clone()).unwrap();
+        user_vault.key_box_mut().get_mut(&sid).unwrap().iv = vec![2];
+        let history = user_vault.get_secret_history(&sid).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].version, 1);
+        assert_eq!(history[0].secret.name(), None);
+        assert_eq!(history[0].symmetric_crypto_material.as_ref().unwrap().iv, vec![1]);
+
+        // restoring brings back the secret and its key, and keeps the current version
+        let restored = user_vault.restore_secret_version(&sid, 1).unwrap();
+        assert_eq!(restored.name(), None);
+        assert_eq!(user_vault.key_box().get(&sid).unwrap().iv, vec![1]);
+        let history = user_vault.get_secret_history(&sid).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].secret.name(), Some("renamed".to_string()));
+        assert_eq!(
+            user_vault.restore_secret_version(&sid, 42),
+            Err(SmartVaultErr::SecretVersionDoesNotExist(42))
+        );
+
+        // the history is bounded, the oldest versions are dropped
+        for _ in 0..MAX_SECRET_VERSIONS {
+            user_vault.update_secret(secret.clone()).unwrap();
+        }
+        let history = user_vault.get_secret_history(&sid).unwrap();
+        assert_eq!(history.len(), MAX_SECRET_VERSIONS);
+        assert_eq!(history[0].version, 3);
+
+        // and removed together with the secret
+        user_vault.remove_secret(&sid).unwrap();
+        assert_eq!(
+            user_vault.get_secret_history(&sid),
+            Err(SmartVaultErr::SecretDoesNotExist(sid))
+        );
+    }
+
+    #[test]
+    fn utest_user_vault_trash() {
+        let mut user_vault: UserVault = UserVault::new();
+        let bob = Principal::from_slice(&[2; 29]);
+        user_vault.add_heir(heir(bob)).unwrap();
+        let secret: Secret = Secret::new_test_instance();
+        let sid = secret.id().clone();
+        user_vault.add_secret(secret.clone()).unwrap();
+        user_vault
+            .key_box_mut()
+            .insert(sid.clone(), SecretSymmetricCryptoMaterial::default());
+        let mut testament = Testament::new("testament".to_string());
+        testament.add_heir(bob);
+        testament.add_secret(sid.clone());
+        testament
+            .key_box_mut()
+            .insert(sid.clone(), SecretSymmetricCryptoMaterial::default());
+        user_vault.add_testament(testament.clone()).unwrap();
+
+        // removed secrets are hidden from the heirs
+        user_vault.remove_secret(&sid).unwrap();
+        assert!(user_vault.key_box().get(&sid).is_none());
+        let for_heirs = user_vault.get_testament_for_heir(testament.id(), &bob).unwrap();
+        assert!(for_heirs.secrets().is_empty());
+        assert!(for_heirs.key_box().is_empty());
+
+        // until they are restored
+        let secret_id = TrashItemID::Secret(sid.clone());
+        user_vault.restore_from_trash(&secret_id).unwrap();
+        assert_eq!(user_vault.get_secret(&sid), Ok(&secret));
+        assert!(user_vault.key_box().get(&sid).is_some());
+        assert_eq!(
+            user_vault
+                .get_testament_for_heir(testament.id(), &bob)
+                .unwrap()
+                .secrets()
+                .len(),
+            1
+        );
+        assert_eq!(
+            user_vault.restore_from_trash(&secret_id).unwrap_err(),
+            SmartVaultErr::TrashItemDoesNotExist(sid)
+        );
+
+        // restored testaments need their heirs in the address book
+        let testament_id = TrashItemID::Testament(testament.id().clone());
+        user_vault.remove_testament(testament.id()).unwrap();
+        assert_eq!(user_vault.trash().list().len(), 1);
+        user_vault.remove_heir(&bob).unwrap();
+        assert_eq!(
+            user_vault.restore_from_trash(&testament_id).unwrap_err(),
+            SmartVaultErr::InvalidTestament(vec![TestamentProblem::HeirNotInAddressBook(
+                bob.to_string()
+            )])
+        );
+        assert_eq!(user_vault.trash().list().len(), 1);
+        user_vault.trash_mut().empty();
+        assert!(user_vault.trash().is_empty());
+    }
+
+    #[test]
+    fn utest_user_vault_remove_secret_cascade() {
+        let mut user_vault: UserVault = UserVault::new();
+        let bob = Principal::from_slice(&[2; 29]);
+        user_vault.add_heir(heir(bob)).unwrap();
+        let secret: Secret = Secret::new_test_instance();
+        let sid = secret.id().clone();
+        user_vault.add_secret(secret).unwrap();
+        user_vault
+            .key_box_mut()
+            .insert(sid.clone(), SecretSymmetricCryptoMaterial::default());
+        let mut with_secret = Testament::new("with secret".to_string());
+        with_secret.add_heir(bob);
+        with_secret.add_secret(sid.clone());
+        with_secret.key_box_mut().insert(
+            sid.clone(),
+            SecretSymmetricCryptoMaterial {
+                iv: vec![7],
+                ..Default::default()
+            },
+        );
+        user_vault.add_testament(with_secret).unwrap();
+        let mut without_secret = Testament::new("without secret".to_string());
+        without_secret.add_heir(bob);
+        user_vault.add_testament(without_secret).unwrap();
+
+        // the removal reaches the vault key box and every testament
+        assert_eq!(
+            user_vault.remove_secret(&sid),
+            Ok(SecretRemovalReport {
+                secret_id: sid.clone(),
+                removed_key_box_entry: true,
+                testaments: vec!["with secret".to_string()],
+            })
+        );
+        assert!(user_vault.key_box().is_empty());
+        let testament = user_vault.get_testament(&"with secret".to_string()).unwrap();
+        assert!(testament.secrets().is_empty());
+        assert!(testament.key_box().is_empty());
+
+        // restoring the secret puts it back into the testaments
+        user_vault
+            .restore_from_trash(&TrashItemID::Secret(sid.clone()))
+            .unwrap();
+        let testament = user_vault.get_testament(&"with secret".to_string()).unwrap();
+        assert!(testament.secrets().contains(&sid));
+        assert_eq!(testament.key_box().get(&sid).unwrap().iv, vec![7]);
+
+        // references left behind by older versions are cleaned up
+        user_vault.secrets.remove(&sid);
+        assert_eq!(
+            user_vault.remove_dangling_secret_references(),
+            vec!["with secret".to_string()]
+        );
+        assert!(user_vault.key_box().is_empty());
+        assert!(user_vault.remove_dangling_secret_references().is_empty());
+    }
+
+    #[test]
+    fn utest_user_vault_allocations() {
+        let mut user_vault: UserVault = UserVault::new();
+        let bob = Principal::from_slice(&[2; 29]);
+        let carol = Principal::from_slice(&[3; 29]);
+        user_vault.add_heir(heir(bob)).unwrap();
+        user_vault.add_heir(heir(carol)).unwrap();
+        let house: Secret = Secret::new_test_instance();
+        let hid = house.id().clone();
+        user_vault.add_secret(house).unwrap();
+        thread::sleep(std::time::Duration::from_millis(1)); // test secrets get their id from the current time
+        let seed: Secret = Secret::new_test_instance();
+        let seed_id = seed.id().clone();
+        user_vault.add_secret(seed).unwrap();
+
+        let mut testament = Testament::new("testament".to_string());
+        testament.add_heir(bob);
+        testament.add_secret(hid.clone());
+        testament.add_secret(seed_id.clone());
+        let allocation = HeirAllocation {
+            secrets: HashSet::from([hid.clone(), "unknown".to_string()]),
+            key_box: BTreeMap::new(),
+        };
+        testament.allocations_mut().insert(bob, allocation.clone());
+        testament.allocations_mut().insert(carol, allocation);
+
+        // there are allocations, so only the allocations need key box entries
+        assert_eq!(
+            user_vault.add_testament(testament.clone()).unwrap_err(),
+            SmartVaultErr::InvalidTestament(vec![
+                TestamentProblem::AllocationKeyBoxEntryMissing {
+                    heir: bob.to_string(),
+                    secret_id: hid.clone(),
+                },
+                TestamentProblem::AllocatedSecretNotInTestament {
+                    heir: bob.to_string(),
+                    secret_id: "unknown".to_string(),
+                },
+                TestamentProblem::AllocationForNonHeir(carol.to_string()),
+                TestamentProblem::AllocationKeyBoxEntryMissing {
+                    heir: carol.to_string(),
+                    secret_id: hid.clone(),
+                },
+                TestamentProblem::AllocatedSecretNotInTestament {
+                    heir: carol.to_string(),
+                    secret_id: "unknown".to_string(),
+                },
+            ])
+        );
+        testament.add_heir(carol);
+        testament.allocations_mut().insert(
+            bob,
+            HeirAllocation {
+                secrets: HashSet::from([hid.clone()]),
+                key_box: BTreeMap::from([(hid.clone(), SecretSymmetricCryptoMaterial::default())]),
+            },
+        );
+        testament.allocations_mut().insert(
+            carol,
+            HeirAllocation {
+                secrets: HashSet::from([seed_id.clone()]),
+                key_box: BTreeMap::from([(
+                    seed_id.clone(),
+                    SecretSymmetricCryptoMaterial::default(),
+                )]),
+            },
+        );
+        user_vault.add_testament(testament.clone()).unwrap();
+
+        // each heir only gets their share
+        let for_bob = user_vault.get_testament_for_heir(testament.id(), &bob).unwrap();
+        assert_eq!(for_bob.secrets(), &HashSet::from([hid.clone()]));
+        let for_carol = user_vault.get_testament_for_heir(testament.id(), &carol).unwrap();
+        assert_eq!(for_carol.secrets(), &HashSet::from([seed_id.clone()]));
+        assert!(for_carol.key_box().contains_key(&seed_id));
+
+        // removing and restoring a secret keeps the allocation
+        user_vault.remove_secret(&seed_id).unwrap();
+        let for_carol = user_vault.get_testament_for_heir(testament.id(), &carol).unwrap();
+        assert!(for_carol.secrets().is_empty());
+        user_vault
+            .restore_from_trash(&TrashItemID::Secret(seed_id.clone()))
+            .unwrap();
+        let for_carol = user_vault.get_testament_for_heir(testament.id(), &carol).unwrap();
+        assert_eq!(for_carol.secrets(), &HashSet::from([seed_id.clone()]));
+        assert!(for_carol.key_box().contains_key(&seed_id));
+
+        // an heir without an allocation gets an empty share, a non-heir nothing at all
+        let dave = Principal::from_slice(&[4; 29]);
+        assert_eq!(
+            user_vault
+                .get_testament_for_heir(testament.id(), &dave)
+                .unwrap_err(),
+            SmartVaultErr::NoTestamentsForHeir(dave.to_string())
+        );
+        user_vault.add_heir(heir(dave)).unwrap();
+        testament.add_heir(dave);
+        user_vault.update_testament(testament.clone()).unwrap();
+        let for_dave = user_vault
+            .get_testament_for_heir(testament.id(), &dave)
+            .unwrap();
+        assert!(for_dave.secrets().is_empty());
+        assert!(for_dave.key_box().is_empty());
+        assert!(for_dave.allocations().is_empty());
+    }
+
+    #[test]
+    fn utest_user_vault_release_stages() {
+        let mut user_vault: UserVault = UserVault::new();
+        let bob = Principal::from_slice(&[2; 29]);
+        user_vault.add_heir(heir(bob)).unwrap();
+        let secret: Secret = Secret::new_test_instance();
+        let sid = secret.id().clone();
+        user_vault.add_secret(secret).unwrap();
+
+        let mut testament = Testament::new("testament".to_string());
+        testament.add_heir(bob);
+        testament.add_secret(sid.clone());
+        testament
+            .key_box_mut()
+            .insert(sid.clone(), SecretSymmetricCryptoMaterial::default());
+        let unlock = StageUnlock::AfterRelease { delay_secs: 60 };
+        for secrets in [vec![sid.clone()], vec![sid.clone(), "unknown".to_string()]] {
+            testament.release_stages_mut().push(ReleaseStage {
+                name: None,
+                secrets: secrets.into_iter().collect(),
+                unlock: unlock.clone(),
+            });
+        }
+
+        // staged secrets need to be part of the testament, and only of one stage
+        assert_eq!(
+            user_vault.add_testament(testament.clone()).unwrap_err(),
+            SmartVaultErr::InvalidTestament(vec![
+                TestamentProblem::SecretInSeveralStages(sid.clone()),
+                TestamentProblem::StagedSecretNotInTestament("unknown".to_string()),
+            ])
+        );
+        testament.release_stages_mut().pop();
+        user_vault.add_testament(testament.clone()).unwrap();
+
+        // a removed and restored secret keeps its schedule
+        user_vault.remove_secret(&sid).unwrap();
+        let stages = user_vault
+            .get_testament(testament.id())
+            .unwrap()
+            .release_stages();
+        assert!(stages[0].secrets.is_empty());
+        user_vault
+            .testaments_mut()
+            .get_mut(testament.id())
+            .unwrap()
+            .release_stages_mut()
+            .clear();
+        user_vault
+            .restore_from_trash(&TrashItemID::Secret(sid.clone()))
+            .unwrap();
+        let stages = user_vault
+            .get_testament(testament.id())
+            .unwrap()
+            .release_stages();
+        assert_eq!(stages.len(), 1);
+        assert!(stages[0].secrets.contains(&sid));
+        assert_eq!(stages[0].unlock, unlock);
+    }
 }
@@ -1,86 +1,439 @@
 use ic_cdk_timers::TimerId;
 use std::{
     cell::RefCell,
+    collections::BTreeMap,
     time::Duration,
 };
-use candid::Principal;
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use crate::common::admin::verify_caller_is_admin;
 use crate::common::error::SmartVaultErr;
+use crate::common::user::User;
 use crate::common::uuid::UUID;
+use crate::notifications::outbox::{self, NotificationKind};
+use crate::smart_vaults::condition_schedule::ConditionSchedule;
 use crate::smart_vaults::master_vault::MasterVault;
-use crate::smart_vaults::smart_vault::{MASTERVAULT, USER_REGISTRY};
+use crate::smart_vaults::smart_vault::{CONDITION_SCHEDULE, MASTERVAULT, USER_REGISTRY};
+use crate::smart_vaults::testament::TestamentID;
 use crate::smart_vaults::user_registry::UserRegistry;
 use crate::utils::time;
 
+pub const DEFAULT_INTERVAL_SECS: u64 = 60;
+// Shorter intervals would burn cycles without any benefit
+const MIN_INTERVAL_SECS: u64 = 10;
+// Upper bound of the evaluations per tick, the rest is continued right after the tick
+const MAX_EVALUATIONS_PER_TICK: usize = 100;
+// A failed evaluation is retried after this delay
+const RETRY_DELAY_SECS: u64 = 3600;
+
+pub type ConditionTimerID = u64;
+
+/// A persisted schedule for checking the login date condition.
+/// Survives upgrades and is re-armed in post_upgrade.
+///
+/// The evaluations of a tick run in messages of their own. The tick counts them as pending,
+/// together with its continuation, and its outcome is set once none is pending anymore.
+/// An evaluation which traps stays pending, so the next tick sets the outcome instead.
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq)]
+pub struct ConditionTimer {
+    pub id: ConditionTimerID,
+    pub interval_secs: u64,
+    pub next_run: Option<u64>,
+    pub last_run: Option<u64>,
+    pub last_outcome: Option<ConditionTimerOutcome>,
+    pub pending_evaluations: u32,
+    pub pending_failures: Vec<String>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Clone, PartialEq)]
+pub enum ConditionTimerOutcome {
+    Success,
+    Failure(String),
+}
+
+impl ConditionTimer {
+    pub fn new(id: ConditionTimerID, interval_secs: u64) -> Self {
+        Self {
+            id,
+            interval_secs,
+            next_run: None,
+            last_run: None,
+            last_outcome: None,
+            pending_evaluations: 0,
+            pending_failures: Vec::new(),
+        }
+    }
+
+    /// Adds the evaluations handed over by the tick.
+    fn add_pending(&mut self, count: u32) {
+        self.pending_evaluations += count;
+    }
+
+    /// Completes a pending evaluation of the tick, with the failure if it failed.
+    /// The last one sets the outcome of the tick.
+    fn complete_pending(&mut self, failure: Option<String>) {
+        self.pending_failures.extend(failure);
+        self.pending_evaluations = self.pending_evaluations.saturating_sub(1);
+        if self.pending_evaluations == 0 {
+            self.conclude_tick();
+        }
+    }
+
+    /// Sets the outcome of the tick, counting the evaluations still pending as failed.
+    fn conclude_tick(&mut self) {
+        let mut failures = std::mem::take(&mut self.pending_failures);
+        if self.pending_evaluations > 0 {
+            failures.push(format!(
+                "{} evaluations did not complete",
+                self.pending_evaluations
+            ));
+            self.pending_evaluations = 0;
+        }
+        self.last_outcome = Some(if failures.is_empty() {
+            ConditionTimerOutcome::Success
+        } else {
+            ConditionTimerOutcome::Failure(failures.join(", "))
+        });
+    }
+}
+
 thread_local! {
-    // The global vector to keep multiple timer IDs.
-    static TIMER_IDS: RefCell<Vec<TimerId>> = RefCell::new(Vec::new());
+    // The timer schedules, part of the persisted heap state.
+    pub static CONDITION_TIMERS: RefCell<BTreeMap<ConditionTimerID, ConditionTimer>> = RefCell::new(BTreeMap::new());
+
+    // The armed ic timers. These do not survive upgrades and are re-armed from CONDITION_TIMERS.
+    static TIMER_IDS: RefCell<BTreeMap<ConditionTimerID, TimerId>> = RefCell::new(BTreeMap::new());
+
+    // Whether a continuation of a tick is already armed, it belongs to the tick which armed it
+    static CONTINUATION_ARMED: RefCell<bool> = RefCell::new(false);
 }
 
+/// Starts an additional periodic check of the login date condition.
 #[ic_cdk_macros::update]
-fn start_with_interval_secs(secs: u64) {
-    let secs = Duration::from_secs(secs);
+#[candid_method(update)]
+pub fn start_condition_timer(interval_secs: u64) -> Result<ConditionTimer, SmartVaultErr> {
+    verify_caller_is_admin()?;
+    verify_interval(interval_secs)?;
+    Ok(add_condition_timer(interval_secs))
+}
 
-    // Schedule a new periodic task to increment the counter.
-    let timer_id = ic_cdk_timers::set_timer_interval(secs, periodic_task);
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn update_condition_timer(
+    id: ConditionTimerID,
+    interval_secs: u64,
+) -> Result<ConditionTimer, SmartVaultErr> {
+    verify_caller_is_admin()?;
+    verify_interval(interval_secs)?;
+    CONDITION_TIMERS.with(|ct| -> Result<(), SmartVaultErr> {
+        let mut condition_timers = ct.borrow_mut();
+        let condition_timer = condition_timers
+            .get_mut(&id)
+            .ok_or_else(|| SmartVaultErr::ConditionTimerDoesNotExist(id.to_string()))?;
+        condition_timer.interval_secs = interval_secs;
+        Ok(())
+    })?;
+    disarm_timer(id);
+    arm_timer(id, interval_secs);
+    get_condition_timer(id)
+}
 
-    // Add the timer ID to the global vector.
-    TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().push(timer_id));
+#[ic_cdk_macros::update]
+#[candid_method(update)]
+pub fn cancel_condition_timer(id: ConditionTimerID) -> Result<(), SmartVaultErr> {
+    verify_caller_is_admin()?;
+    CONDITION_TIMERS
+        .with(|ct| ct.borrow_mut().remove(&id))
+        .ok_or_else(|| SmartVaultErr::ConditionTimerDoesNotExist(id.to_string()))?;
+    disarm_timer(id);
+    Ok(())
 }
 
+#[ic_cdk_macros::query]
+#[candid_method(query)]
+pub fn get_condition_timers() -> Result<Vec<ConditionTimer>, SmartVaultErr> {
+    verify_caller_is_admin()?;
+    Ok(CONDITION_TIMERS.with(|ct| ct.borrow().values().cloned().collect()))
+}
 
 // This function is called by the init macro from lib.rs
 pub fn init_condition() {
-    start_with_interval_secs(60);
+    add_condition_timer(DEFAULT_INTERVAL_SECS);
 }
 
-fn periodic_task() {
-    // read last login date of all users
-    let users = USER_REGISTRY.with(
-        |ur: &RefCell<UserRegistry>| -> Vec<(Principal, u64)> {
-            let user_registry = ur.borrow();
-            user_registry.get_all_last_login_dates()
-        },
-    );
+// This function is called by the post_upgrade hook, after the heap state has been restored
+pub fn rearm_timers() {
+    let condition_timers: Vec<(ConditionTimerID, u64)> = CONDITION_TIMERS.with(|ct| {
+        ct.borrow()
+            .values()
+            .map(|t| (t.id, t.interval_secs))
+            .collect()
+    });
+    for (id, interval_secs) in condition_timers {
+        arm_timer(id, interval_secs);
+    }
+}
+
+fn verify_interval(interval_secs: u64) -> Result<(), SmartVaultErr> {
+    if interval_secs < MIN_INTERVAL_SECS {
+        return Err(SmartVaultErr::InvalidConditionTimerInterval(interval_secs));
+    }
+    Ok(())
+}
+
+fn get_condition_timer(id: ConditionTimerID) -> Result<ConditionTimer, SmartVaultErr> {
+    CONDITION_TIMERS
+        .with(|ct| ct.borrow().get(&id).cloned())
+        .ok_or_else(|| SmartVaultErr::ConditionTimerDoesNotExist(id.to_string()))
+}
+
+fn add_condition_timer(interval_secs: u64) -> ConditionTimer {
+    let id = CONDITION_TIMERS.with(|ct| {
+        let mut condition_timers = ct.borrow_mut();
+        let id = condition_timers.keys().next_back().map_or(0, |id| id + 1);
+        condition_timers.insert(id, ConditionTimer::new(id, interval_secs));
+        id
+    });
+    arm_timer(id, interval_secs);
+    CONDITION_TIMERS.with(|ct| ct.borrow()[&id].clone())
+}
+
+fn disarm_timer(id: ConditionTimerID) {
+    if let Some(timer_id) = TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().remove(&id)) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+}
 
+fn arm_timer(id: ConditionTimerID, interval_secs: u64) {
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval_secs), move || {
+        run_periodic_task(id)
+    });
+    TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().insert(id, timer_id));
+
+    let next_run = time::get_current_time() + interval_secs * 1000000000;
+    CONDITION_TIMERS.with(|ct| {
+        if let Some(condition_timer) = ct.borrow_mut().get_mut(&id) {
+            condition_timer.next_run = Some(next_run);
+        }
+    });
+}
+
+fn run_periodic_task(id: ConditionTimerID) {
+    let now = time::get_current_time();
+    with_condition_timer(id, |condition_timer| {
+        // Whatever the previous tick left pending did not complete, e.g. because it trapped
+        if condition_timer.pending_evaluations > 0 {
+            condition_timer.conclude_tick();
+        }
+        condition_timer.last_run = Some(now);
+        condition_timer.next_run = Some(now + condition_timer.interval_secs * 1000000000);
+    });
+
+    let pending = periodic_task(id);
+    with_condition_timer(id, |condition_timer| {
+        if pending == 0 {
+            condition_timer.conclude_tick();
+        } else {
+            condition_timer.add_pending(pending);
+        }
+    });
+}
+
+/// Runs the remaining evaluations of a tick which hit MAX_EVALUATIONS_PER_TICK.
+fn run_continuation(timer_id: ConditionTimerID) {
+    CONTINUATION_ARMED.with(|armed| *armed.borrow_mut() = false);
+    let pending = periodic_task(timer_id);
+    with_condition_timer(timer_id, |condition_timer| {
+        condition_timer.add_pending(pending);
+        condition_timer.complete_pending(None);
+    });
+}
+
+/// Returns whether the continuation was armed by this tick.
+fn arm_continuation(timer_id: ConditionTimerID) -> bool {
+    let already_armed = CONTINUATION_ARMED.with(|armed| armed.replace(true));
+    if !already_armed {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || run_continuation(timer_id));
+    }
+    !already_armed
+}
+
+fn with_condition_timer(id: ConditionTimerID, f: impl FnOnce(&mut ConditionTimer)) {
+    CONDITION_TIMERS.with(|ct| {
+        if let Some(condition_timer) = ct.borrow_mut().get_mut(&id) {
+            f(condition_timer);
+        }
+    });
+}
+
+/// Hands the testaments whose deadline has passed over to their evaluation.
+/// Every testament is evaluated by a timer of its own, which runs in a message of its own,
+/// so a testament which traps during its evaluation only rolls back its own evaluation.
+/// Before that, its entry is moved past the deadline by the retry delay. This move is
+/// committed with the tick, so a trapping testament is retried later instead of being
+/// picked up again by every tick.
+/// Returns how many evaluations and continuations were handed over.
+fn periodic_task(timer_id: ConditionTimerID) -> u32 {
     let current_time: u64 = time::get_current_time();
 
-    // iterate over all existing users
-    for (principal, last_login_date) in &users {
+    // One more than allowed is read to find out whether work is left for a continuation
+    let mut due = CONDITION_SCHEDULE.with(|cs: &RefCell<ConditionSchedule>| {
+        cs.borrow().get_due(current_time, MAX_EVALUATIONS_PER_TICK + 1)
+    });
+    let mut pending = 0;
+    if due.len() > MAX_EVALUATIONS_PER_TICK {
+        due.truncate(MAX_EVALUATIONS_PER_TICK);
+        if arm_continuation(timer_id) {
+            pending += 1;
+        }
+    }
 
-        // Get user vault id
-        let user_vault_id = USER_REGISTRY.with(
-            |ur: &RefCell<UserRegistry>| -> Result<UUID, SmartVaultErr> {
-                let user_registry = ur.borrow();
-                let user = user_registry.get_user(&principal)?;
-                user.user_vault_id.ok_or_else(|| SmartVaultErr::UserVaultDoesNotExist(principal.to_text()))
-            },
-        );
-        if let Err(e) = &user_vault_id {
-            ic_cdk::println!("ERROR: {:?}", e);
+    for (testament_id, testator) in due {
+        CONDITION_SCHEDULE.with(|cs: &RefCell<ConditionSchedule>| {
+            cs.borrow_mut().schedule(
+                &testament_id,
+                testator,
+                current_time + RETRY_DELAY_SECS * 1000000000,
+            )
+        });
+        ic_cdk_timers::set_timer(Duration::ZERO, move || {
+            run_evaluation(timer_id, testament_id, testator)
+        });
+        pending += 1;
+    }
+    pending
+}
+
+/// Evaluates a single testament and schedules its next evaluation.
+/// A failing testament is logged and stays scheduled for the retry.
+/// Either way, the evaluation is completed in the tick of the condition timer.
+fn run_evaluation(timer_id: ConditionTimerID, testament_id: TestamentID, testator: Principal) {
+    let current_time: u64 = time::get_current_time();
+    let next_evaluation = match evaluate_testament(&testament_id, &testator, current_time) {
+        Ok(next_evaluation) => next_evaluation,
+        // The testament or its testator is gone, so there is nothing left to evaluate
+        Err(SmartVaultErr::TestamentDoesNotExist(_))
+        | Err(SmartVaultErr::UserDoesNotExist(_))
+        | Err(SmartVaultErr::UserVaultDoesNotExist(_)) => None,
+        Err(e) => {
+            ic_cdk::println!(
+                "ERROR: evaluating testament {:?} failed: {:?}",
+                testament_id,
+                e
+            );
+            let failure = format!("{}: {}", testament_id, e);
+            with_condition_timer(timer_id, |condition_timer| {
+                condition_timer.complete_pending(Some(failure))
+            });
+            return;
         }
+    };
 
-        MASTERVAULT.with(|ms: &RefCell<MasterVault>| -> () {
+    CONDITION_SCHEDULE.with(|cs: &RefCell<ConditionSchedule>| {
+        let mut condition_schedule = cs.borrow_mut();
+        match next_evaluation {
+            Some(deadline) => condition_schedule.schedule(&testament_id, testator, deadline),
+            None => condition_schedule.unschedule(&testament_id),
+        }
+    });
+    with_condition_timer(timer_id, |condition_timer| {
+        condition_timer.complete_pending(None)
+    });
+}
+
+/// Evaluates the condition of a single testament, reminds the testator if a reminder
+/// milestone is reached and returns when the testament needs to be evaluated next.
+fn evaluate_testament(
+    testament_id: &TestamentID,
+    testator: &Principal,
+    current_time: u64,
+) -> Result<Option<u64>, SmartVaultErr> {
+    let user = USER_REGISTRY.with(
+        |ur: &RefCell<UserRegistry>| -> Result<User, SmartVaultErr> {
+            ur.borrow().get_user(testator)
+        },
+    )?;
+    let user_vault_id: UUID = user
+        .user_vault_id
+        .ok_or_else(|| SmartVaultErr::UserVaultDoesNotExist(testator.to_text()))?;
+
+    let (reminder, release_date, next_evaluation) = MASTERVAULT.with(
+        |ms: &RefCell<MasterVault>| -> Result<(Option<u8>, Option<u64>, Option<u64>), SmartVaultErr> {
             let mut master_vault = ms.borrow_mut();
+            let mut user_vault = master_vault.get_user_vault(&user_vault_id)?;
+            let testament = user_vault.get_testament_mut(testament_id)?;
 
-            // Get all testaments for principal
-            let testaments = master_vault.get_user_testament_list_mut(&user_vault_id.unwrap());
-            if let Err(e) = &testaments {
-                ic_cdk::println!("ERROR: {:?}", e);
+            let check_in = user.check_in.as_ref();
+            let release_state = testament.release_state().clone();
+            if testament.evaluate_condition(current_time, user.date_last_login, check_in) {
+                ic_cdk::println!("Condition of testament {:?} of user {:?} is met, condition status is set to true", testament_id, testator.to_text());
+                outbox::notify_release(testament);
             }
+            let reminder = testament.due_reminder(current_time, user.date_last_login);
+            let release_date = testament.inactivity_release_date(user.date_last_login);
+            let next_evaluation = testament.next_evaluation(current_time, user.date_last_login, check_in);
 
-            // Iterate over testaments and update condition status
-            for testament in testaments.unwrap() {
-
-                // Check last login date
-                let max_last_login_time: u64 = testament.condition_arg() * 1000000000; // in nanoseconds
-                if last_login_date < &current_time.saturating_sub(max_last_login_time) {
-                    // Last login date earlier than allowed, set condition status of all user testaments to true
-                    ic_cdk::println!("Last login date of user {:?} is older than {:?} seconds, condition status of all its testaments is set to true", principal.to_text(), testament.condition_arg());
-                    testament.set_condition_status(true);
-                } else {
-                    ic_cdk::println!("Last login date of user {:?} is NOT older than {:?} seconds!", principal.to_text(), testament.condition_arg());
-                }
+            // Vaults are read by value, so the changes need to be written back.
+            // Most evaluations change nothing, which spares writing the whole vault.
+            if reminder.is_some() || *testament.release_state() != release_state {
+                master_vault.save_user_vault(user_vault);
             }
-        });
+            Ok((reminder, release_date, next_evaluation))
+        },
+    )?;
+
+    // Remind the testator to log in before the testament gets released
+    if let (Some(milestone), Some(release_date)) = (reminder, release_date) {
+        outbox::notify(
+            *testator,
+            NotificationKind::InactivityReminder {
+                testament_id: testament_id.clone(),
+                milestone,
+                release_date,
+            },
+        );
     }
-}
\ No newline at end of file
+    Ok(next_evaluation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utest_condition_timer_outcome() {
+        let mut condition_timer = ConditionTimer::new(0, DEFAULT_INTERVAL_SECS);
+
+        // the outcome is set by the last pending evaluation, with all failures of the tick
+        condition_timer.add_pending(3);
+        condition_timer.complete_pending(Some("t1: failed".to_string()));
+        condition_timer.complete_pending(None);
+        assert_eq!(condition_timer.last_outcome, None);
+        condition_timer.complete_pending(Some("t2: failed".to_string()));
+        assert_eq!(
+            condition_timer.last_outcome,
+            Some(ConditionTimerOutcome::Failure(
+                "t1: failed, t2: failed".to_string()
+            ))
+        );
+
+        // the failures of a tick do not leak into the next one
+        condition_timer.add_pending(1);
+        condition_timer.complete_pending(None);
+        assert_eq!(
+            condition_timer.last_outcome,
+            Some(ConditionTimerOutcome::Success)
+        );
+
+        // evaluations which trapped never complete and fail the tick
+        condition_timer.add_pending(2);
+        condition_timer.complete_pending(None);
+        condition_timer.conclude_tick();
+        assert_eq!(
+            condition_timer.last_outcome,
+            Some(ConditionTimerOutcome::Failure(
+                "1 evaluations did not complete".to_string()
+            ))
+        );
+        assert_eq!(condition_timer.pending_evaluations, 0);
+    }
+}
@@ -1,4 +1,5 @@
 use colored::*;
+use tests::notifications::test_notifications;
 use tests::smart_vaults_testaments::test_smart_vaults_testaments;
 use tests::users::test_smart_vaults_users;
 
@@ -60,6 +61,9 @@ async fn main() -> Result<()> {
         "user" => {
             test_smart_vaults_users().await?;
         }
+        "notify" => {
+            test_notifications().await?;
+        }
         _ => {
             print_help_menu();
             return Ok(());
@@ -91,6 +95,11 @@ fn print_help_menu() {
         "sm".green(),
         sh(2)
     );
+    println!(
+        "   {}{} Test reminders and notifications",
+        "notify".green(),
+        sh(6)
+    );
 }
 
 fn sh(n: i32) -> String {
@@ -1,5 +1,6 @@
 pub mod div;
 pub mod encryption;
+pub mod notifications;
 pub mod smart_vaults_secrets;
 pub mod smart_vaults_testaments;
 pub mod users;
@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use colored::Colorize;
+use ic_agent::{identity::BasicIdentity, Agent, Identity};
+
+use crate::{
+    types::{
+        notification::{Notification, NotificationID, NotificationKind},
+        smart_vault_err::SmartVaultErr,
+        testament::{AddTestamentArgs, Condition},
+    },
+    utils::{
+        agent::{create_identity, get_dfx_agent_with_identity, make_call_with_agent, CallType},
+        dfx::call_backend_as_controller,
+        mock_server::MockServer,
+        testament::add_user_testament,
+        user::{add_heir, create_user, delete_user},
+    },
+};
+
+const MOCK_SERVER_PORT: u16 = 8459;
+const WEBHOOK_SERVER_PORT: u16 = 8460;
+// The condition timer ticks every 60 seconds and the delivery timer every 30 seconds,
+// so everything sent by a timer arrives within this time
+const TIMER_TIMEOUT: Duration = Duration::from_secs(180);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub async fn test_notifications() -> Result<()> {
+    println!(
+        "\n{}",
+        "Testing reminders and notifications".yellow().bold()
+    );
+    test_inactivity_reminder().await?;
+    test_heir_added_webhook().await?;
+    Ok(())
+}
+
+async fn test_inactivity_reminder() -> anyhow::Result<()> {
+    let mock_server = MockServer::start(MOCK_SERVER_PORT, 200).await?;
+    call_backend_as_controller(
+        "set_notification_endpoint",
+        &format!("(opt \"{}\")", mock_server.url),
+    )?;
+
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+    let new_user_1 = create_user(&a1).await?;
+
+    // Bob (heir)
+    let i2: BasicIdentity = create_identity();
+    let bob = i2.sender().unwrap();
+    let a2: Agent = get_dfx_agent_with_identity(i2).await?;
+    add_heir(&a1, &a2).await?;
+
+    // Alice is reminded after 10% of her inactivity period, i.e. 60 seconds after login
+    let ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament reminder".into(),
+        name: Some("Reminder".into()),
+        heirs: HashSet::from([bob]),
+        secrets: HashSet::new(),
+        key_box: BTreeMap::new(),
+        condition: Condition::LastLogin {
+            max_inactivity_secs: 600,
+        },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: Some(vec![10]),
+        allocations: None,
+        release_stages: None,
+    };
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+    assert!(get_notifications(&a1).await?.is_empty());
+
+    // wait for the login date condition timer to send the reminder
+    println!("   Waiting for the reminder...");
+    let started = Instant::now();
+    let notifications = loop {
+        let notifications = get_notifications(&a1).await?;
+        if !notifications.is_empty() {
+            break notifications;
+        }
+        wait_for_timers(started).await?;
+    };
+    assert_eq!(notifications.len(), 1);
+    assert!(matches!(
+        &notifications[0].kind,
+        NotificationKind::InactivityReminder { testament_id, milestone: 10, .. }
+            if *testament_id == testament.id
+    ));
+    println!("   Reminder is in the outbox");
+
+    // the reminder got pushed to the notification endpoint as well
+    while !mock_server
+        .bodies()
+        .iter()
+        .any(|body| body.contains(&testament.id))
+    {
+        wait_for_timers(started).await?;
+    }
+    println!("   Reminder was pushed to the notification endpoint");
+
+    // acknowledged notifications are gone
+    acknowledge_notifications(&a1, vec![notifications[0].id]).await?;
+    assert!(get_notifications(&a1).await?.is_empty());
+
+    // Cleanup
+    call_backend_as_controller("set_notification_endpoint", "(null)")?;
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_heir_added_webhook() -> anyhow::Result<()> {
+    let mock_server = MockServer::start(WEBHOOK_SERVER_PORT, 200).await?;
+    call_backend_as_controller(
+        "set_notification_endpoint",
+        &format!("(opt \"{}\")", mock_server.url),
+    )?;
+
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+    let new_user_1 = create_user(&a1).await?;
+
+    // Bob (heir)
+    let i2: BasicIdentity = create_identity();
+    let a2: Agent = get_dfx_agent_with_identity(i2).await?;
+    let new_user_2 = create_user(&a2).await?;
+
+    add_heir(&a1, &a2).await?;
+    let mut heirs = HashSet::new();
+    heirs.insert(new_user_2.id);
+    let ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament webhook".into(),
+        name: Some("Webhook".into()),
+        heirs,
+        secrets: HashSet::new(),
+        key_box: BTreeMap::new(),
+        condition: Condition::LastLogin {
+            max_inactivity_secs: 100000,
+        },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: Some(vec![]),
+        allocations: None,
+        release_stages: None,
+    };
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+
+    // Bob finds the notification in his outbox right away
+    let notifications = get_notifications(&a2).await?;
+    assert!(notifications.iter().any(|n| matches!(
+        &n.kind,
+        NotificationKind::HeirAdded { testament_id, testator }
+            if *testament_id == testament.id && *testator == new_user_1.id
+    )));
+    println!("   Heir got notified in the outbox");
+
+    // wait for the delivery timer to post the notification
+    println!("   Waiting for the webhook delivery...");
+    let started = Instant::now();
+    while !mock_server.bodies().iter().any(|body| {
+        body.contains("HeirAdded")
+            && body.contains(&testament.id)
+            && body.contains("idempotency_key")
+    }) {
+        wait_for_timers(started).await?;
+    }
+    println!("   Notification was delivered to the webhook");
+
+    // Cleanup
+    call_backend_as_controller("set_notification_endpoint", "(null)")?;
+    delete_user(&a1, new_user_1.id).await?;
+    delete_user(&a2, new_user_2.id).await?;
+    Ok(())
+}
+
+/// Waits before polling again for something sent by a timer,
+/// failing once TIMER_TIMEOUT has passed since started.
+async fn wait_for_timers(started: Instant) -> anyhow::Result<()> {
+    if started.elapsed() > TIMER_TIMEOUT {
+        anyhow::bail!("Nothing arrived within {:?}", TIMER_TIMEOUT);
+    }
+    tokio::time::sleep(POLL_INTERVAL).await;
+    Ok(())
+}
+
+async fn get_notifications(agent: &Agent) -> anyhow::Result<Vec<Notification>, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_notifications".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+async fn acknowledge_notifications(
+    agent: &Agent,
+    ids: Vec<NotificationID>,
+) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("acknowledge_notifications".into()),
+        Some(ids),
+    )
+    .await
+    .unwrap()
+}
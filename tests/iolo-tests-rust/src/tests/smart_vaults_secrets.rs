@@ -12,7 +12,9 @@ use crate::{
     },
     utils::{
         agent::{create_identity, get_dfx_agent_with_identity, make_call_with_agent, CallType},
-        secret::{add_user_secret, update_user_secret},
+        secret::{
+            add_user_secret, get_secret_history, restore_secret_version, update_user_secret,
+        },
         user::{create_user, delete_user},
         vetkd::{
             aes_gcm_decrypt, aes_gcm_encrypt, get_aes_256_gcm_key_for_uservault,
@@ -24,6 +26,7 @@ use crate::{
 pub async fn test_smart_vaults_secrets() -> Result<()> {
     println!("\n{}", "Testing smart vault secrets".yellow().bold());
     test_secret_lifecycle().await?;
+    test_secret_history().await?;
     Ok(())
 }
 
@@ -181,3 +184,53 @@ async fn test_secret_lifecycle() -> anyhow::Result<()> {
     println!("   User successfully deleted");
     Ok(())
 }
+
+async fn test_secret_history() -> anyhow::Result<()> {
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+    let user = create_user(&a1).await?;
+
+    let key = |iv: u8| SecretSymmetricCryptoMaterial {
+        encrypted_symmetric_key: vec![iv],
+        iv: vec![iv],
+        username_decryption_nonce: None,
+        password_decryption_nonce: None,
+        notes_decryption_nonce: None,
+    };
+    let add_secret_args = AddSecretArgs {
+        id: "history".to_string(),
+        category: Some(SecretCategory::Note),
+        name: Some("first".to_string()),
+        username: None,
+        password: None,
+        url: None,
+        notes: Some(vec![1]),
+        symmetric_crypto_material: key(1),
+    };
+    let mut secret = add_user_secret(&a1, &add_secret_args).await.unwrap();
+    assert!(get_secret_history(&a1, secret.id.clone()).await?.is_empty());
+
+    // updating keeps the previous version, including its decryption material
+    secret.name = Some("second".to_string());
+    update_user_secret(&a1, secret.clone()).await?;
+    let history = get_secret_history(&a1, secret.id.clone()).await?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].version, 1);
+    assert_eq!(history[0].secret.name, Some("first".to_string()));
+    assert_eq!(history[0].symmetric_crypto_material.as_ref().unwrap().iv, vec![1]);
+    println!("   Previous version of the secret is kept");
+
+    let restored = restore_secret_version(&a1, &secret.id, 1).await?;
+    assert_eq!(restored.name, Some("first".to_string()));
+    let history = get_secret_history(&a1, secret.id.clone()).await?;
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].secret.name, Some("second".to_string()));
+    assert_eq!(
+        restore_secret_version(&a1, &secret.id, 42).await.unwrap_err(),
+        SmartVaultErr::SecretVersionDoesNotExist(42)
+    );
+    println!("   Previous version of the secret restored");
+
+    delete_user(&a1, user.id).await?;
+    Ok(())
+}
@@ -1,20 +1,34 @@
 use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
 
 use anyhow::Result;
-use candid::Principal;
+use candid::{Decode, Encode, Principal};
 use colored::Colorize;
 use ic_agent::{identity::BasicIdentity, Agent, Identity};
 
 use crate::{
     types::{
         secret::{AddSecretArgs, Secret, SecretCategory, SecretSymmetricCryptoMaterial},
-        smart_vault_err::SmartVaultErr,
-        testament::{AddTestamentArgs, Testament},
+        smart_vault_err::{SmartVaultErr, TestamentProblem},
+        testament::{
+            AddTestamentArgs, Condition, HeirAllocation, RearmTestamentArgs, ReleaseStage,
+            ReleaseState, StageUnlock, Testament, TestamentListEntry, TestamentResponse,
+        },
+        trash::TrashItemID,
     },
     utils::{
-        agent::{create_identity, get_dfx_agent_with_identity, make_call_with_agent, CallType},
-        secret::add_user_secret,
-        user::{create_user, delete_user},
+        agent::{
+            create_identity, get_dfx_agent_with_identity, get_iolo_backend_canister,
+            make_call_with_agent, CallType,
+        },
+        dfx::call_backend_as_controller,
+        secret::{add_user_secret, remove_user_secret},
+        testament::add_user_testament,
+        trash::{list_trash, restore_from_trash},
+        user::{
+            add_heir, create_user, delete_user, get_current_user, remove_heir,
+            update_user_login_date,
+        },
         vetkd::{
             aes_gcm_decrypt, aes_gcm_encrypt, get_aes_256_gcm_key_for_testament,
             get_aes_256_gcm_key_for_uservault, get_local_random_aes_256_gcm_key,
@@ -28,6 +42,14 @@ pub async fn test_smart_vaults_testaments() -> Result<()> {
         "Testing smart vaults and testaments".yellow().bold()
     );
     test_testament_lifecycle().await?;
+    test_heir_secret_access().await?;
+    test_heir_allocation().await?;
+    test_staged_release().await?;
+    test_heir_quorum().await?;
+    test_validator_confirmation().await?;
+    test_release_veto().await?;
+    test_rearm_testament().await?;
+    test_delete_testator().await?;
     Ok(())
 }
 
@@ -42,12 +64,12 @@ async fn test_testament_lifecycle() -> anyhow::Result<()> {
     // Bob
     let identity_bob: BasicIdentity = create_identity();
     let principal_bob: Principal = identity_bob.sender().unwrap();
-    let _agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
 
     // Eve
     let identity_eve: BasicIdentity = create_identity();
     // let principal_eve: Principal = identity_eve.sender().unwrap();
-    let _agent_eve: Agent = get_dfx_agent_with_identity(identity_eve).await?;
+    let agent_eve: Agent = get_dfx_agent_with_identity(identity_eve).await?;
 
     ////////////////////////////////////////////////////////////////////////////////////////////////////
     //
@@ -135,10 +157,41 @@ async fn test_testament_lifecycle() -> anyhow::Result<()> {
         heirs,
         secrets: HashSet::new(),
         key_box: BTreeMap::new(),
+        condition: Condition::LastLogin {
+            max_inactivity_secs: 100,
+        },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: None,
+        allocations: None,
+        release_stages: None,
     };
 
+    // Bob needs to be one of Alice's heirs first
+    assert_eq!(
+        add_user_testament(&a1, &ada).await.unwrap_err(),
+        SmartVaultErr::InvalidTestament(vec![TestamentProblem::HeirNotInAddressBook(
+            principal_bob.to_string()
+        )])
+    );
+    add_heir(&a1, &agent_bob).await?;
     let mut testament = add_user_testament(&a1, &ada).await.unwrap();
 
+    // Testament ids identify testaments across all vaults, so Eve cannot use Alice's id
+    let user_eve = create_user(&agent_eve).await?;
+    add_heir(&agent_eve, &agent_bob).await?;
+    assert_eq!(
+        add_user_testament(&agent_eve, &ada).await.unwrap_err(),
+        SmartVaultErr::TestamentAlreadyExists(testament.id.clone())
+    );
+    delete_user(&agent_eve, user_eve.id).await?;
+
+    // and cannot be removed from the heirs as long as the testament names him
+    assert_eq!(
+        remove_heir(&a1, principal_bob).await.unwrap_err(),
+        SmartVaultErr::HeirIsPartOfTestaments(vec![testament.id.clone()])
+    );
+
     // get all testaments
     let testament_list: Result<Vec<Testament>, SmartVaultErr> = make_call_with_agent(
         &a1,
@@ -269,6 +322,800 @@ async fn test_testament_lifecycle() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn test_heir_secret_access() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+
+    // Bob (heir)
+    let identity_bob: BasicIdentity = create_identity();
+    let principal_bob: Principal = identity_bob.sender().unwrap();
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+
+    // Eve (not an heir)
+    let identity_eve: BasicIdentity = create_identity();
+    let agent_eve: Agent = get_dfx_agent_with_identity(identity_eve).await?;
+
+    let new_user_1 = create_user(&a1).await?;
+    add_heir(&a1, &agent_bob).await?;
+
+    // Alice has two secrets, but only the first one goes into the testament
+    let mut secret_ids = Vec::new();
+    for id in ["inherited secret", "private secret"] {
+        let add_secret_args = AddSecretArgs {
+            id: id.to_string(),
+            category: Some(SecretCategory::Note),
+            name: Some(id.to_string()),
+            username: None,
+            password: None,
+            url: None,
+            notes: Some(vec![1, 2, 3]),
+            symmetric_crypto_material: SecretSymmetricCryptoMaterial {
+                encrypted_symmetric_key: vec![1, 2, 3],
+                iv: vec![1, 2, 3],
+                username_decryption_nonce: None,
+                password_decryption_nonce: None,
+                notes_decryption_nonce: Some(vec![1, 2, 3]),
+            },
+        };
+        let secret: Secret = add_user_secret(&a1, &add_secret_args).await.unwrap();
+        secret_ids.push(secret.id);
+    }
+    let inherited_secret = secret_ids[0].clone();
+    let private_secret = secret_ids[1].clone();
+
+    let mut heirs = HashSet::new();
+    heirs.insert(principal_bob);
+    let mut secrets = HashSet::new();
+    secrets.insert(inherited_secret.clone());
+    let mut key_box = BTreeMap::new();
+    key_box.insert(
+        inherited_secret.clone(),
+        SecretSymmetricCryptoMaterial {
+            encrypted_symmetric_key: vec![4, 5, 6],
+            iv: vec![4, 5, 6],
+            username_decryption_nonce: None,
+            password_decryption_nonce: None,
+            notes_decryption_nonce: Some(vec![1, 2, 3]),
+        },
+    );
+    let ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament heir access".into(),
+        name: Some("Heir access".into()),
+        heirs,
+        secrets,
+        key_box,
+        condition: Condition::LastLogin {
+            max_inactivity_secs: 0,
+        },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: None,
+        allocations: None,
+        release_stages: None,
+    };
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+
+    // the condition is not met yet
+    assert_eq!(
+        get_secret_as_heir(&agent_bob, &inherited_secret, &testament.id)
+            .await
+            .unwrap_err(),
+        SmartVaultErr::InvalidTestamentCondition
+    );
+
+    // wait for the login date condition timer to release the testament
+    println!("   Waiting for the login date condition to be checked...");
+    tokio::time::sleep(Duration::from_secs(70)).await;
+
+    // Bob can read the inherited secret and its key
+    let secret = get_secret_as_heir(&agent_bob, &inherited_secret, &testament.id).await?;
+    assert_eq!(secret.id, inherited_secret);
+    let crypto_material =
+        get_secret_symmetric_crypto_material_as_heir(&agent_bob, &inherited_secret, &testament.id)
+            .await?;
+    assert_eq!(crypto_material.encrypted_symmetric_key, vec![4, 5, 6]);
+
+    // but nothing outside of the testament
+    for sid in [private_secret.clone(), "does not exist".to_string()] {
+        assert_eq!(
+            get_secret_as_heir(&agent_bob, &sid, &testament.id)
+                .await
+                .unwrap_err(),
+            SmartVaultErr::SecretNotInTestament(sid.clone())
+        );
+        assert_eq!(
+            get_secret_symmetric_crypto_material_as_heir(&agent_bob, &sid, &testament.id)
+                .await
+                .unwrap_err(),
+            SmartVaultErr::SecretNotInTestament(sid.clone())
+        );
+    }
+
+    // and Eve cannot read anything at all
+    assert_eq!(
+        get_secret_as_heir(&agent_eve, &inherited_secret, &testament.id)
+            .await
+            .unwrap_err(),
+        SmartVaultErr::TestamentDoesNotExist(testament.id.clone())
+    );
+    println!("   Heir secret access is scoped to the testament");
+
+    // removing a secret removes it from the testaments, so the heirs cannot read it anymore
+    let report = remove_user_secret(&a1, inherited_secret.clone()).await?;
+    assert!(report.removed_key_box_entry);
+    assert_eq!(report.testaments, vec![testament.id.clone()]);
+    let trash = list_trash(&a1).await?;
+    assert_eq!(trash.len(), 1);
+    assert_eq!(trash[0].id, TrashItemID::Secret(inherited_secret.clone()));
+    assert_eq!(
+        get_secret_symmetric_crypto_material_as_heir(&agent_bob, &inherited_secret, &testament.id)
+            .await
+            .unwrap_err(),
+        SmartVaultErr::SecretNotInTestament(inherited_secret.clone())
+    );
+
+    // until the testator restores it, together with its place in the testament
+    restore_from_trash(&a1, TrashItemID::Secret(inherited_secret.clone())).await?;
+    assert!(list_trash(&a1).await?.is_empty());
+    let secret = get_secret_as_heir(&agent_bob, &inherited_secret, &testament.id).await?;
+    assert_eq!(secret.id, inherited_secret);
+    println!("   Removed secrets are hidden from the heirs until restored");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_heir_allocation() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+
+    // Bob and Carol (heirs)
+    let identity_bob: BasicIdentity = create_identity();
+    let principal_bob: Principal = identity_bob.sender().unwrap();
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+    let identity_carol: BasicIdentity = create_identity();
+    let principal_carol: Principal = identity_carol.sender().unwrap();
+    let agent_carol: Agent = get_dfx_agent_with_identity(identity_carol).await?;
+
+    // Eve (not an heir)
+    let identity_eve: BasicIdentity = create_identity();
+    let principal_eve: Principal = identity_eve.sender().unwrap();
+
+    let new_user_1 = create_user(&a1).await?;
+    add_heir(&a1, &agent_bob).await?;
+    add_heir(&a1, &agent_carol).await?;
+
+    // The house documents go to Bob, the crypto seed to Carol
+    let crypto_material = |key: u8| SecretSymmetricCryptoMaterial {
+        encrypted_symmetric_key: vec![key],
+        iv: vec![key],
+        username_decryption_nonce: None,
+        password_decryption_nonce: None,
+        notes_decryption_nonce: Some(vec![key]),
+    };
+    let mut allocations = BTreeMap::new();
+    for (id, heir, key) in [
+        ("house documents", principal_bob, 1),
+        ("crypto seed", principal_carol, 2),
+    ] {
+        let add_secret_args = AddSecretArgs {
+            id: id.to_string(),
+            category: Some(SecretCategory::Note),
+            name: Some(id.to_string()),
+            username: None,
+            password: None,
+            url: None,
+            notes: Some(vec![1, 2, 3]),
+            symmetric_crypto_material: crypto_material(0),
+        };
+        let secret: Secret = add_user_secret(&a1, &add_secret_args).await.unwrap();
+        let allocation = HeirAllocation {
+            secrets: HashSet::from([secret.id.clone()]),
+            key_box: BTreeMap::from([(secret.id, crypto_material(key))]),
+        };
+        allocations.insert(heir, allocation);
+    }
+    let house = allocations[&principal_bob].secrets.iter().next().unwrap().clone();
+    let seed = allocations[&principal_carol].secrets.iter().next().unwrap().clone();
+
+    let mut ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament heir allocation".into(),
+        name: Some("Heir allocation".into()),
+        heirs: HashSet::from([principal_bob, principal_carol]),
+        secrets: HashSet::from([house.clone(), seed.clone()]),
+        key_box: BTreeMap::new(),
+        condition: Condition::HeirQuorum { quorum: 1 },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: None,
+        allocations: Some(allocations.clone()),
+        release_stages: None,
+    };
+
+    // only heirs of the testament can get an allocation
+    let mut with_eve = allocations.clone();
+    with_eve.insert(principal_eve, HeirAllocation::default());
+    ada.allocations = Some(with_eve);
+    assert_eq!(
+        add_user_testament(&a1, &ada).await.unwrap_err(),
+        SmartVaultErr::InvalidTestament(vec![TestamentProblem::AllocationForNonHeir(
+            principal_eve.to_string()
+        )])
+    );
+    ada.allocations = Some(allocations);
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+    confirm_testator_passed(&agent_bob, &testament.id).await?;
+
+    // each heir only sees their share
+    let for_bob = get_testament_as_heir(&agent_bob, &testament.id).await?;
+    assert_eq!(for_bob.secrets.len(), 1);
+    assert_eq!(for_bob.secrets[0].id, house);
+    assert_eq!(for_bob.key_box[&house].encrypted_symmetric_key, vec![1]);
+    assert_eq!(
+        for_bob.allocations.keys().collect::<Vec<_>>(),
+        vec![&principal_bob]
+    );
+    let crypto_material =
+        get_secret_symmetric_crypto_material_as_heir(&agent_carol, &seed, &testament.id).await?;
+    assert_eq!(crypto_material.encrypted_symmetric_key, vec![2]);
+
+    // and cannot read the share of the other heir
+    assert_eq!(
+        get_secret_as_heir(&agent_bob, &seed, &testament.id)
+            .await
+            .unwrap_err(),
+        SmartVaultErr::SecretNotInTestament(seed.clone())
+    );
+    assert_eq!(
+        get_secret_symmetric_crypto_material_as_heir(&agent_carol, &house, &testament.id)
+            .await
+            .unwrap_err(),
+        SmartVaultErr::SecretNotInTestament(house.clone())
+    );
+    println!("   Heirs only get the secrets allocated to them");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_staged_release() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+
+    // Bob (heir)
+    let identity_bob: BasicIdentity = create_identity();
+    let principal_bob: Principal = identity_bob.sender().unwrap();
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+
+    let new_user_1 = create_user(&a1).await?;
+    add_heir(&a1, &agent_bob).await?;
+
+    let mut key_box = BTreeMap::new();
+    for id in ["farewell letter", "wallet seed"] {
+        let crypto_material = SecretSymmetricCryptoMaterial {
+            encrypted_symmetric_key: vec![1, 2, 3],
+            iv: vec![1, 2, 3],
+            username_decryption_nonce: None,
+            password_decryption_nonce: None,
+            notes_decryption_nonce: Some(vec![1, 2, 3]),
+        };
+        let add_secret_args = AddSecretArgs {
+            id: id.to_string(),
+            category: Some(SecretCategory::Note),
+            name: Some(id.to_string()),
+            username: None,
+            password: None,
+            url: None,
+            notes: Some(vec![1, 2, 3]),
+            symmetric_crypto_material: crypto_material.clone(),
+        };
+        let secret: Secret = add_user_secret(&a1, &add_secret_args).await.unwrap();
+        key_box.insert(secret.id, crypto_material);
+    }
+    let letter = "farewell letter".to_string();
+    let seed = "wallet seed".to_string();
+
+    // The letter is released right away, the wallet seed at a date far in the future
+    let unlock_date = u64::MAX / 2;
+    let ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament staged release".into(),
+        name: Some("Staged release".into()),
+        heirs: HashSet::from([principal_bob]),
+        secrets: key_box.keys().cloned().collect(),
+        key_box,
+        condition: Condition::HeirQuorum { quorum: 1 },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: None,
+        allocations: None,
+        release_stages: Some(vec![ReleaseStage {
+            name: Some("18th birthday".into()),
+            secrets: HashSet::from([seed.clone()]),
+            unlock: StageUnlock::AtDate { date: unlock_date },
+        }]),
+    };
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+    confirm_testator_passed(&agent_bob, &testament.id).await?;
+
+    // Bob sees both secrets, but only gets the key of the unlocked one
+    let released = get_testament_as_heir(&agent_bob, &testament.id).await?;
+    assert_eq!(released.secrets.len(), 2);
+    assert_eq!(
+        released.locked_secrets,
+        BTreeMap::from([(seed.clone(), unlock_date)])
+    );
+    assert!(released.key_box.contains_key(&letter));
+    assert!(!released.key_box.contains_key(&seed));
+    get_secret_symmetric_crypto_material_as_heir(&agent_bob, &letter, &testament.id).await?;
+
+    // the locked secret cannot be read before its unlock date
+    assert_eq!(
+        get_secret_as_heir(&agent_bob, &seed, &testament.id)
+            .await
+            .unwrap_err(),
+        SmartVaultErr::SecretLocked(seed.clone())
+    );
+    assert_eq!(
+        get_secret_symmetric_crypto_material_as_heir(&agent_bob, &seed, &testament.id)
+            .await
+            .unwrap_err(),
+        SmartVaultErr::SecretLocked(seed.clone())
+    );
+    println!("   Staged secrets stay locked until their unlock date");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_heir_quorum() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+
+    // Bob and Carol (heirs)
+    let identity_bob: BasicIdentity = create_identity();
+    let principal_bob: Principal = identity_bob.sender().unwrap();
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+    let identity_carol: BasicIdentity = create_identity();
+    let principal_carol: Principal = identity_carol.sender().unwrap();
+    let agent_carol: Agent = get_dfx_agent_with_identity(identity_carol).await?;
+
+    // Eve (not an heir)
+    let identity_eve: BasicIdentity = create_identity();
+    let agent_eve: Agent = get_dfx_agent_with_identity(identity_eve).await?;
+
+    let new_user_1 = create_user(&a1).await?;
+    add_heir(&a1, &agent_bob).await?;
+    add_heir(&a1, &agent_carol).await?;
+
+    let mut heirs = HashSet::new();
+    heirs.insert(principal_bob);
+    heirs.insert(principal_carol);
+    let ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament heir quorum".into(),
+        name: Some("Heir quorum".into()),
+        heirs,
+        secrets: HashSet::new(),
+        key_box: BTreeMap::new(),
+        condition: Condition::HeirQuorum { quorum: 2 },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: None,
+        allocations: None,
+        release_stages: None,
+    };
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+
+    // Eve cannot confirm
+    assert_eq!(
+        confirm_testator_passed(&agent_eve, &testament.id).await.unwrap_err(),
+        SmartVaultErr::TestamentDoesNotExist(testament.id.clone())
+    );
+
+    // one confirmation is not enough, and a revoked one does not count
+    confirm_testator_passed(&agent_bob, &testament.id).await?;
+    revoke_testator_passed(&agent_bob, &testament.id).await?;
+    confirm_testator_passed(&agent_carol, &testament.id).await?;
+    assert_eq!(
+        get_testament_as_heir(&agent_bob, &testament.id).await.unwrap_err(),
+        SmartVaultErr::InvalidTestamentCondition
+    );
+
+    // the second confirmation releases the testament right away
+    confirm_testator_passed(&agent_bob, &testament.id).await?;
+    let released = get_testament_as_heir(&agent_bob, &testament.id).await?;
+    assert!(released.condition_status);
+    assert_eq!(released.heir_confirmations.len(), 2);
+
+    // which cannot be undone anymore
+    assert_eq!(
+        revoke_testator_passed(&agent_carol, &testament.id).await.unwrap_err(),
+        SmartVaultErr::InvalidTestamentCondition
+    );
+    println!("   Testament released by heir quorum");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_validator_confirmation() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+
+    // Bob (heir)
+    let identity_bob: BasicIdentity = create_identity();
+    let principal_bob: Principal = identity_bob.sender().unwrap();
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+
+    // Victor (validator)
+    let identity_victor: BasicIdentity = create_identity();
+    let principal_victor: Principal = identity_victor.sender().unwrap();
+    let agent_victor: Agent = get_dfx_agent_with_identity(identity_victor).await?;
+
+    let new_user_1 = create_user(&a1).await?;
+    add_heir(&a1, &agent_bob).await?;
+
+    // Bob cannot be heir and validator at the same time
+    let mut heirs = HashSet::new();
+    heirs.insert(principal_bob);
+    let mut validators = HashSet::new();
+    validators.insert(principal_bob);
+    let mut ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament validator".into(),
+        name: Some("Validator".into()),
+        heirs,
+        secrets: HashSet::new(),
+        key_box: BTreeMap::new(),
+        condition: Condition::ValidatorQuorum { quorum: 1 },
+        grace_period_secs: 0,
+        validators,
+        reminder_milestones: None,
+        allocations: None,
+        release_stages: None,
+    };
+    assert_eq!(
+        add_user_testament(&a1, &ada).await.unwrap_err(),
+        SmartVaultErr::InvalidTestament(vec![TestamentProblem::ValidatorIsHeir(
+            principal_bob.to_string()
+        )])
+    );
+
+    ada.validators = HashSet::from([principal_victor]);
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+
+    // Victor sees the testament, but cannot read it
+    let testament_list = get_testament_list_as_validator(&agent_victor).await?;
+    assert_eq!(testament_list.len(), 1);
+    assert_eq!(testament_list[0].id, testament.id);
+    assert_eq!(
+        get_testament_as_heir(&agent_victor, &testament.id).await.unwrap_err(),
+        SmartVaultErr::TestamentDoesNotExist(testament.id.clone())
+    );
+
+    // the heir confirmation does not count for the validator quorum
+    confirm_testator_passed(&agent_bob, &testament.id).await?;
+    assert_eq!(
+        get_testament_as_heir(&agent_bob, &testament.id).await.unwrap_err(),
+        SmartVaultErr::InvalidTestamentCondition
+    );
+
+    // Victor's confirmation releases the testament
+    confirm_testator_passed(&agent_victor, &testament.id).await?;
+    let released = get_testament_as_heir(&agent_bob, &testament.id).await?;
+    assert!(released.condition_status);
+    assert!(released.validator_confirmations.contains_key(&principal_victor));
+    println!("   Testament released by validator confirmation");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_delete_testator() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+
+    // Bob (heir)
+    let identity_bob: BasicIdentity = create_identity();
+    let principal_bob: Principal = identity_bob.sender().unwrap();
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+
+    let new_user_1 = create_user(&a1).await?;
+    add_heir(&a1, &agent_bob).await?;
+
+    let ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament deleted testator".into(),
+        name: Some("Deleted".into()),
+        heirs: HashSet::from([principal_bob]),
+        secrets: HashSet::new(),
+        key_box: BTreeMap::new(),
+        condition: Condition::HeirQuorum { quorum: 1 },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: None,
+        allocations: None,
+        release_stages: None,
+    };
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+    assert_eq!(get_testament_list_as_heir(&agent_bob).await?.len(), 1);
+
+    // Deleting Alice removes her testament from Bob's view as well
+    delete_user(&a1, new_user_1.id).await?;
+    assert!(get_testament_list_as_heir(&agent_bob).await?.is_empty());
+    assert_eq!(
+        confirm_testator_passed(&agent_bob, &testament.id)
+            .await
+            .unwrap_err(),
+        SmartVaultErr::TestamentDoesNotExist(testament.id.clone())
+    );
+    println!("   Testament of the deleted testator is gone");
+
+    // Nothing is left behind in the registry, in none of the steps of the check
+    for step in ["Vaults", "Testators", "Heirs", "Validators"] {
+        let args = format!(
+            "(record {{ repair = false; cursor = opt variant {{ {} = record {{ after = null }} }}; page_size = opt 10000 }})",
+            step
+        );
+        let report = call_backend_as_controller("check_registry_consistency", &args)?;
+        assert!(!report.contains(&testament.id));
+    }
+    println!("   Testament registry is consistent");
+    Ok(())
+}
+
+async fn test_release_veto() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+
+    // Bob (heir)
+    let identity_bob: BasicIdentity = create_identity();
+    let principal_bob: Principal = identity_bob.sender().unwrap();
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+
+    let new_user_1 = create_user(&a1).await?;
+    add_heir(&a1, &agent_bob).await?;
+
+    let ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament veto".into(),
+        name: Some("Veto".into()),
+        heirs: HashSet::from([principal_bob]),
+        secrets: HashSet::new(),
+        key_box: BTreeMap::new(),
+        condition: Condition::HeirQuorum { quorum: 1 },
+        grace_period_secs: 3600,
+        validators: HashSet::new(),
+        reminder_milestones: None,
+        allocations: None,
+        release_stages: None,
+    };
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+
+    // Bob's confirmation starts the grace period, which Bob can see
+    confirm_testator_passed(&agent_bob, &testament.id).await?;
+    let testament_list = get_testament_list_as_heir(&agent_bob).await?;
+    assert!(matches!(
+        testament_list[0].release_state,
+        ReleaseState::GracePeriod { .. }
+    ));
+    assert_eq!(
+        get_testament_as_heir(&agent_bob, &testament.id).await.unwrap_err(),
+        SmartVaultErr::InvalidTestamentCondition
+    );
+
+    // Alice vetoes the release, which counts as a login
+    let login_before_veto = get_current_user(&a1).await?.date_last_login;
+    let vetoed = veto_release(&a1, &testament.id).await?;
+    assert!(matches!(vetoed.release_state, ReleaseState::Cancelled { .. }));
+    assert!(get_current_user(&a1).await?.date_last_login > login_before_veto);
+    assert!(vetoed.heir_confirmations.is_empty());
+    assert_eq!(
+        veto_release(&a1, &testament.id).await.unwrap_err(),
+        SmartVaultErr::ReleaseNotPending(testament.id.clone())
+    );
+
+    // A new confirmation starts over, and Alice logging in cancels the release as well
+    confirm_testator_passed(&agent_bob, &testament.id).await?;
+    update_user_login_date(&a1).await?;
+    let testament_list = get_testament_list_as_heir(&agent_bob).await?;
+    assert!(matches!(
+        testament_list[0].release_state,
+        ReleaseState::Cancelled { .. }
+    ));
+    println!("   Pending release cancelled by the testator");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_rearm_testament() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+
+    // Bob (heir)
+    let identity_bob: BasicIdentity = create_identity();
+    let principal_bob: Principal = identity_bob.sender().unwrap();
+    let agent_bob: Agent = get_dfx_agent_with_identity(identity_bob).await?;
+
+    let new_user_1 = create_user(&a1).await?;
+    add_heir(&a1, &agent_bob).await?;
+
+    let ada: AddTestamentArgs = AddTestamentArgs {
+        id: "Testament rearm".into(),
+        name: Some("Rearm".into()),
+        heirs: HashSet::from([principal_bob]),
+        secrets: HashSet::new(),
+        key_box: BTreeMap::new(),
+        condition: Condition::HeirQuorum { quorum: 1 },
+        grace_period_secs: 0,
+        validators: HashSet::new(),
+        reminder_milestones: None,
+        allocations: None,
+        release_stages: None,
+    };
+    let testament = add_user_testament(&a1, &ada).await.unwrap();
+    let rta = RearmTestamentArgs {
+        testament_id: testament.id.clone(),
+        key_box: BTreeMap::new(),
+        allocation_key_boxes: None,
+        reason: Some("Bob was wrong".into()),
+    };
+
+    // Only released testaments can be rearmed
+    assert_eq!(
+        rearm_testament(&a1, &rta).await.unwrap_err(),
+        SmartVaultErr::TestamentNotReleased(testament.id.clone())
+    );
+
+    // Bob erroneously releases the testament, Alice rearms it
+    confirm_testator_passed(&agent_bob, &testament.id).await?;
+    assert!(get_testament_as_heir(&agent_bob, &testament.id).await?.condition_status);
+    let rearmed = rearm_testament(&a1, &rta).await?;
+    assert_eq!(rearmed.release_state, ReleaseState::Armed);
+    assert_eq!(rearmed.key_epoch, 1);
+    assert_eq!(rearmed.release_history.len(), 1);
+    assert!(rearmed.heir_confirmations.is_empty());
+    assert_eq!(
+        get_testament_as_heir(&agent_bob, &testament.id).await.unwrap_err(),
+        SmartVaultErr::InvalidTestamentCondition
+    );
+    println!("   Released testament rearmed by the testator");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn rearm_testament(
+    agent: &Agent,
+    args: &RearmTestamentArgs,
+) -> anyhow::Result<Testament, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("rearm_testament".into()),
+        Some(args),
+    )
+    .await
+    .unwrap()
+}
+
+async fn veto_release(agent: &Agent, testament_id: &String) -> anyhow::Result<Testament, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("veto_release".into()),
+        Some(testament_id),
+    )
+    .await
+    .unwrap()
+}
+
+async fn get_testament_list_as_heir(
+    agent: &Agent,
+) -> anyhow::Result<Vec<TestamentListEntry>, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_testament_list_as_heir".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+async fn get_testament_list_as_validator(
+    agent: &Agent,
+) -> anyhow::Result<Vec<TestamentListEntry>, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_testament_list_as_validator".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+async fn confirm_testator_passed(
+    agent: &Agent,
+    testament_id: &String,
+) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("confirm_testator_passed".into()),
+        Some(testament_id),
+    )
+    .await
+    .unwrap()
+}
+
+async fn revoke_testator_passed(
+    agent: &Agent,
+    testament_id: &String,
+) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("revoke_testator_passed".into()),
+        Some(testament_id),
+    )
+    .await
+    .unwrap()
+}
+
+async fn get_testament_as_heir(
+    agent: &Agent,
+    testament_id: &String,
+) -> anyhow::Result<TestamentResponse, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_testament_as_heir".into()),
+        Some(testament_id),
+    )
+    .await
+    .unwrap()
+}
+
+async fn get_secret_as_heir(
+    agent: &Agent,
+    secret_id: &String,
+    testament_id: &String,
+) -> anyhow::Result<Secret, SmartVaultErr> {
+    let res = agent
+        .query(&get_iolo_backend_canister(), "get_secret_as_heir")
+        .with_arg(Encode!(secret_id, testament_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+
+    Decode!(&res, Result<Secret, SmartVaultErr>).unwrap()
+}
+
+async fn get_secret_symmetric_crypto_material_as_heir(
+    agent: &Agent,
+    secret_id: &String,
+    testament_id: &String,
+) -> anyhow::Result<SecretSymmetricCryptoMaterial, SmartVaultErr> {
+    let res = agent
+        .query(
+            &get_iolo_backend_canister(),
+            "get_secret_symmetric_crypto_material_as_heir",
+        )
+        .with_arg(Encode!(secret_id, testament_id).unwrap())
+        .call()
+        .await
+        .unwrap();
+
+    Decode!(&res, Result<SecretSymmetricCryptoMaterial, SmartVaultErr>).unwrap()
+}
+
 async fn update_user_testament(
     agent: &Agent,
     args: Testament,
@@ -283,14 +1130,3 @@ async fn update_user_testament(
 
     t
 }
-
-async fn add_user_testament(
-    agent: &Agent,
-    args: &AddTestamentArgs,
-) -> anyhow::Result<Testament, SmartVaultErr> {
-    let t: Result<Testament, SmartVaultErr> =
-        make_call_with_agent(agent, CallType::Update("add_testament".into()), Some(args))
-            .await
-            .unwrap();
-    t
-}
@@ -1,13 +1,29 @@
+use std::time::Duration;
+
 use anyhow::Result;
 use candid::Principal;
 use colored::Colorize;
 use ic_agent::{identity::BasicIdentity, Agent, Identity};
 
 use crate::{
-    types::smart_vault_err::SmartVaultErr,
+    types::{
+        secret::{AddSecretArgs, SecretCategory, SecretSymmetricCryptoMaterial},
+        smart_vault_err::SmartVaultErr,
+        user::{
+            CheckInSchedule, CreateHeirInvitationArgs, InvitationStatus, SetRecoveryGuardiansArgs,
+        },
+    },
     utils::{
         agent::{create_identity, get_dfx_agent_with_identity},
-        user::{create_user, delete_user},
+        secret::{add_user_secret, get_secret_list},
+        user::{
+            accept_heir_invitation, approve_account_recovery, check_in, complete_account_recovery,
+            confirm_principal_link, create_heir_invitation, create_user, delete_user,
+            get_account_recovery, get_check_in_status, get_current_user, get_heir_invitations,
+            get_linked_principals, remove_linked_principal, request_account_recovery,
+            request_principal_link, revoke_heir_invitation, set_check_in_schedule,
+            set_recovery_guardians, update_user_login_date, veto_account_recovery,
+        },
     },
 };
 
@@ -17,6 +33,10 @@ pub async fn test_smart_vaults_users() -> Result<()> {
         "Testing smart vaults and testaments".yellow().bold()
     );
     test_user_lifecycle().await?;
+    test_heir_invitation().await?;
+    test_check_in().await?;
+    test_linked_principals().await?;
+    test_account_recovery().await?;
     Ok(())
 }
 
@@ -66,3 +86,282 @@ async fn test_user_lifecycle() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+async fn test_heir_invitation() -> anyhow::Result<()> {
+    // Alice (testator)
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+    let new_user_1 = create_user(&a1).await?;
+
+    // Bob (heir)
+    let i2: BasicIdentity = create_identity();
+    let p2: Principal = i2.sender().unwrap();
+    let a2: Agent = get_dfx_agent_with_identity(i2).await?;
+
+    let args = CreateHeirInvitationArgs {
+        name: Some("Bob".into()),
+        email: Some("bob@example.com".into()),
+        valid_for_secs: None,
+    };
+    let invitation = create_heir_invitation(&a1, &args).await?;
+    let revoked = create_heir_invitation(&a1, &args).await?;
+    assert_eq!(invitation.status, InvitationStatus::Pending);
+    assert_eq!(get_heir_invitations(&a1).await?.len(), 2);
+    println!("   Invitations created");
+
+    // Alice cannot accept her own invitation
+    assert_eq!(
+        accept_heir_invitation(&a1, &invitation.claim_code).await,
+        Err(SmartVaultErr::InvalidInvitee(new_user_1.id.to_string()))
+    );
+
+    // Bob redeems the claim code from his own principal, exactly once
+    let accepted = accept_heir_invitation(&a2, &invitation.claim_code).await?;
+    assert!(matches!(
+        accepted.status,
+        InvitationStatus::Accepted { heir, .. } if heir == p2
+    ));
+    assert_eq!(
+        accept_heir_invitation(&a2, &invitation.claim_code).await,
+        Err(SmartVaultErr::InvitationDoesNotExist(invitation.claim_code.clone()))
+    );
+    // the accepted invitation is used up
+    assert_eq!(get_heir_invitations(&a1).await?.len(), 1);
+    println!("   Invitation accepted by the heir");
+
+    // A revoked invitation cannot be accepted anymore
+    revoke_heir_invitation(&a1, &revoked.claim_code).await?;
+    assert_eq!(
+        accept_heir_invitation(&a2, &revoked.claim_code).await,
+        Err(SmartVaultErr::InvitationDoesNotExist(revoked.claim_code.clone()))
+    );
+    assert!(get_heir_invitations(&a1).await?.is_empty());
+    println!("   Invitation revoked");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_check_in() -> anyhow::Result<()> {
+    let i1: BasicIdentity = create_identity();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+    let new_user_1 = create_user(&a1).await?;
+
+    // Checking in needs a schedule
+    assert_eq!(
+        check_in(&a1).await,
+        Err(SmartVaultErr::CheckInNotConfigured(new_user_1.id.to_string()))
+    );
+    assert!(matches!(
+        set_check_in_schedule(&a1, Some(CheckInSchedule::Recurring { interval_secs: 0 })).await,
+        Err(SmartVaultErr::InvalidCheckInSchedule(_))
+    ));
+
+    let schedule = CheckInSchedule::Recurring { interval_secs: 2 };
+    set_check_in_schedule(&a1, Some(schedule.clone())).await?;
+    let status = get_check_in_status(&a1).await?;
+    assert_eq!(status.schedule, schedule);
+    assert_eq!(status.missed_check_ins, 0);
+    println!("   Check-in schedule configured");
+
+    // The deadlines pass without a check-in
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert!(get_check_in_status(&a1).await?.missed_check_ins >= 2);
+
+    // A check-in starts the count anew
+    let status = check_in(&a1).await?;
+    assert_eq!(status.missed_check_ins, 0);
+    assert!(status.last_check_in.is_some());
+    println!("   Missed check-ins counted and reset by a check-in");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_linked_principals() -> anyhow::Result<()> {
+    // Alice and her backup device
+    let i1: BasicIdentity = create_identity();
+    let p1: Principal = i1.sender().unwrap();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+    let new_user_1 = create_user(&a1).await?;
+
+    let i2: BasicIdentity = create_identity();
+    let p2: Principal = i2.sender().unwrap();
+    let a2: Agent = get_dfx_agent_with_identity(i2).await?;
+
+    // The device has no access before it confirms the link
+    let link = request_principal_link(&a1, p2).await?;
+    assert_eq!(link.owner, p1);
+    assert_eq!(get_linked_principals(&a1).await?.pending, vec![link]);
+    assert_eq!(
+        get_current_user(&a2).await.unwrap_err(),
+        SmartVaultErr::UserDoesNotExist(p2.to_string())
+    );
+    confirm_principal_link(&a2, p1).await?;
+    assert_eq!(get_linked_principals(&a1).await?.linked, vec![p2]);
+    println!("   Principal linked");
+
+    // The device acts on behalf of Alice
+    assert_eq!(get_current_user(&a2).await?.id, p1);
+    assert_eq!(get_linked_principals(&a2).await?.linked, vec![p2]);
+    assert_eq!(update_user_login_date(&a2).await?.id, p1);
+    let schedule = CheckInSchedule::Recurring { interval_secs: 60 };
+    set_check_in_schedule(&a2, Some(schedule)).await?;
+    assert!(check_in(&a2).await?.last_check_in.is_some());
+    println!("   Linked principal resolved to the account");
+
+    // but cannot manage the account
+    let p3: Principal = create_identity().sender().unwrap();
+    assert_eq!(
+        request_principal_link(&a2, p3).await.unwrap_err(),
+        SmartVaultErr::NotAuthorized(p2.to_string())
+    );
+    assert_eq!(
+        remove_linked_principal(&a2, p2).await.unwrap_err(),
+        SmartVaultErr::NotAuthorized(p2.to_string())
+    );
+    assert_eq!(
+        set_recovery_guardians(&a2, None).await.unwrap_err(),
+        SmartVaultErr::NotAuthorized(p2.to_string())
+    );
+    assert_eq!(
+        veto_account_recovery(&a2).await.unwrap_err(),
+        SmartVaultErr::NotAuthorized(p2.to_string())
+    );
+    assert_eq!(
+        delete_user(&a2, p1).await.unwrap_err(),
+        SmartVaultErr::NotAuthorized(p2.to_string())
+    );
+    println!("   Linked principal cannot manage the account");
+
+    // An unlinked device loses access
+    remove_linked_principal(&a1, p2).await?;
+    assert_eq!(
+        get_current_user(&a2).await.unwrap_err(),
+        SmartVaultErr::UserDoesNotExist(p2.to_string())
+    );
+    println!("   Principal unlinked");
+
+    // Cleanup
+    delete_user(&a1, new_user_1.id).await?;
+    Ok(())
+}
+
+async fn test_account_recovery() -> anyhow::Result<()> {
+    // Alice, who is going to lose her principal and her device
+    let i1: BasicIdentity = create_identity();
+    let p1: Principal = i1.sender().unwrap();
+    let a1: Agent = get_dfx_agent_with_identity(i1).await?;
+    create_user(&a1).await?;
+    let secret = add_user_secret(&a1, &note("recovered note")).await?;
+
+    let i5: BasicIdentity = create_identity();
+    let p5: Principal = i5.sender().unwrap();
+    let a5: Agent = get_dfx_agent_with_identity(i5).await?;
+    request_principal_link(&a1, p5).await?;
+    confirm_principal_link(&a5, p1).await?;
+
+    // Bob and Carol, her guardians
+    let i2: BasicIdentity = create_identity();
+    let p2: Principal = i2.sender().unwrap();
+    let a2: Agent = get_dfx_agent_with_identity(i2).await?;
+    let i3: BasicIdentity = create_identity();
+    let p3: Principal = i3.sender().unwrap();
+    let a3: Agent = get_dfx_agent_with_identity(i3).await?;
+
+    // Alice's new principal
+    let i4: BasicIdentity = create_identity();
+    let p4: Principal = i4.sender().unwrap();
+    let a4: Agent = get_dfx_agent_with_identity(i4).await?;
+
+    let args = SetRecoveryGuardiansArgs {
+        guardians: vec![p2, p3],
+        threshold: 2,
+        window_secs: Some(3),
+    };
+    set_recovery_guardians(&a1, Some(args)).await?;
+    assert_eq!(get_account_recovery(&a1).await?.guardians, vec![p2, p3]);
+    println!("   Recovery guardians set");
+
+    // Only guardians can ask for a recovery
+    assert_eq!(
+        request_account_recovery(&a4, p1, p4).await,
+        Err(SmartVaultErr::NotARecoveryGuardian(p4.to_string()))
+    );
+
+    // Alice vetoes a request she did not make
+    request_account_recovery(&a2, p1, p4).await?;
+    veto_account_recovery(&a1).await?;
+    assert_eq!(
+        approve_account_recovery(&a3, p1, p4).await,
+        Err(SmartVaultErr::RecoveryRequestDoesNotExist(p1.to_string()))
+    );
+    println!("   Recovery vetoed");
+
+    // Bob asks for the recovery and Carol approves, but the window has to close first
+    request_account_recovery(&a2, p1, p4).await?;
+    let request = approve_account_recovery(&a3, p1, p4).await?;
+    assert_eq!(request.approvals, vec![p2, p3]);
+    assert_eq!(
+        complete_account_recovery(&a4, p1).await,
+        Err(SmartVaultErr::RecoveryWindowOpen(request.closes_at))
+    );
+    println!("   Recovery approved");
+
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    assert_eq!(
+        complete_account_recovery(&a2, p1).await,
+        Err(SmartVaultErr::RecoveryRequestDoesNotExist(p1.to_string()))
+    );
+    let user = complete_account_recovery(&a4, p1).await?;
+    assert_eq!(user.id, p4);
+    assert_eq!(get_current_user(&a4).await?.id, p4);
+    assert_eq!(get_account_recovery(&a4).await?.account, p4);
+    println!("   Account recovered to the new principal");
+
+    // The vault comes along
+    let secrets = get_secret_list(&a4).await?;
+    assert_eq!(secrets.len(), 1);
+    assert_eq!(secrets[0].id, secret.id);
+
+    // The old principal and the linked device lose access
+    assert_eq!(
+        get_current_user(&a1).await.unwrap_err(),
+        SmartVaultErr::UserDoesNotExist(p1.to_string())
+    );
+    assert_eq!(
+        get_current_user(&a5).await.unwrap_err(),
+        SmartVaultErr::UserDoesNotExist(p5.to_string())
+    );
+    assert!(get_linked_principals(&a4).await?.linked.is_empty());
+    assert_eq!(
+        complete_account_recovery(&a4, p1).await,
+        Err(SmartVaultErr::RecoveryNotConfigured(p1.to_string()))
+    );
+    println!("   Old principal and linked devices locked out");
+
+    // Cleanup
+    delete_user(&a4, p4).await?;
+    Ok(())
+}
+
+fn note(id: &str) -> AddSecretArgs {
+    AddSecretArgs {
+        id: id.to_string(),
+        category: Some(SecretCategory::Note),
+        name: Some(id.to_string()),
+        username: None,
+        password: None,
+        url: None,
+        notes: Some(vec![1, 2, 3]),
+        symmetric_crypto_material: SecretSymmetricCryptoMaterial {
+            encrypted_symmetric_key: vec![1, 2, 3],
+            iv: vec![1, 2, 3],
+            username_decryption_nonce: None,
+            password_decryption_nonce: None,
+            notes_decryption_nonce: Some(vec![1, 2, 3]),
+        },
+    }
+}
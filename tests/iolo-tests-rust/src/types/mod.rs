@@ -1,4 +1,6 @@
+pub mod notification;
 pub mod secret;
 pub mod smart_vault_err;
 pub mod testament;
+pub mod trash;
 pub mod user;
@@ -0,0 +1,46 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use super::testament::TestamentID;
+
+pub type NotificationID = u64;
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum NotificationKind {
+    InactivityReminder {
+        testament_id: TestamentID,
+        milestone: u8,
+        release_date: u64,
+    },
+    TestamentReleased {
+        testament_id: TestamentID,
+        testator: Principal,
+    },
+    HeirAdded {
+        testament_id: TestamentID,
+        testator: Principal,
+    },
+    TestamentRemoved {
+        testament_id: TestamentID,
+        testator: Principal,
+    },
+    HeirInvitationAccepted {
+        claim_code: String,
+        heir: Principal,
+    },
+    PrincipalLinked {
+        principal: Principal,
+    },
+    RecoveryRequested {
+        account: Principal,
+        new_principal: Principal,
+        closes_at: u64,
+    },
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Notification {
+    pub id: NotificationID,
+    pub date_created: u64,
+    pub kind: NotificationKind,
+}
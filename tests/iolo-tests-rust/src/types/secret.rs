@@ -28,6 +28,21 @@ pub struct Secret {
     pub notes: Option<Vec<u8>>,
 }
 
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SecretRemovalReport {
+    pub secret_id: SecretID,
+    pub removed_key_box_entry: bool,
+    pub testaments: Vec<String>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct SecretVersion {
+    pub version: u64,
+    pub date_archived: u64,
+    pub secret: Secret,
+    pub symmetric_crypto_material: Option<SecretSymmetricCryptoMaterial>,
+}
+
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
 pub struct AddSecretArgsOld {
     pub secret: Secret,
@@ -4,10 +4,62 @@ use candid::{CandidType, Principal};
 
 use serde::{Deserialize, Serialize};
 
-use super::secret::{SecretID, SecretSymmetricCryptoMaterial};
+use super::secret::{SecretID, SecretListEntry, SecretSymmetricCryptoMaterial};
 
 pub type TestamentID = String;
 
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum Condition {
+    LastLogin { max_inactivity_secs: u64 },
+    FixedDate { release_date: u64 },
+    HeirQuorum { quorum: u64 },
+    ValidatorQuorum { quorum: u64 },
+    MissedCheckIns { count: u64 },
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum ReleaseState {
+    Armed,
+    Triggered { triggered_at: u64 },
+    GracePeriod { triggered_at: u64, release_at: u64 },
+    Released { released_at: u64 },
+    Cancelled { cancelled_at: u64 },
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ReleaseIncident {
+    pub released_at: u64,
+    pub rearmed_at: u64,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ReminderMark {
+    pub last_login: u64,
+    pub milestone: u8,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct HeirAllocation {
+    pub secrets: HashSet<SecretID>,
+    pub key_box: KeyBox,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum StageUnlock {
+    AfterRelease { delay_secs: u64 },
+    AtDate { date: u64 },
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct ReleaseStage {
+    pub name: Option<String>,
+    pub secrets: HashSet<SecretID>,
+    pub unlock: StageUnlock,
+}
+
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
 pub struct Testament {
     pub id: TestamentID,
@@ -24,6 +76,39 @@ pub struct Testament {
     /// This key is itself encrypted using the Testament decryption key,
     /// which itself is derived by vetkd.
     pub key_box: BTreeMap<SecretID, SecretSymmetricCryptoMaterial>,
+    pub allocations: BTreeMap<Principal, HeirAllocation>,
+    pub release_stages: Vec<ReleaseStage>,
+    pub condition_status: bool,
+    pub condition: Condition,
+    pub release_state: ReleaseState,
+    pub grace_period_secs: u64,
+    pub release_history: Vec<ReleaseIncident>,
+    pub key_epoch: u64,
+    pub reminder_milestones: Vec<u8>,
+    pub last_reminder: Option<ReminderMark>,
+    pub heir_confirmations: BTreeMap<Principal, u64>,
+    pub validators: HashSet<Principal>,
+    pub validator_confirmations: BTreeMap<Principal, u64>,
+}
+
+/// The testament as returned by get_testament_as_heir. Only the fields used by the tests are decoded.
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct TestamentResponse {
+    pub id: TestamentID,
+    pub name: Option<String>,
+    pub testator: Principal,
+    pub heirs: HashSet<Principal>,
+    pub secrets: Vec<SecretListEntry>,
+    pub key_box: KeyBox,
+    pub allocations: BTreeMap<Principal, HeirAllocation>,
+    pub release_stages: Vec<ReleaseStage>,
+    pub locked_secrets: BTreeMap<SecretID, u64>,
+    pub condition_status: bool,
+    pub condition: Condition,
+    pub release_state: ReleaseState,
+    pub grace_period_secs: u64,
+    pub heir_confirmations: BTreeMap<Principal, u64>,
+    pub validator_confirmations: BTreeMap<Principal, u64>,
 }
 
 /// The struct provided by the backend when calling "create_secret". It contains:
@@ -35,12 +120,36 @@ pub struct AddTestamentArgs {
     pub heirs: HashSet<Principal>,
     pub secrets: HashSet<SecretID>,
     pub key_box: KeyBox,
+    pub condition: Condition,
+    pub grace_period_secs: u64,
+    pub validators: HashSet<Principal>,
+    pub reminder_milestones: Option<Vec<u8>>,
+    pub allocations: Option<BTreeMap<Principal, HeirAllocation>>,
+    pub release_stages: Option<Vec<ReleaseStage>>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TestamentListEntry {
+    pub id: TestamentID,
+    pub name: Option<String>,
+    pub testator: Principal,
+    pub condition_status: bool,
+    pub release_state: ReleaseState,
 }
 
 #[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
 pub struct TestamentKeyDerviationArgs {
     pub encryption_public_key: Vec<u8>,
     pub testament_id: String,
+    pub key_epoch: Option<u64>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct RearmTestamentArgs {
+    pub testament_id: TestamentID,
+    pub key_box: KeyBox,
+    pub allocation_key_boxes: Option<BTreeMap<Principal, KeyBox>>,
+    pub reason: Option<String>,
 }
 
 pub type KeyBox = BTreeMap<SecretID, SecretSymmetricCryptoMaterial>;
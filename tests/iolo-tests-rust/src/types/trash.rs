@@ -0,0 +1,16 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum TrashItemID {
+    Secret(String),
+    Testament(String),
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TrashListEntry {
+    pub id: TrashItemID,
+    pub name: Option<String>,
+    pub date_trashed: u64,
+    pub purge_at: u64,
+}
@@ -7,5 +7,88 @@ pub struct User {
     pub date_created: u64,
     pub date_modified: u64,
     pub date_last_login: Option<u64>,
-    pub user_vault_id: u128, // TODO make option
+    pub user_vault_id: Option<u128>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct AddUserArgs {
+    pub id: Principal,
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct CreateHeirInvitationArgs {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub valid_for_secs: Option<u64>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum InvitationStatus {
+    Pending,
+    Expired,
+    Accepted { heir: Principal, date_accepted: u64 },
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct HeirInvitation {
+    pub claim_code: String,
+    pub testator: Principal,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub date_created: u64,
+    pub expires_at: u64,
+    pub status: InvitationStatus,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum CheckInSchedule {
+    Recurring { interval_secs: u64 },
+    FixedDates { dates: Vec<u64> },
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CheckInStatus {
+    pub schedule: CheckInSchedule,
+    pub last_check_in: Option<u64>,
+    pub missed_check_ins: u64,
+    pub next_deadline: Option<u64>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PendingPrincipalLink {
+    pub owner: Principal,
+    pub principal: Principal,
+    pub expires_at: u64,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct LinkedPrincipals {
+    pub linked: Vec<Principal>,
+    pub pending: Vec<PendingPrincipalLink>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone)]
+pub struct SetRecoveryGuardiansArgs {
+    pub guardians: Vec<Principal>,
+    pub threshold: u64,
+    pub window_secs: Option<u64>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RecoveryRequest {
+    pub new_principal: Principal,
+    pub date_created: u64,
+    pub closes_at: u64,
+    pub approvals: Vec<Principal>,
+}
+
+#[derive(Debug, CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub struct AccountRecovery {
+    pub account: Principal,
+    pub guardians: Vec<Principal>,
+    pub threshold: u64,
+    pub window_secs: u64,
+    pub request: Option<RecoveryRequest>,
 }
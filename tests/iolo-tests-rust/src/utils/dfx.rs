@@ -53,3 +53,11 @@ pub fn get_backend_canister_id() -> anyhow::Result<String> {
     let id = cmd!(sh, "dfx canister id iolo_backend").read().unwrap();
     Ok(id)
 }
+
+/// Calls the backend with the dfx identity. It is a controller of the canister and thus an admin.
+pub fn call_backend_as_controller(method: &str, arg: &str) -> anyhow::Result<String> {
+    let sh = Shell::new()?;
+    sh.change_dir("../../");
+    let output = cmd!(sh, "dfx canister call iolo_backend {method} {arg}").read()?;
+    Ok(output)
+}
@@ -0,0 +1,68 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A minimal local http server standing in for a notification endpoint.
+/// It answers every request with the configured status and records the request bodies.
+pub struct MockServer {
+    pub url: String,
+    bodies: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    pub async fn start(port: u16, status: u16) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = bodies.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    if let Some(body) = read_body(&mut stream).await {
+                        recorded.lock().unwrap().push(body);
+                    }
+                    let response = format!("HTTP/1.1 {} Mock\r\nContent-Length: 0\r\n\r\n", status);
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        Ok(Self {
+            url: format!("http://localhost:{}/notify", port),
+            bodies,
+        })
+    }
+
+    pub fn bodies(&self) -> Vec<String> {
+        self.bodies.lock().unwrap().clone()
+    }
+}
+
+/// Reads a request and returns its body, relying on the content-length header.
+async fn read_body(stream: &mut tokio::net::TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buffer).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buffer[..n]);
+
+        let text = String::from_utf8_lossy(&request).to_string();
+        let Some(header_end) = text.find("\r\n\r\n") else {
+            continue;
+        };
+        let content_length = text[..header_end]
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+        let body = &request[header_end + 4..];
+        if body.len() >= content_length {
+            return Some(String::from_utf8_lossy(&body[..content_length]).to_string());
+        }
+    }
+}
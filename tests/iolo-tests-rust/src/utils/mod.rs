@@ -1,5 +1,8 @@
 pub mod agent;
 pub mod dfx;
+pub mod mock_server;
 pub mod secret;
+pub mod testament;
+pub mod trash;
 pub mod user;
 pub mod vetkd;
@@ -1,11 +1,14 @@
+use candid::{Decode, Encode};
 use ic_agent::Agent;
 
 use crate::types::{
-    secret::{AddSecretArgs, Secret},
+    secret::{
+        AddSecretArgs, Secret, SecretID, SecretListEntry, SecretRemovalReport, SecretVersion,
+    },
     smart_vault_err::SmartVaultErr,
 };
 
-use super::agent::{make_call_with_agent, CallType};
+use super::agent::{get_iolo_backend_canister, make_call_with_agent, CallType};
 
 pub async fn add_user_secret(
     agent: &Agent,
@@ -30,3 +33,60 @@ pub async fn update_user_secret(
 
     s
 }
+
+pub async fn remove_user_secret(
+    agent: &Agent,
+    secret_id: SecretID,
+) -> anyhow::Result<SecretRemovalReport, SmartVaultErr> {
+    let res: Result<SecretRemovalReport, SmartVaultErr> = make_call_with_agent(
+        agent,
+        CallType::Update("remove_secret".into()),
+        Some(secret_id),
+    )
+    .await
+    .unwrap();
+
+    res
+}
+
+pub async fn get_secret_list(agent: &Agent) -> anyhow::Result<Vec<SecretListEntry>, SmartVaultErr> {
+    let list: Result<Vec<SecretListEntry>, SmartVaultErr> = make_call_with_agent(
+        agent,
+        CallType::Query("get_secret_list".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap();
+
+    list
+}
+
+pub async fn get_secret_history(
+    agent: &Agent,
+    secret_id: SecretID,
+) -> anyhow::Result<Vec<SecretVersion>, SmartVaultErr> {
+    let history: Result<Vec<SecretVersion>, SmartVaultErr> = make_call_with_agent(
+        agent,
+        CallType::Query("get_secret_history".into()),
+        Some(secret_id),
+    )
+    .await
+    .unwrap();
+
+    history
+}
+
+pub async fn restore_secret_version(
+    agent: &Agent,
+    secret_id: &SecretID,
+    version: u64,
+) -> anyhow::Result<Secret, SmartVaultErr> {
+    let res = agent
+        .update(&get_iolo_backend_canister(), "restore_secret_version")
+        .with_arg(Encode!(secret_id, &version).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+
+    Decode!(&res, Result<Secret, SmartVaultErr>).unwrap()
+}
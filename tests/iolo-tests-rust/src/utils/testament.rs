@@ -0,0 +1,19 @@
+use ic_agent::Agent;
+
+use crate::types::{
+    smart_vault_err::SmartVaultErr,
+    testament::{AddTestamentArgs, Testament},
+};
+
+use super::agent::{make_call_with_agent, CallType};
+
+pub async fn add_user_testament(
+    agent: &Agent,
+    args: &AddTestamentArgs,
+) -> anyhow::Result<Testament, SmartVaultErr> {
+    let t: Result<Testament, SmartVaultErr> =
+        make_call_with_agent(agent, CallType::Update("add_testament".into()), Some(args))
+            .await
+            .unwrap();
+    t
+}
@@ -0,0 +1,35 @@
+use ic_agent::Agent;
+
+use crate::types::{
+    smart_vault_err::SmartVaultErr,
+    trash::{TrashItemID, TrashListEntry},
+};
+
+use super::agent::{make_call_with_agent, CallType};
+
+pub async fn list_trash(agent: &Agent) -> anyhow::Result<Vec<TrashListEntry>, SmartVaultErr> {
+    let trash: Result<Vec<TrashListEntry>, SmartVaultErr> = make_call_with_agent(
+        agent,
+        CallType::Query("list_trash".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap();
+
+    trash
+}
+
+pub async fn restore_from_trash(
+    agent: &Agent,
+    id: TrashItemID,
+) -> anyhow::Result<(), SmartVaultErr> {
+    let res: Result<(), SmartVaultErr> = make_call_with_agent(
+        agent,
+        CallType::Update("restore_from_trash".into()),
+        Some(id),
+    )
+    .await
+    .unwrap();
+
+    res
+}
@@ -1,11 +1,18 @@
 use anyhow::Result;
-use candid::Principal;
+use candid::{Decode, Encode, Principal};
 
 use ic_agent::Agent;
 
 use crate::{
-    types::{smart_vault_err::SmartVaultErr, user::User},
-    utils::agent::{make_call_with_agent, CallType},
+    types::{
+        smart_vault_err::SmartVaultErr,
+        user::{
+            AccountRecovery, CheckInSchedule, CheckInStatus, CreateHeirInvitationArgs,
+            HeirInvitation, LinkedPrincipals, PendingPrincipalLink, RecoveryRequest,
+            SetRecoveryGuardiansArgs, User,
+        },
+    },
+    utils::agent::{get_iolo_backend_canister, make_call_with_agent, CallType},
 };
 
 pub async fn delete_user(agent: &Agent, u: Principal) -> anyhow::Result<(), SmartVaultErr> {
@@ -31,3 +38,257 @@ pub async fn create_user(agent: &Agent) -> anyhow::Result<User, SmartVaultErr> {
 
     user
 }
+
+pub async fn get_current_user(agent: &Agent) -> anyhow::Result<User, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_current_user".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn update_user_login_date(agent: &Agent) -> anyhow::Result<User, SmartVaultErr> {
+    let user: Result<User, SmartVaultErr> = make_call_with_agent(
+        agent,
+        CallType::Update("update_user_login_date".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap();
+
+    user
+}
+
+pub async fn create_heir_invitation(
+    agent: &Agent,
+    args: &CreateHeirInvitationArgs,
+) -> anyhow::Result<HeirInvitation, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("create_heir_invitation".into()),
+        Some(args),
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn get_heir_invitations(
+    agent: &Agent,
+) -> anyhow::Result<Vec<HeirInvitation>, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_heir_invitations".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn accept_heir_invitation(
+    agent: &Agent,
+    claim_code: &str,
+) -> anyhow::Result<HeirInvitation, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("accept_heir_invitation".into()),
+        Some(claim_code.to_string()),
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn revoke_heir_invitation(
+    agent: &Agent,
+    claim_code: &str,
+) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("revoke_heir_invitation".into()),
+        Some(claim_code.to_string()),
+    )
+    .await
+    .unwrap()
+}
+
+/// Adds the principal to the heirs of the caller, so testaments can name it.
+/// Adds the heir the only way there is: the testator invites and the heir accepts.
+pub async fn add_heir(
+    testator: &Agent,
+    heir: &Agent,
+) -> anyhow::Result<HeirInvitation, SmartVaultErr> {
+    let args = CreateHeirInvitationArgs {
+        name: None,
+        email: None,
+        valid_for_secs: None,
+    };
+    let invitation = create_heir_invitation(testator, &args).await?;
+    accept_heir_invitation(heir, &invitation.claim_code).await
+}
+
+pub async fn remove_heir(agent: &Agent, heir: Principal) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(agent, CallType::Update("remove_heir".into()), Some(heir))
+        .await
+        .unwrap()
+}
+
+pub async fn set_check_in_schedule(
+    agent: &Agent,
+    schedule: Option<CheckInSchedule>,
+) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("set_check_in_schedule".into()),
+        Some(schedule),
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn check_in(agent: &Agent) -> anyhow::Result<CheckInStatus, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("check_in".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn get_check_in_status(agent: &Agent) -> anyhow::Result<CheckInStatus, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_check_in_status".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn request_principal_link(
+    agent: &Agent,
+    principal: Principal,
+) -> anyhow::Result<PendingPrincipalLink, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("request_principal_link".into()),
+        Some(principal),
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn confirm_principal_link(
+    agent: &Agent,
+    owner: Principal,
+) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("confirm_principal_link".into()),
+        Some(owner),
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn remove_linked_principal(
+    agent: &Agent,
+    principal: Principal,
+) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("remove_linked_principal".into()),
+        Some(principal),
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn get_linked_principals(
+    agent: &Agent,
+) -> anyhow::Result<LinkedPrincipals, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_linked_principals".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn set_recovery_guardians(
+    agent: &Agent,
+    args: Option<SetRecoveryGuardiansArgs>,
+) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("set_recovery_guardians".into()),
+        Some(args),
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn get_account_recovery(agent: &Agent) -> anyhow::Result<AccountRecovery, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Query("get_account_recovery".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn request_account_recovery(
+    agent: &Agent,
+    account: Principal,
+    new_principal: Principal,
+) -> anyhow::Result<RecoveryRequest, SmartVaultErr> {
+    let res = agent
+        .update(&get_iolo_backend_canister(), "request_account_recovery")
+        .with_arg(Encode!(&account, &new_principal).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+
+    Decode!(&res, Result<RecoveryRequest, SmartVaultErr>).unwrap()
+}
+
+pub async fn approve_account_recovery(
+    agent: &Agent,
+    account: Principal,
+    new_principal: Principal,
+) -> anyhow::Result<RecoveryRequest, SmartVaultErr> {
+    let res = agent
+        .update(&get_iolo_backend_canister(), "approve_account_recovery")
+        .with_arg(Encode!(&account, &new_principal).unwrap())
+        .call_and_wait()
+        .await
+        .unwrap();
+
+    Decode!(&res, Result<RecoveryRequest, SmartVaultErr>).unwrap()
+}
+
+pub async fn veto_account_recovery(agent: &Agent) -> anyhow::Result<(), SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("veto_account_recovery".into()),
+        Option::<Vec<u8>>::None,
+    )
+    .await
+    .unwrap()
+}
+
+pub async fn complete_account_recovery(
+    agent: &Agent,
+    account: Principal,
+) -> anyhow::Result<User, SmartVaultErr> {
+    make_call_with_agent(
+        agent,
+        CallType::Update("complete_account_recovery".into()),
+        Some(account),
+    )
+    .await
+    .unwrap()
+}
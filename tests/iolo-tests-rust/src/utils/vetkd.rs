@@ -101,6 +101,7 @@ pub async fn get_aes_256_gcm_key_for_testament(testament_id: String) -> Result<V
     let tkda: TestamentKeyDerviationArgs = TestamentKeyDerviationArgs {
         encryption_public_key: tsk.public_key(),
         testament_id: testament_id.clone(),
+        key_epoch: None,
     };
 
     // We ask the backend for a new symmetric key and also ask it to encrypt it using the public key of our transport secret key